@@ -0,0 +1,420 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+
+use crate::db;
+use crate::sql::{query, query_as, Engine, Pool};
+
+/// Version of the JSON export format.
+const EXPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Export {
+    version: u32,
+    bridges: Vec<Bridge>,
+    hubs: Vec<Hub>,
+    message_mappings: Vec<MessageMapping>,
+    reaction_mappings: Vec<ReactionMapping>,
+    topics: Vec<Topic>,
+    comment_threads: Vec<CommentThread>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Bridge {
+    name: String,
+    members: Vec<Member>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct Member {
+    chat: String,
+    #[serde(skip)]
+    bridge: String,
+    webhook_url: Option<String>,
+    send_reactions: bool,
+    receive_reactions: bool,
+    forum: bool,
+    crosspost: bool,
+    direction: String,
+    // missing from exports made before they could be changed
+    #[serde(default = "enabled")]
+    attachments: bool,
+    #[serde(default)]
+    name_format: Option<String>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct Hub {
+    name: String,
+    guild_id: i64,
+    category_id: Option<i64>,
+    #[serde(default)]
+    approval_channel: Option<i64>,
+    #[serde(default)]
+    max_pending: Option<i64>,
+    #[serde(default)]
+    max_channels_per_day: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct MessageMapping {
+    origin_chat: String,
+    origin_message: String,
+    mirror_chat: String,
+    mirror_message: String,
+    has_caption: bool,
+    created_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct ReactionMapping {
+    origin_chat: String,
+    origin_message: String,
+    summary_chat: String,
+    summary_message: String,
+    reactions: String,
+    created_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct Topic {
+    discord_thread: i64,
+    discord_channel: i64,
+    telegram_chat: i64,
+    telegram_topic: i64,
+    name: String,
+    closed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct CommentThread {
+    discord_thread: i64,
+    discord_channel: i64,
+    telegram_chat: i64,
+    telegram_message: i64,
+}
+
+fn database_file(database_url: &str) -> Result<PathBuf> {
+    if Engine::of_url(database_url) == Engine::Postgres {
+        eyre::bail!("Backups of Postgres databases are made with pg_dump and pg_restore");
+    }
+    let options = SqliteConnectOptions::from_str(database_url)?;
+    Ok(options.get_filename().to_path_buf())
+}
+
+/// Writes a consistent copy of the database to `path`, which works while the
+/// bot is running.
+pub async fn backup(database_url: &str, path: &Path) -> Result<()> {
+    database_file(database_url)?;
+    if path.exists() {
+        eyre::bail!("{} already exists", path.display());
+    }
+    let pool = SqlitePool::connect_with(SqliteConnectOptions::from_str(database_url)?).await?;
+    sqlx::query("VACUUM INTO $1")
+        .bind(path.to_string_lossy())
+        .execute(&pool)
+        .await?;
+    pool.close().await;
+
+    println!("Backed up the database to {}", path.display());
+    Ok(())
+}
+
+/// Replaces the database with a backup, keeping the current database next to it.
+/// The bot has to be stopped for this.
+pub async fn restore(database_url: &str, path: &Path) -> Result<()> {
+    let database = database_file(database_url)?;
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let backup = SqlitePool::connect_with(options)
+        .await
+        .map_err(|e| eyre::eyre!("Couldn't open {}: {e}", path.display()))?;
+    let version =
+        sqlx::query_as::<_, (i64,)>("SELECT COALESCE(MAX(version), 0) FROM schema_version")
+            .fetch_one(&backup)
+            .await
+            .map_err(|e| {
+                eyre::eyre!(
+                    "{} isn't a backup of the bot's database: {e}",
+                    path.display()
+                )
+            })?
+            .0 as usize;
+    if version > db::schema_version() {
+        eyre::bail!(
+            "The backup has schema version {version}, but this version of the bot only knows {}",
+            db::schema_version()
+        );
+    }
+
+    let mut restoring = database.clone().into_os_string();
+    restoring.push(".restoring");
+    let restoring = PathBuf::from(restoring);
+    if restoring.exists() {
+        std::fs::remove_file(&restoring)?;
+    }
+    sqlx::query("VACUUM INTO $1")
+        .bind(restoring.to_string_lossy())
+        .execute(&backup)
+        .await?;
+    backup.close().await;
+
+    if database.exists() {
+        let mut previous = database.clone().into_os_string();
+        previous.push(".before-restore");
+        std::fs::rename(&database, &previous)?;
+        println!(
+            "Moved the current database to {}",
+            Path::new(&previous).display()
+        );
+    }
+    for suffix in ["-wal", "-shm"] {
+        let mut file = database.clone().into_os_string();
+        file.push(suffix);
+        if Path::new(&file).exists() {
+            std::fs::remove_file(&file)?;
+        }
+    }
+    std::fs::rename(&restoring, &database)?;
+
+    println!(
+        "Restored {} from {} (schema version {version})",
+        database.display(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Writes the bridges, hubs and mappings to `path` as JSON.
+pub async fn export(pool: &Pool, path: &Path) -> Result<()> {
+    let bridge_names = query_as::<(String,)>("SELECT name FROM bridges ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+    let members = query_as::<Member>(
+        "SELECT chat, bridge, webhook_url, send_reactions, receive_reactions, forum, crosspost, direction, attachments, name_format FROM bridge_members ORDER BY rowid",
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut bridges = bridge_names
+        .into_iter()
+        .map(|(name,)| Bridge {
+            name,
+            members: vec![],
+        })
+        .collect::<Vec<_>>();
+    for member in members {
+        if let Some(bridge) = bridges.iter_mut().find(|b| b.name == member.bridge) {
+            bridge.members.push(member);
+        }
+    }
+
+    let export = Export {
+        version: EXPORT_VERSION,
+        bridges,
+        hubs: query_as(
+            "SELECT name, guild_id, category_id, approval_channel, max_pending, max_channels_per_day FROM hubs ORDER BY name",
+        )
+        .fetch_all(pool)
+        .await?,
+        message_mappings: query_as(
+            "SELECT origin_chat, origin_message, mirror_chat, mirror_message, has_caption, CAST(created_at AS TEXT) AS created_at FROM message_mapping ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await?,
+        reaction_mappings: query_as(
+            "SELECT origin_chat, origin_message, summary_chat, summary_message, reactions, CAST(created_at AS TEXT) AS created_at FROM reaction_mapping ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await?,
+        topics: query_as(
+            "SELECT discord_thread, discord_channel, telegram_chat, telegram_topic, name, closed FROM topic_mapping",
+        )
+        .fetch_all(pool)
+        .await?,
+        comment_threads: query_as(
+            "SELECT discord_thread, discord_channel, telegram_chat, telegram_message FROM comment_thread",
+        )
+        .fetch_all(pool)
+        .await?,
+    };
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), &export)?;
+
+    println!(
+        "Exported {} bridges, {} hubs, {} message mappings and {} reaction mappings to {}",
+        export.bridges.len(),
+        export.hubs.len(),
+        export.message_mappings.len(),
+        export.reaction_mappings.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Merges an export into the database. Chats that are already in a bridge and
+/// hubs whose name is taken are left as they are.
+pub async fn import(pool: &Pool, path: &Path) -> Result<()> {
+    let file = std::fs::File::open(path)?;
+    let export: Export = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| eyre::eyre!("{}: {e}", path.display()))?;
+    if export.version > EXPORT_VERSION {
+        eyre::bail!(
+            "{} has export format version {}, but this version of the bot only knows {EXPORT_VERSION}",
+            path.display(),
+            export.version
+        );
+    }
+
+    let created_at = match pool.engine() {
+        Engine::Sqlite => "$6",
+        Engine::Postgres => "CAST($6 AS TIMESTAMP)",
+    };
+    let insert_message = format!(
+        "INSERT INTO message_mapping (origin_chat, origin_message, mirror_chat, mirror_message, has_caption, created_at)
+        SELECT $1, $2, $3, $4, $5, COALESCE({created_at}, CURRENT_TIMESTAMP)
+        WHERE NOT EXISTS (
+            SELECT 1 FROM message_mapping WHERE origin_chat = $1 AND origin_message = $2 AND mirror_chat = $3 AND mirror_message = $4
+        )"
+    );
+    let insert_reaction = format!(
+        "INSERT INTO reaction_mapping (origin_chat, origin_message, summary_chat, summary_message, reactions, created_at)
+        VALUES ($1, $2, $3, $4, $5, COALESCE({created_at}, CURRENT_TIMESTAMP)) ON CONFLICT DO NOTHING"
+    );
+
+    let mut tx = pool.begin().await?;
+    let (mut members, mut skipped_members) = (0, 0);
+    for bridge in &export.bridges {
+        let created = query("INSERT INTO bridges (name) VALUES ($1) ON CONFLICT DO NOTHING")
+            .bind(&bridge.name)
+            .execute(&mut tx)
+            .await?
+            .rows_affected()
+            > 0;
+        let mut joined = 0;
+        for member in &bridge.members {
+            if let Err(e) = member.chat.parse::<crate::platform::ChatRef>() {
+                eyre::bail!("Invalid bridge member: {e}");
+            }
+            if let Err(e) = member.direction.parse::<db::Direction>() {
+                eyre::bail!("{e} for bridge member {}", member.chat);
+            }
+            joined += query(
+                "INSERT INTO bridge_members (chat, bridge, webhook_url, send_reactions, receive_reactions, forum, crosspost, direction, attachments, name_format) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) ON CONFLICT DO NOTHING",
+            )
+            .bind(&member.chat)
+            .bind(&bridge.name)
+            .bind(&member.webhook_url)
+            .bind(member.send_reactions)
+            .bind(member.receive_reactions)
+            .bind(member.forum)
+            .bind(member.crosspost)
+            .bind(&member.direction)
+            .bind(member.attachments)
+            .bind(&member.name_format)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        }
+        members += joined;
+        skipped_members += bridge.members.len() as u64 - joined;
+        if created && joined == 0 {
+            query("DELETE FROM bridges WHERE name = $1")
+                .bind(&bridge.name)
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+
+    let mut hubs = 0;
+    for hub in &export.hubs {
+        hubs += query(
+            "INSERT INTO hubs (name, guild_id, category_id, approval_channel, max_pending, max_channels_per_day)
+            VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING",
+        )
+        .bind(&hub.name)
+        .bind(hub.guild_id)
+        .bind(hub.category_id)
+        .bind(hub.approval_channel)
+        .bind(hub.max_pending)
+        .bind(hub.max_channels_per_day)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    }
+
+    let mut messages = 0;
+    for m in &export.message_mappings {
+        messages += query(&insert_message)
+            .bind(&m.origin_chat)
+            .bind(&m.origin_message)
+            .bind(&m.mirror_chat)
+            .bind(&m.mirror_message)
+            .bind(m.has_caption)
+            .bind(&m.created_at)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+    }
+
+    let mut reactions = 0;
+    for r in &export.reaction_mappings {
+        reactions += query(&insert_reaction)
+            .bind(&r.origin_chat)
+            .bind(&r.origin_message)
+            .bind(&r.summary_chat)
+            .bind(&r.summary_message)
+            .bind(&r.reactions)
+            .bind(&r.created_at)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+    }
+
+    for topic in &export.topics {
+        query(
+            "INSERT INTO topic_mapping (discord_thread, discord_channel, telegram_chat, telegram_topic, name, closed) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING",
+        )
+        .bind(topic.discord_thread)
+        .bind(topic.discord_channel)
+        .bind(topic.telegram_chat)
+        .bind(topic.telegram_topic)
+        .bind(&topic.name)
+        .bind(topic.closed)
+        .execute(&mut tx)
+        .await?;
+    }
+    for thread in &export.comment_threads {
+        query(
+            "INSERT INTO comment_thread (discord_thread, discord_channel, telegram_chat, telegram_message) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+        )
+        .bind(thread.discord_thread)
+        .bind(thread.discord_channel)
+        .bind(thread.telegram_chat)
+        .bind(thread.telegram_message)
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    println!(
+        "Imported {members} bridge members, {hubs} hubs, {messages} message mappings and {reactions} reaction mappings from {}",
+        path.display()
+    );
+    if skipped_members > 0 {
+        println!("Skipped {skipped_members} bridge members whose chats are already bridged");
+    }
+    if hubs < export.hubs.len() as u64 {
+        println!(
+            "Skipped {} hubs whose names are taken",
+            export.hubs.len() as u64 - hubs
+        );
+    }
+    println!("Restart the bot for it to pick up the imported bridges and hubs.");
+    Ok(())
+}
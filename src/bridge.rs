@@ -0,0 +1,632 @@
+use dashmap::DashMap;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+use crate::db;
+use crate::discord as d;
+use crate::format;
+use crate::platform::{
+    discord::DiscordPlatform,
+    irc::IrcPlatform,
+    matrix::MatrixPlatform,
+    telegram::{TelegramPlatform, GENERAL_TOPIC},
+    Author, Avatar, BridgeMessage, ChatRef, MessageRef, Mirror, Platform, ReactionUpdate,
+    Reactions, ReplyTarget, UserRef,
+};
+use crate::sql::Pool;
+use crate::telegram as t;
+
+pub struct Bridge {
+    pub db: Pool,
+    pub discord: DiscordPlatform,
+    pub telegram: TelegramPlatform,
+    pub matrix: Option<MatrixPlatform>,
+    pub irc: Option<IrcPlatform>,
+    pub deliveries: DashMap<ChatRef, Deliveries>,
+    /// Held while a channel is created in a hub, so that requests at the same
+    /// time can't both get past its daily limit.
+    pub hub_locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+/// When a message was last mirrored into a chat, and when mirroring one last
+/// failed, since the bot started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deliveries {
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+}
+
+impl Bridge {
+    /// Gets the platform of the chat, which is `None` for chats on networks
+    /// that aren't configured.
+    pub fn platform(&self, chat: &ChatRef) -> Option<&dyn Platform> {
+        match chat {
+            ChatRef::Discord(_) => Some(&self.discord),
+            ChatRef::Telegram(_) => Some(&self.telegram),
+            ChatRef::Matrix(_) => self.matrix.as_ref().map(|m| m as &dyn Platform),
+            ChatRef::Irc(_) => self.irc.as_ref().map(|i| i as &dyn Platform),
+        }
+    }
+
+    /// Gets the chat that is part of the bridge, which for a thread mirroring
+    /// a Telegram topic is its parent channel.
+    pub fn home(&self, chat: &ChatRef) -> ChatRef {
+        match *chat {
+            ChatRef::Discord(channel) => match db::thread_parent(channel) {
+                Some(parent) => ChatRef::Discord(parent),
+                None => chat.clone(),
+            },
+            _ => chat.clone(),
+        }
+    }
+
+    pub fn peers(&self, chat: &ChatRef) -> Vec<ChatRef> {
+        let chat = self.home(chat);
+        db::bridged_chats(&chat)
+            .into_iter()
+            .filter(|peer| self.platform(peer).is_some())
+            .collect()
+    }
+
+    pub fn is_bridged(&self, chat: &ChatRef) -> bool {
+        !self.peers(chat).is_empty()
+    }
+
+    /// Whether messages from `from` are mirrored to `to`, going by the
+    /// directions of the two chats.
+    pub fn relays(&self, from: &ChatRef, to: &ChatRef) -> bool {
+        db::direction(&self.home(from)).sends() && db::direction(&self.home(to)).receives()
+    }
+
+    pub fn sends(&self, chat: &ChatRef) -> bool {
+        db::direction(&self.home(chat)).sends()
+    }
+
+    /// Gets the peers that messages from the chat are mirrored to.
+    pub fn targets(&self, chat: &ChatRef) -> Vec<ChatRef> {
+        self.peers(chat)
+            .into_iter()
+            .filter(|peer| self.relays(chat, peer))
+            .collect()
+    }
+
+    fn record_delivery(&self, chat: &ChatRef, mirrors: &[Mirror]) {
+        let mut deliveries = self.deliveries.entry(chat.clone()).or_default();
+        if mirrors.is_empty() {
+            deliveries.last_failure = Some(SystemTime::now());
+        } else {
+            deliveries.last_success = Some(SystemTime::now());
+        }
+    }
+
+    /// Applies the settings of the chat a message is mirrored into: its name
+    /// format, and listing attachments by name if it doesn't take files.
+    fn adapt<'a>(&self, chat: &ChatRef, msg: &'a BridgeMessage) -> Cow<'a, BridgeMessage> {
+        let chat = self.home(chat);
+        let name_format = db::name_format(&chat);
+        let attachments = db::forwards_attachments(&chat) || msg.attachments.is_empty();
+        if name_format.is_none() && attachments {
+            return Cow::Borrowed(msg);
+        }
+        let mut msg = msg.clone();
+        if let Some(name_format) = name_format {
+            let platform = msg.source.chat().platform_name();
+            msg.author.name = format::apply_name_format(&name_format, &msg.author.name, platform);
+        }
+        if !attachments {
+            for attachment in std::mem::take(&mut msg.attachments) {
+                if !msg.text.is_empty() {
+                    msg.text.push('\n');
+                }
+                msg.text
+                    .push_str(&format!("[Attachment: {}]", attachment.filename));
+            }
+        }
+        Cow::Owned(msg)
+    }
+
+    pub fn hub_lock(&self, hub: &str) -> Arc<Mutex<()>> {
+        self.hub_locks.entry(hub.to_string()).or_default().clone()
+    }
+
+    pub async fn avatar(&self, user: &UserRef) -> Option<Avatar> {
+        match user {
+            UserRef::Discord(_) => self.discord.avatar(user).await,
+            UserRef::Telegram(_) | UserRef::TelegramChat(_) => self.telegram.avatar(user).await,
+            UserRef::Matrix(_) => self.matrix.as_ref()?.avatar(user).await,
+            UserRef::Irc(_) => None,
+        }
+    }
+
+    /// Finds the message corresponding to `message` in `chat`, whether `message`
+    /// is the original or one of its mirrors.
+    pub async fn counterpart(&self, message: &MessageRef, chat: &ChatRef) -> Option<Mirror> {
+        let chat = self.home(chat);
+        if self.home(&message.chat()) == chat {
+            return Some(Mirror {
+                message: message.clone(),
+                has_caption: false,
+            });
+        }
+        match db::get_mirrors(&self.db, message).await {
+            Ok(mirrors) => mirrors
+                .into_iter()
+                .find(|m| self.home(&m.message.chat()) == chat),
+            Err(e) => {
+                log::error!("Database lookup failed: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn relay_message(&self, msg: BridgeMessage) {
+        for chat in self.targets(&msg.source.chat()) {
+            let Some(platform) = self.platform(&chat) else {
+                continue;
+            };
+            let mirrors = platform.send(self, &chat, &self.adapt(&chat, &msg)).await;
+            self.record_delivery(&chat, &mirrors);
+            for mirror in mirrors {
+                if let Err(e) = db::insert_mapping(&self.db, &msg.source, &mirror).await {
+                    log::error!("Failed to insert message mapping: {}", e);
+                }
+            }
+        }
+    }
+
+    pub async fn relay_edit(&self, msg: BridgeMessage) {
+        for chat in self.targets(&msg.source.chat()) {
+            // an edited message with no known counterpart is ignored
+            if let (Some(platform), Some(mirror)) = (
+                self.platform(&chat),
+                self.counterpart(&msg.source, &chat).await,
+            ) {
+                platform.edit(self, &mirror, &self.adapt(&chat, &msg)).await;
+            }
+        }
+    }
+
+    pub async fn relay_delete(&self, message: MessageRef) {
+        if !self.sends(&message.chat()) {
+            return;
+        }
+        match db::delete_mapping(&self.db, &message).await {
+            Ok(mirrors) => {
+                for mirror in mirrors {
+                    if let Some(platform) = self.platform(&mirror.chat()) {
+                        platform.delete(&mirror).await;
+                    }
+                }
+            }
+            Err(e) => log::error!("Failed to delete message mapping: {}", e),
+        }
+    }
+
+    pub async fn relay_pin(&self, message: MessageRef) {
+        for chat in self.targets(&message.chat()) {
+            let Some(platform) = self.platform(&chat) else {
+                continue;
+            };
+            match self.counterpart(&message, &chat).await {
+                Some(mirror) => platform.pin(&mirror.message).await,
+                // the message was never mirrored there, so there's nothing to pin
+                None => log::debug!("Not pinning unmapped message {message:?} in {chat}"),
+            }
+        }
+    }
+
+    pub async fn relay_reaction(&self, reaction: ReactionUpdate) {
+        let source = self.home(&reaction.message.chat());
+        let origin = match db::origin_of(&self.db, &reaction.message).await {
+            Ok(origin) => origin,
+            Err(e) => {
+                log::error!("Failed to get message mapping: {}", e);
+                return;
+            }
+        };
+        for chat in self.targets(&source) {
+            if !db::should_forward_reactions(&source, &chat) {
+                continue;
+            }
+            let Some(platform) = self.platform(&chat) else {
+                continue;
+            };
+            match db::get_reaction_summary(&self.db, &origin, &chat).await {
+                Ok(Some((summary, text))) => {
+                    let mut reactions = platform.parse_reactions(&text);
+                    reaction.change.apply(&mut reactions, &reaction.reactor);
+                    if reactions.values().all(Vec::is_empty) {
+                        if platform.delete_reactions(&summary).await {
+                            if let Err(e) =
+                                db::remove_reaction_summary(&self.db, &origin, &chat).await
+                            {
+                                log::error!("Failed to remove reaction message mapping: {}", e);
+                            }
+                        }
+                    } else {
+                        let new_text = platform.format_reactions(&reactions);
+                        if platform.edit_reactions(&summary, &new_text).await {
+                            if let Err(e) =
+                                db::update_reaction_summary(&self.db, &origin, &chat, &new_text)
+                                    .await
+                            {
+                                log::error!("Failed to update reaction message mapping: {}", e);
+                            }
+                        }
+                    }
+                }
+                Ok(None) => {
+                    let Some(mirror) = self.counterpart(&reaction.message, &chat).await else {
+                        log::info!("Got reaction {reaction:?} with no known counterpart");
+                        continue;
+                    };
+                    let mut reactions = Reactions::new();
+                    reaction.change.apply(&mut reactions, &reaction.reactor);
+                    if reactions.values().all(Vec::is_empty) {
+                        log::info!("Got reaction removal for unknown message, {reaction:?}");
+                        continue;
+                    }
+                    let text = platform.format_reactions(&reactions);
+                    if let Some(summary) = platform.send_reactions(&mirror.message, &text).await {
+                        if let Err(e) =
+                            db::insert_reaction_summary(&self.db, &origin, &summary, &text).await
+                        {
+                            log::error!("Failed to insert reaction message mapping: {}", e);
+                        }
+                    }
+                }
+                Err(e) => log::error!("Failed to get reaction message mapping: {}", e),
+            }
+        }
+    }
+
+    /// Creates a thread for the Telegram topic in every Discord channel of the
+    /// bridge that mirrors topics and doesn't have one yet.
+    pub async fn mirror_topic(&self, telegram_chat: t::ChatId, topic: t::ThreadId, name: &str) {
+        let chat = ChatRef::Telegram(telegram_chat);
+        if !db::is_forum(&chat) {
+            return;
+        }
+        let existing = db::topic_threads(telegram_chat, topic);
+        for peer in self.targets(&chat) {
+            let ChatRef::Discord(channel) = peer else {
+                continue;
+            };
+            if !db::is_forum(&peer) || existing.iter().any(|t| t.discord_channel == channel) {
+                continue;
+            }
+            let Some(thread) = self.discord.create_thread(channel, name).await else {
+                continue;
+            };
+            self.insert_topic(telegram_chat, topic, channel, thread, name)
+                .await;
+        }
+    }
+
+    /// Creates a topic for the Discord thread in every Telegram forum of the
+    /// bridge that mirrors topics and doesn't have one yet.
+    pub async fn mirror_thread(&self, channel: d::ChannelId, thread: d::ChannelId, name: &str) {
+        let chat = ChatRef::Discord(channel);
+        if !db::is_forum(&chat) {
+            return;
+        }
+        for peer in self.targets(&chat) {
+            let ChatRef::Telegram(telegram_chat) = peer else {
+                continue;
+            };
+            if !db::is_forum(&peer) || db::thread_topic(thread, telegram_chat).is_some() {
+                continue;
+            }
+            let Some(topic) = self.telegram.create_topic(telegram_chat, name).await else {
+                continue;
+            };
+            self.insert_topic(telegram_chat, topic, channel, thread, name)
+                .await;
+        }
+    }
+
+    async fn insert_topic(
+        &self,
+        telegram_chat: t::ChatId,
+        telegram_topic: t::ThreadId,
+        discord_channel: d::ChannelId,
+        discord_thread: d::ChannelId,
+        name: &str,
+    ) {
+        let topic = db::Topic {
+            telegram_chat,
+            telegram_topic,
+            discord_channel,
+            discord_thread,
+            name: name.to_string(),
+            closed: false,
+        };
+        if let Err(e) = db::insert_topic(&self.db, topic).await {
+            log::error!("Failed to insert topic mapping: {e}");
+        }
+    }
+
+    /// Renames, closes or reopens the threads mirroring a Telegram topic.
+    pub async fn relay_topic_update(
+        &self,
+        telegram_chat: t::ChatId,
+        topic: t::ThreadId,
+        name: Option<&str>,
+        closed: Option<bool>,
+    ) {
+        for mirror in db::topic_threads(telegram_chat, topic) {
+            if !self.relays(
+                &ChatRef::Telegram(telegram_chat),
+                &ChatRef::Discord(mirror.discord_channel),
+            ) {
+                continue;
+            }
+            let name = name.unwrap_or(&mirror.name);
+            let closed = closed.unwrap_or(mirror.closed);
+            // changes made by the bot come back as events, which have to be ignored
+            if name == mirror.name && closed == mirror.closed {
+                continue;
+            }
+            if let Err(e) = db::update_topic(&self.db, &mirror, name, closed).await {
+                log::error!("Failed to update topic mapping: {e}");
+            }
+            self.discord
+                .edit_thread(mirror.discord_thread, name, closed)
+                .await;
+        }
+    }
+
+    /// Renames, closes or reopens the Telegram topics mirroring a thread.
+    pub async fn relay_thread_update(&self, thread: d::ChannelId, name: &str, closed: bool) {
+        for mirror in db::thread_topics(thread) {
+            if !self.relays(
+                &ChatRef::Discord(mirror.discord_channel),
+                &ChatRef::Telegram(mirror.telegram_chat),
+            ) {
+                continue;
+            }
+            if name == mirror.name && closed == mirror.closed {
+                continue;
+            }
+            if let Err(e) = db::update_topic(&self.db, &mirror, name, closed).await {
+                log::error!("Failed to update topic mapping: {e}");
+            }
+            // the General topic can't be renamed or closed like the others
+            if mirror.telegram_topic == GENERAL_TOPIC {
+                continue;
+            }
+            if name != mirror.name {
+                self.telegram
+                    .rename_topic(mirror.telegram_chat, mirror.telegram_topic, name)
+                    .await;
+            }
+            if closed != mirror.closed {
+                self.telegram
+                    .close_topic(mirror.telegram_chat, mirror.telegram_topic, closed)
+                    .await;
+            }
+        }
+    }
+
+    /// Gets the channel post that a message in a discussion group comments on,
+    /// along with the post's automatic forward to the group.
+    pub async fn commented_post(&self, msg: &BridgeMessage) -> Option<(MessageRef, t::MessageId)> {
+        let MessageRef::Telegram(group, _) = msg.source else {
+            return None;
+        };
+        let root = msg.topic?.0;
+        let post = match db::origin_of(&self.db, &MessageRef::Telegram(group, root)).await {
+            Ok(post) => post,
+            Err(e) => {
+                log::error!("Failed to get message mapping: {e}");
+                return None;
+            }
+        };
+        (post.chat() != ChatRef::Telegram(group) && self.is_bridged(&post.chat()))
+            .then_some((post, root))
+    }
+
+    /// Gets the Discord thread on the mirror of a channel post, opening one if
+    /// there isn't one yet.
+    async fn comment_thread(
+        &self,
+        channel: d::ChannelId,
+        post: &MessageRef,
+        group: t::ChatId,
+        root: t::MessageId,
+    ) -> Option<d::ChannelId> {
+        match db::comment_thread(&self.db, group, root, channel).await {
+            Ok(Some(thread)) => return Some(thread),
+            Ok(None) => {}
+            Err(e) => {
+                log::error!("Failed to get comment thread: {e}");
+                return None;
+            }
+        }
+        let Some(MessageRef::Discord(_, message)) = self
+            .counterpart(post, &ChatRef::Discord(channel))
+            .await
+            .map(|m| m.message)
+        else {
+            return None;
+        };
+        let thread = self
+            .discord
+            .create_message_thread(channel, message, "Comments")
+            .await?;
+        if let Err(e) = db::insert_comment_thread(&self.db, channel, thread, group, root).await {
+            log::error!("Failed to insert comment thread: {e}");
+        }
+        Some(thread)
+    }
+
+    /// Relays a comment from a discussion group to the threads on the mirrors
+    /// of the channel post it comments on.
+    pub async fn relay_comment(
+        &self,
+        mut msg: BridgeMessage,
+        post: MessageRef,
+        root: t::MessageId,
+    ) {
+        let MessageRef::Telegram(group, _) = msg.source else {
+            return;
+        };
+        if !self.sends(&msg.source.chat()) {
+            return;
+        }
+        // comments "reply" to the automatic forward unless they reply to another comment
+        if msg
+            .reply_to
+            .as_ref()
+            .is_some_and(|r| r.message == MessageRef::Telegram(group, root))
+        {
+            msg.reply_to = None;
+        }
+        for chat in self.targets(&post.chat()) {
+            let ChatRef::Discord(channel) = chat else {
+                continue;
+            };
+            if self
+                .comment_thread(channel, &post, group, root)
+                .await
+                .is_none()
+            {
+                continue;
+            }
+            let mirrors = self
+                .discord
+                .send(self, &chat, &self.adapt(&chat, &msg))
+                .await;
+            self.record_delivery(&chat, &mirrors);
+            for mirror in mirrors {
+                if let Err(e) = db::insert_mapping(&self.db, &msg.source, &mirror).await {
+                    log::error!("Failed to insert message mapping: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Finds the automatic forward of the channel post that a Discord thread was
+    /// opened on, which messages in the thread are relayed as comments on.
+    pub async fn comment_root(
+        &self,
+        thread: d::ChannelId,
+        parent: Option<d::ChannelId>,
+    ) -> Option<MessageRef> {
+        match db::thread_comments(&self.db, thread).await {
+            Ok(Some((parent, root))) => {
+                return self.sends(&ChatRef::Discord(parent)).then_some(root)
+            }
+            Ok(None) => {}
+            Err(e) => {
+                log::error!("Failed to get comment thread: {e}");
+                return None;
+            }
+        }
+        let parent = parent?;
+        let peers = self.peers(&ChatRef::Discord(parent));
+        if peers.is_empty() || !self.sends(&ChatRef::Discord(parent)) {
+            return None;
+        }
+        // a thread opened on a message has the same id as the message
+        let post = MessageRef::Discord(parent, d::MessageId::new(thread.get()));
+        let mirrors = match db::get_mirrors(&self.db, &post).await {
+            Ok(mirrors) => mirrors,
+            Err(e) => {
+                log::error!("Failed to get message mapping: {e}");
+                return None;
+            }
+        };
+        let (group, root) = mirrors.into_iter().find_map(|m| match m.message {
+            MessageRef::Telegram(chat, id) if !peers.contains(&ChatRef::Telegram(chat)) => {
+                Some((chat, id))
+            }
+            _ => None,
+        })?;
+        if let Err(e) = db::insert_comment_thread(&self.db, parent, thread, group, root).await {
+            log::error!("Failed to insert comment thread: {e}");
+        }
+        Some(MessageRef::Telegram(group, root))
+    }
+
+    /// Gets the chat whose settings apply to comments in the chat, which for a
+    /// Discord comment thread is the channel of the post it was opened on.
+    async fn comment_home(&self, chat: &ChatRef) -> ChatRef {
+        if let ChatRef::Discord(thread) = *chat {
+            match db::thread_comments(&self.db, thread).await {
+                Ok(Some((parent, _))) => return ChatRef::Discord(parent),
+                Ok(None) => {}
+                Err(e) => log::error!("Failed to get comment thread: {e}"),
+            }
+        }
+        self.home(chat)
+    }
+
+    /// Whether comments in `from` are mirrored to `to`, going by the directions
+    /// of the chats they belong to.
+    async fn relays_comment(&self, from: &ChatRef, to: &ChatRef) -> bool {
+        db::direction(&self.comment_home(from).await).sends()
+            && db::direction(&self.comment_home(to).await).receives()
+    }
+
+    /// Relays a message in a Discord thread on a mirrored channel post to the
+    /// post's discussion group.
+    pub async fn relay_thread_comment(&self, mut msg: BridgeMessage, root: MessageRef) {
+        let group = root.chat();
+        if !self.relays_comment(&msg.source.chat(), &group).await {
+            return;
+        }
+        if msg.reply_to.is_none() {
+            msg.reply_to = Some(ReplyTarget {
+                message: root.clone(),
+                author: Author {
+                    name: String::new(),
+                    user: None,
+                },
+                text: String::new(),
+                is_quote: false,
+            });
+        }
+        let mirrors = self
+            .telegram
+            .send(self, &group, &self.adapt(&group, &msg))
+            .await;
+        self.record_delivery(&group, &mirrors);
+        for mirror in mirrors {
+            if let Err(e) = db::insert_mapping(&self.db, &msg.source, &mirror).await {
+                log::error!("Failed to insert message mapping: {}", e);
+            }
+        }
+    }
+
+    /// Comments aren't part of a bridge, so their edits go straight to their
+    /// mirrors.
+    pub async fn relay_comment_edit(&self, msg: BridgeMessage) {
+        let mirrors = match db::get_mirrors(&self.db, &msg.source).await {
+            Ok(mirrors) => mirrors,
+            Err(e) => {
+                log::error!("Failed to get message mapping: {e}");
+                return;
+            }
+        };
+        let source = msg.source.chat();
+        for mirror in mirrors {
+            let chat = mirror.message.chat();
+            if !self.relays_comment(&source, &chat).await {
+                continue;
+            }
+            if let Some(platform) = self.platform(&chat) {
+                let home = self.comment_home(&chat).await;
+                platform.edit(self, &mirror, &self.adapt(&home, &msg)).await;
+            }
+        }
+    }
+
+    pub async fn notify(&self, chat: &ChatRef, text: &str) {
+        if let Some(platform) = self.platform(chat) {
+            platform.notify(chat, text).await;
+        }
+    }
+}
@@ -0,0 +1,680 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use eyre::Result;
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+
+use crate::db::{BridgeMember, Direction, Hub};
+use crate::discord as d;
+use crate::platform::ChatRef;
+use crate::telegram as t;
+
+/// The contents of `config.toml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub options: Options,
+    /// Bridges and hubs used to be kept in the config file; they are only read
+    /// to import them into the database.
+    pub bridges: BTreeMap<String, BridgeConfig>,
+    pub chat_mappings: Option<ChatMappings>,
+    pub hubs: BTreeMap<String, HubConfig>,
+}
+
+/// The `[options]` of the config file, which can be reloaded while running.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Options {
+    pub admins: Vec<d::UserId>,
+    pub image_channel: Option<d::ChannelId>,
+    pub retention: Retention,
+    /// Lets chats bridge to a hub by its name instead of an invite, as they
+    /// could before hubs had invites.
+    pub public_hub_names: bool,
+}
+
+/// How long message and reaction mappings are kept, after which edits,
+/// deletions, replies and reactions can no longer be matched up with the
+/// mirrors of a message.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    /// Mappings are kept forever if this isn't set or is 0.
+    pub days: Option<u32>,
+    /// Overrides of `days` for the bridges with these names.
+    pub bridges: BTreeMap<String, RetentionDays>,
+    /// Whether to compact the database after deleting mappings.
+    pub vacuum: bool,
+}
+
+/// How long a bridge keeps its mappings, overriding the global retention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(untagged, expecting = "expected a number of days or \"forever\"")]
+pub enum RetentionDays {
+    Days(u32),
+    Forever(Forever),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Forever {
+    #[serde(rename = "forever")]
+    Forever,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BridgeConfig {
+    pub members: Vec<MemberConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MemberConfig {
+    #[serde(deserialize_with = "chat_ref")]
+    pub chat: ChatRef,
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    #[serde(default = "enabled")]
+    pub send_reactions: bool,
+    #[serde(default = "enabled")]
+    pub receive_reactions: bool,
+    #[serde(default)]
+    pub forum: bool,
+    #[serde(default)]
+    pub crosspost: bool,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default = "enabled")]
+    pub attachments: bool,
+    #[serde(default)]
+    pub name_format: Option<String>,
+}
+
+/// Discord channel and Telegram chat pairs from before bridge groups.
+#[derive(Debug)]
+pub enum ChatMappings {
+    /// `discord channel id = [telegram chat id, webhook url]`
+    Table(BTreeMap<String, LegacyMapping>),
+    List(Vec<ChatMapping>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected a [telegram chat id] or [telegram chat id, webhook url] array"
+)]
+pub enum LegacyMapping {
+    Chat((i64,)),
+    Webhook((i64, String)),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMapping {
+    pub discord_channel_id: u64,
+    pub telegram_chat_id: i64,
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    // the bot used to save this under "receive_reactions"
+    #[serde(default, alias = "receive_reactions")]
+    pub reactions_forwarding: Option<ReactionsForwarding>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected a boolean, a [telegram to discord, discord to telegram] pair of booleans, or one of \"both\", \"t->d\" and \"d->t\""
+)]
+pub enum ReactionsForwarding {
+    All(bool),
+    Each([bool; 2]),
+    Mode(ReactionsMode),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum ReactionsMode {
+    #[serde(rename = "both")]
+    Both,
+    #[serde(rename = "t->d")]
+    TelegramToDiscord,
+    #[serde(rename = "d->t")]
+    DiscordToTelegram,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected a guild id or a [guild id, category id] array"
+)]
+pub enum HubConfig {
+    Server(u64),
+    Category([u64; 2]),
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn chat_ref<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ChatRef, D::Error> {
+    let chat = String::deserialize(deserializer)?;
+    chat.parse().map_err(|e| {
+        de::Error::custom(format!(
+            "{e}, expected a chat like \"discord:<channel id>\" or \"telegram:<chat id>\""
+        ))
+    })
+}
+
+impl<'de> Deserialize<'de> for ChatMappings {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ChatMappingsVisitor;
+
+        impl<'de> Visitor<'de> for ChatMappingsVisitor {
+            type Value = ChatMappings;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a table or an array of tables")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ChatMappings, A::Error> {
+                Deserialize::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(ChatMappings::Table)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<ChatMappings, A::Error> {
+                Deserialize::deserialize(de::value::SeqAccessDeserializer::new(seq))
+                    .map(ChatMappings::List)
+            }
+        }
+
+        deserializer.deserialize_any(ChatMappingsVisitor)
+    }
+}
+
+impl ReactionsForwarding {
+    /// Whether reactions are forwarded Telegram to Discord and Discord to
+    /// Telegram.
+    fn directions(self) -> (bool, bool) {
+        match self {
+            Self::All(all) => (all, all),
+            Self::Each([t_to_d, d_to_t]) => (t_to_d, d_to_t),
+            Self::Mode(ReactionsMode::Both) => (true, true),
+            Self::Mode(ReactionsMode::TelegramToDiscord) => (true, false),
+            Self::Mode(ReactionsMode::DiscordToTelegram) => (false, true),
+        }
+    }
+}
+
+impl Retention {
+    /// How many days the mappings of the bridge are kept, or `None` if they
+    /// are never pruned.
+    pub fn days_for(&self, bridge: Option<&str>) -> Option<u32> {
+        let days = match bridge.and_then(|name| self.bridges.get(name)) {
+            Some(RetentionDays::Days(days)) => Some(*days),
+            Some(RetentionDays::Forever(_)) => None,
+            None => self.days,
+        };
+        days.filter(|&days| days > 0)
+    }
+}
+
+impl From<HubConfig> for Hub {
+    fn from(hub: HubConfig) -> Self {
+        match hub {
+            HubConfig::Server(guild_id) => Hub::Server(d::GuildId::from(guild_id)),
+            HubConfig::Category([guild_id, category_id]) => {
+                Hub::Category(d::GuildId::from(guild_id), d::ChannelId::from(category_id))
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Parses the config, returning the keys it didn't recognize alongside it.
+    pub fn parse(content: &str) -> Result<(Self, Vec<String>)> {
+        let mut unknown = vec![];
+        let mut track = serde_path_to_error::Track::new();
+        let deserializer = toml::Deserializer::parse(content)?;
+        let deserializer = serde_path_to_error::Deserializer::new(deserializer, &mut track);
+        let config =
+            serde_ignored::deserialize(deserializer, |path| unknown.push(path.to_string()))
+                .map_err(|e| match track.path().to_string() {
+                    key if key == "." => eyre::eyre!("{e}"),
+                    key => eyre::eyre!("`{key}`: {e}"),
+                })?;
+        Ok((config, unknown))
+    }
+
+    /// Reads the config file, warning about keys it doesn't recognize.
+    pub fn read(path: &Path) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| eyre::eyre!("{}: {e}", path.display()))?;
+        let (config, unknown) =
+            Self::parse(&content).map_err(|e| eyre::eyre!("{}: {e}", path.display()))?;
+        for key in unknown {
+            log::warn!("{}: unknown key `{key}`", path.display());
+        }
+        Ok(config)
+    }
+
+    /// The bridges of the config file, with the chat mappings from before bridge
+    /// groups turned into bridges of their own.
+    pub fn bridges(&self) -> Vec<(String, Vec<BridgeMember>)> {
+        let mut bridges = self
+            .bridges
+            .iter()
+            .map(|(name, bridge)| {
+                let members = bridge.members.iter().map(MemberConfig::member).collect();
+                (name.clone(), members)
+            })
+            .collect::<Vec<_>>();
+
+        let mappings = match &self.chat_mappings {
+            None => vec![],
+            Some(ChatMappings::Table(mappings)) => mappings
+                .iter()
+                .filter_map(|(discord_channel_id, mapping)| {
+                    let Ok(discord_channel_id) = discord_channel_id.parse::<u64>() else {
+                        log::warn!(
+                            "Invalid Discord channel id in chat_mappings: {discord_channel_id}"
+                        );
+                        return None;
+                    };
+                    let (telegram_chat_id, webhook_url) = match mapping {
+                        LegacyMapping::Chat((chat,)) => (*chat, None),
+                        LegacyMapping::Webhook((chat, url)) => (*chat, Some(url.clone())),
+                    };
+                    Some((
+                        discord_channel_id,
+                        telegram_chat_id,
+                        webhook_url,
+                        (true, true),
+                    ))
+                })
+                .collect(),
+            Some(ChatMappings::List(mappings)) => mappings
+                .iter()
+                .map(|mapping| {
+                    (
+                        mapping.discord_channel_id,
+                        mapping.telegram_chat_id,
+                        mapping.webhook_url.clone(),
+                        mapping
+                            .reactions_forwarding
+                            .map_or((true, true), ReactionsForwarding::directions),
+                    )
+                })
+                .collect(),
+        };
+        for (discord_channel_id, telegram_chat_id, webhook_url, (t_to_d, d_to_t)) in mappings {
+            let name = (1..)
+                .map(|i| match i {
+                    1 => "bridge".to_string(),
+                    i => format!("bridge-{i}"),
+                })
+                .find(|name| bridges.iter().all(|(n, _)| n != name))
+                .expect("ran out of bridge names");
+            let members = vec![
+                BridgeMember {
+                    chat: ChatRef::Discord(d::ChannelId::from(discord_channel_id)),
+                    webhook_url: webhook_url.filter(|s| !s.is_empty()),
+                    send_reactions: d_to_t,
+                    receive_reactions: t_to_d,
+                    forum: false,
+                    crosspost: false,
+                    direction: Direction::Both,
+                    attachments: true,
+                    name_format: None,
+                },
+                BridgeMember {
+                    chat: ChatRef::Telegram(t::ChatId(telegram_chat_id)),
+                    webhook_url: None,
+                    send_reactions: t_to_d,
+                    receive_reactions: d_to_t,
+                    forum: false,
+                    crosspost: false,
+                    direction: Direction::Both,
+                    attachments: true,
+                    name_format: None,
+                },
+            ];
+            bridges.push((name, members));
+        }
+
+        bridges
+    }
+
+    pub fn hubs(&self) -> Vec<(String, Hub)> {
+        self.hubs
+            .iter()
+            .map(|(name, hub)| (name.clone(), Hub::from(*hub)))
+            .collect()
+    }
+}
+
+impl MemberConfig {
+    fn member(&self) -> BridgeMember {
+        BridgeMember {
+            chat: self.chat.clone(),
+            webhook_url: self.webhook_url.clone().filter(|s| !s.is_empty()),
+            send_reactions: self.send_reactions,
+            receive_reactions: self.receive_reactions,
+            forum: self.forum,
+            crosspost: self.crosspost,
+            direction: self.direction,
+            attachments: self.attachments,
+            name_format: self.name_format.clone(),
+        }
+    }
+}
+
+/// Validates the config file and prints what it contains, for
+/// `telediscord check-config`.
+pub fn check(path: &Path) -> Result<()> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| eyre::eyre!("{}: {e}", path.display()))?;
+    let (config, unknown) =
+        Config::parse(&content).map_err(|e| eyre::eyre!("{}: {e}", path.display()))?;
+    for key in &unknown {
+        println!("warning: {}: unknown key `{key}`", path.display());
+    }
+
+    let Options {
+        admins,
+        image_channel,
+        retention,
+        public_hub_names,
+    } = &config.options;
+    println!("admins: {admins:?}");
+    match image_channel {
+        Some(channel) => println!("image_channel: {channel}"),
+        None => println!("image_channel: none (Telegram profile pictures won't be shown)"),
+    }
+    match retention.days_for(None) {
+        Some(days) => println!("retention: {days} days"),
+        None => println!("retention: forever"),
+    }
+    for name in retention.bridges.keys() {
+        match retention.days_for(Some(name)) {
+            Some(days) => println!("  {name}: {days} days"),
+            None => println!("  {name}: forever"),
+        }
+    }
+    if *public_hub_names {
+        println!("public_hub_names: hubs can be bridged to by name, without an invite");
+    }
+
+    let bridges = config.bridges();
+    println!("{} bridges", bridges.len());
+    for (name, members) in &bridges {
+        println!("  {name}");
+        for member in members {
+            let name_format = member
+                .name_format
+                .as_ref()
+                .map(|f| format!("names shown as \"{f}\""));
+            let mut flags = vec![];
+            if !member.send_reactions {
+                flags.push("no sent reactions");
+            }
+            if !member.receive_reactions {
+                flags.push("no received reactions");
+            }
+            if member.forum {
+                flags.push("forum");
+            }
+            if member.crosspost {
+                flags.push("crosspost");
+            }
+            match member.direction {
+                Direction::Both => {}
+                Direction::In => flags.push("only receives messages"),
+                Direction::Out => flags.push("only sends messages"),
+            }
+            if !member.attachments {
+                flags.push("attachments listed by name");
+            }
+            if let Some(name_format) = &name_format {
+                flags.push(name_format);
+            }
+            if member.webhook_url.is_some() {
+                flags.push("webhook");
+            }
+            if flags.is_empty() {
+                println!("    {}", member.chat);
+            } else {
+                println!("    {} ({})", member.chat, flags.join(", "));
+            }
+        }
+    }
+
+    let hubs = config.hubs();
+    println!("{} hubs", hubs.len());
+    for (name, hub) in &hubs {
+        match hub {
+            Hub::Server(guild) => println!("  {name}: server {guild}"),
+            Hub::Category(guild, category) => {
+                println!("  {name}: category {category} in server {guild}")
+            }
+        }
+    }
+
+    if !bridges.is_empty() || !hubs.is_empty() {
+        println!("(bridges and hubs are only imported into a database that doesn't have them yet)");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> (Config, Vec<String>) {
+        Config::parse(content).unwrap()
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        let (config, unknown) = parse("");
+        assert_eq!(config.options, Options::default());
+        assert!(config.bridges().is_empty());
+        assert!(config.hubs().is_empty());
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let (config, unknown) = parse(
+            "[options]
+            admins = [123]
+            image_channel = 456
+            public_hub_names = true
+            [options.retention]
+            days = 30
+            vacuum = true",
+        );
+        assert!(unknown.is_empty());
+        assert_eq!(config.options.admins, vec![d::UserId::new(123)]);
+        assert_eq!(config.options.image_channel, Some(d::ChannelId::new(456)));
+        assert!(config.options.public_hub_names);
+        assert_eq!(config.options.retention.days, Some(30));
+        assert!(config.options.retention.vacuum);
+    }
+
+    #[test]
+    fn test_parse_unknown_keys() {
+        let (config, unknown) = parse(
+            "[options]
+            admin = [123]
+            [options.retention]
+            dayz = 30
+            [extra]
+            key = 1",
+        );
+        assert!(config.options.admins.is_empty());
+        assert_eq!(config.options.retention.days, None);
+        assert_eq!(
+            unknown,
+            vec!["extra", "options.admin", "options.retention.dayz"]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_value_names_key() {
+        let error = Config::parse("[options]\nimage_channel = \"general\"").unwrap_err();
+        assert!(error.to_string().starts_with("`options.image_channel`: "));
+
+        let error = Config::parse("[options.retention]\ndays = -1").unwrap_err();
+        assert!(error.to_string().starts_with("`options.retention.days`: "));
+
+        assert!(Config::parse("[options").is_err());
+    }
+
+    #[test]
+    fn test_retention_overrides() {
+        let (config, _) = parse(
+            "[options.retention]
+            days = 30
+            [options.retention.bridges]
+            short = 7
+            off = 0
+            kept = \"forever\"",
+        );
+        let retention = &config.options.retention;
+        assert_eq!(retention.days_for(None), Some(30));
+        assert_eq!(retention.days_for(Some("other")), Some(30));
+        assert_eq!(retention.days_for(Some("short")), Some(7));
+        assert_eq!(retention.days_for(Some("off")), None);
+        assert_eq!(retention.days_for(Some("kept")), None);
+
+        let (config, _) = parse("[options.retention]\ndays = 0");
+        assert_eq!(config.options.retention.days_for(None), None);
+
+        let error = Config::parse("[options.retention.bridges]\nname = \"never\"").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("expected a number of days or \"forever\""));
+    }
+
+    #[test]
+    fn test_parse_bridges() {
+        let (config, unknown) = parse(
+            "[[bridges.main.members]]
+            chat = \"discord:1\"
+            webhook_url = \"\"
+            direction = \"in\"
+            [[bridges.main.members]]
+            chat = \"telegram:-100\"
+            send_reactions = false
+            name_format = \"{name} ({platform})\"",
+        );
+        assert!(unknown.is_empty());
+        let bridges = config.bridges();
+        assert_eq!(bridges.len(), 1);
+        let (name, members) = &bridges[0];
+        assert_eq!(name, "main");
+        assert_eq!(members[0].chat, ChatRef::Discord(d::ChannelId::new(1)));
+        // an empty webhook url means there's no webhook
+        assert_eq!(members[0].webhook_url, None);
+        assert_eq!(members[0].direction, Direction::In);
+        assert!(members[0].send_reactions);
+        assert_eq!(members[1].chat, ChatRef::Telegram(t::ChatId(-100)));
+        assert!(!members[1].send_reactions);
+        assert!(members[1].receive_reactions);
+        assert_eq!(members[1].direction, Direction::Both);
+        assert_eq!(
+            members[1].name_format.as_deref(),
+            Some("{name} ({platform})")
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_chat() {
+        let error = Config::parse("[[bridges.main.members]]\nchat = \"slack:1\"").unwrap_err();
+        assert!(error.to_string().contains("expected a chat like"));
+    }
+
+    #[test]
+    fn test_legacy_chat_mappings_table() {
+        let (config, _) = parse(
+            "[chat_mappings]
+            1 = [-100]
+            2 = [-200, \"https://discord.com/api/webhooks/2/token\"]
+            nonsense = [-300]",
+        );
+        let bridges = config.bridges();
+        assert_eq!(bridges.len(), 2);
+        let (name, members) = &bridges[0];
+        assert_eq!(name, "bridge");
+        assert_eq!(members[0].chat, ChatRef::Discord(d::ChannelId::new(1)));
+        assert_eq!(members[1].chat, ChatRef::Telegram(t::ChatId(-100)));
+        let (name, members) = &bridges[1];
+        assert_eq!(name, "bridge-2");
+        assert_eq!(
+            members[0].webhook_url.as_deref(),
+            Some("https://discord.com/api/webhooks/2/token")
+        );
+    }
+
+    #[test]
+    fn test_legacy_chat_mappings_list() {
+        let (config, unknown) = parse(
+            "[[bridges.bridge.members]]
+            chat = \"discord:9\"
+            [[chat_mappings]]
+            discord_channel_id = 1
+            telegram_chat_id = -100
+            reactions_forwarding = \"t->d\"
+            [[chat_mappings]]
+            discord_channel_id = 2
+            telegram_chat_id = -200
+            receive_reactions = [false, true]
+            [[chat_mappings]]
+            discord_channel_id = 3
+            telegram_chat_id = -300
+            reactions_forwarding = false",
+        );
+        assert!(unknown.is_empty());
+        let bridges = config.bridges();
+        // names of bridges from the config file aren't reused
+        let names = bridges.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["bridge", "bridge-2", "bridge-3", "bridge-4"]);
+        let reactions = |members: &[BridgeMember]| {
+            (
+                members[0].send_reactions,
+                members[0].receive_reactions,
+                members[1].send_reactions,
+                members[1].receive_reactions,
+            )
+        };
+        // Telegram to Discord only: Telegram sends, Discord receives
+        assert_eq!(reactions(&bridges[1].1), (false, true, true, false));
+        // the old name of reactions_forwarding still works
+        assert_eq!(reactions(&bridges[2].1), (true, false, false, true));
+        assert_eq!(reactions(&bridges[3].1), (false, false, false, false));
+    }
+
+    #[test]
+    fn test_parse_hubs() {
+        let (config, _) = parse(
+            "[hubs]
+            server = 1
+            category = [1, 2]",
+        );
+        let hubs = config.hubs();
+        assert_eq!(hubs.len(), 2);
+        assert!(matches!(
+            hubs[0],
+            (ref name, Hub::Category(g, c)) if name == "category" && g.get() == 1 && c.get() == 2
+        ));
+        assert!(matches!(
+            hubs[1],
+            (ref name, Hub::Server(g)) if name == "server" && g.get() == 1
+        ));
+
+        let error = Config::parse("[hubs]\nbad = \"name\"").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("expected a guild id or a [guild id, category id] array"));
+    }
+}
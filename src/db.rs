@@ -1,11 +1,15 @@
 use dashmap::DashMap;
 use eyre::Result;
 use lazy_static::lazy_static;
-use sqlx::{sqlite::SqlitePool, Row};
-use std::fs;
-use toml::Table;
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::SystemTime;
 
+use crate::config::{Config, Options, Retention};
 use crate::discord as d;
+use crate::platform::{ChatRef, MessageRef, Mirror, UserRef};
+use crate::sql::{query, query_as, Engine, Pool, Transaction, Value};
 use crate::telegram as t;
 
 #[derive(Copy, Clone, Debug)]
@@ -14,7 +18,7 @@ pub enum Hub {
     Category(d::GuildId, d::ChannelId),
 }
 impl Hub {
-    fn guild(&self) -> d::GuildId {
+    pub fn guild(&self) -> d::GuildId {
         match *self {
             Self::Category(g, _) => g,
             Self::Server(g) => g,
@@ -22,193 +26,642 @@ impl Hub {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct TelegramEntry {
-    chat_id: t::ChatId,
-    receive_reactions: bool,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BridgeMember {
+    pub chat: ChatRef,
+    pub webhook_url: Option<String>,
+    pub send_reactions: bool,
+    pub receive_reactions: bool,
+    /// Forum topics of a Telegram chat, or threads of a Discord channel, are
+    /// mirrored to each other instead of being merged into one chat.
+    pub forum: bool,
+    /// Messages mirrored into a Discord announcement channel are published to
+    /// the servers following it.
+    pub crosspost: bool,
+    pub direction: Direction,
+    /// Attachments mirrored into the chat are sent as files rather than listed
+    /// by name.
+    pub attachments: bool,
+    /// How the names of authors are shown in the chat, with `{name}` and
+    /// `{platform}` filled in.
+    pub name_format: Option<String>,
+}
+
+/// Which way messages flow between a chat and the rest of its bridge, including
+/// edits, deletions and reactions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Both,
+    /// The chat only mirrors the rest of the bridge.
+    In,
+    /// The chat only feeds the rest of the bridge.
+    Out,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Both => "both",
+            Self::In => "in",
+            Self::Out => "out",
+        }
+    }
+
+    pub fn sends(self) -> bool {
+        self != Self::In
+    }
+
+    pub fn receives(self) -> bool {
+        self != Self::Out
+    }
+}
+
+impl std::str::FromStr for Direction {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "both" => Self::Both,
+            "in" => Self::In,
+            "out" => Self::Out,
+            _ => eyre::bail!("Invalid direction \"{s}\""),
+        })
+    }
+}
+
+impl BridgeMember {
+    fn new(chat: ChatRef) -> Self {
+        Self {
+            chat,
+            webhook_url: None,
+            send_reactions: true,
+            receive_reactions: true,
+            forum: false,
+            crosspost: false,
+            direction: Direction::Both,
+            attachments: true,
+            name_format: None,
+        }
+    }
 }
 
+/// A Telegram forum topic and the Discord thread (or forum post) mirroring it.
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct DiscordEntry {
-    channel_id: d::ChannelId,
-    webhook_url: Option<String>,
-    send_reactions: bool,
-    receive_reactions: bool,
+pub struct Topic {
+    pub telegram_chat: t::ChatId,
+    pub telegram_topic: t::ThreadId,
+    pub discord_channel: d::ChannelId,
+    pub discord_thread: d::ChannelId,
+    pub name: String,
+    pub closed: bool,
 }
 
 lazy_static! {
-    static ref DISCORD_TO_TELEGRAM_CACHE: DashMap<d::ChannelId, TelegramEntry> = DashMap::new();
-    static ref TELEGRAM_TO_DISCORD_CACHE: DashMap<t::ChatId, DiscordEntry> = DashMap::new();
-    static ref ADMINS: tokio::sync::RwLock<Vec<d::UserId>> = vec![].into();
-    static ref DISCORD_IMAGE_CHANNEL: tokio::sync::RwLock<Option<d::ChannelId>> = None.into();
+    static ref BRIDGES: DashMap<String, Vec<BridgeMember>> = DashMap::new();
+    static ref CHAT_BRIDGES: DashMap<ChatRef, String> = DashMap::new();
+    static ref OPTIONS: tokio::sync::RwLock<Options> = Default::default();
     static ref HUBS: DashMap<String, Hub> = DashMap::new();
+    static ref TOPICS: DashMap<(d::ChannelId, t::ChatId), Topic> = DashMap::new();
 }
 
-const CONFIG_FILE: &str = "config.toml";
-const MESSAGE_MAPPING_DB: &str = "messages.db";
+static CONFIG_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Run in order at startup, each in a transaction; the schema version is the
+/// number of migrations that have been run. Only ever append to this.
+const MIGRATIONS: &[&str] = &[
+    // the whole schema as of when versioning was introduced; older databases
+    // have only some of these tables, so IF NOT EXISTS creates the ones missing
+    "CREATE TABLE IF NOT EXISTS message_mapping (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        origin_chat TEXT NOT NULL,
+        origin_message TEXT NOT NULL,
+        mirror_chat TEXT NOT NULL,
+        mirror_message TEXT NOT NULL,
+        has_caption BOOLEAN NOT NULL DEFAULT 0,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS reaction_mapping (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        origin_chat TEXT NOT NULL,
+        origin_message TEXT NOT NULL,
+        summary_chat TEXT NOT NULL,
+        summary_message TEXT NOT NULL,
+        reactions TEXT NOT NULL DEFAULT '{}',
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS telegram_chats (
+        chat_id BIGINT PRIMARY KEY,
+        title TEXT NOT NULL,
+        is_member BOOLEAN NOT NULL DEFAULT 1,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS topic_mapping (
+        discord_thread BIGINT NOT NULL,
+        discord_channel BIGINT NOT NULL,
+        telegram_chat BIGINT NOT NULL,
+        telegram_topic BIGINT NOT NULL,
+        name TEXT NOT NULL,
+        closed BOOLEAN NOT NULL DEFAULT 0,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (discord_thread, telegram_chat)
+    );
+    CREATE TABLE IF NOT EXISTS comment_thread (
+        discord_thread BIGINT PRIMARY KEY,
+        discord_channel BIGINT NOT NULL,
+        telegram_chat BIGINT NOT NULL,
+        telegram_message BIGINT NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS bridges (
+        name TEXT PRIMARY KEY,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS bridge_members (
+        chat TEXT PRIMARY KEY,
+        bridge TEXT NOT NULL REFERENCES bridges (name) ON DELETE CASCADE,
+        webhook_url TEXT,
+        send_reactions BOOLEAN NOT NULL DEFAULT 1,
+        receive_reactions BOOLEAN NOT NULL DEFAULT 1,
+        forum BOOLEAN NOT NULL DEFAULT 0,
+        crosspost BOOLEAN NOT NULL DEFAULT 0,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS hubs (
+        name TEXT PRIMARY KEY,
+        guild_id BIGINT NOT NULL,
+        category_id BIGINT,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );",
+    // every lookup of a mapping goes through one of these
+    "CREATE INDEX IF NOT EXISTS message_mapping_origin ON message_mapping (origin_chat, origin_message);
+    CREATE INDEX IF NOT EXISTS message_mapping_mirror ON message_mapping (mirror_chat, mirror_message);
+    CREATE INDEX IF NOT EXISTS comment_thread_telegram ON comment_thread (telegram_chat, telegram_message);",
+    // there's one reaction summary per message and chat, which replacing a summary relies on
+    "DELETE FROM reaction_mapping WHERE id NOT IN (
+        SELECT MAX(id) FROM reaction_mapping GROUP BY origin_chat, origin_message, summary_chat
+    );
+    CREATE UNIQUE INDEX reaction_mapping_summary ON reaction_mapping (origin_chat, origin_message, summary_chat);",
+    "ALTER TABLE bridge_members ADD COLUMN direction TEXT NOT NULL DEFAULT 'both';",
+    "ALTER TABLE bridges ADD COLUMN created_by TEXT;",
+    "ALTER TABLE bridge_members ADD COLUMN attachments BOOLEAN NOT NULL DEFAULT 1;
+    ALTER TABLE bridge_members ADD COLUMN name_format TEXT;",
+    "CREATE TABLE hub_invites (
+        token TEXT PRIMARY KEY,
+        hub TEXT NOT NULL,
+        created_by TEXT,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        expires_at BIGINT,
+        max_uses BIGINT,
+        uses BIGINT NOT NULL DEFAULT 0
+    );",
+    "ALTER TABLE hubs ADD COLUMN approval_channel BIGINT;
+    ALTER TABLE hubs ADD COLUMN max_pending BIGINT;
+    ALTER TABLE hubs ADD COLUMN max_channels_per_day BIGINT;
+    CREATE TABLE hub_requests (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        hub TEXT NOT NULL,
+        chat TEXT NOT NULL,
+        chat_name TEXT NOT NULL,
+        requested_by TEXT,
+        direction TEXT NOT NULL DEFAULT 'both',
+        created_at BIGINT NOT NULL
+    );
+    CREATE TABLE hub_channels (
+        hub TEXT NOT NULL,
+        channel BIGINT NOT NULL,
+        created_at BIGINT NOT NULL
+    );",
+    // pruning deletes the old mappings of one chat at a time
+    "CREATE INDEX message_mapping_age ON message_mapping (origin_chat, created_at);
+    CREATE INDEX reaction_mapping_age ON reaction_mapping (origin_chat, created_at);",
+
+    "ALTER TABLE hub_requests ADD COLUMN invite TEXT;",
+];
+
+/// The same migrations for Postgres, which has no databases from before
+/// versioning. Version numbers have to match the SQLite ones.
+const POSTGRES_MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS message_mapping (
+        id BIGSERIAL PRIMARY KEY,
+        origin_chat TEXT NOT NULL,
+        origin_message TEXT NOT NULL,
+        mirror_chat TEXT NOT NULL,
+        mirror_message TEXT NOT NULL,
+        has_caption BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS reaction_mapping (
+        id BIGSERIAL PRIMARY KEY,
+        origin_chat TEXT NOT NULL,
+        origin_message TEXT NOT NULL,
+        summary_chat TEXT NOT NULL,
+        summary_message TEXT NOT NULL,
+        reactions TEXT NOT NULL DEFAULT '{}',
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS telegram_chats (
+        chat_id BIGINT PRIMARY KEY,
+        title TEXT NOT NULL,
+        is_member BOOLEAN NOT NULL DEFAULT TRUE,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS topic_mapping (
+        discord_thread BIGINT NOT NULL,
+        discord_channel BIGINT NOT NULL,
+        telegram_chat BIGINT NOT NULL,
+        telegram_topic BIGINT NOT NULL,
+        name TEXT NOT NULL,
+        closed BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (discord_thread, telegram_chat)
+    );
+    CREATE TABLE IF NOT EXISTS comment_thread (
+        discord_thread BIGINT PRIMARY KEY,
+        discord_channel BIGINT NOT NULL,
+        telegram_chat BIGINT NOT NULL,
+        telegram_message BIGINT NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS bridges (
+        name TEXT PRIMARY KEY,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS bridge_members (
+        -- stands in for SQLite's implicit rowid, which keeps members in the order they joined
+        rowid BIGSERIAL,
+        chat TEXT PRIMARY KEY,
+        bridge TEXT NOT NULL REFERENCES bridges (name) ON DELETE CASCADE,
+        webhook_url TEXT,
+        send_reactions BOOLEAN NOT NULL DEFAULT TRUE,
+        receive_reactions BOOLEAN NOT NULL DEFAULT TRUE,
+        forum BOOLEAN NOT NULL DEFAULT FALSE,
+        crosspost BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS hubs (
+        name TEXT PRIMARY KEY,
+        guild_id BIGINT NOT NULL,
+        category_id BIGINT,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );",
+    "CREATE INDEX IF NOT EXISTS message_mapping_origin ON message_mapping (origin_chat, origin_message);
+    CREATE INDEX IF NOT EXISTS message_mapping_mirror ON message_mapping (mirror_chat, mirror_message);
+    CREATE INDEX IF NOT EXISTS comment_thread_telegram ON comment_thread (telegram_chat, telegram_message);",
+    "CREATE UNIQUE INDEX reaction_mapping_summary ON reaction_mapping (origin_chat, origin_message, summary_chat);",
+    "ALTER TABLE bridge_members ADD COLUMN direction TEXT NOT NULL DEFAULT 'both';",
+    "ALTER TABLE bridges ADD COLUMN created_by TEXT;",
+    "ALTER TABLE bridge_members ADD COLUMN attachments BOOLEAN NOT NULL DEFAULT TRUE;
+    ALTER TABLE bridge_members ADD COLUMN name_format TEXT;",
+    "CREATE TABLE hub_invites (
+        token TEXT PRIMARY KEY,
+        hub TEXT NOT NULL,
+        created_by TEXT,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        expires_at BIGINT,
+        max_uses BIGINT,
+        uses BIGINT NOT NULL DEFAULT 0
+    );",
+    "ALTER TABLE hubs ADD COLUMN approval_channel BIGINT;
+    ALTER TABLE hubs ADD COLUMN max_pending BIGINT;
+    ALTER TABLE hubs ADD COLUMN max_channels_per_day BIGINT;
+    CREATE TABLE hub_requests (
+        id BIGSERIAL PRIMARY KEY,
+        hub TEXT NOT NULL,
+        chat TEXT NOT NULL,
+        chat_name TEXT NOT NULL,
+        requested_by TEXT,
+        direction TEXT NOT NULL DEFAULT 'both',
+        created_at BIGINT NOT NULL
+    );
+    CREATE TABLE hub_channels (
+        hub TEXT NOT NULL,
+        channel BIGINT NOT NULL,
+        created_at BIGINT NOT NULL
+    );",
+    "CREATE INDEX message_mapping_age ON message_mapping (origin_chat, created_at);
+    CREATE INDEX reaction_mapping_age ON reaction_mapping (origin_chat, created_at);",
+
+    "ALTER TABLE hub_requests ADD COLUMN invite TEXT;",
+];
+
+const _: () = assert!(MIGRATIONS.len() == POSTGRES_MIGRATIONS.len());
+
+pub async fn init_db(config_file: PathBuf, database_url: &str) -> Result<Pool> {
+    CONFIG_FILE
+        .set(config_file)
+        .map_err(|_| eyre::eyre!("the database is already initialized"))?;
+
+    let pool = Pool::connect(database_url).await?;
+
+    let legacy = pool.engine() == Engine::Sqlite
+        && query(
+            "SELECT 1 FROM pragma_table_info('message_mapping') WHERE name = 'discord_message_id'",
+        )
+        .fetch_optional(&pool)
+        .await?
+        .is_some();
+    if legacy {
+        let mut tx = pool.begin().await?;
+        query("ALTER TABLE message_mapping RENAME TO legacy_message_mapping")
+            .execute(&mut tx)
+            .await?;
+        query("ALTER TABLE reaction_mapping RENAME TO legacy_reaction_mapping")
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+    }
 
-pub async fn init_db() -> Result<SqlitePool> {
-    use std::path::Path;
+    let tables = match pool.engine() {
+        Engine::Sqlite => "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = $1",
+        Engine::Postgres => {
+            "SELECT 1 FROM information_schema.tables WHERE table_schema = current_schema() AND table_name = $1"
+        }
+    };
+    // the renamed tables are only dropped once their mappings are copied, so a
+    // copy that was interrupted is picked up again
+    let legacy = legacy
+        || query(tables)
+            .bind("legacy_message_mapping")
+            .fetch_optional(&pool)
+            .await?
+            .is_some();
+
+    // bridges used to be kept in the config file
+    let import = query(tables)
+        .bind("bridge_members")
+        .fetch_optional(&pool)
+        .await?
+        .is_none();
+
+    migrate(&pool).await?;
+
+    load_options().await?;
+    if import {
+        import_config(&pool).await?;
+    }
+    load_bridges(&pool).await?;
+    load_topics(&pool).await?;
 
-    // Create the database file if it doesn't exist
-    if !Path::new(MESSAGE_MAPPING_DB).exists() {
-        std::fs::File::create(MESSAGE_MAPPING_DB)?;
+    if legacy {
+        migrate_legacy_mappings(&pool).await?;
     }
-    let pool = SqlitePool::connect("sqlite:messages.db").await?;
 
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS message_mapping (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            discord_message_id BIGINT NOT NULL,
-            telegram_message_id BIGINT NOT NULL,
-            telegram_chat_id BIGINT NOT NULL,
-            has_caption BOOLEAN NOT NULL DEFAULT 0,
-            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    Ok(pool)
+}
+
+/// The schema version this version of the bot migrates databases to.
+pub fn schema_version() -> usize {
+    MIGRATIONS.len()
+}
+
+async fn migrate(pool: &Pool) -> Result<()> {
+    query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
         )",
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS reaction_mapping (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            discord_message_id BIGINT NOT NULL,
-            telegram_message_id BIGINT NOT NULL,
-            telegram_chat_id BIGINT NOT NULL,
-            reactions TEXT NOT NULL DEFAULT '{}',
-            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-        )",
+    let (current,) =
+        query_as::<(i64,)>("SELECT CAST(COALESCE(MAX(version), 0) AS BIGINT) FROM schema_version")
+            .fetch_one(pool)
+            .await?;
+    let current = current as usize;
+    let migrations = match pool.engine() {
+        Engine::Sqlite => MIGRATIONS,
+        Engine::Postgres => POSTGRES_MIGRATIONS,
+    };
+    if current > migrations.len() {
+        eyre::bail!(
+            "The database has schema version {current}, but this version of the bot only knows {}",
+            migrations.len()
+        );
+    }
+    for (i, migration) in migrations.iter().enumerate().skip(current) {
+        let version = i + 1;
+        log::info!("Migrating the database to schema version {version}");
+        let mut tx = pool.begin().await?;
+        tx.execute_script(migration).await?;
+        query("INSERT INTO schema_version (version) VALUES ($1)")
+            .bind(version as i64)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+    }
+
+    Ok(())
+}
+
+// Before bridge groups, every mapping was between a Discord message and a
+// Telegram message, with the Discord channel implied by the chat mapping.
+async fn migrate_legacy_mappings(pool: &Pool) -> Result<()> {
+    fn discord_channel(telegram_chat_id: t::ChatId) -> Option<d::ChannelId> {
+        bridged_chats(&ChatRef::Telegram(telegram_chat_id))
+            .into_iter()
+            .find_map(|c| match c {
+                ChatRef::Discord(c) => Some(c),
+                _ => None,
+            })
+    }
+
+    let mut tx = pool.begin().await?;
+
+    // the reaction summaries look their messages up by the Telegram side
+    query(
+        "CREATE INDEX IF NOT EXISTS legacy_message_mapping_telegram ON legacy_message_mapping (telegram_message_id, telegram_chat_id)",
     )
-    .execute(&pool)
+    .execute(&mut tx)
     .await?;
 
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS telegram_chats (
-            chat_id BIGINT PRIMARY KEY,
-            title TEXT NOT NULL,
-            is_member BOOLEAN NOT NULL DEFAULT 1,
-            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-        )",
+    let messages = query_as::<(i64, i64, i64, bool)>(
+        "SELECT discord_message_id, telegram_message_id, telegram_chat_id, has_caption FROM legacy_message_mapping",
     )
-    .execute(&pool)
+    .fetch_all(&mut tx)
     .await?;
+    for (discord_message_id, telegram_message_id, telegram_chat_id, has_caption) in messages {
+        let telegram_chat_id = t::ChatId(telegram_chat_id);
+        let Some(discord_channel_id) = discord_channel(telegram_chat_id) else {
+            continue;
+        };
+        query(
+            "INSERT INTO message_mapping (origin_chat, origin_message, mirror_chat, mirror_message, has_caption) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(ChatRef::Discord(discord_channel_id).to_string())
+        .bind(discord_message_id.to_string())
+        .bind(ChatRef::Telegram(telegram_chat_id).to_string())
+        .bind(telegram_message_id.to_string())
+        .bind(has_caption)
+        .execute(&mut tx)
+        .await?;
+    }
 
-    load_config().await?;
-
-    Ok(pool)
-}
-
-pub async fn insert_mapping(
-    pool: &SqlitePool,
-    discord_message_id: d::MessageId,
-    telegram_message_id: t::MessageId,
-    telegram_chat_id: t::ChatId,
-    has_caption: bool,
-) -> Result<()> {
-    sqlx::query(
-        "INSERT INTO message_mapping (discord_message_id, telegram_message_id, telegram_chat_id, has_caption) VALUES (?, ?, ?, ?)",
+    let reactions = query_as::<(i64, i64, i64, String)>(
+        "SELECT discord_message_id, telegram_message_id, telegram_chat_id, reactions FROM legacy_reaction_mapping",
     )
-    .bind(i64::from(discord_message_id))
-    .bind(telegram_message_id.0 as i64)
-    .bind(telegram_chat_id.0)
-    .bind(has_caption)
-    .execute(pool)
+    .fetch_all(&mut tx)
     .await?;
+    for (discord_message_id, telegram_message_id, telegram_chat_id, reactions) in reactions {
+        let telegram_chat_id = t::ChatId(telegram_chat_id);
+        let Some(discord_channel_id) = discord_channel(telegram_chat_id) else {
+            continue;
+        };
+        // the Discord message of a legacy mapping is always treated as the
+        // origin, so a summary posted on Discord has to be traced back to it
+        let (origin_message, summary_chat, summary_message) = if reactions.starts_with("<b>") {
+            (
+                discord_message_id,
+                ChatRef::Telegram(telegram_chat_id),
+                telegram_message_id,
+            )
+        } else {
+            let Some((origin_message,)) = query_as::<(i64,)>(
+                "SELECT discord_message_id FROM legacy_message_mapping WHERE telegram_message_id = $1 AND telegram_chat_id = $2",
+            )
+            .bind(telegram_message_id)
+            .bind(telegram_chat_id.0)
+            .fetch_optional(&mut tx)
+            .await?
+            else {
+                continue;
+            };
+            (
+                origin_message,
+                ChatRef::Discord(discord_channel_id),
+                discord_message_id,
+            )
+        };
+        query(
+            "INSERT INTO reaction_mapping (origin_chat, origin_message, summary_chat, summary_message, reactions) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (origin_chat, origin_message, summary_chat) DO UPDATE SET
+            summary_message = excluded.summary_message,
+            reactions = excluded.reactions,
+            created_at = CURRENT_TIMESTAMP",
+        )
+        .bind(ChatRef::Discord(discord_channel_id).to_string())
+        .bind(origin_message.to_string())
+        .bind(summary_chat.to_string())
+        .bind(summary_message.to_string())
+        .bind(reactions)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    query("DROP TABLE legacy_message_mapping")
+        .execute(&mut tx)
+        .await?;
+    query("DROP TABLE legacy_reaction_mapping")
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
 
     Ok(())
 }
 
-pub async fn get_telegram_message_id(
-    pool: &SqlitePool,
-    discord_message_id: d::MessageId,
-) -> Result<Vec<(t::MessageId, bool)>> {
-    let result = sqlx::query(
-        "SELECT telegram_message_id, has_caption FROM message_mapping WHERE discord_message_id = ?",
+fn message_ref(chat: &str, id: &str) -> Result<MessageRef> {
+    MessageRef::from_parts(chat.parse()?, id)
+}
+
+pub async fn insert_mapping(pool: &Pool, origin: &MessageRef, mirror: &Mirror) -> Result<()> {
+    query(
+        "INSERT INTO message_mapping (origin_chat, origin_message, mirror_chat, mirror_message, has_caption) VALUES ($1, $2, $3, $4, $5)",
     )
-    .bind(i64::from(discord_message_id))
-    .fetch_all(pool)
+    .bind(origin.chat().to_string())
+    .bind(origin.id())
+    .bind(mirror.message.chat().to_string())
+    .bind(mirror.message.id())
+    .bind(mirror.has_caption)
+    .execute(pool)
     .await?;
 
-    Ok(result
-        .into_iter()
-        .map(|row| {
-            (
-                t::MessageId(row.get::<i64, _>(0) as i32),
-                row.get::<bool, _>(1),
-            )
-        })
-        .collect())
+    Ok(())
 }
 
-pub async fn get_discord_message_id(
-    pool: &SqlitePool,
-    telegram_message_id: t::MessageId,
-    telegram_chat_id: t::ChatId,
-) -> Result<Vec<d::MessageId>> {
-    let result = sqlx::query(
-        "SELECT discord_message_id FROM message_mapping WHERE telegram_message_id = ? AND telegram_chat_id = ?",
+/// Finds the message that `message` was mirrored from, or `message` itself if
+/// it isn't a mirror.
+pub async fn origin_of(pool: &Pool, message: &MessageRef) -> Result<MessageRef> {
+    let result = query_as::<(String, String)>(
+        "SELECT origin_chat, origin_message FROM message_mapping WHERE mirror_chat = $1 AND mirror_message = $2 LIMIT 1",
     )
-    .bind(telegram_message_id.0 as i64)
-    .bind(telegram_chat_id.0)
-    .fetch_all(pool)
+    .bind(message.chat().to_string())
+    .bind(message.id())
+    .fetch_optional(pool)
     .await?;
 
-    Ok(result
-        .into_iter()
-        .map(|row| d::MessageId::from(row.get::<i64, _>(0) as u64))
-        .collect())
+    match result {
+        Some((chat, id)) => message_ref(&chat, &id),
+        None => Ok(message.clone()),
+    }
 }
 
-pub async fn delete_by_discord(
-    pool: &SqlitePool,
-    discord_message_id: d::MessageId,
-) -> Result<Vec<t::MessageId>> {
-    let result = sqlx::query(
-        "DELETE FROM message_mapping WHERE discord_message_id = ? RETURNING telegram_message_id",
+/// Gets every other message that is a copy of `message`, including the origin
+/// if `message` is itself a mirror.
+pub async fn get_mirrors(pool: &Pool, message: &MessageRef) -> Result<Vec<Mirror>> {
+    let origin = origin_of(pool, message).await?;
+    let result = query_as::<(String, String, bool)>(
+        "SELECT mirror_chat, mirror_message, has_caption FROM message_mapping WHERE origin_chat = $1 AND origin_message = $2",
     )
-    .bind(i64::from(discord_message_id))
+    .bind(origin.chat().to_string())
+    .bind(origin.id())
     .fetch_all(pool)
     .await?;
 
-    Ok(result
-        .into_iter()
-        .map(|row| t::MessageId(row.get::<i64, _>(0) as i32))
-        .collect())
+    let mut mirrors = vec![Mirror {
+        message: origin,
+        has_caption: false,
+    }];
+    for (chat, id, has_caption) in result {
+        mirrors.push(Mirror {
+            message: message_ref(&chat, &id)?,
+            has_caption,
+        });
+    }
+    mirrors.retain(|m| m.message != *message);
+    Ok(mirrors)
 }
 
-pub async fn delete_by_telegram(
-    pool: &SqlitePool,
-    telegram_message_id: t::MessageId,
-    telegram_chat_id: t::ChatId,
-) -> Result<Vec<d::MessageId>> {
-    let result = sqlx::query(
-        "DELETE FROM message_mapping WHERE telegram_message_id = ? AND telegram_chat_id = ? RETURNING discord_message_id",
+pub async fn delete_mapping(pool: &Pool, message: &MessageRef) -> Result<Vec<MessageRef>> {
+    let origin = origin_of(pool, message).await?;
+    let result = query_as::<(String, String)>(
+        "DELETE FROM message_mapping WHERE origin_chat = $1 AND origin_message = $2 RETURNING mirror_chat, mirror_message",
     )
-    .bind(telegram_message_id.0 as i64)
-    .bind(telegram_chat_id.0)
+    .bind(origin.chat().to_string())
+    .bind(origin.id())
     .fetch_all(pool)
     .await?;
 
-    Ok(result
-        .into_iter()
-        .map(|row| d::MessageId::from(row.get::<i64, _>(0) as u64))
-        .collect())
+    let mut deleted = vec![origin];
+    for (chat, id) in result {
+        deleted.push(message_ref(&chat, &id)?);
+    }
+    deleted.retain(|m| m != message);
+    Ok(deleted)
 }
 
-pub async fn insert_reaction_mapping(
-    pool: &SqlitePool,
-    discord_message_id: d::MessageId,
-    telegram_message_id: t::MessageId,
-    telegram_chat_id: t::ChatId,
+// Reaction summaries are keyed by the origin of the message that was reacted
+// to, with one summary per chat in the bridge.
+pub async fn insert_reaction_summary(
+    pool: &Pool,
+    origin: &MessageRef,
+    summary: &MessageRef,
     reactions: &str,
 ) -> Result<()> {
-    sqlx::query(
-        "INSERT OR REPLACE INTO reaction_mapping (discord_message_id, telegram_message_id, telegram_chat_id, reactions) VALUES (?, ?, ?, ?)",
+    query(
+        "INSERT INTO reaction_mapping (origin_chat, origin_message, summary_chat, summary_message, reactions) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (origin_chat, origin_message, summary_chat) DO UPDATE SET
+            summary_message = excluded.summary_message,
+            reactions = excluded.reactions,
+            created_at = CURRENT_TIMESTAMP",
     )
-    .bind(i64::from(discord_message_id))
-    .bind(telegram_message_id.0 as i64)
-    .bind(telegram_chat_id.0)
+    .bind(origin.chat().to_string())
+    .bind(origin.id())
+    .bind(summary.chat().to_string())
+    .bind(summary.id())
     .bind(reactions)
     .execute(pool)
     .await?;
@@ -216,337 +669,726 @@ pub async fn insert_reaction_mapping(
     Ok(())
 }
 
-pub async fn get_telegram_reaction_message_id(
-    pool: &SqlitePool,
-    discord_message_id: d::MessageId,
-) -> Result<Option<(t::MessageId, String)>> {
-    let result = sqlx::query_as::<_, (i64, String)>(
-        "SELECT telegram_message_id, reactions FROM reaction_mapping WHERE discord_message_id = ?",
-    )
-    .bind(i64::from(discord_message_id))
-    .fetch_optional(pool)
-    .await?;
-
-    Ok(result.map(|(id, reactions)| (t::MessageId(id as i32), reactions)))
-}
-
-pub async fn get_discord_reaction_message_id(
-    pool: &SqlitePool,
-    telegram_message_id: t::MessageId,
-    telegram_chat_id: t::ChatId,
-) -> Result<Option<(d::MessageId, String)>> {
-    let result = sqlx::query_as::<_, (i64, String)>(
-        "SELECT discord_message_id, reactions FROM reaction_mapping WHERE telegram_message_id = ? AND telegram_chat_id = ?",
+pub async fn get_reaction_summary(
+    pool: &Pool,
+    origin: &MessageRef,
+    chat: &ChatRef,
+) -> Result<Option<(MessageRef, String)>> {
+    let result = query_as::<(String, String)>(
+        "SELECT summary_message, reactions FROM reaction_mapping WHERE origin_chat = $1 AND origin_message = $2 AND summary_chat = $3",
     )
-    .bind(telegram_message_id.0 as i64)
-    .bind(telegram_chat_id.0)
+    .bind(origin.chat().to_string())
+    .bind(origin.id())
+    .bind(chat.to_string())
     .fetch_optional(pool)
     .await?;
 
-    Ok(result.map(|(id, reactions)| (d::MessageId::from(id as u64), reactions)))
-}
-
-pub async fn update_telegram_reaction_mapping(
-    pool: &SqlitePool,
-    discord_message_id: d::MessageId,
-    reactions: &str,
-) -> Result<()> {
-    sqlx::query("UPDATE reaction_mapping SET reactions = ? WHERE discord_message_id = ?")
-        .bind(reactions)
-        .bind(i64::from(discord_message_id))
-        .execute(pool)
-        .await?;
-
-    Ok(())
+    match result {
+        Some((id, reactions)) => Ok(Some((
+            MessageRef::from_parts(chat.clone(), &id)?,
+            reactions,
+        ))),
+        None => Ok(None),
+    }
 }
 
-pub async fn update_discord_reaction_mapping(
-    pool: &SqlitePool,
-    telegram_message_id: t::MessageId,
-    telegram_chat_id: t::ChatId,
+pub async fn update_reaction_summary(
+    pool: &Pool,
+    origin: &MessageRef,
+    chat: &ChatRef,
     reactions: &str,
 ) -> Result<()> {
-    sqlx::query(
-        "UPDATE reaction_mapping SET reactions = ? WHERE telegram_message_id = ? AND telegram_chat_id = ?",
+    query(
+        "UPDATE reaction_mapping SET reactions = $1 WHERE origin_chat = $2 AND origin_message = $3 AND summary_chat = $4",
     )
     .bind(reactions)
-    .bind(telegram_message_id.0 as i64)
-    .bind(telegram_chat_id.0)
+    .bind(origin.chat().to_string())
+    .bind(origin.id())
+    .bind(chat.to_string())
     .execute(pool)
     .await?;
 
     Ok(())
 }
 
-pub async fn remove_reaction_mapping_by_discord(
-    pool: &SqlitePool,
-    discord_message_id: d::MessageId,
+pub async fn remove_reaction_summary(
+    pool: &Pool,
+    origin: &MessageRef,
+    chat: &ChatRef,
 ) -> Result<()> {
-    sqlx::query("DELETE FROM reaction_mapping WHERE discord_message_id = ?")
-        .bind(i64::from(discord_message_id))
-        .execute(pool)
-        .await?;
-
-    Ok(())
-}
-
-pub async fn remove_reaction_mapping_by_telegram(
-    pool: &SqlitePool,
-    telegram_message_id: t::MessageId,
-    telegram_chat_id: t::ChatId,
-) -> Result<()> {
-    sqlx::query(
-        "DELETE FROM reaction_mapping WHERE telegram_message_id = ? AND telegram_chat_id = ?",
+    query(
+        "DELETE FROM reaction_mapping WHERE origin_chat = $1 AND origin_message = $2 AND summary_chat = $3",
     )
-    .bind(telegram_message_id.0 as i64)
-    .bind(telegram_chat_id.0)
+    .bind(origin.chat().to_string())
+    .bind(origin.id())
+    .bind(chat.to_string())
     .execute(pool)
     .await?;
 
     Ok(())
 }
 
-async fn load_config() -> Result<()> {
-    if !std::path::Path::new(CONFIG_FILE).exists() {
-        fs::write(CONFIG_FILE, "")?;
-    }
-
-    let content = fs::read_to_string(CONFIG_FILE)?;
-    let config: Table = toml::from_str(&content)?;
-
-    // Load chat mappings
-    if let Some(chat_mappings) = config.get("chat_mappings") {
-        match chat_mappings {
-            // old format
-            toml::Value::Table(chat_mappings) => {
-                for (discord_channel_id, telegram_chat_id) in chat_mappings {
-                    let discord_channel_id = d::ChannelId::from(discord_channel_id.parse::<u64>()?);
-                    let vals = telegram_chat_id.as_array().unwrap();
-                    let telegram_chat_id = t::ChatId(vals[0].as_integer().unwrap() as i64);
-                    let webhook_url = vals.get(1).and_then(|v| v.as_str()).map(String::from);
-
-                    DISCORD_TO_TELEGRAM_CACHE.insert(
-                        discord_channel_id,
-                        TelegramEntry {
-                            chat_id: telegram_chat_id,
-                            receive_reactions: true,
-                        },
-                    );
-                    TELEGRAM_TO_DISCORD_CACHE.insert(
-                        telegram_chat_id,
-                        DiscordEntry {
-                            channel_id: discord_channel_id,
-                            webhook_url,
-                            send_reactions: true,
-                            receive_reactions: true,
-                        },
-                    );
-                }
-            }
-            // new format
-            toml::Value::Array(chat_mappings) => {
-                for chat_mapping in chat_mappings {
-                    if let Some(chat_mapping) = chat_mapping.as_table() {
-                        let discord_channel_id = d::ChannelId::from(
-                            chat_mapping
-                                .get("discord_channel_id")
-                                .unwrap()
-                                .as_integer()
-                                .unwrap() as u64,
-                        );
-                        let telegram_chat_id = t::ChatId(
-                            chat_mapping
-                                .get("telegram_chat_id")
-                                .unwrap()
-                                .as_integer()
-                                .unwrap() as i64,
-                        );
-                        let webhook_url = chat_mapping
-                            .get("webhook_url")
-                            .and_then(|v| v.as_str())
-                            .map(String::from);
-                        let reactions_forwarding = if let Some(reactions_forwarding) =
-                            chat_mapping.get("reactions_forwarding")
-                        {
-                            match reactions_forwarding {
-                                toml::Value::Array(a) => {
-                                    if a.len() != 2 {
-                                        panic!("Invalid reactions forwarding mode: {a:?}");
-                                    }
-                                    let t_to_d = a[0].as_bool().unwrap();
-                                    let d_to_t = a[1].as_bool().unwrap();
-                                    (t_to_d, d_to_t)
-                                }
-                                toml::Value::Boolean(true) => (true, true),
-                                toml::Value::Boolean(false) => (false, false),
-                                toml::Value::String(s) => match s.as_str() {
-                                    "both" => (true, true),
-                                    "t->d" => (true, false),
-                                    "d->t" => (false, true),
-                                    _ => panic!("Invalid reactions forwarding mode: {s}"),
-                                },
-                                _ => panic!(
-                                    "Invalid reactions forwarding mode: {reactions_forwarding:?}"
-                                ),
-                            }
-                        } else {
-                            (true, true)
-                        };
-                        DISCORD_TO_TELEGRAM_CACHE.insert(
-                            discord_channel_id,
-                            TelegramEntry {
-                                chat_id: telegram_chat_id,
-                                receive_reactions: reactions_forwarding.1,
-                            },
-                        );
-                        TELEGRAM_TO_DISCORD_CACHE.insert(
-                            telegram_chat_id,
-                            DiscordEntry {
-                                channel_id: discord_channel_id,
-                                webhook_url,
-                                send_reactions: reactions_forwarding.1,
-                                receive_reactions: reactions_forwarding.0,
-                            },
-                        );
+/// Deletes the message and reaction mappings that are older than the retention
+/// period of their chat's bridge, returning how many of each were deleted.
+pub async fn prune_mappings(pool: &Pool) -> Result<(u64, u64)> {
+    let retention = retention().await;
+    if retention.days_for(None).is_none()
+        && retention
+            .bridges
+            .keys()
+            .all(|name| retention.days_for(Some(name)).is_none())
+    {
+        return Ok((0, 0));
+    }
+
+    let chats = query_as::<(String,)>(
+        "SELECT origin_chat FROM message_mapping UNION SELECT origin_chat FROM reaction_mapping",
+    )
+    .fetch_all(pool)
+    .await?;
+    let cutoff = match pool.engine() {
+        Engine::Sqlite => "datetime('now', $2)",
+        Engine::Postgres => "CURRENT_TIMESTAMP + CAST($2 AS INTERVAL)",
+    };
+    let delete_messages =
+        format!("DELETE FROM message_mapping WHERE origin_chat = $1 AND created_at < {cutoff}");
+    let delete_reactions =
+        format!("DELETE FROM reaction_mapping WHERE origin_chat = $1 AND created_at < {cutoff}");
+    let (mut messages, mut reactions) = (0, 0);
+    for (chat,) in chats {
+        let bridge = match chat.parse::<ChatRef>() {
+            Ok(parsed) => {
+                let home = match parsed {
+                    ChatRef::Discord(channel) => {
+                        thread_parent(channel).map_or(parsed, ChatRef::Discord)
                     }
+                    _ => parsed,
+                };
+                match bridge_of(&home) {
+                    Some(bridge) => Some(bridge),
+                    None => comment_thread_parent(pool, &home)
+                        .await?
+                        .and_then(|parent| bridge_of(&ChatRef::Discord(parent))),
                 }
             }
-            _ => {}
-        }
+            Err(_) => None,
+        };
+        let Some(days) = retention.days_for(bridge.as_deref()) else {
+            continue;
+        };
+        let age = format!("-{days} days");
+        messages += query(&delete_messages)
+            .bind(&chat)
+            .bind(&age)
+            .execute(pool)
+            .await?
+            .rows_affected();
+        reactions += query(&delete_reactions)
+            .bind(&chat)
+            .bind(&age)
+            .execute(pool)
+            .await?
+            .rows_affected();
+    }
+    if retention.vacuum && messages + reactions > 0 {
+        query("VACUUM").execute(pool).await?;
     }
 
-    if let Some(hubs) = config.get("hubs").and_then(|v| v.as_table()) {
-        for (name, hub) in hubs {
-            if let Some(guild_id) = hub.as_integer() {
-                let guild_id = d::GuildId::from(guild_id as u64);
-                if let Some(prev) = HUBS.insert(name.clone(), Hub::Server(guild_id)) {
-                    log::warn!(
-                        "Multiple hubs named \"{name}\": {prev:?} and Hub::Server({guild_id})"
-                    );
-                };
-                continue;
-            }
-            if let Some(ids) = hub
-                .as_array()
-                .and_then(|v| v.iter().map(|i| i.as_integer()).collect::<Option<Vec<_>>>())
-                .and_then(|v| <[i64; 2]>::try_from(v).ok())
-            {
-                let [guild_id, channel_id] = ids.map(|i| i as u64);
-                let guild_id = d::GuildId::from(guild_id);
-                let channel_id = d::ChannelId::from(channel_id);
-                if let Some(prev) = HUBS.insert(name.clone(), Hub::Category(guild_id, channel_id)) {
-                    log::warn!("Multiple hubs named \"{name}\": {prev:?} and Hub::Category({guild_id}, {channel_id})");
-                };
-                continue;
-            }
-            log::warn!("Invalid format for hub named \"{name}\": {hub:?}. Should be either a single guild id or a 2-item array of guild id and category id")
-        }
+    Ok((messages, reactions))
+}
+
+fn config_file() -> &'static Path {
+    CONFIG_FILE
+        .get()
+        .map_or(Path::new("config.toml"), |path| path)
+}
+
+fn read_config() -> Result<Config> {
+    if !config_file().exists() {
+        std::fs::write(config_file(), "")?;
     }
 
-    // Load admins
-    *ADMINS.write().await = config
-        .get("options")
-        .and_then(|t| t.get("admins"))
-        .and_then(|v| v.as_array())
-        .unwrap_or(&vec![])
-        .into_iter()
-        .filter_map(|u| u.as_integer())
-        .map(|i| i as u64)
-        .map(Into::into)
-        .collect();
-
-    *DISCORD_IMAGE_CHANNEL.write().await = config
-        .get("options")
-        .and_then(|t| t.get("image_channel"))
-        .and_then(|v| v.as_integer())
-        .map(|i| i as u64)
-        .map(Into::into);
+    Config::read(config_file())
+}
+
+async fn load_options() -> Result<()> {
+    *OPTIONS.write().await = read_config()?.options;
 
     Ok(())
 }
 
-async fn save_config() -> Result<()> {
-    let mut mappings = vec![];
-
-    use toml::Value;
+pub fn config_modified() -> Option<std::time::SystemTime> {
+    std::fs::metadata(config_file())
+        .and_then(|m| m.modified())
+        .ok()
+}
 
-    fn int(x: impl Into<u64>) -> Value {
-        Value::Integer(x.into() as i64)
+/// Reads the options from the config file again, keeping the current ones if
+/// the file is invalid.
+pub async fn reload_options() {
+    fn ids<T: std::fmt::Display>(ids: impl IntoIterator<Item = T>) -> String {
+        let ids = ids.into_iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        format!("[{}]", ids.join(", "))
     }
 
-    for entry in TELEGRAM_TO_DISCORD_CACHE.iter() {
-        let mut mapping = Table::new();
-        mapping.insert("telegram_chat_id".to_string(), int(entry.key().0 as u64));
-        mapping.insert(
-            "discord_channel_id".to_string(),
-            int(entry.value().channel_id),
+    let path = config_file();
+    let parsed = if path.exists() {
+        Config::read(path).map(|config| config.options)
+    } else {
+        Err(eyre::eyre!("{} doesn't exist", path.display()))
+    };
+    let options = match parsed {
+        Ok(options) => options,
+        Err(e) => {
+            log::error!("Not reloading options: {e}");
+            return;
+        }
+    };
+    let mut current = OPTIONS.write().await;
+    if *current == options {
+        log::info!("Reloaded {}, nothing changed", path.display());
+        return;
+    }
+    if current.admins != options.admins {
+        log::info!(
+            "Reloaded {}: admins changed from {} to {}",
+            path.display(),
+            ids(&current.admins),
+            ids(&options.admins)
         );
-        mapping.insert(
-            "webhook_url".to_string(),
-            Value::String(entry.value().webhook_url.clone().unwrap_or_default()),
+    }
+    if current.image_channel != options.image_channel {
+        log::info!(
+            "Reloaded {}: image_channel changed from {} to {}",
+            path.display(),
+            ids(current.image_channel),
+            ids(options.image_channel)
         );
-        mapping.insert(
-            "receive_reactions".to_string(),
-            Value::Array(vec![
-                Value::Boolean(entry.value().receive_reactions),
-                Value::Boolean(entry.value().send_reactions),
-            ]),
+    }
+    if current.public_hub_names != options.public_hub_names {
+        log::info!(
+            "Reloaded {}: public_hub_names changed from {} to {}",
+            path.display(),
+            current.public_hub_names,
+            options.public_hub_names
         );
-        mappings.push(Value::Table(mapping));
     }
+    if current.retention != options.retention {
+        log::info!(
+            "Reloaded {}: retention changed from {:?} to {:?}",
+            path.display(),
+            current.retention,
+            options.retention
+        );
+    }
+    *current = options;
+}
 
-    let mut hubs = Table::new();
+/// Copies the bridges and hubs from the config file, where they were kept
+/// before they moved to the database, including chat mappings from before
+/// bridge groups.
+async fn import_config(pool: &Pool) -> Result<()> {
+    let config = read_config()?;
 
-    for entry in &*HUBS {
-        hubs.insert(
-            entry.key().to_string(),
-            match *entry.value() {
-                Hub::Server(g) => int(g),
-                Hub::Category(g, c) => Value::Array(vec![int(g), int(c)]),
-            },
+    for (name, members) in config.bridges() {
+        insert_bridge(name, members);
+    }
+    for (name, hub) in config.hubs() {
+        HUBS.insert(name, hub);
+    }
+
+    let mut tx = pool.begin().await?;
+    for entry in BRIDGES.iter() {
+        query("INSERT INTO bridges (name) VALUES ($1)")
+            .bind(entry.key())
+            .execute(&mut tx)
+            .await?;
+        for member in entry.value() {
+            // a chat that was in several bridges stays in the last one
+            if bridge_of(&member.chat).as_ref() != Some(entry.key()) {
+                continue;
+            }
+            insert_member(&mut tx, entry.key(), member).await?;
+        }
+    }
+    for entry in HUBS.iter() {
+        insert_hub(&mut tx, entry.key(), *entry.value()).await?;
+    }
+    tx.commit().await?;
+
+    if !BRIDGES.is_empty() || !HUBS.is_empty() {
+        log::info!(
+            "Imported {} bridges and {} hubs from {} into the database. They can be removed from the file now.",
+            BRIDGES.len(),
+            HUBS.len(),
+            config_file().display()
         );
     }
 
-    let mut options = Table::new();
-    options.insert(
-        "admins".to_string(),
-        toml::Value::Array(ADMINS.read().await.iter().copied().map(int).collect()),
-    );
-    if let Some(image_channel) = &*DISCORD_IMAGE_CHANNEL.read().await {
-        options.insert("image_channel".to_string(), int(*image_channel));
+    Ok(())
+}
+
+async fn load_bridges(pool: &Pool) -> Result<()> {
+    BRIDGES.clear();
+    CHAT_BRIDGES.clear();
+    HUBS.clear();
+
+    let bridges = query_as::<(String,)>("SELECT name FROM bridges")
+        .fetch_all(pool)
+        .await?;
+    for (name,) in bridges {
+        BRIDGES.insert(name, vec![]);
+    }
+    let members = query_as::<(
+        String,
+        String,
+        Option<String>,
+        bool,
+        bool,
+        bool,
+        bool,
+        String,
+        bool,
+        Option<String>,
+    )>(
+        "SELECT chat, bridge, webhook_url, send_reactions, receive_reactions, forum, crosspost, direction, attachments, name_format FROM bridge_members ORDER BY rowid",
+    )
+    .fetch_all(pool)
+    .await?;
+    for (
+        chat,
+        name,
+        webhook_url,
+        send_reactions,
+        receive_reactions,
+        forum,
+        crosspost,
+        direction,
+        attachments,
+        name_format,
+    ) in members
+    {
+        let chat: ChatRef = match chat.parse() {
+            Ok(chat) => chat,
+            Err(e) => {
+                log::warn!("Invalid bridge member {chat}: {e}");
+                continue;
+            }
+        };
+        let direction = direction.parse().unwrap_or_else(|e| {
+            log::warn!("{e} for bridge member {chat}");
+            Direction::Both
+        });
+        CHAT_BRIDGES.insert(chat.clone(), name.clone());
+        BRIDGES.entry(name).or_default().push(BridgeMember {
+            chat,
+            webhook_url,
+            send_reactions,
+            receive_reactions,
+            forum,
+            crosspost,
+            direction,
+            attachments,
+            name_format,
+        });
     }
-    let mut config = Table::new();
-    config.insert("chat_mappings".to_string(), Value::Array(mappings));
-    config.insert("options".to_string(), Value::Table(options));
 
-    let toml_string = toml::to_string(&config)?;
-    fs::write(CONFIG_FILE, toml_string)?;
+    let hubs =
+        query_as::<(String, i64, Option<i64>)>("SELECT name, guild_id, category_id FROM hubs")
+            .fetch_all(pool)
+            .await?;
+    for (name, guild_id, category_id) in hubs {
+        let guild_id = d::GuildId::from(guild_id as u64);
+        let hub = match category_id {
+            Some(category_id) => Hub::Category(guild_id, d::ChannelId::from(category_id as u64)),
+            None => Hub::Server(guild_id),
+        };
+        HUBS.insert(name, hub);
+    }
 
     Ok(())
 }
 
+async fn insert_member(tx: &mut Transaction, name: &str, member: &BridgeMember) -> Result<()> {
+    query(
+        "INSERT INTO bridge_members (chat, bridge, webhook_url, send_reactions, receive_reactions, forum, crosspost, direction, attachments, name_format) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+    )
+    .bind(member.chat.to_string())
+    .bind(name)
+    .bind(&member.webhook_url)
+    .bind(member.send_reactions)
+    .bind(member.receive_reactions)
+    .bind(member.forum)
+    .bind(member.crosspost)
+    .bind(member.direction.as_str())
+    .bind(member.attachments)
+    .bind(&member.name_format)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+async fn insert_hub(tx: &mut Transaction, name: &str, hub: Hub) -> Result<()> {
+    let (guild_id, category_id) = match hub {
+        Hub::Server(g) => (g, None),
+        Hub::Category(g, c) => (g, Some(c.get() as i64)),
+    };
+    query("INSERT INTO hubs (name, guild_id, category_id) VALUES ($1, $2, $3)")
+        .bind(name)
+        .bind(guild_id.get() as i64)
+        .bind(category_id)
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(())
+}
+
+fn insert_bridge(name: String, members: Vec<BridgeMember>) {
+    for member in &members {
+        if let Some(prev) = CHAT_BRIDGES.insert(member.chat.clone(), name.clone()) {
+            log::warn!(
+                "{} is in multiple bridges: \"{prev}\" and \"{name}\"",
+                member.chat
+            );
+        }
+    }
+    BRIDGES.insert(name, members);
+}
+
 pub async fn get_hub_server(name: &str) -> Option<Hub> {
     HUBS.get(name).as_deref().copied()
 }
 
-pub async fn add_hub_server(name: String, hub: Hub) -> Result<bool> {
-    let entry = HUBS.entry(name);
-    if matches!(entry, dashmap::Entry::Occupied(_)) {
+pub async fn add_hub_server(pool: &Pool, name: String, hub: Hub) -> Result<bool> {
+    if HUBS.contains_key(&name) {
         return Ok(false);
     }
-    entry.insert(hub);
+    let mut tx = pool.begin().await?;
+    insert_hub(&mut tx, &name, hub).await?;
+    tx.commit().await?;
+    HUBS.insert(name, hub);
 
-    save_config().await?;
     Ok(true)
 }
 
-pub async fn remove_hub_server(name: &str, guild_id: d::GuildId) -> Result<Option<Hub>> {
+pub async fn remove_hub_server(
+    pool: &Pool,
+    name: &str,
+    guild_id: d::GuildId,
+) -> Result<Option<Hub>> {
+    let mut tx = pool.begin().await?;
+    let removed = query("DELETE FROM hubs WHERE name = $1 AND guild_id = $2")
+        .bind(name)
+        .bind(guild_id.get() as i64)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    if removed > 0 {
+        for table in ["hub_invites", "hub_requests", "hub_channels"] {
+            query(&format!("DELETE FROM {table} WHERE hub = $1"))
+                .bind(name)
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+    tx.commit().await?;
     let removed = HUBS
         .remove_if(name, |_, hub| hub.guild() == guild_id)
         .map(|e| e.1);
 
-    save_config().await?;
-    return Ok(removed);
+    Ok(removed)
+}
+
+/// A secret token that lets a chat bridge to a hub, until it expires or has
+/// been used `max_uses` times.
+#[derive(Debug, Clone)]
+pub struct HubInvite {
+    pub token: String,
+    pub hub: String,
+    /// Unix time.
+    pub expires_at: Option<i64>,
+    pub max_uses: Option<i64>,
+    pub uses: i64,
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+pub async fn create_hub_invite(
+    pool: &Pool,
+    hub: &str,
+    created_by: &UserRef,
+    expires_at: Option<i64>,
+    max_uses: Option<i64>,
+) -> Result<String> {
+    let token = rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect::<String>();
+    query(
+        "INSERT INTO hub_invites (token, hub, created_by, expires_at, max_uses) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(&token)
+    .bind(hub)
+    .bind(created_by.to_string())
+    .bind(expires_at)
+    .bind(max_uses)
+    .execute(pool)
+    .await?;
+
+    Ok(token)
+}
+
+/// The invites of a hub that can still be used.
+pub async fn hub_invites(pool: &Pool, hub: &str) -> Result<Vec<HubInvite>> {
+    let invites = query_as::<(String, String, Option<i64>, Option<i64>, i64)>(
+        "SELECT token, hub, expires_at, max_uses, uses FROM hub_invites
+        WHERE hub = $1 AND (expires_at IS NULL OR expires_at > $2) AND (max_uses IS NULL OR uses < max_uses)
+        ORDER BY created_at",
+    )
+    .bind(hub)
+    .bind(unix_now())
+    .fetch_all(pool)
+    .await?;
+
+    Ok(invites
+        .into_iter()
+        .map(|(token, hub, expires_at, max_uses, uses)| HubInvite {
+            token,
+            hub,
+            expires_at,
+            max_uses,
+            uses,
+        })
+        .collect())
+}
+
+/// Revokes an invite to one of the server's hubs, returning whether there was
+/// one.
+pub async fn revoke_hub_invite(pool: &Pool, token: &str, guild_id: d::GuildId) -> Result<bool> {
+    let revoked = query(
+        "DELETE FROM hub_invites WHERE token = $1 AND hub IN (SELECT name FROM hubs WHERE guild_id = $2)",
+    )
+    .bind(token)
+    .bind(guild_id.get() as i64)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(revoked > 0)
+}
+
+/// Uses up one use of the invite, returning its hub unless the invite is
+/// unknown, expired or used up.
+pub async fn use_hub_invite(pool: &Pool, token: &str) -> Result<Option<(String, Hub)>> {
+    let hub = query_as::<(String,)>(
+        "UPDATE hub_invites SET uses = uses + 1
+        WHERE token = $1 AND (expires_at IS NULL OR expires_at > $2) AND (max_uses IS NULL OR uses < max_uses)
+        AND hub IN (SELECT name FROM hubs)
+        RETURNING hub",
+    )
+    .bind(token)
+    .bind(unix_now())
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(hub.and_then(|(name,)| {
+        let hub = *HUBS.get(&name)?;
+        Some((name, hub))
+    }))
+}
+
+/// Gives back the use of an invite whose hub couldn't be bridged to.
+pub async fn unuse_hub_invite(pool: &Pool, token: &str) -> Result<()> {
+    query("UPDATE hub_invites SET uses = uses - 1 WHERE token = $1 AND uses > 0")
+        .bind(token)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// How a hub limits the channels that chats bridging to it get.
+#[derive(Debug, Clone, Default)]
+pub struct HubSettings {
+    /// Requests to bridge to the hub are posted here for approval, if set,
+    /// and the channel is only created once they're approved.
+    pub approval_channel: Option<d::ChannelId>,
+    pub max_pending: Option<i64>,
+    pub max_channels_per_day: Option<i64>,
+}
+
+pub async fn hub_settings(pool: &Pool, hub: &str) -> Result<HubSettings> {
+    let settings = query_as::<(Option<i64>, Option<i64>, Option<i64>)>(
+        "SELECT approval_channel, max_pending, max_channels_per_day FROM hubs WHERE name = $1",
+    )
+    .bind(hub)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some((approval_channel, max_pending, max_channels_per_day)) = settings else {
+        return Ok(HubSettings::default());
+    };
+    Ok(HubSettings {
+        approval_channel: approval_channel.map(|c| d::ChannelId::from(c as u64)),
+        max_pending,
+        max_channels_per_day,
+    })
+}
+
+/// Changes the settings of one of the server's hubs, returning whether there
+/// was one.
+pub async fn set_hub_settings(
+    pool: &Pool,
+    hub: &str,
+    guild_id: d::GuildId,
+    settings: &HubSettings,
+) -> Result<bool> {
+    let updated = query(
+        "UPDATE hubs SET approval_channel = $1, max_pending = $2, max_channels_per_day = $3
+        WHERE name = $4 AND guild_id = $5",
+    )
+    .bind(settings.approval_channel.map(|c| c.get() as i64))
+    .bind(settings.max_pending)
+    .bind(settings.max_channels_per_day)
+    .bind(hub)
+    .bind(guild_id.get() as i64)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(updated > 0)
+}
+
+/// A chat waiting for a hub's moderators to approve creating its channel.
+#[derive(Debug, Clone)]
+pub struct HubRequest {
+    pub id: i64,
+    pub hub: String,
+    pub chat: ChatRef,
+    pub chat_name: String,
+    pub requested_by: Option<UserRef>,
+    pub direction: Direction,
+    /// The invite the request used, whose use is given back if the channel
+    /// isn't created.
+    pub invite: Option<String>,
+}
+
+pub async fn add_hub_request(
+    pool: &Pool,
+    hub: &str,
+    chat: &ChatRef,
+    chat_name: &str,
+    requested_by: &UserRef,
+    direction: Direction,
+    invite: Option<&str>,
+) -> Result<i64> {
+    let (id,) = query_as::<(i64,)>(
+        "INSERT INTO hub_requests (hub, chat, chat_name, requested_by, direction, created_at, invite)
+        VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+    )
+    .bind(hub)
+    .bind(chat.to_string())
+    .bind(chat_name)
+    .bind(requested_by.to_string())
+    .bind(direction.as_str())
+    .bind(unix_now())
+    .bind(invite.map(str::to_string))
+    .fetch_one(pool)
+    .await?;
+
+    Ok(id)
+}
+
+pub async fn hub_request(pool: &Pool, id: i64) -> Result<Option<HubRequest>> {
+    let request = query_as::<(
+        i64,
+        String,
+        String,
+        String,
+        Option<String>,
+        String,
+        Option<String>,
+    )>(
+        "SELECT id, hub, chat, chat_name, requested_by, direction, invite FROM hub_requests WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(request.and_then(
+        |(id, hub, chat, chat_name, requested_by, direction, invite)| {
+            Some(HubRequest {
+                id,
+                hub,
+                chat: chat.parse().ok()?,
+                chat_name,
+                requested_by: requested_by.and_then(|user| user.parse().ok()),
+                direction: direction.parse().unwrap_or_default(),
+                invite,
+            })
+        },
+    ))
+}
+
+/// Removes a request once it's been answered, returning whether it was still
+/// there, so that it's only answered once.
+pub async fn remove_hub_request(pool: &Pool, id: i64) -> Result<bool> {
+    let removed = query("DELETE FROM hub_requests WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    Ok(removed > 0)
+}
+
+/// The number of requests waiting for approval in the hub, and whether one of
+/// them is from `chat`.
+pub async fn pending_hub_requests(pool: &Pool, hub: &str, chat: &ChatRef) -> Result<(i64, bool)> {
+    let (pending, from_chat) = query_as::<(i64, i64)>(
+        "SELECT COUNT(*), COUNT(CASE WHEN chat = $2 THEN 1 END) FROM hub_requests WHERE hub = $1",
+    )
+    .bind(hub)
+    .bind(chat.to_string())
+    .fetch_one(pool)
+    .await?;
+
+    Ok((pending, from_chat > 0))
+}
+
+/// Records a channel created in the hub, which counts towards its daily limit.
+pub async fn add_hub_channel(pool: &Pool, hub: &str, channel: d::ChannelId) -> Result<()> {
+    query("INSERT INTO hub_channels (hub, channel, created_at) VALUES ($1, $2, $3)")
+        .bind(hub)
+        .bind(channel.get() as i64)
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// The number of channels created in the hub in the last day.
+pub async fn hub_channels_today(pool: &Pool, hub: &str) -> Result<i64> {
+    let (count,) =
+        query_as::<(i64,)>("SELECT COUNT(*) FROM hub_channels WHERE hub = $1 AND created_at > $2")
+            .bind(hub)
+            .bind(unix_now() - 24 * 60 * 60)
+            .fetch_one(pool)
+            .await?;
+
+    Ok(count)
+}
+
+pub async fn public_hub_names() -> bool {
+    OPTIONS.read().await.public_hub_names
 }
 
 pub fn hubs_for_server(guild_id: d::GuildId) -> Vec<(String, Hub)> {
@@ -555,116 +1397,338 @@ pub fn hubs_for_server(guild_id: d::GuildId) -> Vec<(String, Hub)> {
         .collect()
 }
 
-pub async fn set_chat_mapping(
-    discord_channel_id: d::ChannelId,
-    telegram_chat_id: t::ChatId,
-    webhook_url: Option<String>,
+pub fn bridge_of(chat: &ChatRef) -> Option<String> {
+    CHAT_BRIDGES.get(chat).map(|name| name.clone())
+}
+
+pub fn bridge_members(name: &str) -> Vec<BridgeMember> {
+    BRIDGES
+        .get(name)
+        .map(|members| members.clone())
+        .unwrap_or_default()
+}
+
+pub fn member(chat: &ChatRef) -> Option<BridgeMember> {
+    let name = bridge_of(chat)?;
+    let members = BRIDGES.get(&name)?;
+    members.iter().find(|m| m.chat == *chat).cloned()
+}
+
+/// Turns `base` into a bridge name that isn't taken yet.
+pub fn unique_bridge_name(base: &str) -> String {
+    let base = base
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_lowercase();
+    let base = if base.is_empty() { "bridge" } else { &base };
+    if !BRIDGES.contains_key(base) {
+        return base.to_string();
+    }
+    (2..)
+        .map(|i| format!("{base}-{i}"))
+        .find(|name| !BRIDGES.contains_key(name))
+        .expect("ran out of bridge names")
+}
+
+/// Adds the chats to the named bridge, creating the bridge if it doesn't exist.
+pub async fn join_bridge(
+    pool: &Pool,
+    name: &str,
+    chats: &[ChatRef],
+    created_by: Option<&UserRef>,
 ) -> Result<()> {
-    // Insert new mapping
-    DISCORD_TO_TELEGRAM_CACHE.insert(
-        discord_channel_id,
-        TelegramEntry {
-            chat_id: telegram_chat_id,
-            receive_reactions: true,
-        },
-    );
-    TELEGRAM_TO_DISCORD_CACHE.insert(
-        telegram_chat_id,
-        DiscordEntry {
-            channel_id: discord_channel_id,
-            webhook_url,
-            send_reactions: true,
-            receive_reactions: true,
-        },
-    );
+    let mut joining = vec![];
+    for chat in chats {
+        match bridge_of(chat) {
+            Some(current) if current == name => {}
+            Some(current) => eyre::bail!("{chat} is already part of the bridge \"{current}\""),
+            None => joining.push(BridgeMember::new(chat.clone())),
+        }
+    }
+    let mut tx = pool.begin().await?;
+    query("INSERT INTO bridges (name, created_by) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(name)
+        .bind(created_by.map(|user| user.to_string()))
+        .execute(&mut tx)
+        .await?;
+    for member in &joining {
+        insert_member(&mut tx, name, member).await?;
+    }
+    tx.commit().await?;
 
-    // Save the updated mappings to the TOML file
-    save_config().await
+    for member in joining {
+        CHAT_BRIDGES.insert(member.chat.clone(), name.to_string());
+        BRIDGES.entry(name.to_string()).or_default().push(member);
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-pub enum EitherChatId {
-    Discord(d::ChannelId),
-    Telegram(t::ChatId),
+/// Gets when the bridge was created, as a Unix timestamp, and who created it,
+/// which isn't known for bridges from before that was recorded.
+pub async fn bridge_created(pool: &Pool, name: &str) -> Result<(Option<i64>, Option<UserRef>)> {
+    let created_at = match pool.engine() {
+        Engine::Sqlite => "CAST(strftime('%s', created_at) AS INTEGER)",
+        Engine::Postgres => "CAST(EXTRACT(EPOCH FROM created_at AT TIME ZONE 'UTC') AS BIGINT)",
+    };
+    let sql = format!("SELECT {created_at}, created_by FROM bridges WHERE name = $1");
+    let result = query_as::<(Option<i64>, Option<String>)>(&sql)
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+
+    let Some((created_at, created_by)) = result else {
+        return Ok((None, None));
+    };
+    Ok((created_at, created_by.and_then(|user| user.parse().ok())))
 }
-pub async fn remove_chat_mapping(
-    id: EitherChatId,
-) -> Result<(t::ChatId, d::ChannelId, Option<String>)> {
-    let removed = match id {
-        EitherChatId::Discord(id) => {
-            let Some(removed) = DISCORD_TO_TELEGRAM_CACHE.remove(&id).map(|(_, v)| v) else {
-                eyre::bail!("No mapping found for Discord channel {id}");
-            };
-            TELEGRAM_TO_DISCORD_CACHE.retain(
-                |_,
-                 DiscordEntry {
-                     channel_id: stored_id,
-                     ..
-                 }| *stored_id != id,
-            );
-            (removed.chat_id, id, None)
+
+/// Counts the messages from `from` that are mirrored in `to`, as far as their
+/// mappings have been kept.
+pub async fn count_mirrored(pool: &Pool, from: &ChatRef, to: &ChatRef) -> Result<i64> {
+    let (count,) = query_as::<(i64,)>(
+        "SELECT COUNT(*) FROM message_mapping WHERE origin_chat = $1 AND mirror_chat = $2",
+    )
+    .bind(from.to_string())
+    .bind(to.to_string())
+    .fetch_one(pool)
+    .await?;
+
+    Ok(count)
+}
+
+/// Removes the chat from its bridge, returning the name of the bridge and the
+/// chats left in it. A bridge left with a single chat is removed entirely.
+pub async fn leave_bridge(pool: &Pool, chat: &ChatRef) -> Result<Option<(String, Vec<ChatRef>)>> {
+    let Some(name) = bridge_of(chat) else {
+        return Ok(None);
+    };
+    let left = bridge_members(&name).len() - 1;
+    let mut tx = pool.begin().await?;
+    query("DELETE FROM bridge_members WHERE chat = $1")
+        .bind(chat.to_string())
+        .execute(&mut tx)
+        .await?;
+    if left < 2 {
+        query("DELETE FROM bridge_members WHERE bridge = $1")
+            .bind(&name)
+            .execute(&mut tx)
+            .await?;
+        query("DELETE FROM bridges WHERE name = $1")
+            .bind(&name)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    CHAT_BRIDGES.remove(chat);
+    let mut remaining = vec![];
+    if let Some(mut members) = BRIDGES.get_mut(&name) {
+        members.retain(|m| m.chat != *chat);
+        remaining = members.iter().map(|m| m.chat.clone()).collect();
+    }
+    if remaining.len() < 2 {
+        BRIDGES.remove(&name);
+        for chat in &remaining {
+            CHAT_BRIDGES.remove(chat);
         }
-        EitherChatId::Telegram(id) => {
-            let Some(removed) = TELEGRAM_TO_DISCORD_CACHE.remove(&id).map(|(_, v)| v) else {
-                eyre::bail!("No mapping found for Telegram chat {id}");
-            };
-            DISCORD_TO_TELEGRAM_CACHE.retain(
-                |_,
-                 TelegramEntry {
-                     chat_id: stored_id, ..
-                 }| *stored_id != id,
-            );
-            (id, removed.channel_id, removed.webhook_url)
+    }
+
+    Ok(Some((name, remaining)))
+}
+
+/// Moves a chat's place in its bridge to a new chat, keeping its settings.
+pub async fn replace_chat(pool: &Pool, old: &ChatRef, new: ChatRef) -> Result<()> {
+    let Some(name) = bridge_of(old) else {
+        eyre::bail!("No bridge found for {old}");
+    };
+    query("UPDATE bridge_members SET chat = $1 WHERE chat = $2")
+        .bind(new.to_string())
+        .bind(old.to_string())
+        .execute(pool)
+        .await?;
+    CHAT_BRIDGES.remove(old);
+    if let Some(mut members) = BRIDGES.get_mut(&name) {
+        for member in members.iter_mut().filter(|m| m.chat == *old) {
+            member.chat = new.clone();
         }
+    }
+    CHAT_BRIDGES.insert(new, name);
+
+    Ok(())
+}
+
+pub fn bridged_chats(chat: &ChatRef) -> Vec<ChatRef> {
+    let Some(name) = bridge_of(chat) else {
+        return vec![];
     };
+    bridge_members(&name)
+        .into_iter()
+        .map(|m| m.chat)
+        .filter(|c| c != chat)
+        .collect()
+}
 
-    save_config().await?;
-    Ok(removed)
+pub fn all_chats() -> Vec<ChatRef> {
+    CHAT_BRIDGES.iter().map(|e| e.key().clone()).collect()
 }
 
-pub async fn admins() -> Vec<d::UserId> {
-    ADMINS.read().await.clone()
+pub fn get_webhook_url(discord_channel_id: d::ChannelId) -> Option<String> {
+    member(&ChatRef::Discord(discord_channel_id))?.webhook_url
 }
 
-pub async fn discord_image_channel() -> Option<d::ChannelId> {
-    (&*DISCORD_IMAGE_CHANNEL.read().await).as_ref().copied()
+pub async fn set_webhook_url(
+    pool: &Pool,
+    discord_channel_id: d::ChannelId,
+    webhook_url: String,
+) -> Result<()> {
+    update_member(
+        pool,
+        &ChatRef::Discord(discord_channel_id),
+        &[("webhook_url", webhook_url.clone().into())],
+        |m| m.webhook_url = Some(webhook_url),
+    )
+    .await
+}
+
+pub fn is_forum(chat: &ChatRef) -> bool {
+    member(chat).is_some_and(|m| m.forum)
+}
+
+pub async fn set_forum(pool: &Pool, chat: &ChatRef, forum: bool) -> Result<()> {
+    update_member(pool, chat, &[("forum", forum.into())], |m| m.forum = forum).await
+}
+
+pub fn is_crosspost(chat: &ChatRef) -> bool {
+    member(chat).is_some_and(|m| m.crosspost)
+}
+
+pub async fn set_crosspost(pool: &Pool, chat: &ChatRef, crosspost: bool) -> Result<()> {
+    update_member(pool, chat, &[("crosspost", crosspost.into())], |m| {
+        m.crosspost = crosspost
+    })
+    .await
+}
+
+pub fn direction(chat: &ChatRef) -> Direction {
+    member(chat).map_or(Direction::Both, |m| m.direction)
+}
+
+pub async fn set_direction(pool: &Pool, chat: &ChatRef, direction: Direction) -> Result<()> {
+    update_member(
+        pool,
+        chat,
+        &[("direction", direction.as_str().into())],
+        |m| m.direction = direction,
+    )
+    .await
+}
+
+pub async fn set_reactions(pool: &Pool, chat: &ChatRef, send: bool, receive: bool) -> Result<()> {
+    update_member(
+        pool,
+        chat,
+        &[
+            ("send_reactions", send.into()),
+            ("receive_reactions", receive.into()),
+        ],
+        |m| {
+            m.send_reactions = send;
+            m.receive_reactions = receive;
+        },
+    )
+    .await
+}
+
+pub fn forwards_attachments(chat: &ChatRef) -> bool {
+    member(chat).is_none_or(|m| m.attachments)
 }
 
-pub fn get_telegram_chat_id(discord_channel_id: d::ChannelId) -> Option<t::ChatId> {
-    DISCORD_TO_TELEGRAM_CACHE
-        .get(&discord_channel_id)
-        .map(|v| v.chat_id)
+pub async fn set_attachments(pool: &Pool, chat: &ChatRef, attachments: bool) -> Result<()> {
+    update_member(pool, chat, &[("attachments", attachments.into())], |m| {
+        m.attachments = attachments
+    })
+    .await
+}
+
+pub fn name_format(chat: &ChatRef) -> Option<String> {
+    member(chat)?.name_format
 }
 
-pub fn get_discord_channel_id(
-    telegram_chat_id: t::ChatId,
-) -> Option<(d::ChannelId, Option<String>)> {
-    TELEGRAM_TO_DISCORD_CACHE
-        .get(&telegram_chat_id)
-        .map(|v| (v.channel_id, v.webhook_url.clone()))
+pub async fn set_name_format(
+    pool: &Pool,
+    chat: &ChatRef,
+    name_format: Option<String>,
+) -> Result<()> {
+    update_member(
+        pool,
+        chat,
+        &[("name_format", name_format.clone().into())],
+        |m| m.name_format = name_format,
+    )
+    .await
 }
 
-pub fn should_forward_reactions(from: EitherChatId) -> bool {
-    match from {
-        EitherChatId::Discord(id) => DISCORD_TO_TELEGRAM_CACHE
-            .get(&id)
-            .map(|v| v.receive_reactions)
-            .unwrap_or(true),
-        EitherChatId::Telegram(id) => TELEGRAM_TO_DISCORD_CACHE
-            .get(&id)
-            .map(|v| v.receive_reactions)
-            .unwrap_or(true),
+// Only the given columns are written, and only the matching fields of the
+// cached member changed, so that setters running at the same time, like
+// creating a webhook during a send and changing a setting, don't undo each
+// other.
+async fn update_member(
+    pool: &Pool,
+    chat: &ChatRef,
+    columns: &[(&str, Value)],
+    update: impl FnOnce(&mut BridgeMember),
+) -> Result<()> {
+    let Some(name) = bridge_of(chat) else {
+        eyre::bail!("No bridge found for {chat}");
+    };
+    let set = columns
+        .iter()
+        .enumerate()
+        .map(|(i, (column, _))| format!("{column} = ${}", i + 1))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!(
+        "UPDATE bridge_members SET {set} WHERE chat = ${}",
+        columns.len() + 1
+    );
+    let mut update_query = query(&sql);
+    for (_, value) in columns {
+        update_query = update_query.bind(value.clone());
+    }
+    update_query.bind(chat.to_string()).execute(pool).await?;
+    if let Some(mut members) = BRIDGES.get_mut(&name) {
+        if let Some(member) = members.iter_mut().find(|m| m.chat == *chat) {
+            update(member);
+        }
     }
+
+    Ok(())
 }
 
-pub async fn get_telegram_chats(
-    pool: &SqlitePool,
-) -> Result<Vec<(t::ChatId, String)>, sqlx::Error> {
-    sqlx::query_as(
+pub fn should_forward_reactions(from: &ChatRef, to: &ChatRef) -> bool {
+    member(from).is_none_or(|m| m.send_reactions && m.direction.sends())
+        && member(to).is_none_or(|m| m.receive_reactions && m.direction.receives())
+}
+
+pub async fn admins() -> Vec<d::UserId> {
+    OPTIONS.read().await.admins.clone()
+}
+
+pub async fn retention() -> Retention {
+    OPTIONS.read().await.retention.clone()
+}
+
+pub async fn discord_image_channel() -> Option<d::ChannelId> {
+    OPTIONS.read().await.image_channel
+}
+
+pub async fn get_telegram_chats(pool: &Pool) -> Result<Vec<(t::ChatId, String)>, sqlx::Error> {
+    query_as(
         r#"
         SELECT chat_id, title
         FROM telegram_chats
-        WHERE is_member = 1
+        WHERE is_member = TRUE
         "#,
     )
     .fetch_all(pool)
@@ -677,15 +1741,15 @@ pub async fn get_telegram_chats(
 }
 
 pub async fn update_chat_membership(
-    pool: &SqlitePool,
+    pool: &Pool,
     chat_id: t::ChatId,
     title: &str,
     is_member: bool,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query(
+    query(
         r#"
         INSERT INTO telegram_chats (chat_id, title, is_member)
-        VALUES (?, ?, ?)
+        VALUES ($1, $2, $3)
         ON CONFLICT(chat_id) DO UPDATE SET
             title = excluded.title,
             is_member = excluded.is_member,
@@ -700,3 +1764,188 @@ pub async fn update_chat_membership(
 
     Ok(())
 }
+
+async fn load_topics(pool: &Pool) -> Result<()> {
+    let rows = query_as::<(i64, i64, i64, i64, String, bool)>(
+        "SELECT discord_thread, discord_channel, telegram_chat, telegram_topic, name, closed FROM topic_mapping",
+    )
+    .fetch_all(pool)
+    .await?;
+    for (thread, channel, chat, topic, name, closed) in rows {
+        let thread = d::ChannelId::from(thread as u64);
+        TOPICS.insert(
+            (thread, t::ChatId(chat)),
+            Topic {
+                telegram_chat: t::ChatId(chat),
+                telegram_topic: t::ThreadId(t::MessageId(topic as i32)),
+                discord_channel: d::ChannelId::from(channel as u64),
+                discord_thread: thread,
+                name,
+                closed,
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// Gets the channel that a thread mirroring a Telegram topic was created in.
+pub fn thread_parent(thread: d::ChannelId) -> Option<d::ChannelId> {
+    TOPICS
+        .iter()
+        .find(|e| e.discord_thread == thread)
+        .map(|e| e.discord_channel)
+}
+
+pub fn thread_topic(thread: d::ChannelId, chat: t::ChatId) -> Option<Topic> {
+    TOPICS.get(&(thread, chat)).map(|topic| topic.clone())
+}
+
+/// Gets every Telegram topic mirroring the thread.
+pub fn thread_topics(thread: d::ChannelId) -> Vec<Topic> {
+    TOPICS
+        .iter()
+        .filter(|e| e.discord_thread == thread)
+        .map(|e| e.value().clone())
+        .collect()
+}
+
+/// Gets every thread mirroring the Telegram topic.
+pub fn topic_threads(chat: t::ChatId, topic: t::ThreadId) -> Vec<Topic> {
+    TOPICS
+        .iter()
+        .filter(|e| e.telegram_chat == chat && e.telegram_topic == topic)
+        .map(|e| e.value().clone())
+        .collect()
+}
+
+pub async fn insert_topic(pool: &Pool, topic: Topic) -> Result<()> {
+    query(
+        "INSERT INTO topic_mapping (discord_thread, discord_channel, telegram_chat, telegram_topic, name, closed) VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (discord_thread, telegram_chat) DO UPDATE SET
+            discord_channel = excluded.discord_channel,
+            telegram_topic = excluded.telegram_topic,
+            name = excluded.name,
+            closed = excluded.closed",
+    )
+    .bind(topic.discord_thread.get() as i64)
+    .bind(topic.discord_channel.get() as i64)
+    .bind(topic.telegram_chat.0)
+    .bind(topic.telegram_topic.0 .0)
+    .bind(&topic.name)
+    .bind(topic.closed)
+    .execute(pool)
+    .await?;
+    TOPICS.insert((topic.discord_thread, topic.telegram_chat), topic);
+
+    Ok(())
+}
+
+pub async fn update_topic(pool: &Pool, topic: &Topic, name: &str, closed: bool) -> Result<()> {
+    query(
+        "UPDATE topic_mapping SET name = $1, closed = $2 WHERE discord_thread = $3 AND telegram_chat = $4",
+    )
+    .bind(name)
+    .bind(closed)
+    .bind(topic.discord_thread.get() as i64)
+    .bind(topic.telegram_chat.0)
+    .execute(pool)
+    .await?;
+    if let Some(mut topic) = TOPICS.get_mut(&(topic.discord_thread, topic.telegram_chat)) {
+        topic.name = name.to_string();
+        topic.closed = closed;
+    }
+
+    Ok(())
+}
+
+// A channel post's comments live in the discussion group, as replies to the
+// automatic forward of the post, and on Discord in a thread on its mirror.
+pub async fn insert_comment_thread(
+    pool: &Pool,
+    discord_channel: d::ChannelId,
+    discord_thread: d::ChannelId,
+    telegram_chat: t::ChatId,
+    telegram_message: t::MessageId,
+) -> Result<()> {
+    query(
+        "INSERT INTO comment_thread (discord_thread, discord_channel, telegram_chat, telegram_message) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (discord_thread) DO UPDATE SET
+            discord_channel = excluded.discord_channel,
+            telegram_chat = excluded.telegram_chat,
+            telegram_message = excluded.telegram_message",
+    )
+    .bind(discord_thread.get() as i64)
+    .bind(discord_channel.get() as i64)
+    .bind(telegram_chat.0)
+    .bind(telegram_message.0)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn comment_thread(
+    pool: &Pool,
+    telegram_chat: t::ChatId,
+    telegram_message: t::MessageId,
+    discord_channel: d::ChannelId,
+) -> Result<Option<d::ChannelId>> {
+    let result = query_as::<(i64,)>(
+        "SELECT discord_thread FROM comment_thread WHERE telegram_chat = $1 AND telegram_message = $2 AND discord_channel = $3",
+    )
+    .bind(telegram_chat.0)
+    .bind(telegram_message.0)
+    .bind(discord_channel.get() as i64)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(result.map(|(thread,)| d::ChannelId::from(thread as u64)))
+}
+
+/// Gets the channel that comments in the chat are mirrored from or to, for a
+/// Discord comment thread or the discussion group of a Telegram channel.
+pub async fn comment_thread_parent(pool: &Pool, chat: &ChatRef) -> Result<Option<d::ChannelId>> {
+    let result = match *chat {
+        ChatRef::Discord(thread) => {
+            query_as::<(i64,)>(
+                "SELECT discord_channel FROM comment_thread WHERE discord_thread = $1",
+            )
+            .bind(thread.get() as i64)
+            .fetch_optional(pool)
+            .await?
+        }
+        ChatRef::Telegram(group) => {
+            query_as::<(i64,)>(
+                "SELECT discord_channel FROM comment_thread WHERE telegram_chat = $1 LIMIT 1",
+            )
+            .bind(group.0)
+            .fetch_optional(pool)
+            .await?
+        }
+        _ => None,
+    };
+
+    Ok(result.map(|(channel,)| d::ChannelId::new(channel as u64)))
+}
+
+/// Gets the channel a comment thread was opened in and the message it comments
+/// on.
+pub async fn thread_comments(
+    pool: &Pool,
+    discord_thread: d::ChannelId,
+) -> Result<Option<(d::ChannelId, MessageRef)>> {
+    let result = query_as::<(i64, i64, i64)>(
+        "SELECT discord_channel, telegram_chat, telegram_message FROM comment_thread WHERE discord_thread = $1",
+    )
+    .bind(discord_thread.get() as i64)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(result.map(|(channel, chat, message)| {
+        (
+            d::ChannelId::new(channel as u64),
+            MessageRef::Telegram(t::ChatId(chat), t::MessageId(message as i32)),
+        )
+    }))
+}
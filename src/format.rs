@@ -91,6 +91,14 @@ pub async fn discord_author_name(ctx: &impl d::CacheHttp, msg: &d::Message) -> S
         .unwrap_or_else(|| msg.author.name.clone())
 }
 
+/// Fills in a chat's name format, e.g. "{name} ({platform})". `{platform}` goes
+/// first so that it isn't replaced in names containing it.
+pub fn apply_name_format(name_format: &str, name: &str, platform: &str) -> String {
+    name_format
+        .replace("{platform}", platform)
+        .replace("{name}", name)
+}
+
 pub async fn discord_reactor_name(ctx: &d::Context, reaction: &d::Reaction) -> String {
     if let Some(member) = &reaction.member {
         member
@@ -195,9 +203,11 @@ pub fn telegram_author_name(msg: &t::Message) -> String {
         .as_ref()
         .map(|u| u.full_name())
         .or_else(|| {
-            msg.sender_chat
-                .as_ref()
-                .and_then(|c| c.title().map(Into::into))
+            let title = msg.sender_chat.as_ref()?.title()?;
+            Some(match msg.author_signature() {
+                Some(signature) => format!("{signature} ({title})"),
+                None => title.into(),
+            })
         })
         .unwrap_or("Unknown [this shouldn't be possible]".into())
 }
@@ -267,15 +277,27 @@ pub fn format_telegram_reaction_message(
         .join("\n")
 }
 
+fn telegram_reaction_string(reaction: &t::ReactionType) -> String {
+    match reaction {
+        t::ReactionType::Emoji { emoji } => emoji.replace("❤", "❤️"),
+        t::ReactionType::CustomEmoji { custom_emoji_id } => custom_emoji_id.to_string(),
+        t::ReactionType::Paid => {
+            "paid reaction [the api doesn't give me any other info lmao]".into()
+        }
+    }
+}
+
 pub fn filter_telegram_reactions(reactions: &[t::ReactionType]) -> Vec<String> {
+    reactions.iter().map(telegram_reaction_string).collect()
+}
+
+/// Formats anonymous reactions, e.g. in channels, as "👍 ×3".
+pub fn telegram_reaction_counts(reactions: &[t::ReactionCount]) -> Vec<String> {
     reactions
         .iter()
-        .map(|r| match r {
-            t::ReactionType::Emoji { emoji } => emoji.replace("❤", "❤️"),
-            t::ReactionType::CustomEmoji { custom_emoji_id } => custom_emoji_id.to_string(),
-            t::ReactionType::Paid => {
-                "paid reaction [the api doesn't give me any other info lmao]".into()
-            }
+        .map(|r| match r.total_count {
+            1 => telegram_reaction_string(&r.r#type),
+            n => format!("{} ×{n}", telegram_reaction_string(&r.r#type)),
         })
         .collect()
 }
@@ -315,3 +337,459 @@ pub fn format_discord_reaction_message(
         .collect::<Vec<_>>()
         .join("\n")
 }
+
+pub fn discord_to_matrix_format(content: &str) -> String {
+    // Matrix HTML is close enough to Telegram's that only spoilers and line
+    // breaks need fixing up
+    let html = discord_to_telegram_format(content)
+        .replace("<tg-spoiler>", "<span data-mx-spoiler>")
+        .replace("</tg-spoiler>", "</span>");
+    let html = html.trim_end_matches('\n');
+    let mut result = String::new();
+    for (i, part) in html.split("<pre>").enumerate() {
+        let (pre, rest) = match part.split_once("</pre>") {
+            Some((pre, rest)) if i > 0 => (Some(pre), rest),
+            _ => (None, part),
+        };
+        if let Some(pre) = pre {
+            result.push_str(&format!("<pre>{pre}</pre>"));
+        }
+        result.push_str(&rest.replace('\n', "<br>"));
+    }
+    result
+}
+
+pub fn matrix_to_discord_format(html: &str) -> String {
+    let (text, entities) = parse_matrix_html(html);
+    telegram_to_discord_format(&text, t::MessageEntityRef::parse(&text, &entities))
+}
+
+fn decode_html(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+fn html_attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    ['"', '\''].into_iter().find_map(|quote| {
+        let start = attributes.find(&format!("{name}={quote}"))? + name.len() + 2;
+        let len = attributes[start..].find(quote)?;
+        Some(&attributes[start..start + len])
+    })
+}
+
+/// Splits Matrix HTML into plain text and Telegram entities, so that it can be
+/// converted the same way as Telegram messages.
+fn parse_matrix_html(html: &str) -> (String, Vec<t::MessageEntity>) {
+    fn utf16_len(s: &str) -> usize {
+        s.encode_utf16().count()
+    }
+    fn push_text(text: &mut String, s: &str, in_pre: bool) {
+        if in_pre {
+            text.push_str(&decode_html(s));
+        } else {
+            text.push_str(&decode_html(&s.replace('\n', "")));
+        }
+    }
+    fn start_block(text: &mut String) {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+    }
+
+    let mut text = String::new();
+    let mut entities = vec![];
+    let mut open: Vec<(String, usize, Option<t::MessageEntityKind>)> = vec![];
+    // the reply fallback is dropped since replies are bridged separately
+    let mut in_reply: usize = 0;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let in_pre = open.iter().any(|(name, ..)| name == "pre");
+        if in_reply == 0 {
+            push_text(&mut text, &rest[..start], in_pre);
+        }
+        let Some(len) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = &rest[start + 1..start + len];
+        rest = &rest[start + len + 1..];
+
+        let closing = tag.starts_with('/');
+        let tag = tag.trim_start_matches('/').trim_end_matches('/');
+        let (name, attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let name = name.to_lowercase();
+        if name == "mx-reply" {
+            if closing {
+                in_reply = in_reply.saturating_sub(1);
+            } else {
+                in_reply += 1;
+            }
+            continue;
+        }
+        if in_reply > 0 {
+            continue;
+        }
+        match (name.as_str(), closing) {
+            ("br", _) => text.push('\n'),
+            ("img", _) => text.push_str(&decode_html(
+                html_attribute(attributes, "alt").unwrap_or_default(),
+            )),
+            ("hr", _) => {
+                start_block(&mut text);
+                text.push_str("---\n");
+            }
+            (_, false) => {
+                let kind = match name.as_str() {
+                    "b" | "strong" => Some(t::MessageEntityKind::Bold),
+                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                        start_block(&mut text);
+                        Some(t::MessageEntityKind::Bold)
+                    }
+                    "i" | "em" => Some(t::MessageEntityKind::Italic),
+                    "u" => Some(t::MessageEntityKind::Underline),
+                    "s" | "del" | "strike" => Some(t::MessageEntityKind::Strikethrough),
+                    "code" => match open.last_mut() {
+                        Some((_, _, Some(t::MessageEntityKind::Pre { language }))) => {
+                            *language = html_attribute(attributes, "class")
+                                .and_then(|c| c.strip_prefix("language-"))
+                                .map(String::from);
+                            None
+                        }
+                        _ => Some(t::MessageEntityKind::Code),
+                    },
+                    "pre" => {
+                        start_block(&mut text);
+                        Some(t::MessageEntityKind::Pre { language: None })
+                    }
+                    "blockquote" => {
+                        start_block(&mut text);
+                        Some(t::MessageEntityKind::Blockquote)
+                    }
+                    "a" => html_attribute(attributes, "href")
+                        .and_then(|href| url::Url::parse(&decode_html(href)).ok())
+                        .map(|url| t::MessageEntityKind::TextLink { url }),
+                    "span" if attributes.contains("data-mx-spoiler") => {
+                        Some(t::MessageEntityKind::Spoiler)
+                    }
+                    "p" | "div" | "ul" | "ol" => {
+                        start_block(&mut text);
+                        None
+                    }
+                    "li" => {
+                        start_block(&mut text);
+                        text.push_str("• ");
+                        None
+                    }
+                    _ => None,
+                };
+                open.push((name, utf16_len(&text), kind));
+            }
+            (_, true) => {
+                let Some(i) = open.iter().rposition(|(n, ..)| *n == name) else {
+                    continue;
+                };
+                let (_, offset, kind) = open.remove(i);
+                let length = utf16_len(&text) - offset;
+                if let Some(kind) = kind.filter(|_| length > 0) {
+                    entities.push(t::MessageEntity::new(kind, offset, length));
+                }
+            }
+        }
+    }
+    if in_reply == 0 {
+        let in_pre = open.iter().any(|(name, ..)| name == "pre");
+        push_text(&mut text, rest, in_pre);
+    }
+    (text, entities)
+}
+
+pub fn discord_to_irc_format(content: &str) -> String {
+    use discord_md::ast::{MarkdownElement, MarkdownElementCollection};
+
+    fn element_to_irc(element: &MarkdownElement) -> String {
+        match element {
+            MarkdownElement::Bold(x) => format!("\x02{}\x02", collection_to_irc(x.content())),
+            MarkdownElement::ItalicsStar(x) => {
+                format!("\x1D{}\x1D", collection_to_irc(x.content()))
+            }
+            MarkdownElement::ItalicsUnderscore(x) => {
+                format!("\x1D{}\x1D", collection_to_irc(x.content()))
+            }
+            MarkdownElement::Strikethrough(x) => {
+                format!("\x1E{}\x1E", collection_to_irc(x.content()))
+            }
+            MarkdownElement::Underline(x) => {
+                format!("\x1F{}\x1F", collection_to_irc(x.content()))
+            }
+            // black on black
+            MarkdownElement::Spoiler(x) => {
+                format!("\x0301,01{}\x03", collection_to_irc(x.content()))
+            }
+            MarkdownElement::OneLineCode(x) => format!("\x11{}\x11", x.content()),
+            MarkdownElement::MultiLineCode(x) => x.content().to_string(),
+            MarkdownElement::BlockQuote(x) => collection_to_irc(x.content()),
+            MarkdownElement::Plain(x) => x.content().to_string(),
+        }
+    }
+
+    fn collection_to_irc(collection: &MarkdownElementCollection) -> String {
+        collection.get().iter().map(element_to_irc).collect()
+    }
+
+    collection_to_irc(discord_md::parse(content).content())
+}
+
+pub fn irc_to_discord_format(content: &str) -> String {
+    let mut result = String::new();
+    let mut open: Vec<&str> = vec![];
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        let marker = match c {
+            '\x02' => "**",
+            '\x1D' => "_",
+            '\x1F' => "__",
+            '\x1E' => "~~",
+            '\x11' => "`",
+            '\x0F' => {
+                for marker in open.drain(..).rev() {
+                    result.push_str(marker);
+                }
+                continue;
+            }
+            // colours have no equivalent, so they're dropped along with their codes
+            '\x03' => {
+                for _ in 0..2 {
+                    chars.next_if(char::is_ascii_digit);
+                }
+                let mut ahead = chars.clone();
+                if ahead.next() == Some(',') && ahead.peek().is_some_and(char::is_ascii_digit) {
+                    chars.next();
+                    for _ in 0..2 {
+                        chars.next_if(char::is_ascii_digit);
+                    }
+                }
+                continue;
+            }
+            '\x16' => continue,
+            c => {
+                result.push(c);
+                continue;
+            }
+        };
+        match open.iter().rposition(|&m| m == marker) {
+            // markers opened after this one have to be closed first and then reopened
+            Some(i) => {
+                let inner = open.split_off(i);
+                for m in inner.iter().rev() {
+                    result.push_str(m);
+                }
+                for &m in &inner[1..] {
+                    result.push_str(m);
+                    open.push(m);
+                }
+            }
+            None => {
+                result.push_str(marker);
+                open.push(marker);
+            }
+        }
+    }
+    for marker in open.into_iter().rev() {
+        result.push_str(marker);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discord_to_matrix_format() {
+        assert_eq!(
+            discord_to_matrix_format("**bold** and *italic*"),
+            "<b>bold</b> and <i>italic</i>"
+        );
+        assert_eq!(
+            discord_to_matrix_format("a ||secret||"),
+            "a <span data-mx-spoiler>secret</span>"
+        );
+        assert_eq!(discord_to_matrix_format("line\nbreak"), "line<br>break");
+        assert_eq!(discord_to_matrix_format("1 < 2 & 3"), "1 &lt; 2 &amp; 3");
+    }
+
+    #[test]
+    fn test_discord_to_matrix_format_keeps_code_block_lines() {
+        assert_eq!(
+            discord_to_matrix_format("before\n```\nlet a;\nlet b;\n```\nafter"),
+            "before<br><pre>\nlet a;\nlet b;\n</pre><br>after"
+        );
+    }
+
+    #[test]
+    fn test_matrix_to_discord_format() {
+        assert_eq!(
+            matrix_to_discord_format("<b>bold</b> and <em>italic</em>"),
+            "**bold** and _italic_"
+        );
+        assert_eq!(
+            matrix_to_discord_format("<del>gone</del> <code>x</code>"),
+            "~~gone~~ `x`"
+        );
+        assert_eq!(
+            matrix_to_discord_format("<a href=\"https://example.com/\">link</a>"),
+            "[link](https://example.com/)"
+        );
+        assert_eq!(
+            matrix_to_discord_format("<span data-mx-spoiler>secret</span>"),
+            "||secret||"
+        );
+        assert_eq!(
+            matrix_to_discord_format("1 &lt; 2 &amp;&amp; 3"),
+            "1 < 2 && 3"
+        );
+    }
+
+    #[test]
+    fn test_matrix_to_discord_format_blocks() {
+        assert_eq!(matrix_to_discord_format("<p>one</p><p>two</p>"), "one\ntwo");
+        assert_eq!(
+            matrix_to_discord_format("<ul><li>a</li><li>b</li></ul>"),
+            "• a\n• b"
+        );
+        assert_eq!(matrix_to_discord_format("a<br/>b"), "a\nb");
+        assert_eq!(
+            matrix_to_discord_format(
+                "<pre><code class=\"language-rust\">fn main() {}\n</code></pre>"
+            ),
+            "```rust\nfn main() {}\n```"
+        );
+    }
+
+    #[test]
+    fn test_matrix_to_discord_format_drops_reply_fallback() {
+        assert_eq!(
+            matrix_to_discord_format(
+                "<mx-reply><blockquote><a href=\"https://matrix.to/#/!room\">In reply to</a> quoted</blockquote></mx-reply>answer"
+            ),
+            "answer"
+        );
+    }
+
+    #[test]
+    fn test_matrix_to_discord_format_multibyte() {
+        assert_eq!(
+            matrix_to_discord_format("héllo 🎉 <b>wörld</b>"),
+            "héllo 🎉 **wörld**"
+        );
+    }
+
+    #[test]
+    fn test_matrix_to_discord_format_malformed_html() {
+        // an unterminated tag is kept as text
+        assert_eq!(matrix_to_discord_format("a <b"), "a <b");
+        // an unclosed element has no end, so it isn't formatted
+        assert_eq!(matrix_to_discord_format("<b>bold"), "bold");
+        // closing tags that were never opened are ignored
+        assert_eq!(matrix_to_discord_format("text</i>"), "text");
+        // a link whose href is missing its closing quote isn't a link
+        assert_eq!(
+            matrix_to_discord_format("<a href=\"https://example.com>link</a>"),
+            "link"
+        );
+    }
+
+    #[test]
+    fn test_html_attribute() {
+        assert_eq!(html_attribute("href=\"a\" alt='b'", "href"), Some("a"));
+        assert_eq!(html_attribute("href=\"a\" alt='b'", "alt"), Some("b"));
+        assert_eq!(html_attribute("href=\"a", "href"), None);
+        assert_eq!(html_attribute("", "href"), None);
+    }
+
+    #[test]
+    fn test_parse_matrix_html_entities_use_utf16_offsets() {
+        let (text, entities) = parse_matrix_html("🎉 <b>x</b>");
+        assert_eq!(text, "🎉 x");
+        assert_eq!(
+            entities,
+            vec![t::MessageEntity::new(t::MessageEntityKind::Bold, 3, 1)]
+        );
+    }
+
+    #[test]
+    fn test_discord_to_irc_format() {
+        assert_eq!(
+            discord_to_irc_format("**bold** *italic* __under__ ~~strike~~"),
+            "\x02bold\x02 \x1Ditalic\x1D \x1Funder\x1F \x1Estrike\x1E"
+        );
+        assert_eq!(discord_to_irc_format("`code`"), "\x11code\x11");
+        assert_eq!(discord_to_irc_format("||secret||"), "\x0301,01secret\x03");
+        assert_eq!(discord_to_irc_format("1 < 2 & 3"), "1 < 2 & 3");
+    }
+
+    #[test]
+    fn test_irc_to_discord_format() {
+        assert_eq!(
+            irc_to_discord_format("\x02bold\x02 plain"),
+            "**bold** plain"
+        );
+        assert_eq!(irc_to_discord_format("\x1Ditalic\x1D"), "_italic_");
+        assert_eq!(irc_to_discord_format("\x1Funder\x1F"), "__under__");
+        assert_eq!(irc_to_discord_format("\x1Estrike\x1E"), "~~strike~~");
+        assert_eq!(irc_to_discord_format("\x11code\x11"), "`code`");
+    }
+
+    #[test]
+    fn test_irc_to_discord_format_unterminated() {
+        // formatting runs until the end of the line
+        assert_eq!(irc_to_discord_format("\x02bold"), "**bold**");
+        assert_eq!(irc_to_discord_format("\x02\x1Dboth"), "**_both_**");
+        // \x0F resets everything
+        assert_eq!(irc_to_discord_format("\x02\x1Da\x0Fb"), "**_a_**b");
+    }
+
+    #[test]
+    fn test_irc_to_discord_format_overlapping() {
+        // italics opened inside bold are closed and reopened around the end of bold
+        assert_eq!(irc_to_discord_format("\x02a\x1Db\x02c\x1D"), "**a_b_**_c_");
+    }
+
+    #[test]
+    fn test_irc_to_discord_format_drops_colours() {
+        assert_eq!(irc_to_discord_format("\x0304red\x03 plain"), "red plain");
+        assert_eq!(irc_to_discord_format("\x034,12both\x03"), "both");
+        // a comma after a colour is only part of it if a background follows
+        assert_eq!(irc_to_discord_format("\x034,text"), ",text");
+        assert_eq!(irc_to_discord_format("\x16reversed"), "reversed");
+        assert_eq!(irc_to_discord_format("\x0304héllo"), "héllo");
+    }
+
+    #[test]
+    fn test_apply_name_format() {
+        assert_eq!(
+            apply_name_format("{name} ({platform})", "Alice", "Telegram"),
+            "Alice (Telegram)"
+        );
+        assert_eq!(apply_name_format("[{platform}]", "Alice", "IRC"), "[IRC]");
+        assert_eq!(
+            apply_name_format("no placeholders", "Alice", "IRC"),
+            "no placeholders"
+        );
+        assert_eq!(apply_name_format("{name} {name}", "Bob", "IRC"), "Bob Bob");
+    }
+
+    #[test]
+    fn test_apply_name_format_leaves_placeholders_in_names() {
+        assert_eq!(
+            apply_name_format("{name} via {platform}", "{platform} fan", "Matrix"),
+            "{platform} fan via Matrix"
+        );
+        assert_eq!(apply_name_format("{name}!", "Zoë 🎉", "Discord"), "Zoë 🎉!");
+    }
+}
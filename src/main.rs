@@ -1,22 +1,29 @@
 #![allow(dead_code)]
+mod backup;
+mod config;
 mod db;
 mod format;
+mod pairing;
+mod sql;
 
-use dashmap::DashMap;
-use sqlx::SqlitePool;
-use tokio::time::Instant;
-
-use std::{env, future::IntoFuture, sync::Arc, time::Duration};
+use std::{
+    env,
+    future::IntoFuture,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
+};
 
 // use tokio::sync::RwLock;
 mod telegram {
     pub use teloxide::errors::{DownloadError, RequestError};
     pub use teloxide::prelude::*;
     pub use teloxide::types::*;
+    pub use teloxide::utils::command::{BotCommands, ParseError};
 }
 
-use teloxide::{net::Download as _, prelude::*};
-use url::Url;
+use teloxide::prelude::*;
+use teloxide::utils::command::BotCommands as _;
 
 use dotenv;
 
@@ -28,19 +35,21 @@ mod discord {
         },
         async_trait,
         builder::{
-            AutocompleteChoice, CreateAllowedMentions, CreateAttachment,
-            CreateAutocompleteResponse, CreateChannel, CreateCommand, CreateCommandOption,
-            CreateEmbed, CreateEmbedAuthor, CreateInteractionResponse,
-            CreateInteractionResponseMessage, CreateMessage, CreateWebhook, EditMessage,
+            AutocompleteChoice, CreateActionRow, CreateAllowedMentions, CreateAttachment,
+            CreateAutocompleteResponse, CreateButton, CreateChannel, CreateCommand,
+            CreateCommandOption, CreateEmbed, CreateEmbedAuthor, CreateForumPost,
+            CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage,
+            CreateThread, CreateWebhook, EditInteractionResponse, EditMessage, EditThread,
             EditWebhookMessage, ExecuteWebhook,
         },
         cache::Cache,
         http::Http,
         model::{
             application::{
-                Command, CommandInteraction, CommandOptionType, Interaction, InteractionContext,
+                ButtonStyle, Command, CommandInteraction, CommandOptionType, ComponentInteraction,
+                Interaction, InteractionContext,
             },
-            channel::{Attachment, ChannelType, Message, Reaction, ReactionType},
+            channel::{Attachment, ChannelType, GuildChannel, Message, Reaction, ReactionType},
             event::MessageUpdateEvent,
             gateway::Ready,
             id::{ChannelId, GuildId, MessageId},
@@ -51,31 +60,50 @@ mod discord {
         Error,
     };
 }
-use serenity::prelude::Mentionable as _;
 
 use discord as d;
 use telegram as t;
 
 const DISCORD_TOKEN_ENV: &str = "DISCORD_TOKEN";
+const MATRIX_HOMESERVER_ENV: &str = "MATRIX_HOMESERVER_URL";
+const MATRIX_TOKEN_ENV: &str = "MATRIX_ACCESS_TOKEN";
+const IRC_SERVER_ENV: &str = "IRC_SERVER";
+const IRC_PORT_ENV: &str = "IRC_PORT";
+const IRC_TLS_ENV: &str = "IRC_TLS";
+const IRC_NICK_ENV: &str = "IRC_NICK";
+const IRC_PASSWORD_ENV: &str = "IRC_PASSWORD";
+const DATA_DIR_ENV: &str = "TELEDISCORD_DATA_DIR";
+const CONFIG_ENV: &str = "TELEDISCORD_CONFIG";
+const DATABASE_URL_ENV: &str = "TELEDISCORD_DATABASE_URL";
 struct DiscordState {
     telegram_bot: t::Bot,
-    db: SqlitePool,
+    db: sql::Pool,
+    bridge: Arc<OnceLock<Arc<Bridge>>>,
 }
 
 macro_rules! telegram_request {
     ($e:expr$(,)?) => {
-        telegram_request(|| $e, || log::error!("{}:{}", file!(), line!()))
+        $crate::telegram_request(|| $e, || log::error!("{}:{}", file!(), line!()))
     };
     ($e:expr, $log:expr$(,)?) => {
-        telegram_request(|| $e, || $log)
+        $crate::telegram_request(|| $e, || $log)
     };
 }
 macro_rules! discord_request {
     ($e:expr$(,)?) => {
-        discord_request(|| $e, || log::error!("{}:{}", file!(), line!()))
+        $crate::discord_request(|| $e, || log::error!("{}:{}", file!(), line!()))
+    };
+    ($e:expr, $log:expr$(,)?) => {
+        $crate::discord_request(|| $e, || $log)
+    };
+}
+
+macro_rules! matrix_request {
+    ($e:expr$(,)?) => {
+        $crate::matrix_request(|| $e, || log::error!("{}:{}", file!(), line!()))
     };
     ($e:expr, $log:expr$(,)?) => {
-        discord_request(|| $e, || $log)
+        $crate::matrix_request(|| $e, || $log)
     };
 }
 
@@ -85,319 +113,38 @@ macro_rules! edbg {
     };
 }
 
-impl DiscordState {
-    async fn send_message(
-        &self,
-        telegram_chat: t::ChatId,
-        msg_id: d::MessageId,
-        text: &str,
-        attachments: Vec<d::Attachment>,
-        mut stickers: Vec<d::StickerItem>,
-        reply_to_message_id: Option<t::MessageId>,
-    ) {
-        enum AttachmentKind {
-            Image,
-            Video,
-            Audio,
-            Other,
-        }
-        use AttachmentKind as AK;
-
-        fn kind(a: &d::Attachment) -> AttachmentKind {
-            let Some(ct) = a.content_type.as_deref() else {
-                return AK::Other;
-            };
-            if ct.starts_with("video/") || ct == "image/gif" {
-                return AK::Video;
-            } else if ct.starts_with("image/") {
-                return AK::Image;
-            } else if ct.starts_with("audio/") {
-                return AK::Audio;
-            } else {
-                return AK::Other;
-            }
-        }
-
-        fn skind(s: &d::StickerItem) -> AttachmentKind {
-            match s.format_type {
-                d::StickerFormatType::Gif | d::StickerFormatType::Apng => AK::Video,
-                d::StickerFormatType::Png => AK::Image,
-                _ => AK::Other,
-            }
-        }
-
-        macro_rules! _if_method_may_spoiler {
-            (send_photo, $code:expr) => {
-                $code
-            };
-            (send_video, $code:expr) => {
-                $code
-            };
-            ($method:ident, $code:expr) => {};
-        }
-
-        macro_rules! _send_with_method {
-            ($method:ident, $filename:expr, $url:expr, $caption:expr, $replyto:expr $(,)?) => {
-                async {
-                    #[allow(unused_mut)]
-                    let mut f = t::InputFile::url($url.clone());
-                    _if_method_may_spoiler!($method, {
-                        f = f.file_name($filename.trim_start_matches("SPOILER_").to_string());
-                    });
-                    let mut s = self.telegram_bot.$method(telegram_chat, f);
-                    _if_method_may_spoiler!($method, {
-                        s = s.has_spoiler($filename.starts_with("SPOILER_"));
-                    });
-                    if let Some(caption) = $caption {
-                        s = s.caption(caption).parse_mode(t::ParseMode::Html);
-                    }
-                    if let Some(id) = $replyto {
-                        s = s.reply_parameters(t::ReplyParameters::new(id));
-                    }
-                    telegram_request!(s.send_ref()).await
-                }
-            };
-        }
-
-        macro_rules! send_with_attachment {
-            ($a:expr, $caption:expr, $replyto:expr $(,)?) => {
-                async {
-                    let a: &d::Attachment = $a;
-                    let caption: Option<&str> = $caption;
-                    let replyto: Option<t::MessageId> = $replyto;
-                    let url = match Url::parse(a.url.as_str()) {
-                        Ok(url) => url,
-                        Err(e) => {
-                            log::error!("Failed to parse attachment url: {e}");
-                            return None;
-                        }
-                    };
-                    match kind(a) {
-                        AK::Image => {
-                            _send_with_method!(send_photo, a.filename, url, caption, replyto).await
-                        }
-                        AK::Video => {
-                            _send_with_method!(send_video, a.filename, url, caption, replyto).await
-                        }
-                        AK::Audio => {
-                            _send_with_method!(send_audio, a.filename, url, caption, replyto).await
-                        }
-                        AK::Other => {
-                            _send_with_method!(send_document, a.filename, url, caption, replyto)
-                                .await
-                        }
-                    }
-                }
-            };
-        }
-
-        macro_rules! send_with_sticker {
-            ($s:expr, $caption:expr, $replyto:expr $(,)?) => {
-                async {
-                    let s: &d::StickerItem = $s;
-                    let caption: Option<&str> = $caption;
-                    let replyto: Option<t::MessageId> = $replyto;
-                    let Some(url) = s.image_url() else {
-                        log::error!("Failed to get sticker url: {s:?}");
-                        return None;
-                    };
-                    let url = match Url::parse(url.as_str()) {
-                        Ok(url) => url,
-                        Err(e) => {
-                            log::error!("Failed to parse attachment url: {e}");
-                            return None;
-                        }
-                    };
-                    let filename = url
-                        .path_segments()
-                        .and_then(Iterator::last)
-                        .unwrap_or("sticker");
-                    match skind(s) {
-                        AK::Video => {
-                            _send_with_method!(send_video, filename, url, caption, replyto).await
-                        }
-                        AK::Image => {
-                            _send_with_method!(send_photo, filename, url, caption, replyto).await
-                        }
-                        _ => None,
-                    }
-                }
-            };
-        }
-
-        stickers.retain(|s| !matches!(skind(s), AK::Other));
-
-        let mut attachments_processed = false;
-        let total_attachments = attachments.len() + stickers.len();
-        let media_count = attachments
-            .iter()
-            .filter(|a| matches!(kind(a), AK::Image | AK::Video))
-            .count()
-            + stickers.len();
-
-        let telegram_result: Vec<_> = if total_attachments == 1 {
-            attachments_processed = true;
-            if stickers.is_empty() {
-                send_with_attachment!(&attachments[0], Some(&text), reply_to_message_id)
-                    .await
-                    .into_iter()
-                    .collect()
-            } else {
-                send_with_sticker!(&stickers[0], Some(&text), reply_to_message_id)
-                    .await
-                    .into_iter()
-                    .collect()
-            }
-        } else if media_count >= 1 {
-            attachments_processed = total_attachments == media_count;
-            let mut builder = self.telegram_bot.send_media_group(
-                telegram_chat,
-                attachments
-                    .iter()
-                    .filter(|a| matches!(kind(a), AK::Image | AK::Video))
-                    .map(|a| a.url.as_str())
-                    .map(url::Url::parse)
-                    .filter_map(|url| {
-                        url.inspect_err(|e| log::error!("Failed to parse attachment url: {e}"))
-                            .ok()
-                    })
-                    .map(t::InputFile::url)
-                    .zip(attachments.iter())
-                    .map(|(m, a)| {
-                        (
-                            m.file_name(a.filename.trim_start_matches("SPOILER_").to_string()),
-                            a.filename.starts_with("SPOILER_"),
-                            kind(a),
-                        )
-                    })
-                    .chain(stickers.iter().filter_map(|s| {
-                        let Some(url) = s.image_url() else {
-                            return None;
-                        };
-                        let url = match Url::parse(url.as_str()) {
-                            Ok(url) => url,
-                            Err(e) => {
-                                log::error!("Failed to parse attachment url: {e}");
-                                return None;
-                            }
-                        };
-                        let filename = url
-                            .path_segments()
-                            .and_then(Iterator::last)
-                            .unwrap_or("sticker")
-                            .to_string();
-                        Some((t::InputFile::url(url).file_name(filename), false, skind(s)))
-                    }))
-                    .enumerate()
-                    .map(|(i, (m, s, k))| match k {
-                        AK::Image => t::InputMedia::Photo({
-                            let mut m = t::InputMediaPhoto::new(m);
-                            if i == 0 {
-                                m = m.caption(text).parse_mode(t::ParseMode::Html);
-                            }
-                            if s {
-                                m = m.spoiler();
-                            }
-                            m
-                        }),
-                        AK::Video => t::InputMedia::Video({
-                            let mut m = t::InputMediaVideo::new(m);
-                            if i == 0 {
-                                m = m.caption(text).parse_mode(t::ParseMode::Html);
-                            }
-                            if s {
-                                m = m.spoiler();
-                            }
-                            m
-                        }),
-                        _ => unreachable!(),
-                    })
-                    .collect::<Vec<_>>(),
-            );
-            if let Some(id) = reply_to_message_id {
-                builder = builder.reply_parameters(t::ReplyParameters::new(id));
-            }
-            telegram_request!(builder.send_ref())
-                .await
-                .into_iter()
-                .flatten()
-                .collect()
-        } else {
-            vec![]
-        };
-        let telegram_result = if telegram_result.is_empty() {
-            let builder = self
-                .telegram_bot
-                .send_message(telegram_chat, text)
-                .parse_mode(t::ParseMode::Html);
-            let s = if let Some(id) = reply_to_message_id {
-                builder.reply_parameters(t::ReplyParameters::new(id))
-            } else {
-                builder
-            };
-
-            telegram_request!(s.send_ref(), edbg!(text),)
-                .await
-                .into_iter()
-                .collect()
-        } else {
-            telegram_result
-        };
-
-        for telegram_msg in telegram_result {
-            if let Err(e) = db::insert_mapping(
-                &self.db,
-                msg_id,
-                telegram_msg.id,
-                telegram_chat,
-                telegram_msg.caption().is_some(),
-            )
-            .await
-            {
-                log::error!("Failed to insert message mapping: {}", e);
-            }
-        }
-
-        if attachments_processed {
-            return;
-        }
+// declared after the request macros so that they are in scope
+mod bridge;
+mod platform;
+
+use bridge::Bridge;
+use platform::{
+    discord::DiscordPlatform,
+    irc,
+    irc::{IrcConfig, IrcPlatform},
+    matrix,
+    matrix::MatrixPlatform,
+    telegram::TelegramPlatform,
+    ChatRef, MessageRef, Mirror, UserRef,
+};
 
-        let att_futs = attachments
-            .into_iter()
-            .map(|a| async move {
-                if let Some(telegram_msg) = send_with_attachment!(&a, None, None).await {
-                    if let Err(e) =
-                        db::insert_mapping(&self.db, msg_id, telegram_msg.id, telegram_chat, true)
-                            .await
-                    {
-                        log::error!("Failed to insert message mapping: {}", e);
-                    }
-                }
-            })
-            .collect::<Vec<_>>();
-        let stick_futs = stickers
-            .into_iter()
-            .map(|s| async move {
-                if let Some(telegram_msg) = send_with_sticker!(&s, None, None).await {
-                    if let Err(e) =
-                        db::insert_mapping(&self.db, msg_id, telegram_msg.id, telegram_chat, true)
-                            .await
-                    {
-                        log::error!("Failed to insert message mapping: {}", e);
-                    }
-                }
-            })
-            .collect::<Vec<_>>();
-        futures::future::join_all(att_futs).await;
-        futures::future::join_all(stick_futs).await;
+impl DiscordState {
+    fn bridge(&self) -> &Bridge {
+        self.bridge
+            .get()
+            .expect("bridge is set before the client starts")
     }
 
-    async fn get_available_telegram_chats(&self) -> Vec<(t::ChatId, String)> {
+    async fn get_available_telegram_chats(
+        &self,
+        channel: d::ChannelId,
+    ) -> Vec<(t::ChatId, String)> {
+        let bridged = db::bridged_chats(&ChatRef::Discord(channel));
         db::get_telegram_chats(&self.db)
             .await
             .unwrap_or_default()
             .into_iter()
-            .filter(|&(id, _)| db::get_discord_channel_id(id).is_none())
+            .filter(|&(id, _)| !bridged.contains(&ChatRef::Telegram(id)))
             .collect()
     }
 
@@ -405,7 +152,9 @@ impl DiscordState {
         discord_request!(d::Command::create_global_command(
             http,
             d::CreateCommand::new("bridge")
-                .description("Bridge a Telegram chat to this Discord channel.")
+                .description(
+                    "Bridge a Telegram chat, Discord channel, Matrix room or IRC channel to this channel."
+                )
                 .default_member_permissions(d::Permissions::MANAGE_CHANNELS)
                 .add_option(
                     d::CreateCommandOption::new(
@@ -413,18 +162,150 @@ impl DiscordState {
                         "chat",
                         "The Telegram chat to bridge.",
                     )
-                    .required(true)
                     .set_autocomplete(true),
+                )
+                .add_option(d::CreateCommandOption::new(
+                    d::CommandOptionType::String,
+                    "channel",
+                    "The Discord channel to bridge, as an ID or link. It may be in another server.",
+                ))
+                .add_option(d::CreateCommandOption::new(
+                    d::CommandOptionType::String,
+                    "room",
+                    "The ID of the Matrix room to bridge. Only available to bot administrators.",
+                ))
+                .add_option(d::CreateCommandOption::new(
+                    d::CommandOptionType::String,
+                    "irc",
+                    "The IRC channel to bridge, e.g. #channel.",
+                ))
+                .add_option(d::CreateCommandOption::new(
+                    d::CommandOptionType::String,
+                    "code",
+                    "A pairing code from `/bridge_code` in the Telegram chat to bridge.",
+                ))
+                .add_option(d::CreateCommandOption::new(
+                    d::CommandOptionType::String,
+                    "name",
+                    "A name for a new bridge. Defaults to the existing bridge of either chat.",
+                ))
+                .add_option(d::CreateCommandOption::new(
+                    d::CommandOptionType::Boolean,
+                    "forum",
+                    "Mirror the topics of a Telegram forum as threads or forum posts in this channel.",
+                ))
+                .add_option(d::CreateCommandOption::new(
+                    d::CommandOptionType::Boolean,
+                    "crosspost",
+                    "Publish mirrored messages to servers following this announcement channel.",
+                ))
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::String,
+                        "direction",
+                        "Which way messages flow for this channel. Defaults to both ways.",
+                    )
+                    .add_string_choice("Both ways", "both")
+                    .add_string_choice("Only into this channel", "in")
+                    .add_string_choice("Only out of this channel", "out"),
                 ),
         ))
         .await;
+        discord_request!(d::Command::create_global_command(
+            http,
+            d::CreateCommand::new("bridge-code")
+                .description("Get a one-time code that bridges a Telegram chat to this channel with `/pair`.")
+                .default_member_permissions(d::Permissions::MANAGE_CHANNELS),
+        ))
+        .await;
         discord_request!(d::Command::create_global_command(
             http,
             d::CreateCommand::new("unbridge")
-                .description("Remove the Telegram chat bridge in this Discord channel.")
+                .description("Remove this Discord channel from its bridge.")
+                .default_member_permissions(d::Permissions::MANAGE_CHANNELS),
+        ))
+        .await;
+        discord_request!(d::Command::create_global_command(
+            http,
+            d::CreateCommand::new("bridgeinfo")
+                .description("Show what this channel is bridged to and whether mirroring works.")
                 .default_member_permissions(d::Permissions::MANAGE_CHANNELS),
         ))
         .await;
+        discord_request!(d::Command::create_global_command(
+            http,
+            d::CreateCommand::new("bridgeconfig")
+                .description("View or change how this channel takes part in its bridge.")
+                .default_member_permissions(d::Permissions::MANAGE_CHANNELS)
+                .add_option(d::CreateCommandOption::new(
+                    d::CommandOptionType::SubCommand,
+                    "view",
+                    "Show this channel's settings.",
+                ))
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::SubCommand,
+                        "reactions",
+                        "Choose which way reactions are forwarded.",
+                    )
+                    .add_sub_option(d::CreateCommandOption::new(
+                        d::CommandOptionType::Boolean,
+                        "send",
+                        "Forward reactions on this channel's messages to the rest of the bridge.",
+                    ))
+                    .add_sub_option(d::CreateCommandOption::new(
+                        d::CommandOptionType::Boolean,
+                        "receive",
+                        "Forward reactions from the rest of the bridge to this channel.",
+                    )),
+                )
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::SubCommand,
+                        "direction",
+                        "Choose which way messages flow for this channel.",
+                    )
+                    .add_sub_option(
+                        d::CreateCommandOption::new(
+                            d::CommandOptionType::String,
+                            "direction",
+                            "Which way messages flow.",
+                        )
+                        .required(true)
+                        .add_string_choice("Both ways", "both")
+                        .add_string_choice("Only into this channel", "in")
+                        .add_string_choice("Only out of this channel", "out"),
+                    ),
+                )
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::SubCommand,
+                        "attachments",
+                        "Choose whether attachments are mirrored into this channel as files.",
+                    )
+                    .add_sub_option(
+                        d::CreateCommandOption::new(
+                            d::CommandOptionType::Boolean,
+                            "enabled",
+                            "Mirror attachments as files. When disabled, they are listed by name.",
+                        )
+                        .required(true),
+                    ),
+                )
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::SubCommand,
+                        "name-format",
+                        "Choose how the names of people from other chats are shown in this channel.",
+                    )
+                    .add_sub_option(d::CreateCommandOption::new(
+                        d::CommandOptionType::String,
+                        "format",
+                        "E.g. \"{name} ({platform})\". Omit to show names as they are.",
+                    )),
+                ),
+        ))
+        .await;
         discord_request!(d::Command::create_global_command(
             http,
             d::CreateCommand::new("hub")
@@ -467,6 +348,126 @@ impl DiscordState {
                 ).add_context(d::InteractionContext::Guild),
         ))
         .await;
+        discord_request!(d::Command::create_global_command(
+            http,
+            d::CreateCommand::new("hubinvite")
+                .description(
+                    "Manage the invites that let Telegram chats and Matrix rooms bridge to a hub."
+                )
+                .default_member_permissions(d::Permissions::MANAGE_CHANNELS)
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::SubCommand,
+                        "create",
+                        "Make a new invite to a hub.",
+                    )
+                    .add_sub_option(
+                        d::CreateCommandOption::new(
+                            d::CommandOptionType::String,
+                            "hub_name",
+                            "The hub to invite to.",
+                        )
+                        .required(true)
+                        .set_autocomplete(true),
+                    )
+                    .add_sub_option(
+                        d::CreateCommandOption::new(
+                            d::CommandOptionType::Integer,
+                            "expires_in_hours",
+                            "How long the invite works. Omit for an invite that doesn't expire.",
+                        )
+                        .min_int_value(1)
+                        .max_int_value(24 * 365),
+                    )
+                    .add_sub_option(
+                        d::CreateCommandOption::new(
+                            d::CommandOptionType::Integer,
+                            "max_uses",
+                            "How many chats can bridge with the invite. Omit for no limit.",
+                        )
+                        .min_int_value(1),
+                    ),
+                )
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::SubCommand,
+                        "list",
+                        "Show the invites to a hub that can still be used.",
+                    )
+                    .add_sub_option(
+                        d::CreateCommandOption::new(
+                            d::CommandOptionType::String,
+                            "hub_name",
+                            "The hub whose invites to show.",
+                        )
+                        .required(true)
+                        .set_autocomplete(true),
+                    ),
+                )
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::SubCommand,
+                        "revoke",
+                        "Stop an invite from working.",
+                    )
+                    .add_sub_option(
+                        d::CreateCommandOption::new(
+                            d::CommandOptionType::String,
+                            "invite",
+                            "The invite to revoke.",
+                        )
+                        .required(true),
+                    ),
+                )
+                .add_context(d::InteractionContext::Guild),
+        ))
+        .await;
+        discord_request!(d::Command::create_global_command(
+            http,
+            d::CreateCommand::new("hubconfig")
+                .description("Change whether bridging to a hub needs approval and how many channels it gets.")
+                .default_member_permissions(d::Permissions::MANAGE_CHANNELS)
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::String,
+                        "hub_name",
+                        "The hub to change. Give no other options to see its settings.",
+                    )
+                    .required(true)
+                    .set_autocomplete(true),
+                )
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::Channel,
+                        "approval_channel",
+                        "Where requests to bridge are posted for approval. Setting it requires approval.",
+                    )
+                    .channel_types(vec![d::ChannelType::Text]),
+                )
+                .add_option(d::CreateCommandOption::new(
+                    d::CommandOptionType::Boolean,
+                    "require_approval",
+                    "False to create channels without approval again.",
+                ))
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::Integer,
+                        "max_pending",
+                        "How many requests can wait for approval at once. 0 for no limit.",
+                    )
+                    .min_int_value(0),
+                )
+                .add_option(
+                    d::CreateCommandOption::new(
+                        d::CommandOptionType::Integer,
+                        "max_channels_per_day",
+                        "How many channels the hub creates in a day. 0 for no limit.",
+                    )
+                    .min_int_value(0),
+                )
+                .add_context(d::InteractionContext::Guild),
+        ))
+        .await;
         discord_request!(d::Command::create_global_command(
             http,
             d::CreateCommand::new("hubinfo").description("Provides info about the hub feature."),
@@ -496,80 +497,365 @@ impl DiscordState {
             };
         }
 
-        if let Some(existing_telegram_channel) = db::get_telegram_chat_id(command.channel_id) {
+        let option = |name: &str| {
+            command
+                .data
+                .options
+                .iter()
+                .find(|opt| opt.name == name)
+                .map(|opt| &opt.value)
+        };
+        let name = option("name").and_then(|v| v.as_str());
+        let target = match (
+            option("chat"),
+            option("channel"),
+            option("room"),
+            option("irc"),
+            option("code"),
+        ) {
+            (Some(chat), None, None, None, None) => {
+                let Some(chat_id) = chat.as_i64() else {
+                    reply!(ephemeral: "Invalid chat selected.");
+                    return;
+                };
+                ChatRef::Telegram(t::ChatId(chat_id))
+            }
+            (None, Some(channel), None, None, None) => {
+                let Some(channel_id) = channel.as_str().and_then(parse_discord_channel) else {
+                    reply!(ephemeral: "Invalid channel. Use a channel ID or a link to the channel.");
+                    return;
+                };
+                if channel_id == command.channel_id {
+                    reply!(ephemeral: "A channel can't be bridged to itself.");
+                    return;
+                }
+                ChatRef::Discord(channel_id)
+            }
+            (None, None, Some(room), None, None) => {
+                let Some(room) = room.as_str().map(str::trim).filter(|r| r.starts_with('!')) else {
+                    reply!(ephemeral: "Invalid room. Use the room's internal ID, which starts with `!`.");
+                    return;
+                };
+                // there's no way to check the user's permissions in the room from here
+                if !db::admins().await.contains(&command.user.id) {
+                    reply!(ephemeral: "Only bot administrators can bridge Matrix rooms from Discord. Use `!bridge <hub invite>` in the room instead.");
+                    return;
+                }
+                ChatRef::Matrix(room.to_string())
+            }
+            (None, None, None, Some(channel), None) => {
+                let Some(channel) = channel
+                    .as_str()
+                    .map(str::trim)
+                    .filter(|c| irc::is_channel_name(c))
+                else {
+                    reply!(ephemeral: "Invalid IRC channel. Channel names start with `#` or `&`.");
+                    return;
+                };
+                // the bot joins whatever channel is named, which nobody on IRC agreed to
+                if !db::admins().await.contains(&command.user.id) {
+                    reply!(ephemeral: "Only bot administrators can bridge IRC channels.");
+                    return;
+                }
+                ChatRef::Irc(channel.to_lowercase())
+            }
+            (None, None, None, None, Some(code)) => match code.as_str().and_then(pairing::peek) {
+                Some(chat @ ChatRef::Telegram(_)) => chat,
+                Some(_) => {
+                    reply!(ephemeral: "That code was made on Discord. Use it with `/pair` in the Telegram chat instead.");
+                    return;
+                }
+                None => {
+                    reply!(ephemeral: "Invalid or expired code. Run `/bridge_code` in the Telegram chat to get a new one.");
+                    return;
+                }
+            },
+            _ => {
+                reply!(ephemeral: "Specify exactly one of `chat`, `channel`, `room`, `irc` or `code`.");
+                return;
+            }
+        };
+        let kind = target.description();
+        let forum = option("forum").and_then(|v| v.as_bool()).unwrap_or(false);
+        let mut channel_id = command.channel_id;
+        if forum {
+            let ChatRef::Telegram(chat_id) = target else {
+                reply!(ephemeral: "Only Telegram forums can be bridged with `forum`.");
+                return;
+            };
+            if !self.bridge().telegram.is_forum(chat_id).await {
+                reply!(ephemeral: "That Telegram chat doesn't have topics enabled.");
+                return;
+            }
+            // commands can't be used in a forum channel itself, only in its posts
+            if let Some(channel) = &command.channel {
+                if channel.thread_metadata.is_some() {
+                    channel_id = channel.parent_id.unwrap_or(channel_id);
+                }
+            }
+        }
+        let discord_chat = ChatRef::Discord(channel_id);
+        let crosspost = option("crosspost")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if crosspost
+            && command
+                .channel
+                .as_ref()
+                .is_none_or(|c| c.kind != d::ChannelType::News)
+        {
+            reply!(ephemeral: "Only messages in announcement channels can be published.");
+            return;
+        }
+        let direction = option("direction")
+            .and_then(|v| v.as_str())
+            .and_then(|v| v.parse::<db::Direction>().ok());
+
+        if let Some(name) = name {
+            if !db::bridge_members(name).is_empty() {
+                reply!(ephemeral: format!(
+                    "A bridge named \"{name}\" already exists. `name` only names a new bridge, so pick another one."
+                ));
+                return;
+            }
+        }
+        let discord_bridge = db::bridge_of(&discord_chat);
+        let target_bridge = db::bridge_of(&target);
+        if discord_bridge.is_some() && discord_bridge == target_bridge {
             reply!(ephemeral: format!(
-                "This Discord channel is already bridged to a Telegram chat: <#{}>.\nUse /unbridge to remove that bridge.",
-                existing_telegram_channel
+                "This {kind} is already part of this channel's bridge \"{}\".",
+                discord_bridge.unwrap_or_default()
             ));
             return;
         }
-
-        let Some(chat_id) = command
-            .data
-            .options
-            .get(0)
-            .and_then(|opt| opt.value.as_i64())
-        else {
-            reply!(ephemeral: "Invalid chat selected.");
-            return;
-        };
-
-        let telegram_chat_id = t::ChatId(chat_id);
-
-        // Check if this Telegram chat is already mapped to a Discord channel
-        if let Some((existing_discord_channel, _)) = db::get_discord_channel_id(telegram_chat_id) {
+        if let (Some(a), Some(b)) = (&discord_bridge, &target_bridge) {
             reply!(ephemeral: format!(
-                "This Telegram chat is already bridged to another Discord channel: <#{}>",
-                existing_discord_channel
+                "This channel is part of the bridge \"{a}\" and the {kind} is part of the bridge \"{b}\".\nUse /unbridge to remove one of them first."
             ));
             return;
         }
-
-        // Verify that the bot is a member of the chat by trying to get chat info
-        let get_chat_result = telegram_request!(
-            self.telegram_bot.get_chat(telegram_chat_id),
-            log::error!("Failed to get chat info for {}", telegram_chat_id.0)
+        for (current, what) in [
+            (&discord_bridge, "This channel".to_string()),
+            (&target_bridge, format!("The {kind}")),
+        ] {
+            if let (Some(current), Some(name)) = (current, name) {
+                if current != name {
+                    reply!(ephemeral: format!(
+                        "{what} is already part of the bridge \"{current}\".\nUse /unbridge to remove it first."
+                    ));
+                    return;
+                }
+            }
+        }
+        // a pairing code was made by an admin of the chat, who agreed to the join
+        let paired = option("code").is_some();
+        if let (None, Some(existing), false) = (&discord_bridge, &target_bridge, paired) {
+            if !self.manages_bridge(ctx, command.user.id, existing).await {
+                reply!(ephemeral: format!(
+                    "The {kind} is already part of the bridge \"{existing}\", and you don't manage any of its channels. \
+                    Ask someone who does to run /bridge in one of them."
+                ));
+                return;
+            }
+        }
+
+        let title = match &target {
+            &ChatRef::Telegram(chat_id) => self.telegram_chat_title(chat_id).await,
+            &ChatRef::Discord(channel_id) => {
+                self.discord_channel_title(ctx, command.user.id, channel_id)
+                    .await
+            }
+            ChatRef::Matrix(room) => match &self.bridge().matrix {
+                Some(matrix) => matrix
+                    .room_name(room)
+                    .await
+                    .ok_or("The bot isn't in that Matrix room. Please invite it first."),
+                None => Err("Matrix isn't set up for this bot."),
+            },
+            ChatRef::Irc(channel) => match &self.bridge().irc {
+                Some(_) => Ok(channel.clone()),
+                None => Err("IRC isn't set up for this bot."),
+            },
+        };
+        let title = match title {
+            Ok(title) => title,
+            Err(e) => {
+                reply!(ephemeral: e);
+                return;
+            }
+        };
+        let bridge_name = name
+            .map(str::to_string)
+            .or(discord_bridge)
+            .or(target_bridge)
+            .unwrap_or_else(|| db::unique_bridge_name(&title));
+        let db = &self.bridge().db;
+        let chats = [discord_chat.clone(), target.clone()];
+        let user = UserRef::Discord(command.user.id);
+        // the code is only used up once nothing else can stop the bridge
+        let code = option("code").and_then(|v| v.as_str());
+        if let Some(code) = code {
+            if pairing::redeem(code).as_ref() != Some(&target) {
+                reply!(ephemeral: "Invalid or expired code. Run `/bridge_code` in the Telegram chat to get a new one.");
+                return;
+            }
+        }
+        if let Err(e) = db::join_bridge(db, &bridge_name, &chats, Some(&user)).await {
+            log::error!("Failed to join bridge: {}", e);
+            if let Some(code) = code {
+                pairing::restore(code, target.clone());
+            }
+            reply!(ephemeral: "Failed to bridge chat. Please try again later.");
+            return;
+        }
+        if let (ChatRef::Irc(channel), Some(irc)) = (&target, &self.bridge().irc) {
+            irc.join(channel);
+        }
+        if forum {
+            for chat in [&discord_chat, &target] {
+                if let Err(e) = db::set_forum(db, chat, true).await {
+                    log::error!("Failed to enable forum mode: {e}");
+                }
+            }
+        }
+        if crosspost {
+            if let Err(e) = db::set_crosspost(db, &discord_chat, true).await {
+                log::error!("Failed to enable crossposting: {e}");
+            }
+        }
+        if let Some(direction) = direction {
+            if let Err(e) = db::set_direction(db, &discord_chat, direction).await {
+                log::error!("Failed to set direction: {e}");
+            }
+        }
+        reply!(format!(
+            "Successfully bridged {kind} \"{title}\" to this channel as part of the bridge \"{bridge_name}\"!"
+        ));
+
+        let channel_name = command
+            .channel
+            .as_ref()
+            .and_then(|c| c.name.as_deref())
+            .unwrap_or("[name unknown]");
+        for peer in db::bridged_chats(&discord_chat) {
+            let text = if peer == target {
+                format!("A bridge has been created to the Discord channel \"{channel_name}\".")
+            } else {
+                format!("The {kind} \"{title}\" has joined this bridge.")
+            };
+            self.bridge().notify(&peer, &text).await;
+        }
+    }
+
+    async fn telegram_chat_title(&self, chat_id: t::ChatId) -> Result<String, &'static str> {
+        // Verify that the bot is a member of the chat by trying to get chat info
+        let get_chat_result = telegram_request!(
+            self.telegram_bot.get_chat(chat_id),
+            log::error!("Failed to get chat info for {}", chat_id.0)
         )
         .await;
 
         let Some(chat) = get_chat_result else {
             // Bot is not a member of the chat, mark it as not a member in the database
             if let Err(e) =
-                db::update_chat_membership(&self.db, telegram_chat_id, "Unknown chat", false).await
+                db::update_chat_membership(&self.db, chat_id, "Unknown chat", false).await
             {
                 log::error!("Failed to update chat membership: {}", e);
             }
-            reply!(ephemeral: "The bot is not a member of this Telegram chat. Please add the bot to the chat first.");
-            return;
+            return Err("The bot is not a member of this Telegram chat. Please add the bot to the chat first.");
         };
-        let title = chat
+        Ok(chat
             .title()
             .or_else(|| chat.username())
             .unwrap_or("unknown chat")
-            .to_string();
-        match db::set_chat_mapping(command.channel_id, t::ChatId(chat_id), None).await {
-            Ok(()) => reply!(format!(
-                "Successfully bridged Telegram chat \"{title}\" to this channel!"
-            )),
-            Err(e) => {
-                log::error!("Failed to set chat mapping: {}", e);
-                reply!(ephemeral: "Failed to bridge chat. Please try again later.");
-                return;
+            .to_string())
+    }
+
+    /// Whether the user may add chats to the bridge, as a bot admin or someone
+    /// with Manage Channels in one of its Discord channels.
+    async fn manages_bridge(&self, ctx: &d::Context, user: d::UserId, name: &str) -> bool {
+        if db::admins().await.contains(&user) {
+            return true;
+        }
+        for member in db::bridge_members(name) {
+            if let ChatRef::Discord(channel_id) = member.chat {
+                if self
+                    .discord_channel_title(ctx, user, channel_id)
+                    .await
+                    .is_ok()
+                {
+                    return true;
+                }
             }
         }
+        false
+    }
 
-        let telegram_notification = self.telegram_bot.send_message(
-            telegram_chat_id,
-            format!(
-                "A bridge has been created to the Discord channel \"{}\".",
-                command
-                    .channel
-                    .as_ref()
-                    .and_then(|c| c.name.as_deref())
-                    .unwrap_or("[name unknown]")
-            ),
-        );
-        telegram_request!(telegram_notification.send_ref()).await;
+    async fn discord_channel_title(
+        &self,
+        ctx: &d::Context,
+        user: d::UserId,
+        channel_id: d::ChannelId,
+    ) -> Result<String, &'static str> {
+        let Some(channel) = discord_request!(channel_id.to_channel(ctx))
+            .await
+            .and_then(|c| c.guild())
+        else {
+            return Err("The bot can't see that channel. Please add the bot to its server first.");
+        };
+        if !matches!(channel.kind, d::ChannelType::Text | d::ChannelType::News) {
+            return Err("Only text and announcement channels can be bridged.");
+        }
+        // the other side of the bridge is managed by whoever runs the command,
+        // so they need the same permissions there as for this channel
+        let Some(member) = discord_request!(channel.guild_id.member(ctx, user)).await else {
+            return Err("You must be a member of the channel's server to bridge it.");
+        };
+        let permissions = match ctx.cache.guild(channel.guild_id) {
+            Some(guild) => guild.user_permissions_in(&channel, &member),
+            None => return Err("The bot can't see that channel's server. Please try again later."),
+        };
+        if !permissions.manage_channels() {
+            return Err("You need the Manage Channels permission in that channel to bridge it.");
+        }
+        Ok(channel.name)
+    }
+
+    async fn handle_bridge_code_command(&self, ctx: &d::Context, command: &d::CommandInteraction) {
+        macro_rules! reply {
+            ($r:expr $(,)?) => {{
+                let r = $r;
+                let t: &str = r.as_ref();
+                discord_request!(command.create_response(
+                    &ctx.http,
+                    d::CreateInteractionResponse::Message(
+                        d::CreateInteractionResponseMessage::new()
+                            .content(t)
+                            .ephemeral(true),
+                    ),
+                ))
+                .await;
+            }};
+        }
+        if command.guild_id.is_none() {
+            reply!("Only channels in a server can be bridged.");
+            return;
+        }
+        // the permission the command needs can be overridden per channel, and
+        // whoever uses the code is trusted to have been allowed to bridge it
+        if let Err(e) = self
+            .discord_channel_title(ctx, command.user.id, command.channel_id)
+            .await
+        {
+            reply!(e);
+            return;
+        }
+        let code = pairing::issue(ChatRef::Discord(command.channel_id));
+        reply!(format!(
+            "Run `/pair {code}` in the Telegram chat to bridge it to this channel. \
+            The code works once, within {} minutes.",
+            pairing::CODE_LIFETIME.as_secs() / 60
+        ));
     }
 
     async fn handle_unbridge_command(&self, ctx: &d::Context, command: &d::CommandInteraction) {
@@ -592,28 +878,218 @@ impl DiscordState {
                 reply!(internal: $r, true)
             };
         }
-        // Check if the channel is currently bridged
-        let Some(telegram_chat_id) = db::get_telegram_chat_id(command.channel_id) else {
-            reply!(ephemeral: "This channel is not currently bridged to any Telegram chat.");
-            return;
-        };
-        // Remove the bridge mapping
-        match db::remove_chat_mapping(db::EitherChatId::Discord(command.channel_id)).await {
-            Ok(_) => {
-                reply!("Successfully unbridged this channel from Telegram.");
-                let telegram_notification = self.telegram_bot.send_message(
-                    telegram_chat_id,
-                    "The bridge to this channel has been removed.",
-                );
-                telegram_request!(telegram_notification.send_ref()).await;
+        match db::leave_bridge(&self.bridge().db, &ChatRef::Discord(command.channel_id)).await {
+            Ok(Some((_, remaining))) => {
+                reply!("Successfully unbridged this channel.");
+                for chat in remaining {
+                    self.bridge()
+                        .notify(&chat, "A Discord channel has left this bridge.")
+                        .await;
+                }
+            }
+            Ok(None) => {
+                reply!(ephemeral: "This channel is not currently bridged to any chat.");
             }
             Err(e) => {
-                log::error!("Failed to remove chat mapping: {}", e);
+                log::error!("Failed to leave bridge: {}", e);
                 reply!(ephemeral: "Failed to unbridge channel. Please try again later.");
             }
         }
     }
 
+    async fn handle_bridgeinfo_command(&self, ctx: &d::Context, command: &d::CommandInteraction) {
+        macro_rules! reply {
+            ($r:expr $(,)?) => {{
+                let r = $r;
+                let t: &str = r.as_ref();
+                discord_request!(command.create_response(
+                    &ctx.http,
+                    d::CreateInteractionResponse::Message(
+                        d::CreateInteractionResponseMessage::new()
+                            .content(t)
+                            .ephemeral(true),
+                    ),
+                ))
+                .await;
+            }};
+        }
+        let bridge = self.bridge();
+        // a thread mirroring a Telegram topic belongs to its channel's bridge
+        let chat = bridge.home(&ChatRef::Discord(command.channel_id));
+        let ChatRef::Discord(channel_id) = chat else {
+            return;
+        };
+        let Some(name) = db::bridge_of(&chat) else {
+            reply!("This channel is not currently bridged to any chat.");
+            return;
+        };
+        let members = db::bridge_members(&name);
+
+        let mut lines = vec![format!("**Bridge \"{name}\"**")];
+        let (created_at, created_by) =
+            db::bridge_created(&bridge.db, &name)
+                .await
+                .unwrap_or_else(|e| {
+                    log::error!("Failed to look up bridge: {e}");
+                    (None, None)
+                });
+        let created_by = match created_by {
+            Some(UserRef::Discord(user)) => format!(" by <@{user}>"),
+            Some(UserRef::Telegram(user)) => format!(" by Telegram user `{user}`"),
+            Some(UserRef::Matrix(user)) => format!(" by Matrix user `{user}`"),
+            Some(_) | None => String::new(),
+        };
+        match created_at {
+            Some(created_at) => lines.push(format!("Created <t:{created_at}:f>{created_by}")),
+            None if !created_by.is_empty() => lines.push(format!("Created{created_by}")),
+            None => {}
+        }
+        if let Some(member) = members.iter().find(|m| m.chat == chat) {
+            lines.push(format!("This channel: {}", describe_member(member)));
+        }
+        lines.push(format!(
+            "Mirroring into this channel: {}",
+            describe_deliveries(bridge, &chat)
+        ));
+        let webhook = match db::get_webhook_url(channel_id) {
+            None => {
+                "not created yet, which happens when the first message is mirrored here".to_string()
+            }
+            Some(url) => match d::Webhook::from_url(&ctx.http, &url).await {
+                Ok(webhook) if webhook.channel_id == Some(channel_id) => {
+                    format!("working (\"{}\")", webhook.name.unwrap_or_default())
+                }
+                Ok(_) => "belongs to another channel".to_string(),
+                Err(e) => format!("failing: {e}"),
+            },
+        };
+        lines.push(format!("Webhook: {webhook}"));
+
+        for member in members.iter().filter(|m| m.chat != chat) {
+            let peer = &member.chat;
+            let title = chat_title(bridge, peer).await;
+            let id = match peer {
+                ChatRef::Discord(channel) => format!("<#{channel}>"),
+                ChatRef::Telegram(chat_id) => format!("`{chat_id}`"),
+                ChatRef::Matrix(id) | ChatRef::Irc(id) => format!("`{id}`"),
+            };
+            let title = title.map_or("the bot can't see it".to_string(), |t| format!("\"{t}\""));
+            lines.push(format!("\n**{}** {title} ({id})", peer.description()));
+            lines.push(describe_member(member));
+            let (sent, received) = futures::join!(
+                db::count_mirrored(&bridge.db, &chat, peer),
+                db::count_mirrored(&bridge.db, peer, &chat),
+            );
+            match (sent, received) {
+                (Ok(sent), Ok(received)) => lines.push(format!(
+                    "Messages: {sent} mirrored there, {received} mirrored from there"
+                )),
+                (Err(e), _) | (_, Err(e)) => log::error!("Failed to count messages: {e}"),
+            }
+            lines.push(format!(
+                "Mirroring into it: {}",
+                describe_deliveries(bridge, peer)
+            ));
+        }
+
+        reply!(lines.join("\n"));
+    }
+
+    async fn handle_bridgeconfig_command(&self, ctx: &d::Context, command: &d::CommandInteraction) {
+        macro_rules! reply {
+            (internal: $r:expr, $ephem:expr) => {{
+                let r = $r;
+                let t: &str = r.as_ref();
+                discord_request!(command.create_response(
+                    &ctx.http,
+                    d::CreateInteractionResponse::Message(
+                        d::CreateInteractionResponseMessage::new()
+                            .content(t)
+                            .ephemeral($ephem),
+                    ),
+                ))
+                .await;
+            }};
+            ($r:expr $(,)?) => {reply!(internal: $r, false)};
+            (ephemeral: $r:expr $(,)?) => {
+                reply!(internal: $r, true)
+            };
+        }
+        let bridge = self.bridge();
+        let chat = bridge.home(&ChatRef::Discord(command.channel_id));
+        let Some(member) = db::member(&chat) else {
+            reply!(ephemeral: "This channel is not currently bridged to any chat.");
+            return;
+        };
+        let options = command.data.options();
+        let Some(d::ResolvedOption {
+            name: subcommand,
+            value: d::ResolvedValue::SubCommand(options),
+            ..
+        }) = options.first()
+        else {
+            return;
+        };
+        let bool_option = |name: &str| {
+            options.iter().find_map(|opt| match opt.value {
+                d::ResolvedValue::Boolean(b) if opt.name == name => Some(b),
+                _ => None,
+            })
+        };
+        let str_option = |name: &str| {
+            options.iter().find_map(|opt| match opt.value {
+                d::ResolvedValue::String(s) if opt.name == name => Some(s),
+                _ => None,
+            })
+        };
+
+        let result = match *subcommand {
+            "view" => {
+                reply!(ephemeral: format!("This channel: {}", describe_member(&member)));
+                return;
+            }
+            "reactions" => {
+                let send = bool_option("send").unwrap_or(member.send_reactions);
+                let receive = bool_option("receive").unwrap_or(member.receive_reactions);
+                db::set_reactions(&bridge.db, &chat, send, receive).await
+            }
+            "direction" => {
+                let Some(Ok(direction)) = str_option("direction").map(str::parse) else {
+                    reply!(ephemeral: "Invalid direction.");
+                    return;
+                };
+                db::set_direction(&bridge.db, &chat, direction).await
+            }
+            "attachments" => {
+                let Some(enabled) = bool_option("enabled") else {
+                    reply!(ephemeral: "Expected whether to mirror attachments.");
+                    return;
+                };
+                db::set_attachments(&bridge.db, &chat, enabled).await
+            }
+            "name-format" => {
+                let name_format = str_option("format").filter(|f| !f.trim().is_empty());
+                if name_format.is_some_and(|f| !f.contains("{name}")) {
+                    reply!(ephemeral: "The name format has to contain `{name}`, which is replaced by the name.");
+                    return;
+                }
+                db::set_name_format(&bridge.db, &chat, name_format.map(String::from)).await
+            }
+            _ => return,
+        };
+        match (result, db::member(&chat)) {
+            (Ok(()), Some(member)) => reply!(format!(
+                "Updated this channel's settings: {}",
+                describe_member(&member)
+            )),
+            (Ok(()), None) => {}
+            (Err(e), _) => {
+                log::error!("Failed to update bridge settings: {e}");
+                reply!(ephemeral: "Failed to update the settings. Please try again later.");
+            }
+        }
+    }
+
     async fn handle_hub_command(&self, ctx: &d::Context, command: &d::CommandInteraction) {
         macro_rules! reply {
             (internal: $r:expr, $ephem:expr) => {{
@@ -671,9 +1147,9 @@ impl DiscordState {
             Some(c) => db::Hub::Category(guild_id, c),
             None => db::Hub::Server(guild_id),
         };
-        match db::add_hub_server(name.to_string(), hub).await {
+        match db::add_hub_server(&self.bridge().db, name.to_string(), hub).await {
             Ok(true) => reply!(format!(
-                "Successfully created hub named \"{name}\"! Use `/unhub` to undo."
+                "Successfully created hub named \"{name}\"! Use `/hubinvite create` to invite chats to it, or `/unhub` to undo."
             )),
             Ok(false) => {
                 reply!(ephemeral: format!("The name \"{name}\" is taken, try again with another name."))
@@ -716,7 +1192,7 @@ impl DiscordState {
             reply!(ephemeral: "Name cannot contain whitespace or be empty.");
             return;
         }
-        match db::remove_hub_server(name, guild_id).await {
+        match db::remove_hub_server(&self.bridge().db, name, guild_id).await {
             Ok(Some(db::Hub::Category(_, c))) => reply!(format!(
                 "Successfully removed hub named \"{name}\" for category <#{}>!",
                 u64::from(c)
@@ -735,69 +1211,497 @@ impl DiscordState {
             }
         }
     }
-    async fn handle_bridge_autocomplete(
-        &self,
-        ctx: &d::Context,
-        autocomplete: &d::CommandInteraction,
-    ) {
-        let choices = if db::admins().await.contains(&autocomplete.user.id) {
-            self.get_available_telegram_chats()
-                .await
-                .into_iter()
-                // .filter(|chat| chat.title.to_lowercase().contains(&input))
-                .take(25)
-                .map(|(id, title)| {
-                    d::AutocompleteChoice::new(format!("{} ({})", title, id.0), id.0)
-                })
-                .collect::<Vec<_>>()
-        } else {
-            vec![]
-        };
-
-        if let Err(e) = ctx
-            .http
-            .create_interaction_response(
-                autocomplete.id,
-                &autocomplete.token,
-                &d::CreateInteractionResponse::Autocomplete(
-                    d::CreateAutocompleteResponse::new().set_choices(choices),
-                ),
-                vec![],
-            )
-            .await
-        {
-            log::error!("Failed to respond to autocomplete: {}", e);
+    async fn handle_hubinvite_command(&self, ctx: &d::Context, command: &d::CommandInteraction) {
+        macro_rules! reply {
+            (internal: $r:expr, $ephem:expr) => {{
+                let r = $r;
+                let t: &str = r.as_ref();
+                discord_request!(command.create_response(
+                    &ctx.http,
+                    d::CreateInteractionResponse::Message(
+                        d::CreateInteractionResponseMessage::new()
+                            .content(t)
+                            .ephemeral($ephem),
+                    ),
+                ))
+                .await;
+            }};
+            ($r:expr $(,)?) => {reply!(internal: $r, false)};
+            (ephemeral: $r:expr $(,)?) => {
+                reply!(internal: $r, true)
+            };
         }
-    }
-    async fn handle_unhub_autocomplete(
-        &self,
-        ctx: &d::Context,
-        autocomplete: &d::CommandInteraction,
-    ) {
-        let Some(guild_id) = autocomplete.guild_id else {
+        let Some(guild_id) = command.guild_id else {
+            reply!(ephemeral: "Only servers (and not DMs or group DMs) can have hubs.");
             return;
         };
-        let Some(auto) = autocomplete.data.autocomplete() else {
+        let options = command.data.options();
+        let Some(d::ResolvedOption {
+            name: subcommand,
+            value: d::ResolvedValue::SubCommand(options),
+            ..
+        }) = options.first()
+        else {
             return;
         };
-        let choices = db::hubs_for_server(guild_id);
-        let choices = choices
-            .into_iter()
-            .filter(|c| c.0.starts_with(auto.value))
-            .map(|(name, hub)| {
-                d::AutocompleteChoice::new(
-                    match hub {
-                        db::Hub::Category(_, c) => format!("{name} <#{}>", u64::from(c)),
-                        db::Hub::Server(_) => name.clone(),
-                    },
-                    name,
-                )
+        let str_option = |name: &str| {
+            options.iter().find_map(|opt| match opt.value {
+                d::ResolvedValue::String(s) if opt.name == name => Some(s),
+                _ => None,
             })
-            .collect::<Vec<_>>();
-        if let Err(e) = ctx
-            .http
-            .create_interaction_response(
-                autocomplete.id,
+        };
+        let int_option = |name: &str| {
+            options.iter().find_map(|opt| match opt.value {
+                d::ResolvedValue::Integer(i) if opt.name == name => Some(i),
+                _ => None,
+            })
+        };
+        let db = &self.bridge().db;
+
+        if *subcommand == "revoke" {
+            let Some(invite) = str_option("invite").map(str::trim) else {
+                reply!(ephemeral: "Expected an invite to revoke.");
+                return;
+            };
+            match db::revoke_hub_invite(db, invite, guild_id).await {
+                Ok(true) => reply!(ephemeral: "Revoked the invite."),
+                Ok(false) => {
+                    reply!(ephemeral: "No invite to a hub of this server matches that.")
+                }
+                Err(e) => {
+                    log::error!("Failed to revoke hub invite: {e}");
+                    reply!(ephemeral: "Failed to revoke the invite. Please try again later.");
+                }
+            }
+            return;
+        }
+        let Some(name) = str_option("hub_name") else {
+            reply!(ephemeral: "Expected the name of a hub.");
+            return;
+        };
+        if !db::hubs_for_server(guild_id).iter().any(|(n, _)| n == name) {
+            reply!(ephemeral: format!("No hub named \"{name}\" found for your server."));
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        match *subcommand {
+            "create" => {
+                let expires_at = match int_option("expires_in_hours") {
+                    Some(hours) => match hours.checked_mul(3600).and_then(|s| now.checked_add(s)) {
+                        Some(expires_at) => Some(expires_at),
+                        None => {
+                            reply!(ephemeral: "That's too long for an invite to work.");
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+                let max_uses = int_option("max_uses");
+                let user = UserRef::Discord(command.user.id);
+                let invite = match db::create_hub_invite(db, name, &user, expires_at, max_uses)
+                    .await
+                {
+                    Ok(invite) => invite,
+                    Err(e) => {
+                        log::error!("Failed to create hub invite: {e}");
+                        reply!(ephemeral: "Failed to create the invite. Please try again later.");
+                        return;
+                    }
+                };
+                let expiry = match expires_at {
+                    Some(expires_at) => format!("expires <t:{expires_at}:R>"),
+                    None => "doesn't expire".to_string(),
+                };
+                let uses = match max_uses {
+                    Some(1) => "can be used once".to_string(),
+                    Some(max_uses) => format!("can be used {max_uses} times"),
+                    None => "can be used any number of times".to_string(),
+                };
+                reply!(ephemeral: format!(
+                    "Created an invite to the hub \"{name}\": `{invite}`\n\
+                    To bridge a chat to the hub, an admin runs `/bridge {invite}` in the Telegram chat \
+                    or `!bridge {invite}` in the Matrix room. The invite {expiry} and {uses}. \
+                    Only share it with people you trust, and revoke it with `/hubinvite revoke` if needed."
+                ));
+            }
+            "list" => {
+                let invites = match db::hub_invites(db, name).await {
+                    Ok(invites) => invites,
+                    Err(e) => {
+                        log::error!("Failed to get hub invites: {e}");
+                        reply!(ephemeral: "Failed to get the invites. Please try again later.");
+                        return;
+                    }
+                };
+                if invites.is_empty() {
+                    reply!(ephemeral: format!(
+                        "The hub \"{name}\" has no invites. Make one with `/hubinvite create`."
+                    ));
+                    return;
+                }
+                let mut lines = vec![format!("**Invites to the hub \"{name}\"**")];
+                for invite in invites {
+                    let expiry = match invite.expires_at {
+                        Some(expires_at) => format!("expires <t:{expires_at}:R>"),
+                        None => "doesn't expire".to_string(),
+                    };
+                    let uses = match invite.max_uses {
+                        Some(max_uses) => format!("used {} of {max_uses} times", invite.uses),
+                        None => format!("used {} times", invite.uses),
+                    };
+                    lines.push(format!("`{}`: {expiry}, {uses}", invite.token));
+                }
+                reply!(ephemeral: lines.join("\n"));
+            }
+            _ => {}
+        }
+    }
+    async fn handle_hubconfig_command(&self, ctx: &d::Context, command: &d::CommandInteraction) {
+        macro_rules! reply {
+            (internal: $r:expr, $ephem:expr) => {{
+                let r = $r;
+                let t: &str = r.as_ref();
+                discord_request!(command.create_response(
+                    &ctx.http,
+                    d::CreateInteractionResponse::Message(
+                        d::CreateInteractionResponseMessage::new()
+                            .content(t)
+                            .ephemeral($ephem),
+                    ),
+                ))
+                .await;
+            }};
+            ($r:expr $(,)?) => {reply!(internal: $r, false)};
+            (ephemeral: $r:expr $(,)?) => {
+                reply!(internal: $r, true)
+            };
+        }
+        let Some(guild_id) = command.guild_id else {
+            reply!(ephemeral: "Only servers (and not DMs or group DMs) can have hubs.");
+            return;
+        };
+        let options = command.data.options();
+        let str_option = |name: &str| {
+            options.iter().find_map(|opt| match opt.value {
+                d::ResolvedValue::String(s) if opt.name == name => Some(s),
+                _ => None,
+            })
+        };
+        let bool_option = |name: &str| {
+            options.iter().find_map(|opt| match opt.value {
+                d::ResolvedValue::Boolean(b) if opt.name == name => Some(b),
+                _ => None,
+            })
+        };
+        let int_option = |name: &str| {
+            options.iter().find_map(|opt| match opt.value {
+                d::ResolvedValue::Integer(i) if opt.name == name => Some(i),
+                _ => None,
+            })
+        };
+        let channel_option = |name: &str| {
+            options.iter().find_map(|opt| match opt.value {
+                d::ResolvedValue::Channel(c) if opt.name == name => Some(c.id),
+                _ => None,
+            })
+        };
+        let Some(name) = str_option("hub_name") else {
+            reply!(ephemeral: "Expected the name of a hub.");
+            return;
+        };
+        if !db::hubs_for_server(guild_id).iter().any(|(n, _)| n == name) {
+            reply!(ephemeral: format!("No hub named \"{name}\" found for your server."));
+            return;
+        }
+        let db = &self.bridge().db;
+        let mut settings = match db::hub_settings(db, name).await {
+            Ok(settings) => settings,
+            Err(e) => {
+                log::error!("Failed to get hub settings: {e}");
+                reply!(ephemeral: "Failed to get the hub's settings. Please try again later.");
+                return;
+            }
+        };
+        if let Some(channel) = channel_option("approval_channel") {
+            settings.approval_channel = Some(channel);
+        }
+        match bool_option("require_approval") {
+            Some(false) => settings.approval_channel = None,
+            Some(true) if settings.approval_channel.is_none() => {
+                reply!(ephemeral: "Choose an approval_channel for requests to be posted in.");
+                return;
+            }
+            _ => {}
+        }
+        // 0 lifts the limit
+        if let Some(max) = int_option("max_pending") {
+            settings.max_pending = (max > 0).then_some(max);
+        }
+        if let Some(max) = int_option("max_channels_per_day") {
+            settings.max_channels_per_day = (max > 0).then_some(max);
+        }
+        if options.len() > 1 {
+            match db::set_hub_settings(db, name, guild_id, &settings).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    reply!(ephemeral: format!("No hub named \"{name}\" found for your server."));
+                    return;
+                }
+                Err(e) => {
+                    log::error!("Failed to set hub settings: {e}");
+                    reply!(ephemeral: "Failed to change the hub's settings. Please try again later.");
+                    return;
+                }
+            }
+        }
+        let approval = match settings.approval_channel {
+            Some(channel) => format!("requests to bridge are posted in <#{channel}> for approval"),
+            None => "channels are created without approval".to_string(),
+        };
+        let limit = |max: Option<i64>| match max {
+            Some(max) => max.to_string(),
+            None => "no limit".to_string(),
+        };
+        reply!(ephemeral: format!(
+            "**Settings of the hub \"{name}\"**\n\
+            Approval: {approval}\n\
+            Requests waiting for approval: {}\n\
+            Channels created per day: {}",
+            limit(settings.max_pending),
+            limit(settings.max_channels_per_day),
+        ));
+    }
+    async fn handle_hub_request_button(
+        &self,
+        ctx: &d::Context,
+        component: &d::ComponentInteraction,
+    ) {
+        macro_rules! reply {
+            ($r:expr $(,)?) => {{
+                let r = $r;
+                let t: &str = r.as_ref();
+                discord_request!(component.create_response(
+                    &ctx.http,
+                    d::CreateInteractionResponse::Message(
+                        d::CreateInteractionResponseMessage::new()
+                            .content(t)
+                            .ephemeral(true),
+                    ),
+                ))
+                .await;
+            }};
+        }
+        let Some((action, id)) = component
+            .data
+            .custom_id
+            .strip_prefix(HUB_REQUEST_BUTTON)
+            .and_then(|rest| rest.split_once(':'))
+        else {
+            return;
+        };
+        let Ok(id) = id.parse() else { return };
+        let can_manage = component
+            .member
+            .as_ref()
+            .and_then(|m| m.permissions)
+            .is_some_and(|p| p.manage_channels());
+        if !can_manage {
+            reply!("Only members who can manage channels can answer requests to bridge.");
+            return;
+        }
+        let bridge = self.bridge();
+        let request = match db::hub_request(&bridge.db, id).await {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                reply!("This request has already been answered.");
+                return;
+            }
+            Err(e) => {
+                log::error!("Failed to get hub request: {e}");
+                reply!("An internal error occurred. Try again later.");
+                return;
+            }
+        };
+        let hub = db::get_hub_server(&request.hub)
+            .await
+            .filter(|hub| Some(hub.guild()) == component.guild_id);
+        let approve = action == "approve" && hub.is_some();
+        let hub_lock = bridge.hub_lock(&request.hub);
+        let _creating = if approve {
+            Some(hub_lock.lock().await)
+        } else {
+            None
+        };
+        if approve {
+            let settings = match db::hub_settings(&bridge.db, &request.hub).await {
+                Ok(settings) => settings,
+                Err(e) => {
+                    log::error!("Failed to get hub settings: {e}");
+                    reply!("An internal error occurred. Try again later.");
+                    return;
+                }
+            };
+            match hub_at_daily_limit(bridge, &request.hub, &settings).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    reply!("The hub has created as many channels today as it allows. Approve the request again tomorrow.");
+                    return;
+                }
+                Err(e) => {
+                    reply!(e);
+                    return;
+                }
+            }
+        }
+        // whoever removes the request answers it, so it's never answered twice
+        match db::remove_hub_request(&bridge.db, id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                reply!("This request has already been answered.");
+                return;
+            }
+            Err(e) => {
+                log::error!("Failed to remove hub request: {e}");
+                reply!("An internal error occurred. Try again later.");
+                return;
+            }
+        }
+        // creating the channel can take longer than an interaction may go unanswered
+        discord_request!(
+            component.create_response(&ctx.http, d::CreateInteractionResponse::Acknowledge)
+        )
+        .await;
+
+        let moderator = component.user.id;
+        let (created, outcome, notice) = match hub {
+            Some(hub) if approve => {
+                match bridge_to_hub(
+                    bridge,
+                    &request.hub,
+                    hub,
+                    request.chat.clone(),
+                    &request.chat_name,
+                    request.requested_by.as_ref(),
+                    request.direction,
+                )
+                .await
+                {
+                    Ok(()) => (
+                        true,
+                        format!("Approved by <@{moderator}>."),
+                        format!(
+                            "The request to bridge to the hub \"{}\" was approved, and the channel was created.",
+                            request.hub
+                        ),
+                    ),
+                    Err(e) => (
+                        false,
+                        format!("Approved by <@{moderator}>, but the channel couldn't be created: {e}"),
+                        format!(
+                            "The request to bridge to the hub \"{}\" was approved, but the channel couldn't be created. Try again later.",
+                            request.hub
+                        ),
+                    ),
+                }
+            }
+            Some(_) => (
+                false,
+                format!("Denied by <@{moderator}>."),
+                format!(
+                    "The request to bridge to the hub \"{}\" was denied.",
+                    request.hub
+                ),
+            ),
+            None => (
+                false,
+                "The hub no longer exists.".to_string(),
+                format!(
+                    "The request to bridge to the hub \"{}\" was denied, as the hub no longer exists.",
+                    request.hub
+                ),
+            ),
+        };
+        if let (false, Some(invite)) = (created, &request.invite) {
+            if let Err(e) = db::unuse_hub_invite(&bridge.db, invite).await {
+                log::error!("Failed to give back hub invite use: {e}");
+            }
+        }
+        let content = format!("{}\n{outcome}", component.message.content);
+        discord_request!(component.edit_response(
+            &ctx.http,
+            d::EditInteractionResponse::new()
+                .content(&content)
+                .components(vec![]),
+        ))
+        .await;
+        bridge.notify(&request.chat, &notice).await;
+    }
+    async fn handle_bridge_autocomplete(
+        &self,
+        ctx: &d::Context,
+        autocomplete: &d::CommandInteraction,
+    ) {
+        let choices = if db::admins().await.contains(&autocomplete.user.id) {
+            self.get_available_telegram_chats(autocomplete.channel_id)
+                .await
+                .into_iter()
+                // .filter(|chat| chat.title.to_lowercase().contains(&input))
+                .take(25)
+                .map(|(id, title)| {
+                    d::AutocompleteChoice::new(format!("{} ({})", title, id.0), id.0)
+                })
+                .collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+
+        if let Err(e) = ctx
+            .http
+            .create_interaction_response(
+                autocomplete.id,
+                &autocomplete.token,
+                &d::CreateInteractionResponse::Autocomplete(
+                    d::CreateAutocompleteResponse::new().set_choices(choices),
+                ),
+                vec![],
+            )
+            .await
+        {
+            log::error!("Failed to respond to autocomplete: {}", e);
+        }
+    }
+    async fn handle_hub_autocomplete(
+        &self,
+        ctx: &d::Context,
+        autocomplete: &d::CommandInteraction,
+    ) {
+        let Some(guild_id) = autocomplete.guild_id else {
+            return;
+        };
+        let Some(auto) = autocomplete.data.autocomplete() else {
+            return;
+        };
+        let choices = db::hubs_for_server(guild_id);
+        let choices = choices
+            .into_iter()
+            .filter(|c| c.0.starts_with(auto.value))
+            .map(|(name, hub)| {
+                d::AutocompleteChoice::new(
+                    match hub {
+                        db::Hub::Category(_, c) => format!("{name} <#{}>", u64::from(c)),
+                        db::Hub::Server(_) => name.clone(),
+                    },
+                    name,
+                )
+            })
+            .collect::<Vec<_>>();
+        if let Err(e) = ctx
+            .http
+            .create_interaction_response(
+                autocomplete.id,
                 &autocomplete.token,
                 &d::CreateInteractionResponse::Autocomplete(
                     d::CreateAutocompleteResponse::new().set_choices(choices),
@@ -825,49 +1729,35 @@ impl d::EventHandler for DiscordState {
         if msg.webhook_id.is_some() {
             return;
         }
-        let telegram_chat = match db::get_telegram_chat_id(msg.channel_id) {
-            Some(chat_id) => chat_id,
-            None => {
-                log::info!("Got message {msg:?} in unregistered discord channel");
-                return;
-            }
-        };
-        let content = msg.content_safe(&ctx);
-        let has_body = !content.is_empty();
-        let content = format::discord_to_telegram_format(&content);
-        let author = format::discord_author_name(&ctx, &msg).await;
-
-        let mut text = format!("<b>{author}</b>\n{content}");
-
-        let mut reply_to_message_id = None;
-
-        if let Some(ref_msg) = msg.referenced_message.clone() {
-            // if a message is being replied to, find the original message in
-            // the database and reply to that message's reflection
-            let found_mirror = match db::get_telegram_message_id(&self.db, ref_msg.id.into())
-                .await
-                .as_deref()
-            {
-                Ok(&[(mirror_id, _), ..]) => {
-                    reply_to_message_id = Some(mirror_id);
-                    true
+        let bridge = self.bridge();
+        if !bridge.is_bridged(&ChatRef::Discord(msg.channel_id)) {
+            let thread = msg.guild_id.and_then(|guild_id| {
+                let guild = ctx.cache.guild(guild_id)?;
+                let thread = guild.threads.iter().find(|t| t.id == msg.channel_id)?;
+                Some((thread.parent_id?, thread.name.clone()))
+            });
+            match thread {
+                // a thread that was created before its parent mirrored topics
+                Some((parent, name)) if db::is_forum(&ChatRef::Discord(parent)) => {
+                    bridge.mirror_thread(parent, msg.channel_id, &name).await;
                 }
-                Ok([]) => false,
-                Err(e) => {
-                    log::error!("Database lookup failed: {e}");
-                    false
+                _ => {
+                    let parent = thread.map(|(parent, _)| parent);
+                    if let Some(root) = bridge.comment_root(msg.channel_id, parent).await {
+                        for message in platform::discord::bridge_messages(&ctx, &msg).await {
+                            bridge.relay_thread_comment(message, root.clone()).await;
+                        }
+                        return;
+                    }
+                    log::info!("Got message {msg:?} in unregistered discord channel");
+                    return;
                 }
-            };
-            // if we couldn't find the message in the database, copy the message
-            // as a block quote
-            if !found_mirror {
-                let ref_content = format::discord_to_telegram_format(&ref_msg.content);
-                let ref_author = format::discord_author_name(&ctx, &ref_msg).await;
-                text = format!(
-                    "<blockquote expandable><b>{ref_author}</b>\n{ref_content}</blockquote>\n{text}"
-                );
             }
         }
+        // the channel only mirrors the rest of its bridge
+        if !bridge.sends(&ChatRef::Discord(msg.channel_id)) {
+            return;
+        }
         if msg
             .sticker_items
             .iter()
@@ -882,52 +1772,8 @@ impl d::EventHandler for DiscordState {
             let _ = msg.reply(&ctx, "Lottie format stickers (including unfortunately Discord's default stickers) are unsupported.").await;
         }
 
-        if msg.message_snapshots.len() == 0
-            || has_body
-            || !msg.attachments.is_empty()
-            || !msg.sticker_items.is_empty()
-        {
-            self.send_message(
-                telegram_chat,
-                msg.id,
-                &text,
-                msg.attachments,
-                msg.sticker_items,
-                reply_to_message_id,
-            )
-            .await;
-            reply_to_message_id = None;
-        }
-        if msg.message_snapshots.len() > 0 {
-            if msg.message_snapshots.len() > 1 {
-                log::error!(
-                    "More than 1 forwarded message is unsupported, {:?}",
-                    &msg.message_snapshots
-                )
-            }
-            let snapshot = msg
-                .message_snapshots
-                .into_iter()
-                .next()
-                .expect("length > 0");
-            let content = d::content_safe(
-                &ctx,
-                snapshot.content,
-                &d::ContentSafeOptions::default(),
-                &[],
-            );
-            let content = format::discord_to_telegram_format(&content);
-
-            let text = format!("<b>{author}</b> (forwarded)\n{content}");
-            self.send_message(
-                telegram_chat,
-                msg.id,
-                &text,
-                snapshot.attachments,
-                snapshot.sticker_items,
-                reply_to_message_id,
-            )
-            .await;
+        for message in platform::discord::bridge_messages(&ctx, &msg).await {
+            bridge.relay_message(message).await;
         }
     }
 
@@ -946,91 +1792,27 @@ impl d::EventHandler for DiscordState {
             return;
         }
         if upd.webhook_id.is_some_and(|id| id.is_some()) {
-            return;
-        }
-        if upd.content.is_none() {
-            return;
-        }
-        let telegram_chat = match db::get_telegram_chat_id(upd.channel_id.clone()) {
-            Some(chat_id) => chat_id,
-            None => {
-                log::info!("Got message {upd:?} in unregistered discord channel");
-                return;
-            }
-        };
-        match db::get_telegram_message_id(&self.db, upd.id)
-            .await
-            .as_deref()
-        {
-            Ok(&[(mirror_id, has_caption), ..]) => {
-                let content = d::content_safe(
-                    &ctx,
-                    upd.content.as_deref().unwrap_or(""),
-                    &d::ContentSafeOptions::default(),
-                    &[],
-                );
-                let text = format::discord_to_telegram_format(&content);
-
-                let mut msg_with_author =
-                    ctx.cache.message(upd.channel_id, upd.id).map(|m| m.clone());
-
-                if msg_with_author.is_none() {
-                    msg_with_author =
-                        discord_request(|| ctx.http.get_message(upd.channel_id, upd.id), || ())
-                            .await;
-                }
-
-                let author = match msg_with_author {
-                    Some(msg) => format::discord_author_name(&ctx, &msg).await,
-                    None => "Unknown".into(),
-                };
-                let mut message_text = format!("<b>{author}</b>\n{text}");
-
-                if let Some(ref_msg_id) = upd
-                    .referenced_message
-                    .as_ref()
-                    .and_then(|m| m.as_ref().map(|m| m.id))
-                {
-                    let found_mirror = match db::get_telegram_message_id(&self.db, ref_msg_id)
-                        .await
-                        .as_deref()
-                    {
-                        Ok([]) => false,
-                        Ok(_) => true,
-                        Err(e) => {
-                            log::error!("Database lookup failed: {e}");
-                            false
-                        }
-                    };
-                    if !found_mirror {
-                        if let Some(ref_msg) =
-                            upd.referenced_message.as_ref().and_then(|m| m.as_ref())
-                        {
-                            let ref_content = format::discord_to_telegram_format(&ref_msg.content);
-                            let ref_author = format::discord_author_name(&ctx, ref_msg).await;
-                            message_text = format!("<blockquote expandable><b>{ref_author}</b>\n{ref_content}</blockquote>\n{message_text}");
-                        }
-                    }
-                }
-                if !has_caption {
-                    let builder = self
-                        .telegram_bot
-                        .edit_message_text(telegram_chat, mirror_id, message_text)
-                        .parse_mode(t::ParseMode::Html);
-
-                    telegram_request!(builder.send_ref(), edbg!(author, content, text),).await;
-                } else {
-                    let builder = self
-                        .telegram_bot
-                        .edit_message_caption(telegram_chat, mirror_id)
-                        .caption(message_text)
-                        .parse_mode(t::ParseMode::Html);
-                    telegram_request!(builder.send_ref(), edbg!(author, content, text),).await;
+            return;
+        }
+        if upd.content.is_none() {
+            return;
+        }
+        let bridge = self.bridge();
+        if !bridge.is_bridged(&ChatRef::Discord(upd.channel_id)) {
+            if bridge.comment_root(upd.channel_id, None).await.is_some() {
+                if let Some(message) = platform::discord::bridge_edit(&ctx, &upd).await {
+                    bridge.relay_comment_edit(message).await;
                 }
+                return;
             }
-            // the edited message had no known counterpart so do nothing
-            Ok([]) => {}
-            Err(e) => log::error!("Failed to get message mapping: {}", e),
+            log::info!("Got message {upd:?} in unregistered discord channel");
+            return;
+        }
+        if !bridge.sends(&ChatRef::Discord(upd.channel_id)) {
+            return;
+        }
+        if let Some(message) = platform::discord::bridge_edit(&ctx, &upd).await {
+            bridge.relay_edit(message).await;
         }
     }
 
@@ -1041,22 +1823,19 @@ impl d::EventHandler for DiscordState {
         msg_id: d::MessageId,
         _guild_id: Option<d::GuildId>,
     ) {
-        let telegram_chat = match db::get_telegram_chat_id(channel_id.clone()) {
-            Some(chat_id) => chat_id,
-            None => {
-                log::info!("Got message {msg_id:?} in unregistered discord channel");
-                return;
-            }
-        };
-        match db::delete_by_discord(&self.db, msg_id).await {
-            Ok(mirror_ids) => {
-                for telegram_id in mirror_ids {
-                    telegram_request!(self.telegram_bot.delete_message(telegram_chat, telegram_id))
-                        .await;
-                }
-            }
-            Err(e) => log::error!("Failed to delete message mapping: {}", e),
+        let bridge = self.bridge();
+        if !bridge.is_bridged(&ChatRef::Discord(channel_id))
+            && bridge.comment_root(channel_id, None).await.is_none()
+        {
+            log::info!("Got message {msg_id:?} in unregistered discord channel");
+            return;
+        }
+        if !bridge.sends(&ChatRef::Discord(channel_id)) {
+            return;
         }
+        bridge
+            .relay_delete(MessageRef::Discord(channel_id, msg_id))
+            .await;
     }
 
     async fn reaction_add(&self, ctx: d::Context, reaction: d::Reaction) {
@@ -1066,153 +1845,86 @@ impl d::EventHandler for DiscordState {
         {
             return;
         }
-        if !db::should_forward_reactions(db::EitherChatId::Discord(reaction.channel_id)) {
-            return;
-        }
-        let Some(telegram_chat) = db::get_telegram_chat_id(reaction.channel_id) else {
+        let bridge = self.bridge();
+        if !bridge.is_bridged(&ChatRef::Discord(reaction.channel_id)) {
             log::info!("Got reaction {reaction:?} in unregistered discord channel");
             return;
-        };
-        let Some(emoji) = format::discord_reaction_string(&reaction.emoji) else {
-            log::info!("Got reaction {reaction:?} with nameless emoji");
+        }
+        if !bridge.sends(&ChatRef::Discord(reaction.channel_id)) {
             return;
-        };
-        let discord_id = reaction.message_id;
-        let telegram_id = match db::get_telegram_message_id(&self.db, discord_id)
-            .await
-            .as_deref()
-        {
-            Ok(&[(telegram_id, _), ..]) => telegram_id,
-            Ok([]) => {
-                log::info!("Got reaction {reaction:?} with no known counterpart");
-                return;
-            }
-            Err(e) => {
-                log::error!("Failed to get telegram message id: {}", e);
-                return;
-            }
-        };
-        match db::get_telegram_reaction_message_id(&self.db, discord_id).await {
-            Ok(Some((reaction_message_id, reactions))) => {
-                // Reaction message exists, update it
-                let mut reactions = format::parse_telegram_reaction_message(&reactions);
-                let author = format::discord_reactor_name(&ctx, &reaction).await;
-                reactions.entry(author).or_default().push(emoji.to_string());
-
-                let new_text = format::format_telegram_reaction_message(&reactions);
-
-                if let Some(_) = telegram_request!(self
-                    .telegram_bot
-                    .edit_message_text(telegram_chat, reaction_message_id, &new_text)
-                    .parse_mode(t::ParseMode::Html))
-                .await
-                {
-                    if let Err(e) =
-                        db::update_telegram_reaction_mapping(&self.db, discord_id, &new_text).await
-                    {
-                        log::error!("Failed to update reaction message mapping: {}", e);
-                    }
-                }
-            }
-            Err(e) => log::error!("Failed to get reaction message mapping: {}", e),
-            Ok(None) => {
-                // Reaction message doesn't exist, create a new one
-                let author = format::discord_reactor_name(&ctx, &reaction).await;
-                let text = format!("<b>Reactions</b>\n<b>{}</b>: {}", author, emoji);
-
-                if let Some(telegram_msg) = telegram_request!(self
-                    .telegram_bot
-                    .send_message(telegram_chat, &text)
-                    .parse_mode(t::ParseMode::Html)
-                    .reply_parameters(t::ReplyParameters::new(telegram_id)))
-                .await
-                {
-                    if let Err(e) = db::insert_reaction_mapping(
-                        &self.db,
-                        discord_id,
-                        telegram_msg.id,
-                        telegram_chat,
-                        &text,
-                    )
-                    .await
-                    {
-                        log::error!("Failed to insert reaction message mapping: {}", e);
-                    }
-                }
-            }
+        }
+        if let Some(update) = platform::discord::reaction_update(&ctx, &reaction, true).await {
+            bridge.relay_reaction(update).await;
         }
     }
 
     async fn reaction_remove(&self, ctx: d::Context, reaction: d::Reaction) {
-        if !db::should_forward_reactions(db::EitherChatId::Discord(reaction.channel_id)) {
+        let bridge = self.bridge();
+        if !bridge.is_bridged(&ChatRef::Discord(reaction.channel_id)) {
+            log::info!("Got reaction {reaction:?} in unregistered discord channel");
             return;
         }
-        let Some(telegram_chat) = db::get_telegram_chat_id(reaction.channel_id) else {
-            log::info!("Got reaction {reaction:?} in unregistered discord channel");
+        if !bridge.sends(&ChatRef::Discord(reaction.channel_id)) {
             return;
-        };
-        let Some(emoji) = format::discord_reaction_string(&reaction.emoji) else {
-            log::info!("Got reaction {reaction:?} with nameless emoji");
+        }
+        if let Some(update) = platform::discord::reaction_update(&ctx, &reaction, false).await {
+            bridge.relay_reaction(update).await;
+        }
+    }
+
+    async fn thread_create(&self, ctx: d::Context, thread: d::GuildChannel) {
+        // threads created by the bot already mirror a Telegram topic
+        if thread.owner_id == Some(ctx.cache.current_user().id) {
+            return;
+        }
+        let Some(parent) = thread.parent_id else {
             return;
         };
-        let discord_id = reaction.message_id;
-        let author = format::discord_reactor_name(&ctx, &reaction).await;
-        match db::get_telegram_reaction_message_id(&self.db, discord_id).await {
-            Ok(Some((telegram_id, reactions))) => {
-                let mut reactions = format::parse_telegram_reaction_message(&reactions);
-                reactions.entry(author).or_default().retain(|e| e != emoji);
-                let new_text = format::format_telegram_reaction_message(&reactions);
-                if new_text == "<b>Reactions</b>" {
-                    if let Some(_) = telegram_request!(self
-                        .telegram_bot
-                        .delete_message(telegram_chat, telegram_id))
-                    .await
-                    {
-                        if let Err(e) =
-                            db::remove_reaction_mapping_by_discord(&self.db, discord_id).await
-                        {
-                            log::error!("Failed to remove reaction message mapping: {}", e);
-                        }
-                    }
-                } else {
-                    if let Some(_) = telegram_request!(self
-                        .telegram_bot
-                        .edit_message_text(telegram_chat, telegram_id, &new_text)
-                        .parse_mode(t::ParseMode::Html))
-                    .await
-                    {
-                        if let Err(e) =
-                            db::update_telegram_reaction_mapping(&self.db, discord_id, &new_text)
-                                .await
-                        {
-                            log::error!("Failed to update reaction message mapping: {}", e);
-                        }
-                    }
-                }
-            }
-            Ok(None) => {
-                log::info!("Got reaction removal {reaction:?} with no known counterpart");
-            }
-            Err(e) => log::error!("Failed to get reaction message mapping: {}", e),
+        if db::is_forum(&ChatRef::Discord(parent)) {
+            self.bridge()
+                .mirror_thread(parent, thread.id, &thread.name)
+                .await;
+        } else {
+            // a thread opened on a channel post, for its comments
+            self.bridge().comment_root(thread.id, Some(parent)).await;
         }
     }
 
+    async fn thread_update(
+        &self,
+        _ctx: d::Context,
+        _old: Option<d::GuildChannel>,
+        new: d::GuildChannel,
+    ) {
+        let locked = new.thread_metadata.is_some_and(|m| m.locked);
+        self.bridge()
+            .relay_thread_update(new.id, &new.name, locked)
+            .await;
+    }
+
     async fn interaction_create(&self, ctx: d::Context, interaction: d::Interaction) {
         match interaction {
             d::Interaction::Command(command) => match command.data.name.as_str() {
                 "bridge" => self.handle_bridge_command(&ctx, &command).await,
+                "bridge-code" => self.handle_bridge_code_command(&ctx, &command).await,
                 "unbridge" => self.handle_unbridge_command(&ctx, &command).await,
+                "bridgeinfo" => self.handle_bridgeinfo_command(&ctx, &command).await,
+                "bridgeconfig" => self.handle_bridgeconfig_command(&ctx, &command).await,
                 "hub" => self.handle_hub_command(&ctx, &command).await,
                 "unhub" => self.handle_unhub_command(&ctx, &command).await,
+                "hubinvite" => self.handle_hubinvite_command(&ctx, &command).await,
+                "hubconfig" => self.handle_hubconfig_command(&ctx, &command).await,
                 "hubinfo" => {
-                    let info = "Creating a Hub allows people on Telegram who know the name of the hub to bridge channels to the hub. \
-                                A hub can be tied to the whole server or to a specific category.\n\
+                    let info = "Creating a Hub allows people on Telegram or Matrix with an invite to the hub to bridge their chats to it, \
+                                which creates a channel for each chat. A hub can be tied to the whole server or to a specific category.\n\
                                 You can have multiple hubs in the same server and even multiple hubs tied to the same category. \
-                                However, hub names must be globally unique, which means that someone could theoretically discover \
-                                your hub name by attempting to create hubs until they find which fails, then bridge channels to this hub.\n\
-                                However, doing so will only allow them to spam your hub with channels. It will *not* grant access \
-                                to any channels other than the created ones, which is why there is no current mitigation for the issue.\n\
-                                If it becomes an issue, report to the bot administrator.";
+                                Invites are made with `/hubinvite create`, optionally expiring or limited to a number of uses, \
+                                and are used with `/bridge <invite>` on Telegram or `!bridge <invite>` on Matrix.\n\
+                                Invites are secret, so only share them with people you want to bridge to the hub. \
+                                If someone you don't know bridges a chat, revoke the invite with `/hubinvite revoke` and delete the channel. \
+                                To review chats before their channels are created, set an approval channel with `/hubconfig`. \
+                                Hub names must be globally unique, but they are not enough to bridge to a hub \
+                                unless the bot administrator has enabled `public_hub_names`.";
                     discord_request!(command.create_response(
                         &ctx.http,
                         d::CreateInteractionResponse::Message(
@@ -1225,9 +1937,16 @@ impl d::EventHandler for DiscordState {
             },
             d::Interaction::Autocomplete(autocomplete) => match autocomplete.data.name.as_str() {
                 "bridge" => self.handle_bridge_autocomplete(&ctx, &autocomplete).await,
-                "unhub" => self.handle_unhub_autocomplete(&ctx, &autocomplete).await,
+                "unhub" | "hubinvite" | "hubconfig" => {
+                    self.handle_hub_autocomplete(&ctx, &autocomplete).await
+                }
                 _ => {}
             },
+            d::Interaction::Component(component)
+                if component.data.custom_id.starts_with(HUB_REQUEST_BUTTON) =>
+            {
+                self.handle_hub_request_button(&ctx, &component).await
+            }
             _ => {}
         }
     }
@@ -1248,410 +1967,663 @@ impl d::EventHandler for DiscordState {
     }
 }
 
-async fn get_telegram_attachment_as_discord(
-    bot: &t::Bot,
+/// Accepts a channel ID, mention or link.
+fn parse_discord_channel(s: &str) -> Option<d::ChannelId> {
+    let s = s.trim().trim_start_matches("<#").trim_end_matches('>');
+    let id = s.rsplit('/').next()?;
+    id.parse::<u64>()
+        .ok()
+        .filter(|&id| id != 0)
+        .map(d::ChannelId::new)
+}
+
+#[derive(t::BotCommands, Clone, Debug, PartialEq, Eq)]
+#[command(rename_rule = "snake_case")]
+enum TelegramCommand {
+    #[command(description = "show the bot's commands and how to use them")]
+    Help,
+    #[command(
+        description = "bridge this chat: /bridge <hub invite>, /bridge <chat id> or /bridge #<irc channel>, \
+        optionally followed by in or out to only mirror messages into or out of this chat"
+    )]
+    Bridge(String),
+    #[command(
+        description = "get a one-time code that bridges this chat to a Discord channel, \
+        sent in a private message"
+    )]
+    BridgeCode,
+    #[command(
+        description = "bridge this chat to a Discord channel: /pair <code>, with a code from /bridge-code there"
+    )]
+    Pair(String),
+    #[command(description = "remove this chat from its bridge")]
+    Unbridge,
+    #[command(description = "show what this chat is bridged to")]
+    Status,
+    #[command(description = "change how this chat takes part in its bridge")]
+    Settings,
+}
+
+/// Sets the commands Telegram suggests: all of them to group admins, and only
+/// `/help` to everyone else, since the rest need an admin.
+async fn register_telegram_commands(bot: &t::Bot) {
+    let commands = TelegramCommand::bot_commands();
+    let help = commands
+        .iter()
+        .filter(|c| c.command.trim_start_matches('/') == "help")
+        .cloned()
+        .collect::<Vec<_>>();
+    telegram_request!(bot.set_my_commands(help.clone())).await;
+    telegram_request!(bot
+        .set_my_commands(commands.clone())
+        .scope(t::BotCommandScope::AllChatAdministrators))
+    .await;
+}
+
+async fn handle_telegram_help_command(bot: t::Bot, msg: &t::Message) {
+    let text = format!(
+        "{}\n\nAll commands except /help need an administrator capable of managing the chat.",
+        TelegramCommand::descriptions()
+    );
+    let reply = bot
+        .send_message(msg.chat.id, text)
+        .reply_parameters(t::ReplyParameters::new(msg.id));
+    let _ = telegram_request!(reply.send_ref()).await;
+}
+
+async fn handle_telegram_bridge_command(
+    bot: t::Bot,
+    bridge: &Bridge,
     msg: &t::Message,
-) -> Option<d::CreateAttachment> {
-    let common = match &msg.kind {
-        t::MessageKind::Common(common) => common,
-        _ => return None,
+    args: &str,
+) {
+    macro_rules! reply {
+        ($err:expr $(,)?) => {{
+            let err = bot
+                .send_message(msg.chat.id, $err)
+                .parse_mode(t::ParseMode::Html)
+                .reply_parameters(t::ReplyParameters::new(msg.id));
+            let _ = telegram_request!(err.send_ref()).await;
+        }};
+    }
+    let Some(from) = &msg.from else { return };
+    let Some(from) = telegram_request!(bot.get_chat_member(msg.chat.id, from.id)).await else {
+        return;
+    };
+    if !from.can_manage_chat() {
+        reply!("Only administrators capable of managing the chat can create bridges.");
+        return;
+    }
+    let mut args = args.split_whitespace();
+    let (target, direction) = match (args.next(), args.next().map(str::parse), args.next()) {
+        (Some(target), direction, None) if direction.as_ref().is_none_or(Result::is_ok) => {
+            (target, direction.and_then(Result::ok))
+        }
+        _ => {
+            reply!(
+                "Usage: <code>/bridge &lthub invite&gt</code>, <code>/bridge &ltchat id&gt</code> \
+                or <code>/bridge #&ltirc channel&gt</code>, \
+                optionally followed by <code>in</code> or <code>out</code> to only mirror messages into or out of this chat"
+            );
+            return;
+        }
+    };
+    let Some((hub_name, hub, invite)) = find_hub(&bridge.db, target).await else {
+        let chat = match target.parse() {
+            Ok(chat_id) => ChatRef::Telegram(t::ChatId(chat_id)),
+            Err(_) if irc::is_channel_name(target) => ChatRef::Irc(target.to_lowercase()),
+            Err(_) => {
+                reply!(format!("No hub invite found matching \"{target}\". Invites are made on Discord with /hubinvite."));
+                return;
+            }
+        };
+        handle_telegram_chat_bridge(bot, bridge, msg, from.user.id, chat, direction, None).await;
+        return;
+    };
+    let chat_name = msg
+        .chat
+        .title()
+        .or_else(|| msg.chat.username())
+        .unwrap_or("unknown chat name");
+    let chat = ChatRef::Telegram(msg.chat.id);
+    let user = UserRef::Telegram(from.user.id);
+    let requester = match &from.user.username {
+        Some(username) => format!("{} (@{username})", from.user.full_name()),
+        None => from.user.full_name(),
+    };
+    let request = HubBridgeRequest {
+        chat,
+        chat_name,
+        user: &user,
+        requester: &requester,
+        members: telegram_request!(bot.get_chat_member_count(msg.chat.id)).await,
+        direction: direction.unwrap_or_default(),
+        invite,
+    };
+    match request_hub_bridge(bridge, &hub_name, hub, request).await {
+        Ok(false) => reply!("Successfully created and linked channel."),
+        Ok(true) => reply!(
+            "The hub's moderators have been asked to approve the bridge. \
+            The channel will be created once they do."
+        ),
+        Err(e) => {
+            if let Some(invite) = invite {
+                if let Err(e) = db::unuse_hub_invite(&bridge.db, invite).await {
+                    log::error!("Failed to give back hub invite use: {e}");
+                }
+            }
+            reply!(e)
+        }
+    }
+}
+
+/// Finds the hub of an invite, using it up, or with `public_hub_names` the hub
+/// with the name. The invite is returned so that its use can be given back if
+/// bridging fails.
+async fn find_hub<'a>(
+    pool: &sql::Pool,
+    target: &'a str,
+) -> Option<(String, db::Hub, Option<&'a str>)> {
+    match db::use_hub_invite(pool, target).await {
+        Ok(Some((name, hub))) => return Some((name, hub, Some(target))),
+        Ok(None) => {}
+        Err(e) => log::error!("Failed to use hub invite: {e}"),
+    }
+    if !db::public_hub_names().await {
+        return None;
+    }
+    db::get_hub_server(target)
+        .await
+        .map(|hub| (target.to_string(), hub, None))
+}
+
+fn describe_member(member: &db::BridgeMember) -> String {
+    let direction = match member.direction {
+        db::Direction::Both => "messages go both ways",
+        db::Direction::In => "messages only go into it",
+        db::Direction::Out => "messages only go out of it",
+    };
+    let reactions = match (member.send_reactions, member.receive_reactions) {
+        (true, true) => "reactions are sent and received",
+        (true, false) => "reactions are only sent",
+        (false, true) => "reactions are only received",
+        (false, false) => "reactions aren't forwarded",
+    };
+    let mut description = format!("{direction}, {reactions}");
+    if !member.attachments {
+        description.push_str(", attachments are listed by name");
+    }
+    if let Some(name_format) = &member.name_format {
+        description.push_str(&format!(", names are shown as \"{name_format}\""));
+    }
+    description
+}
+
+/// Looks up the name of a chat, which is `None` if the bot can't see it.
+async fn chat_title(bridge: &Bridge, chat: &ChatRef) -> Option<String> {
+    match chat {
+        &ChatRef::Telegram(chat_id) => telegram_request!(bridge.telegram.bot.get_chat(chat_id))
+            .await
+            .and_then(|c| c.title().or_else(|| c.username()).map(str::to_string)),
+        &ChatRef::Discord(channel) => discord_request!(channel.to_channel(&bridge.discord.http))
+            .await
+            .and_then(|c| c.guild())
+            .map(|c| c.name),
+        ChatRef::Matrix(room) => match &bridge.matrix {
+            Some(matrix) => matrix.room_name(room).await,
+            None => None,
+        },
+        ChatRef::Irc(channel) => Some(channel.clone()),
+    }
+}
+
+fn describe_deliveries(bridge: &Bridge, chat: &ChatRef) -> String {
+    let time = |time: Option<SystemTime>| match time {
+        Some(time) => {
+            let secs = time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            format!("<t:{secs}:R>")
+        }
+        None => "not since the bot started".to_string(),
+    };
+    let deliveries = bridge.deliveries.get(chat).map(|d| *d).unwrap_or_default();
+    format!(
+        "last succeeded {}, last failed {}",
+        time(deliveries.last_success),
+        time(deliveries.last_failure)
+    )
+}
+
+const HUB_REQUEST_BUTTON: &str = "hubrequest:";
+
+/// A chat asking to bridge to a hub, with what its moderators are shown about
+/// it if the hub needs approval.
+struct HubBridgeRequest<'a> {
+    chat: ChatRef,
+    chat_name: &'a str,
+    user: &'a UserRef,
+    requester: &'a str,
+    members: Option<u32>,
+    direction: db::Direction,
+    invite: Option<&'a str>,
+}
+
+/// Bridges the chat to the hub, or if the hub needs approval, posts the request
+/// to its approval channel, returning whether the request waits for approval.
+async fn request_hub_bridge(
+    bridge: &Bridge,
+    hub_name: &str,
+    hub: db::Hub,
+    request: HubBridgeRequest<'_>,
+) -> Result<bool, &'static str> {
+    const INTERNAL_ERROR: &str = "An internal error occurred. Try again later.";
+    let settings = db::hub_settings(&bridge.db, hub_name).await.map_err(|e| {
+        log::error!("Failed to get hub settings: {e}");
+        INTERNAL_ERROR
+    })?;
+    let Some(approval_channel) = settings.approval_channel else {
+        let hub_lock = bridge.hub_lock(hub_name);
+        let _creating = hub_lock.lock().await;
+        if hub_at_daily_limit(bridge, hub_name, &settings).await? {
+            return Err(
+                "The hub has created as many channels today as it allows. Try again tomorrow.",
+            );
+        }
+        bridge_to_hub(
+            bridge,
+            hub_name,
+            hub,
+            request.chat,
+            request.chat_name,
+            Some(request.user),
+            request.direction,
+        )
+        .await?;
+        return Ok(false);
+    };
+    let (pending, from_chat) = db::pending_hub_requests(&bridge.db, hub_name, &request.chat)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to count hub requests: {e}");
+            INTERNAL_ERROR
+        })?;
+    if from_chat {
+        return Err("This chat already asked to bridge to the hub and is waiting for approval.");
+    }
+    if settings.max_pending.is_some_and(|max| pending >= max) {
+        return Err(
+            "Too many chats are waiting for approval from the hub's moderators. Try again later.",
+        );
+    }
+    let id = db::add_hub_request(
+        &bridge.db,
+        hub_name,
+        &request.chat,
+        request.chat_name,
+        request.user,
+        request.direction,
+        request.invite,
+    )
+    .await
+    .map_err(|e| {
+        log::error!("Failed to add hub request: {e}");
+        INTERNAL_ERROR
+    })?;
+    let members = match request.members {
+        Some(members) => members.to_string(),
+        None => "unknown".to_string(),
+    };
+    let post = d::CreateMessage::new()
+        .content(format!(
+            "[Hub]: The {} \"{}\" asks to bridge to the hub \"{hub_name}\".\n\
+            Members: {members}\n\
+            Requested by: {} ({})",
+            request.chat.description(),
+            request.chat_name,
+            request.requester,
+            request.user,
+        ))
+        .allowed_mentions(d::CreateAllowedMentions::new())
+        .components(vec![d::CreateActionRow::Buttons(vec![
+            d::CreateButton::new(format!("{HUB_REQUEST_BUTTON}approve:{id}"))
+                .label("Approve")
+                .style(d::ButtonStyle::Success),
+            d::CreateButton::new(format!("{HUB_REQUEST_BUTTON}deny:{id}"))
+                .label("Deny")
+                .style(d::ButtonStyle::Danger),
+        ])]);
+    let http = &bridge.discord.http;
+    if discord_request!(approval_channel.send_message(http, post.clone()))
+        .await
+        .is_none()
+    {
+        if let Err(e) = db::remove_hub_request(&bridge.db, id).await {
+            log::error!("Failed to remove hub request: {e}");
+        }
+        return Err("The request couldn't be posted for the hub's moderators. \
+            Ask them to check the bot's permissions in their approval channel.");
+    }
+    Ok(true)
+}
+
+async fn hub_at_daily_limit(
+    bridge: &Bridge,
+    hub_name: &str,
+    settings: &db::HubSettings,
+) -> Result<bool, &'static str> {
+    let Some(max) = settings.max_channels_per_day else {
+        return Ok(false);
+    };
+    match db::hub_channels_today(&bridge.db, hub_name).await {
+        Ok(created) => Ok(created >= max),
+        Err(e) => {
+            log::error!("Failed to count hub channels: {e}");
+            Err("An internal error occurred. Try again later.")
+        }
+    }
+}
+
+/// Creates a channel for the chat in the hub and bridges the two.
+async fn bridge_to_hub(
+    bridge: &Bridge,
+    hub_name: &str,
+    hub: db::Hub,
+    chat: ChatRef,
+    mut chat_name: &str,
+    user: Option<&UserRef>,
+    direction: db::Direction,
+) -> Result<(), &'static str> {
+    if chat_name.len() > 100 {
+        chat_name = &chat_name[..100];
+    }
+    let http = &bridge.discord.http;
+    let create_channel = d::CreateChannel::new(chat_name).kind(d::ChannelType::Text);
+    let channel = match hub {
+        db::Hub::Server(g) => {
+            discord_request!(g.create_channel(http, create_channel.clone())).await
+        }
+        db::Hub::Category(g, c) => {
+            discord_request!(g.create_channel(http, create_channel.clone().category(c))).await
+        }
+    };
+    let Some(ch) = channel else {
+        return Err("Could not create channel. \
+            Ensure that bot has required Discord permissions in the hub \
+            and hub category exists or try again later.");
+    };
+    let bridge_name = db::bridge_of(&chat).unwrap_or_else(|| db::unique_bridge_name(chat_name));
+    let chats = [chat.clone(), ChatRef::Discord(ch.id)];
+    if let Err(e) = db::join_bridge(&bridge.db, &bridge_name, &chats, user).await {
+        log::error!(
+            "Failed to set mapping for created channel: {e}. Attempting to delete channel."
+        );
+        if let Some(_) = discord_request!(ch.delete(http)).await {
+            log::warn!("Successfully deleted created channel.");
+            return Err("An internal error occurred. Try again later.");
+        } else {
+            log::error!("Could not delete created channel.");
+            let explanation = d::CreateMessage::new().content(
+                "[Hub]: Someone attempted to bridge to this hub, \
+                but an internal error occurred and then this channel could not be deleted. \
+                This channel can be safely deleted.",
+            );
+            let _ = discord_request!(ch.send_message(http, explanation.clone())).await;
+            return Err("The Discord channel was created but a bridge could not be made due to an internal error.");
+        }
+    }
+    if let Err(e) = db::add_hub_channel(&bridge.db, hub_name, ch.id).await {
+        log::error!("Failed to record hub channel: {e}");
+    }
+    if direction != db::Direction::Both {
+        if let Err(e) = db::set_direction(&bridge.db, &chat, direction).await {
+            log::error!("Failed to set direction: {e}");
+        }
+    }
+    let explanation = d::CreateMessage::new().content(format!(
+        "[Hub]: Someone bridged the {} \"{chat_name}\" to this hub. \
+        If this appears to be from someone you do not know, \
+        you should revoke the hub's invites with `/hubinvite revoke` and delete this channel. \
+        Use `/hubinfo` for more info.",
+        chat.description()
+    ));
+    let _ = discord_request!(ch.send_message(http, explanation.clone())).await;
+    Ok(())
+}
+
+async fn handle_telegram_bridge_code_command(bot: t::Bot, msg: &t::Message) {
+    macro_rules! reply {
+        ($text:expr $(,)?) => {{
+            let reply = bot
+                .send_message(msg.chat.id, $text)
+                .reply_parameters(t::ReplyParameters::new(msg.id));
+            let _ = telegram_request!(reply.send_ref()).await;
+        }};
+    }
+    let Some(from) = &msg.from else { return };
+    let Some(from) = telegram_request!(bot.get_chat_member(msg.chat.id, from.id)).await else {
+        return;
     };
-    let (file, file_name) = match common.media_kind.clone() {
-        t::MediaKind::Document(t::MediaDocument { document, .. }) => {
-            (document.file, document.file_name)
-        }
-        t::MediaKind::Photo(t::MediaPhoto { mut photo, .. }) => (photo.pop()?.file, None),
-        t::MediaKind::Video(t::MediaVideo { video, .. }) => (video.file, video.file_name),
-        t::MediaKind::Audio(t::MediaAudio { audio, .. }) => (audio.file, audio.file_name),
-        t::MediaKind::Animation(t::MediaAnimation { animation, .. }) => {
-            (animation.file, animation.file_name)
-        }
-        t::MediaKind::Sticker(t::MediaSticker { sticker, .. }) => (sticker.file, None),
-        _ => return None,
+    if !from.can_manage_chat() {
+        reply!("Only administrators capable of managing the chat can create bridges.");
+        return;
+    }
+    let code = pairing::issue(ChatRef::Telegram(msg.chat.id));
+    let title = msg
+        .chat
+        .title()
+        .or_else(|| msg.chat.username())
+        .unwrap_or("unknown chat");
+    // anyone who sees the code can use it, so it's only sent to the admin
+    let text = format!(
+        "Run /bridge code: {code} in the Discord channel to bridge it to \"{title}\". \
+        The code works once, within {} minutes.",
+        pairing::CODE_LIFETIME.as_secs() / 60
+    );
+    if telegram_request!(bot.send_message(from.user.id, &text), {})
+        .await
+        .is_some()
+    {
+        if !msg.chat.is_private() {
+            reply!("I sent you a pairing code in a private message.");
+        }
+    } else {
+        pairing::redeem(&code);
+        reply!("I couldn't send you the code. Start a private chat with me, then try again.");
+    }
+}
+
+async fn handle_telegram_pair_command(bot: t::Bot, bridge: &Bridge, msg: &t::Message, code: &str) {
+    macro_rules! reply {
+        ($text:expr $(,)?) => {{
+            let reply = bot
+                .send_message(msg.chat.id, $text)
+                .reply_parameters(t::ReplyParameters::new(msg.id));
+            let _ = telegram_request!(reply.send_ref()).await;
+        }};
+    }
+    let Some(from) = &msg.from else { return };
+    let Some(from) = telegram_request!(bot.get_chat_member(msg.chat.id, from.id)).await else {
+        return;
+    };
+    if !from.can_manage_chat() {
+        reply!("Only administrators capable of managing the chat can create bridges.");
+        return;
+    }
+    if code.trim().is_empty() {
+        reply!(
+            "Usage: /pair <code>, with a code from /bridge-code in the Discord channel to bridge"
+        );
+        return;
+    }
+    let target = match pairing::peek(code) {
+        Some(chat @ ChatRef::Discord(_)) => chat,
+        Some(_) => {
+            reply!("That code was made on Telegram. Use it with /bridge code: in the Discord channel instead.");
+            return;
+        }
+        None => {
+            reply!("Invalid or expired code. Run /bridge-code in the Discord channel to get a new one.");
+            return;
+        }
     };
+    handle_telegram_chat_bridge(bot, bridge, msg, from.user.id, target, None, Some(code)).await;
+}
 
-    if file.size > 50 * 1024 * 1024 {
-        telegram_request!(bot
-            .send_message(
-                msg.chat.id,
-                "File too large. Files over 50 MB won't be forwarded"
-            )
-            .reply_parameters(t::ReplyParameters::new(msg.id))
-            .clone())
-        .await;
-        return None;
+async fn handle_telegram_chat_bridge(
+    bot: t::Bot,
+    bridge: &Bridge,
+    msg: &t::Message,
+    user: t::UserId,
+    target: ChatRef,
+    direction: Option<db::Direction>,
+    code: Option<&str>,
+) {
+    macro_rules! reply {
+        ($err:expr $(,)?) => {{
+            let err = bot
+                .send_message(msg.chat.id, $err)
+                .parse_mode(t::ParseMode::Html)
+                .reply_parameters(t::ReplyParameters::new(msg.id));
+            let _ = telegram_request!(err.send_ref()).await;
+        }};
     }
-    let file = match bot.get_file(file.id).await {
-        Ok(file) => file,
-        Err(e) => {
-            log::error!("Failed to get file: {e:?}");
-            return None;
+    let chat = ChatRef::Telegram(msg.chat.id);
+    if target == chat {
+        reply!("A chat can't be bridged to itself.");
+        return;
+    }
+    let kind = target.description();
+    let chat_bridge = db::bridge_of(&chat);
+    let target_bridge = db::bridge_of(&target);
+    match (&chat_bridge, &target_bridge) {
+        (Some(a), Some(b)) if a == b => {
+            reply!(format!(
+                "That {kind} is already part of this chat's bridge."
+            ));
+            return;
         }
-    };
-    let path = file.path;
-    let Some(mut name) = file_name
-        .clone()
-        .or_else(|| path.split('/').last().map(String::from))
-    else {
-        log::error!("Failed to get file name");
-        return None;
-    };
-    if msg.has_media_spoiler() {
-        name = format!("SPOILER_{name}");
+        (Some(_), Some(_)) => {
+            reply!(format!("This chat and the {kind} are already part of different bridges. Remove one with /unbridge first."));
+            return;
+        }
+        _ => {}
     }
-    let mut bytes = Vec::new();
-    let mut retries = RETRIES;
-    let mut backoff = INITIAL_BACKOFF;
-    while let Err(e) = bot.download_file(&path, &mut bytes).await {
-        match e {
-            t::DownloadError::Network(_) if retries > 0 => {
-                tokio::time::sleep(backoff).await;
-                backoff *= 2;
-                retries -= 1;
+    let target_title = match &target {
+        &ChatRef::Telegram(target_id) => {
+            let Some(target_chat) = telegram_request!(bot.get_chat(target_id)).await else {
+                reply!(
+                    "The bot is not a member of that chat. Please add the bot to the chat first."
+                );
+                return;
+            };
+            // whoever bridges the chats has to be able to manage both of them
+            let can_manage = telegram_request!(bot.get_chat_member(target_id, user))
+                .await
+                .is_some_and(|member| member.can_manage_chat());
+            if !can_manage {
+                reply!("Only administrators capable of managing both chats can bridge them.");
+                return;
             }
-            _ => {
-                log::error!("Failed to download file: {e:?}");
-                break;
+            target_chat
+                .title()
+                .or_else(|| target_chat.username())
+                .unwrap_or("unknown chat")
+                .to_string()
+        }
+        // only reached with a pairing code, which someone who can manage the channel made
+        ChatRef::Discord(_) => {
+            let Some(title) = chat_title(bridge, &target).await else {
+                reply!("The bot can no longer see that Discord channel.");
+                return;
+            };
+            title
+        }
+        ChatRef::Irc(channel) if bridge.irc.is_some() => {
+            // the bot joins whatever channel is named, which nobody on IRC agreed
+            // to, so only bot admins bring new channels in, from Discord
+            let Some(existing) = &target_bridge else {
+                reply!("Only bot administrators can bridge new IRC channels. Ask one to run /bridge irc: on Discord, then run /bridge again here.");
+                return;
+            };
+            if !telegram_manages_bridge(&bot, user, existing).await {
+                reply!(format!(
+                    "That IRC channel is already part of the bridge \"{existing}\", and you don't manage any of its chats."
+                ));
+                return;
             }
+            channel.clone()
+        }
+        ChatRef::Irc(_) => {
+            reply!("IRC isn't set up for this bot.");
+            return;
+        }
+        _ => return,
+    };
+    let title = msg
+        .chat
+        .title()
+        .or_else(|| msg.chat.username())
+        .unwrap_or("unknown chat");
+    let bridge_name = chat_bridge
+        .or(target_bridge)
+        .unwrap_or_else(|| db::unique_bridge_name(title));
+    let chats = [chat.clone(), target.clone()];
+    let user = UserRef::Telegram(user);
+    // the code is only used up once nothing else can stop the bridge
+    if let Some(code) = code {
+        if pairing::redeem(code).as_ref() != Some(&target) {
+            reply!("Invalid or expired code. Run /bridge-code in the Discord channel to get a new one.");
+            return;
         }
     }
-    Some(d::CreateAttachment::bytes(bytes, name))
-}
-
-#[derive(Clone, Debug)]
-struct AvatarCacheRecord {
-    url: Arc<str>, // usually a base64 encoded image
-    last_updated: Instant,
-}
-
-async fn telegram_avatar_url_by_id(
-    bot: &t::Bot,
-    cache: &DashMap<t::UserId, AvatarCacheRecord>,
-    user_id: t::UserId,
-    discord_http: &d::Http,
-) -> Option<Arc<str>> {
-    const CACHE_LIFETIME: Duration = Duration::from_secs(60 * 60);
-    let record = cache.get(&user_id);
-    let now = Instant::now();
-    if let Some(record) = record {
-        if record.last_updated + CACHE_LIFETIME > now {
-            return Some(record.url.clone());
-        }
-    }
-    // There was no record, or the record was invalid, we need to fetch the image
-
-    let photos = telegram_request!(bot.get_user_profile_photos(user_id).limit(1)).await;
-    let photo = photos.and_then(|photos| {
-        photos
-            .photos
-            .get(0)
-            .and_then(|sizes| sizes.iter().last().cloned())
-    })?;
-    let file = telegram_request!(bot.get_file(photo.file.id.clone())).await?;
-    let t::File {
-        path,
-        meta: t::FileMeta { size, .. },
-        ..
-    } = file;
-    let mut buf = Vec::with_capacity(size as usize);
-    if let Err(e) = bot.download_file(&path, &mut buf).await {
-        log::error!("Failed to download file: {e:?}");
-        return None;
+    if let Err(e) = db::join_bridge(&bridge.db, &bridge_name, &chats, Some(&user)).await {
+        log::error!("Failed to join bridge: {e}");
+        if let Some(code) = code {
+            pairing::restore(code, target.clone());
+        }
+        reply!("An internal error occurred. Try again later.");
+        return;
     }
-
-    let files = vec![d::CreateAttachment::bytes(buf, "avatar.jpg")];
-    let builder = d::CreateMessage::new().add_files(files.clone());
-    let discord_image_channel = db::discord_image_channel().await?;
-    let message = discord_request!(
-        discord_image_channel.send_message(discord_http.as_ref(), builder.clone())
-    )
-    .await?;
-    let url: Arc<str> = message
-        .attachments
-        .first()
-        .map(|a| Arc::from(a.url.as_str()))
-        .or_else(|| {
-            log::error!("Failed to get avatar URL");
-            None
-        })?;
-
-    let record = AvatarCacheRecord {
-        url: url.clone(),
-        last_updated: Instant::now(),
-    };
-    cache.insert(user_id, record);
-    Some(url)
-}
-
-async fn discord_avatar_url_by_display_name(
-    cache_http: &impl d::CacheHttp,
-    channel: d::ChannelId,
-    display_name: &str,
-) -> Option<String> {
-    let channel = discord_request!(channel.to_channel(cache_http)).await?;
-    let channel = channel.guild()?;
-    let members = channel.members(cache_http.cache()?).ok()?;
-    let member = members
-        .iter()
-        .find(|m| {
-            m.nick
-                .as_deref()
-                .or_else(|| m.user.global_name.as_deref())
-                .unwrap_or(&m.user.name)
-                == display_name
-        })
-        .or_else(|| {
-            members
-                .iter()
-                .find(|m| m.user.global_name.as_deref().unwrap_or(&m.user.name) == display_name)
-        })?;
-    member.user.avatar_url()
-}
-
-struct ReplyInfo {
-    content_suffix: String,
-    embed: d::CreateEmbed,
-    mentions: d::CreateAllowedMentions,
-}
-
-async fn reply_info(
-    bot: &t::Bot,
-    me: &t::Me,
-    msg: &t::Message,
-    avatar_cache: &Arc<DashMap<t::UserId, AvatarCacheRecord>>,
-    discord_http: &d::Http,
-    discord_cache: &Arc<d::Cache>,
-    discord_chat: d::ChannelId,
-    telegram_chat: &t::Chat,
-    db: &SqlitePool,
-    include_author_icon: bool,
-) -> Option<ReplyInfo> {
-    let cache_http = (discord_cache, discord_http);
-    if let Some(ref_msg) = msg.reply_to_message() {
-        let mut mentions = d::CreateAllowedMentions::new();
-        let mut ref_user = None;
-        let mut ref_nick = None;
-        let mut ref_link = None;
-        let mut ref_image = None;
-        let ref_sender_telegram = ref_msg.from.as_ref().map(|f| f.id) != Some(me.id);
-
-        match db::get_discord_message_id(&db, ref_msg.id, telegram_chat.id)
-            .await
-            .as_deref()
-        {
-            Ok(&[mirror_id, ..]) => {
-                let discord_channel = discord_request!(discord_chat.to_channel(cache_http)).await?;
-
-                let mut ref_disc_message = discord_cache
-                    .message(discord_channel, mirror_id)
-                    .map(|msg| msg.clone());
-                if ref_disc_message.is_none() {
-                    ref_disc_message =
-                        discord_request!(discord_http.get_message(discord_chat, mirror_id)).await;
-                }
-                let mut guild_id = None;
-                if let Ok(channel) = discord_chat.to_channel(cache_http).await {
-                    if let Some(c) = channel.guild() {
-                        guild_id = Some(c.guild_id);
-                    }
-                }
-                ref_link = Some(mirror_id.link(discord_chat, guild_id));
-                if let Some(msg) = ref_disc_message {
-                    ref_nick = Some(format::discord_author_name(&cache_http, &msg).await);
-                    ref_user = Some(msg.author);
-                    ref_image = msg.attachments.first().map(|a| a.url.clone()).or_else(|| {
-                        msg.embeds
-                            .first()
-                            .and_then(|e| e.image.as_ref().map(|t| t.url.clone()))
-                    });
-                }
-            }
-            Ok([]) => {
-                log::info!("Replying to message {ref_msg:?} with no known counterpart");
-            }
-            Err(e) => {
-                log::error!("Database lookup failed: {e}");
-            }
+    if let (ChatRef::Irc(channel), Some(irc)) = (&target, &bridge.irc) {
+        irc.join(channel);
+    }
+    if let Some(direction) = direction {
+        if let Err(e) = db::set_direction(&bridge.db, &chat, direction).await {
+            log::error!("Failed to set direction: {e}");
         }
-        let ref_text = msg
-            .quote()
-            .map(|q| {
-                (
-                    q.text.as_str(),
-                    t::MessageEntityRef::parse(&q.text, &q.entities),
-                )
-            })
-            .or_else(|| ref_msg.text().zip(ref_msg.parse_entities()))
-            .or_else(|| ref_msg.caption().zip(ref_msg.parse_caption_entities()))
-            .map(|(t, e)| format::telegram_to_discord_format(t, e))
-            .unwrap_or_default();
-        let (ref_author, ref_text) = if ref_sender_telegram {
-            let ref_author = format::telegram_author_name(ref_msg);
-            (ref_author, ref_text)
-        } else {
-            let mut lines = ref_text.lines();
-            let first_line = lines.next().unwrap_or_else(|| {
-                log::error!("message from bot has no lines");
-                ""
-            });
-            let ref_text = lines.collect::<Vec<_>>().join("\n> ");
-            let ref_author = if let Some(author) = &ref_user {
-                let mention = author.id.mention();
-                mentions = mentions.users(Some(author.id));
-                mention.to_string()
-            } else {
-                first_line
-                    .split("**")
-                    .nth(1)
-                    .unwrap_or("Unknown")
-                    .to_string()
-            };
-            (ref_author, ref_text)
-        };
-        let reply_str = if msg.quote().is_some() {
-            "quoting"
-        } else {
-            "replying to"
-        };
-        let reply_str = if let Some(link) = ref_link {
-            format!("[{reply_str}]({link})")
+    }
+    reply!(format!(
+        "Successfully bridged the {kind} \"{target_title}\" to this chat."
+    ));
+    for peer in db::bridged_chats(&chat) {
+        let text = if peer == target {
+            format!("A bridge has been created to the Telegram chat \"{title}\".")
         } else {
-            reply_str.to_string()
+            format!("The {kind} \"{target_title}\" has joined this bridge.")
         };
-        let mut embed_author = d::CreateEmbedAuthor::new(ref_nick.as_ref().unwrap_or(&ref_author));
-        if include_author_icon {
-            if ref_sender_telegram {
-                if let Some(from) = &ref_msg.from {
-                    if let Some(url) =
-                        telegram_avatar_url_by_id(bot, &*avatar_cache, from.id, discord_http).await
-                    {
-                        embed_author = embed_author.icon_url(&*url);
-                    }
-                }
-            } else if let Some(url) = ref_user.as_ref().and_then(|u| u.avatar_url()) {
-                embed_author = embed_author.icon_url(&*url);
-            }
-        }
-        fn preview(s: &str) -> String {
-            const MAX_LENGTH: usize = 200;
-            const MAX_LINES: usize = 5;
-            let mut changed = false;
-            let s = if s.chars().count() > MAX_LENGTH {
-                changed = true;
-                &s[..s
-                    .char_indices()
-                    .take(MAX_LENGTH)
-                    .filter(|&(_, c)| c.is_whitespace())
-                    .last()
-                    .unwrap()
-                    .0]
-            } else {
-                s
-            };
-            let s = if let Some(last_linebreak) = s
-                .char_indices()
-                .filter(|&(_, c)| c == '\n')
-                .nth(MAX_LINES - 1)
-            {
-                changed = true;
-                &s[..last_linebreak.0 + 1]
-            } else {
-                s
-            };
-            let mut s = s.to_string();
-            if changed {
-                s.push_str("...");
-            }
-            s
-        }
-        let ref_text = preview(&ref_text);
-        let mut embed = d::CreateEmbed::new()
-            .description(ref_text)
-            .author(embed_author);
-        if let Some(image) = ref_image {
-            embed = embed.thumbnail(image);
-        }
-        Some(ReplyInfo {
-            content_suffix: format!("-# **{reply_str} {ref_author}**"),
-            embed,
-            mentions,
-        })
-    } else {
-        None
+        bridge.notify(&peer, &text).await;
     }
 }
 
-const KEYCAPS: [&str; 12] = [
-    "\u{0030}\u{fe0f}\u{20e3}",
-    "\u{0031}\u{fe0f}\u{20e3}",
-    "\u{0032}\u{fe0f}\u{20e3}",
-    "\u{0033}\u{fe0f}\u{20e3}",
-    "\u{0034}\u{fe0f}\u{20e3}",
-    "\u{0035}\u{fe0f}\u{20e3}",
-    "\u{0036}\u{fe0f}\u{20e3}",
-    "\u{0037}\u{fe0f}\u{20e3}",
-    "\u{0038}\u{fe0f}\u{20e3}",
-    "\u{0039}\u{fe0f}\u{20e3}",
-    "\u{1f51f}",
-    "\u{1f520}",
-];
-
-async fn send_poll(
-    webhook: d::Webhook,
-    avatar_handle: tokio::task::JoinHandle<Option<Arc<str>>>,
-    discord_http: Arc<d::Http>,
-    poll: &t::Poll,
-    author: &str,
-) -> Option<d::Message> {
-    let embed = d::CreateEmbed::new()
-        .title(match (&poll.poll_type, poll.allows_multiple_answers) {
-            (t::PollType::Quiz, _) => "Quiz",
-            (_, false) => "Poll (pick one)",
-            (_, true) => "Poll (multiple)",
-        })
-        .description(&poll.question)
-        .fields(poll.options.iter().enumerate().map(|(i, opt)| {
-            (
-                if i < KEYCAPS.len() {
-                    format!("Option {}", KEYCAPS[i])
-                } else {
-                    format!("Option {}", i)
-                },
-                &opt.text,
-                false,
-            )
-        }));
-    let mut builder = d::ExecuteWebhook::new().username(author).embed(embed);
-    if let Ok(Some(avatar_url)) = avatar_handle.await {
-        builder = builder.avatar_url(&*avatar_url);
-    }
-    let discord_result = discord_request!(
-        webhook.execute(discord_http.clone(), true, builder.clone()),
-        edbg!("Poll")
-    )
-    .await
-    .flatten();
-    if let Some(msg) = &discord_result {
-        for i in 0..poll.options.len().min(KEYCAPS.len()) {
-            _ = msg
-                .react(
-                    &discord_http,
-                    d::ReactionType::Unicode(KEYCAPS[i].to_string()),
-                )
-                .await;
+/// Whether the user can manage one of the Telegram chats of the bridge, and so
+/// may add chats to it.
+async fn telegram_manages_bridge(bot: &t::Bot, user: t::UserId, name: &str) -> bool {
+    for member in db::bridge_members(name) {
+        if let ChatRef::Telegram(chat_id) = member.chat {
+            if telegram_request!(bot.get_chat_member(chat_id, user), {})
+                .await
+                .is_some_and(|member| member.can_manage_chat())
+            {
+                return true;
+            }
         }
     }
-    discord_result
+    false
 }
 
-async fn handle_telegram_bridge_command(bot: t::Bot, http: Arc<d::Http>, msg: &t::Message) {
-    let Some(target) = msg
-        .text()
-        .and_then(|s| s.strip_prefix("/bridge "))
-        .map(str::trim)
-    else {
-        return;
-    };
+async fn handle_telegram_unbridge_command(bot: t::Bot, bridge: &Bridge, msg: &t::Message) {
     macro_rules! reply {
         ($err:expr $(,)?) => {{
             let err = bot
@@ -1666,88 +2638,127 @@ async fn handle_telegram_bridge_command(bot: t::Bot, http: Arc<d::Http>, msg: &t
         return;
     };
     if !from.can_manage_chat() {
-        reply!("Only administrators capable of managing the chat can create bridges.")
-    }
-    if let Some((prev_discord_id, _)) = db::get_discord_channel_id(msg.chat.id) {
-        reply!(format!(
-            "This chat is already linked to the discord chat with ID: {prev_discord_id}. Remove with /unbridge first."
-        ));
+        reply!("Only administrators capable of managing the chat can remove bridges.");
         return;
     }
-    if target.contains(char::is_whitespace) || target == "" {
-        reply!(
-            "Usage: <code>/bridge &lthub name&gt</code> \
-            where <code>&lthub name&gt</code> contains no whitespace"
-        );
-        return;
+    let remaining = match db::leave_bridge(&bridge.db, &ChatRef::Telegram(msg.chat.id)).await {
+        Ok(Some((_, remaining))) => remaining,
+        Ok(None) => {
+            reply!("This chat is not bridged to any chats.");
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to leave bridge: {e}");
+            reply!("An internal error occurred trying to remove bridge.");
+            return;
+        }
+    };
+    reply!("Successfully removed bridge!");
+    for chat in remaining {
+        bridge
+            .notify(&chat, "A Telegram chat has left this bridge.")
+            .await;
+    }
+}
+
+async fn handle_telegram_status_command(bot: t::Bot, bridge: &Bridge, msg: &t::Message) {
+    macro_rules! reply {
+        ($text:expr $(,)?) => {{
+            let reply = bot
+                .send_message(msg.chat.id, $text)
+                .reply_parameters(t::ReplyParameters::new(msg.id));
+            let _ = telegram_request!(reply.send_ref()).await;
+        }};
     }
-    let Some(hub) = db::get_hub_server(target).await else {
-        reply!(format!("No hub found matching \"{target}\""));
+    let Some(from) = &msg.from else { return };
+    let Some(from) = telegram_request!(bot.get_chat_member(msg.chat.id, from.id)).await else {
         return;
     };
-    let mut chat_name = msg
-        .chat
-        .title()
-        .or_else(|| msg.chat.username())
-        .unwrap_or("unknown chat name");
-    if chat_name.len() > 100 {
-        chat_name = &chat_name[..100];
+    if !from.can_manage_chat() {
+        reply!("Only administrators capable of managing the chat can see its bridge.");
+        return;
     }
-    let create_channel = d::CreateChannel::new(chat_name).kind(d::ChannelType::Text);
-    let channel = match hub {
-        db::Hub::Server(g) => {
-            discord_request!(g.create_channel(&http, create_channel.clone())).await
-        }
-        db::Hub::Category(g, c) => {
-            discord_request!(g.create_channel(&http, create_channel.clone().category(c))).await
-        }
-    };
-    let Some(ch) = channel else {
-        reply!(
-            "Could not create channel. \
-            Ensure that bot has required Discord permissions in the hub \
-            and hub category exists or try again later."
-        );
+    let chat = ChatRef::Telegram(msg.chat.id);
+    let (Some(name), Some(member)) = (db::bridge_of(&chat), db::member(&chat)) else {
+        reply!("This chat is not bridged to any chats. Use /bridge to create a bridge.");
         return;
     };
-    if let Err(e) = db::set_chat_mapping(ch.id, msg.chat.id, None).await {
-        log::error!(
-            "Failed to set mapping for created channel: {e}. Attempting to delete channel."
+
+    let mut lines = vec![
+        format!("Bridge \"{name}\""),
+        format!("This chat: {}", describe_member(&member)),
+    ];
+    for peer in db::bridge_members(&name).iter().filter(|m| m.chat != chat) {
+        let title = chat_title(bridge, &peer.chat)
+            .await
+            .map_or("the bot can't see it".to_string(), |t| format!("\"{t}\""));
+        lines.push(format!(
+            "\n{} {title} ({})",
+            peer.chat.description(),
+            peer.chat
+        ));
+        lines.push(describe_member(peer));
+        let (sent, received) = futures::join!(
+            db::count_mirrored(&bridge.db, &chat, &peer.chat),
+            db::count_mirrored(&bridge.db, &peer.chat, &chat),
         );
-        if let Some(_) = discord_request!(ch.delete(&http)).await {
-            log::warn!("Successfully deleted created channel.");
-            reply!("An internal error occurred. Try again later.");
-            return;
-        } else {
-            log::error!("Could not delete created channel.");
-            let explanation = d::CreateMessage::new().content(
-                "[Hub]: Someone attempted to bridge to this hub, \
-                but an internal error occurred and then this channel could not be deleted. \
-                This channel can be safely deleted.",
-            );
-            let _ = discord_request!(ch.send_message(&http, explanation.clone())).await;
-            reply!("The Discord channel was created but a bridge could not be made due to an internal error.");
-            return;
+        match (sent, received) {
+            (Ok(sent), Ok(received)) => lines.push(format!(
+                "Messages: {sent} mirrored there, {received} mirrored from there"
+            )),
+            (Err(e), _) | (_, Err(e)) => log::error!("Failed to count messages: {e}"),
         }
     }
-    reply!("Successfully created and linked channel.");
-    let explanation = d::CreateMessage::new().content(format!(
-        "[Hub]: Someone bridged the telegram channel \"{chat_name}\" to this hub. \
-        If this appears to be from someone you do not know, \
-        you should delete the hub and create a new one with a different name. \
-        Use `/hubinfo` for more info."
-    ));
-    let _ = discord_request!(ch.send_message(&http, explanation.clone())).await;
+
+    reply!(lines.join("\n"));
+}
+
+const SETTINGS_CALLBACK: &str = "settings:";
+
+/// The text and buttons of the `/settings` message of a Telegram chat.
+fn telegram_settings(name: &str, member: &db::BridgeMember) -> (String, t::InlineKeyboardMarkup) {
+    let text = format!(
+        "Settings of this chat in the bridge \"{name}\": {}",
+        describe_member(member)
+    );
+    let on_off = |on: bool| if on { "on" } else { "off" };
+    let button = |text: String, setting: &str| {
+        t::InlineKeyboardButton::callback(text, format!("{SETTINGS_CALLBACK}{setting}"))
+    };
+    let direction = match member.direction {
+        db::Direction::Both => "both ways",
+        db::Direction::In => "only into this chat",
+        db::Direction::Out => "only out of this chat",
+    };
+    let attachments = if member.attachments {
+        "sent as files"
+    } else {
+        "listed by name"
+    };
+    let keyboard = t::InlineKeyboardMarkup::new([
+        vec![
+            button(
+                format!("Send reactions: {}", on_off(member.send_reactions)),
+                "send_reactions",
+            ),
+            button(
+                format!("Receive reactions: {}", on_off(member.receive_reactions)),
+                "receive_reactions",
+            ),
+        ],
+        vec![button(format!("Messages: {direction}"), "direction")],
+        vec![button(format!("Media: {attachments}"), "attachments")],
+    ]);
+    (text, keyboard)
 }
 
-async fn handle_telegram_unbridge_command(bot: t::Bot, http: Arc<d::Http>, msg: &t::Message) {
+async fn handle_telegram_settings_command(bot: t::Bot, msg: &t::Message) {
     macro_rules! reply {
-        ($err:expr $(,)?) => {{
-            let err = bot
-                .send_message(msg.chat.id, $err)
-                .parse_mode(t::ParseMode::Html)
+        ($text:expr $(,)?) => {{
+            let reply = bot
+                .send_message(msg.chat.id, $text)
                 .reply_parameters(t::ReplyParameters::new(msg.id));
-            let _ = telegram_request!(err.send_ref()).await;
+            let _ = telegram_request!(reply.send_ref()).await;
         }};
     }
     let Some(from) = &msg.from else { return };
@@ -1755,37 +2766,129 @@ async fn handle_telegram_unbridge_command(bot: t::Bot, http: Arc<d::Http>, msg:
         return;
     };
     if !from.can_manage_chat() {
-        reply!("Only administrators capable of managing the chat can create bridges.")
+        reply!("Only administrators capable of managing the chat can change its settings.");
+        return;
+    }
+    let chat = ChatRef::Telegram(msg.chat.id);
+    let (Some(name), Some(member)) = (db::bridge_of(&chat), db::member(&chat)) else {
+        reply!("This chat is not bridged to any chats. Use /bridge to create a bridge.");
+        return;
+    };
+    let (text, keyboard) = telegram_settings(&name, &member);
+    let reply = bot
+        .send_message(msg.chat.id, text)
+        .reply_markup(keyboard)
+        .reply_parameters(t::ReplyParameters::new(msg.id));
+    let _ = telegram_request!(reply.send_ref()).await;
+}
+
+/// Toggles the setting of a button on a `/settings` message.
+async fn handle_telegram_settings_callback(bot: t::Bot, bridge: &Bridge, query: &t::CallbackQuery) {
+    macro_rules! answer {
+        ($text:expr $(,)?) => {{
+            let answer = bot.answer_callback_query(query.id.clone()).text($text);
+            let _ = telegram_request!(answer.send_ref()).await;
+        }};
     }
-    let Some((prev_discord_id, _)) = db::get_discord_channel_id(msg.chat.id) else {
-        reply!("This chat is not bridged to any chats.");
+    let (Some(setting), Some(message)) = (
+        query
+            .data
+            .as_deref()
+            .and_then(|data| data.strip_prefix(SETTINGS_CALLBACK)),
+        &query.message,
+    ) else {
         return;
     };
-    if let Err(e) = db::remove_chat_mapping(db::EitherChatId::Telegram(msg.chat.id)).await {
-        log::error!("Failed to remove chapping: {e}");
-        reply!("An internal error occurred trying to remove bridge.");
+    let chat_id = message.chat().id;
+    let can_manage = telegram_request!(bot.get_chat_member(chat_id, query.from.id))
+        .await
+        .is_some_and(|member| member.can_manage_chat());
+    if !can_manage {
+        answer!("Only administrators capable of managing the chat can change its settings.");
         return;
     }
-    reply!("Successfully removed bridge!");
-    let notification =
-        d::CreateMessage::new().content("The bridge to this channel has been removed.");
-    let _ = discord_request!(prev_discord_id.send_message(&http, notification.clone()));
+    let chat = ChatRef::Telegram(chat_id);
+    let (Some(name), Some(member)) = (db::bridge_of(&chat), db::member(&chat)) else {
+        answer!("This chat is not bridged to any chats.");
+        return;
+    };
+    let db = &bridge.db;
+    let result = match setting {
+        "send_reactions" => {
+            db::set_reactions(db, &chat, !member.send_reactions, member.receive_reactions).await
+        }
+        "receive_reactions" => {
+            db::set_reactions(db, &chat, member.send_reactions, !member.receive_reactions).await
+        }
+        "direction" => {
+            let direction = match member.direction {
+                db::Direction::Both => db::Direction::In,
+                db::Direction::In => db::Direction::Out,
+                db::Direction::Out => db::Direction::Both,
+            };
+            db::set_direction(db, &chat, direction).await
+        }
+        "attachments" => db::set_attachments(db, &chat, !member.attachments).await,
+        _ => return,
+    };
+    if let Err(e) = result {
+        log::error!("Failed to update bridge settings: {e}");
+        answer!("An internal error occurred. Try again later.");
+        return;
+    }
+    let Some(member) = db::member(&chat) else {
+        return;
+    };
+    let (text, keyboard) = telegram_settings(&name, &member);
+    let edit = bot
+        .edit_message_text(chat_id, message.id(), text)
+        .reply_markup(keyboard);
+    let _ = telegram_request!(edit.send_ref()).await;
+    answer!("Settings updated.");
+}
+
+/// Handles the message if it's one of the bot's commands, returning whether it
+/// was.
+async fn handle_telegram_command(
+    bot: t::Bot,
+    me: &t::Me,
+    bridge: &Bridge,
+    msg: &t::Message,
+) -> bool {
+    let Some(text) = msg.text().filter(|text| text.starts_with('/')) else {
+        return false;
+    };
+    match TelegramCommand::parse(text, me.username()) {
+        Ok(TelegramCommand::Help) => handle_telegram_help_command(bot, msg).await,
+        Ok(TelegramCommand::Bridge(args)) => {
+            handle_telegram_bridge_command(bot, bridge, msg, &args).await
+        }
+        Ok(TelegramCommand::BridgeCode) => handle_telegram_bridge_code_command(bot, msg).await,
+        Ok(TelegramCommand::Pair(code)) => {
+            handle_telegram_pair_command(bot, bridge, msg, &code).await
+        }
+        Ok(TelegramCommand::Unbridge) => handle_telegram_unbridge_command(bot, bridge, msg).await,
+        Ok(TelegramCommand::Status) => handle_telegram_status_command(bot, bridge, msg).await,
+        Ok(TelegramCommand::Settings) => handle_telegram_settings_command(bot, msg).await,
+        // commands of other bots are mirrored like any other message
+        Err(t::ParseError::UnknownCommand(_) | t::ParseError::WrongBotName(_)) => return false,
+        Err(_) => handle_telegram_help_command(bot, msg).await,
+    }
+    true
 }
 
 async fn handle_update(
     bot: t::Bot,
     me: t::Me,
-    avatar_cache: Arc<DashMap<t::UserId, AvatarCacheRecord>>,
     upd: t::Update,
-    discord_http: Arc<d::Http>,
-    discord_cache: Arc<d::Cache>,
-    webhook_cache: Arc<DashMap<d::ChannelId, d::Webhook>>,
-    db: SqlitePool,
+    bridge: Arc<Bridge>,
 ) -> Result<(), eyre::Report> {
     log::info!("{upd:?}");
 
-    let cache_http = (&discord_cache, discord_http.as_ref());
-
+    if let t::UpdateKind::CallbackQuery(query) = &upd.kind {
+        handle_telegram_settings_callback(bot, &bridge, query).await;
+        return Ok(());
+    }
     let Some(telegram_chat) = upd.chat().cloned() else {
         log::error!("Got update {upd:?} without a chat");
         return Ok(());
@@ -1806,452 +2909,440 @@ async fn handle_update(
         .or_else(|| telegram_chat.username())
         .unwrap_or("Unnamed chat")
         .to_string();
-    if let Err(e) = db::update_chat_membership(&db, telegram_chat.id, &title, is_member).await {
+    let db = &bridge.db;
+    if let Err(e) = db::update_chat_membership(db, telegram_chat.id, &title, is_member).await {
         log::error!("Failed to update chat membership: {e:?}");
     }
     if let t::UpdateKind::Message(msg) = &upd.kind {
-        if let Some(text) = msg.text() {
-            if text.starts_with("/bridge") {
-                handle_telegram_bridge_command(bot, discord_http, msg).await;
-                return Ok(());
-            } else if text == "/unbridge" {
-                handle_telegram_unbridge_command(bot, discord_http, msg).await;
-                return Ok(());
-            }
+        if handle_telegram_command(bot.clone(), &me, &bridge, msg).await {
+            return Ok(());
         } else if let Some(&t::ChatMigration::To { chat_id }) = msg.chat_migration() {
             // chat was migrated to a supergroup
-            if let Err(e) = db::update_chat_membership(&db, telegram_chat.id, &title, false).await {
+            if let Err(e) = db::update_chat_membership(db, telegram_chat.id, &title, false).await {
                 log::error!("Failed to update chat membership: {e:?}");
             }
-            if let Err(e) = db::update_chat_membership(&db, chat_id, &title, is_member).await {
+            if let Err(e) = db::update_chat_membership(db, chat_id, &title, is_member).await {
                 log::error!("Failed to update chat membership: {e:?}");
             }
-            match db::remove_chat_mapping(db::EitherChatId::Telegram(telegram_chat.id)).await {
-                Ok((_, discord_chat, webhook)) => {
-                    if let Err(e) = db::set_chat_mapping(discord_chat, chat_id, webhook).await {
-                        log::error!("Failed to set chat mapping: {e:?}");
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to remove chat mapping: {e:?}");
+            let old_chat = ChatRef::Telegram(telegram_chat.id);
+            if db::bridge_of(&old_chat).is_some() {
+                if let Err(e) = db::replace_chat(db, &old_chat, ChatRef::Telegram(chat_id)).await {
+                    log::error!("Failed to move bridge to migrated chat: {e:?}");
                 }
             }
 
             return Ok(());
         }
     }
-    let Some((discord_chat, webhook_url)) = db::get_discord_channel_id(telegram_chat.id) else {
-        log::info!("Got message {upd:?} in unregistered telegram chat");
-        return Ok(());
-    };
-
-    let cached_webhook = webhook_cache.get(&discord_chat).map(|w| w.clone());
-
-    let webhook = match (cached_webhook, webhook_url) {
-        (Some(webhook), _) => webhook,
-        (None, Some(url)) => {
-            let webhook =
-                discord_request!(d::Webhook::from_url(discord_http.clone(), url.as_str())).await;
-            match webhook {
-                Some(webhook) => {
-                    webhook_cache.insert(discord_chat, webhook.clone());
-                    webhook
-                }
-                None => {
-                    log::error!("Failed to get webhook");
-                    return Ok(());
-                }
-            }
-        }
-        (None, None) => {
-            let make_webhook = d::CreateWebhook::new("Discogram");
-            let webhook = discord_request!(
-                discord_chat.create_webhook(discord_http.clone(), make_webhook.clone())
-            )
-            .await;
-            match webhook {
-                Some(webhook) => {
-                    if let Err(e) = db::set_chat_mapping(
-                        discord_chat,
-                        telegram_chat.id,
-                        Some(webhook.url().expect("Bot running without token")),
-                    )
-                    .await
-                    {
-                        log::error!("Failed to insert chat mapping: {}", e);
+    // posts of a channel are forwarded to its discussion group to be commented on
+    if let t::UpdateKind::Message(msg) = &upd.kind {
+        if msg.is_automatic_forward() {
+            if let Some(t::MessageOrigin::Channel {
+                chat, message_id, ..
+            }) = msg.forward_origin()
+            {
+                let post = MessageRef::Telegram(chat.id, *message_id);
+                if bridge.is_bridged(&post.chat()) {
+                    let forward = Mirror {
+                        message: MessageRef::Telegram(msg.chat.id, msg.id),
+                        has_caption: false,
+                    };
+                    if let Err(e) = db::insert_mapping(db, &post, &forward).await {
+                        log::error!("Failed to insert message mapping: {e}");
                     }
-                    webhook_cache.insert(discord_chat, webhook.clone());
-                    webhook
+                    return Ok(());
                 }
-                None => {
-                    log::error!("Failed to create webhook");
+            }
+        }
+    }
+    if !bridge.is_bridged(&ChatRef::Telegram(telegram_chat.id)) {
+        match &upd.kind {
+            t::UpdateKind::Message(msg) => {
+                let mut message = platform::telegram::bridge_message(&me, msg);
+                if let Some((post, root)) = bridge.commented_post(&message).await {
+                    message
+                        .attachments
+                        .extend(platform::telegram::download_attachment(&bot, msg).await);
+                    bridge.relay_comment(message, post, root).await;
                     return Ok(());
                 }
             }
+            t::UpdateKind::EditedMessage(msg) => {
+                let message = platform::telegram::bridge_message(&me, msg);
+                bridge.relay_comment_edit(message).await;
+                return Ok(());
+            }
+            _ => {}
         }
-    };
+        log::info!("Got message {upd:?} in unregistered telegram chat");
+        return Ok(());
+    }
+    // the chat only mirrors the rest of its bridge
+    if !bridge.sends(&ChatRef::Telegram(telegram_chat.id)) {
+        return Ok(());
+    }
 
     match upd.kind {
-        t::UpdateKind::Message(msg) => {
-            if let Some(msg) = msg.pinned_message() {
-                match db::get_discord_message_id(&db, msg.id(), msg.chat().id)
-                    .await
-                    .as_deref()
-                {
-                    Ok(&[discord_id, ..]) => {
-                        let _ = discord_request!(discord_http.pin_message(
-                            discord_chat,
-                            discord_id,
-                            Some("Telegram pin")
-                        ))
-                        .await;
+        t::UpdateKind::Message(msg) | t::UpdateKind::ChannelPost(msg) => {
+            let chat_id = msg.chat.id;
+            if let Some(created) = msg.forum_topic_created() {
+                // topics created by the bot already mirror a Discord thread
+                if msg.from.as_ref().is_none_or(|u| u.id != me.id) {
+                    if let Some(topic) = msg.thread_id {
+                        bridge.mirror_topic(chat_id, topic, &created.name).await;
                     }
-                    Ok([]) => {
-                        log::info!("TODO: implement handling of pins of unmapped messages I guess")
-                    }
-                    Err(e) => log::error!("Failed to get message mapping: {}", e),
                 }
                 return Ok(());
             }
-            let author = format::telegram_author_name(&msg);
-            let text = msg
-                .text()
-                .zip(msg.parse_entities())
-                .or_else(|| msg.caption().zip(msg.parse_caption_entities()))
-                .map(|(t, e)| format::telegram_to_discord_format(t, e));
-            // let mut content = format!("**{author}**\n{}", text.as_deref().unwrap_or(""));
-            let mut content = text.unwrap_or_default();
-            let avatar_handle = {
-                let bot = bot.clone();
-                let avatar_cache = avatar_cache.clone();
-                let from = msg.from.clone();
-                let discord_http = discord_http.clone();
-                tokio::spawn(async move {
-                    match from {
-                        Some(u) => {
-                            telegram_avatar_url_by_id(&bot, &avatar_cache, u.id, &*discord_http)
-                                .await
-                        }
-                        None => None,
-                    }
-                })
-            };
-            if let Some(poll) = msg.poll() {
-                if let Some(discord_msg) =
-                    send_poll(webhook, avatar_handle, discord_http, poll, &author).await
-                {
-                    if let Err(e) =
-                        db::insert_mapping(&db, discord_msg.id, msg.id, telegram_chat.id, false)
-                            .await
-                    {
-                        log::error!("Failed to insert message mapping: {}", e);
-                    }
+            if let Some(thread) = msg.thread_id {
+                if let Some(name) = msg.forum_topic_edited().and_then(|e| e.name.as_deref()) {
+                    bridge
+                        .relay_topic_update(chat_id, thread, Some(name), None)
+                        .await;
+                    return Ok(());
+                }
+                if msg.forum_topic_closed().is_some() {
+                    bridge
+                        .relay_topic_update(chat_id, thread, None, Some(true))
+                        .await;
+                    return Ok(());
                 }
+                if msg.forum_topic_reopened().is_some() {
+                    bridge
+                        .relay_topic_update(chat_id, thread, None, Some(false))
+                        .await;
+                    return Ok(());
+                }
+            }
+            if let Some(topic) = platform::telegram::topic(&msg) {
+                let name = platform::telegram::topic_name(&msg)
+                    .unwrap_or_else(|| format!("Topic {}", topic.0));
+                bridge.mirror_topic(chat_id, topic, &name).await;
+            }
+            if let Some(msg) = msg.pinned_message() {
+                bridge
+                    .relay_pin(MessageRef::Telegram(msg.chat().id, msg.id()))
+                    .await;
+                return Ok(());
+            }
+            let mut message = platform::telegram::bridge_message(&me, &msg);
+            message
+                .attachments
+                .extend(platform::telegram::download_attachment(&bot, &msg).await);
+            bridge.relay_message(message).await;
+        }
+        t::UpdateKind::EditedMessage(msg) | t::UpdateKind::EditedChannelPost(msg) => {
+            let message = platform::telegram::bridge_message(&me, &msg);
+            // an edit to "." deletes the message on both sides
+            if message.text == "." {
+                telegram_request!(bot.delete_message(msg.chat.id, msg.id)).await;
+                bridge.relay_delete(message.source).await;
                 return Ok(());
             }
-            let mut message = d::ExecuteWebhook::new().username(&author);
-            let mut embed = None;
+            bridge.relay_edit(message).await;
+        }
+        t::UpdateKind::MessageReaction(reaction) => {
+            bridge
+                .relay_reaction(platform::telegram::reaction_update(&reaction))
+                .await;
+        }
+        t::UpdateKind::MessageReactionCount(reaction) => {
+            bridge
+                .relay_reaction(platform::telegram::reaction_count_update(&reaction))
+                .await;
+        }
+        _ => {}
+    }
+    Ok(())
+}
 
-            if let Some(origin) = msg.forward_origin() {
-                let mut embed_content = &*content;
-                let original_author = match origin {
-                    t::MessageOrigin::User { sender_user, .. } => sender_user.full_name(),
-                    t::MessageOrigin::Chat {
-                        sender_chat: chat, ..
-                    }
-                    | t::MessageOrigin::Channel { chat, .. } => chat
-                        .title()
-                        .or_else(|| chat.username())
-                        .unwrap_or("Unknown")
-                        .to_string(),
-                    t::MessageOrigin::HiddenUser {
-                        sender_user_name, ..
-                    } => sender_user_name.clone(),
-                };
-                let mut original_author = d::CreateEmbedAuthor::new(&original_author);
-                'set_author: {
-                    if let t::MessageOrigin::User { sender_user, .. } = origin {
-                        if sender_user.id == me.id && !content.starts_with("**Reactions**\n") {
-                            let name = content
-                                .lines()
-                                .next()
-                                .and_then(|s| s.strip_prefix("**"))
-                                .and_then(|s| s.strip_suffix("**"))
-                                .unwrap_or("Unknown [this shouldn't be possible]");
-                            original_author = original_author.name(name);
-                            embed_content = embed_content
-                                .split_once('\n')
-                                .map_or(embed_content, |(_, rest)| rest);
-                            if let Some(url) =
-                                discord_avatar_url_by_display_name(&cache_http, discord_chat, name)
-                                    .await
-                            {
-                                original_author = original_author.icon_url(&*url);
-                                break 'set_author;
-                            }
-                        }
-                        if let Some(url) = telegram_avatar_url_by_id(
-                            &bot,
-                            &avatar_cache,
-                            sender_user.id,
-                            &*discord_http,
-                        )
-                        .await
-                        {
-                            original_author = original_author.icon_url(&*url);
-                        }
-                    }
-                }
-                embed = Some(
-                    d::CreateEmbed::new()
-                        .author(original_author)
-                        .description(embed_content),
-                );
-                content = "-# Forwarded message".to_string();
-            }
-
-            if let Some(ReplyInfo {
-                content_suffix,
-                embed,
-                mentions,
-            }) = reply_info(
-                &bot,
-                &me,
-                &msg,
-                &avatar_cache,
-                &discord_http,
-                &discord_cache,
-                discord_chat,
-                &telegram_chat,
-                &db,
-                true,
-            )
-            .await
-            {
-                message = message.embed(embed).allowed_mentions(mentions);
-                content = format!("{content}\n{content_suffix}");
+async fn handle_matrix_event(
+    bridge: &Bridge,
+    matrix: &MatrixPlatform,
+    room: &str,
+    event: matrix::Event,
+) {
+    if event.sender == matrix.user_id {
+        return;
+    }
+    let chat = ChatRef::Matrix(room.to_string());
+    if event.kind == "m.room.message" && event.replaced_event().is_none() {
+        match event.body().map(str::trim) {
+            Some(text) if text.starts_with("!bridge") => {
+                let target = text["!bridge".len()..].trim();
+                handle_matrix_bridge_command(bridge, matrix, &chat, &event.sender, target).await;
+                return;
+            }
+            Some("!unbridge") => {
+                handle_matrix_unbridge_command(bridge, matrix, &chat, &event.sender).await;
+                return;
             }
+            _ => {}
+        }
+    }
+    if !bridge.is_bridged(&chat) {
+        log::info!("Got event {event:?} in unregistered matrix room");
+        return;
+    }
+    if !bridge.sends(&chat) {
+        return;
+    }
 
-            if let Some(attachment) = get_telegram_attachment_as_discord(&bot, &msg).await {
-                if let Some(e) = embed {
-                    embed = Some(e.attachment(&attachment.filename));
+    match event.kind.as_str() {
+        "m.room.message" => {
+            if event.replaced_event().is_some() {
+                if let Some(message) = matrix.bridge_edit(room, &event).await {
+                    bridge.relay_edit(message).await;
                 }
-                message = message.add_file(attachment);
+            } else {
+                bridge
+                    .relay_message(matrix.bridge_message(room, &event).await)
+                    .await;
             }
-
-            if let Some(e) = embed {
-                message = message.embed(e);
+        }
+        "m.reaction" => {
+            if let Some(update) = matrix.reaction_update(room, &event).await {
+                bridge.relay_reaction(update).await;
             }
-
-            if let Ok(Some(avatar_url)) = avatar_handle.await {
-                message = message.avatar_url(&*avatar_url);
+        }
+        "m.room.redaction" => {
+            let Some(redacted) = event.redacted_event() else {
+                return;
+            };
+            match matrix.redacted_reaction(redacted) {
+                Some(update) => bridge.relay_reaction(update).await,
+                None => {
+                    bridge
+                        .relay_delete(MessageRef::Matrix(room.to_string(), redacted.to_string()))
+                        .await
+                }
             }
+        }
+        _ => {}
+    }
+}
 
-            message = message.content(&content);
-
-            let discord_result = discord_request!(
-                webhook.execute(discord_http.clone(), true, message.clone()),
-                edbg!(author, msg.text().unwrap_or(""), content)
+async fn handle_matrix_bridge_command(
+    bridge: &Bridge,
+    matrix: &MatrixPlatform,
+    chat: &ChatRef,
+    sender: &str,
+    target: &str,
+) {
+    let ChatRef::Matrix(room) = chat else { return };
+    if !matrix.can_manage(room, sender).await {
+        bridge
+            .notify(
+                chat,
+                "Only users who can change the room's settings can create bridges.",
             )
             .await;
-
-            if let Some(Some(discord_msg)) = discord_result {
-                if let Err(e) =
-                    db::insert_mapping(&db, discord_msg.id, msg.id, telegram_chat.id, false).await
-                {
-                    log::error!("Failed to insert message mapping: {}", e);
+        return;
+    }
+    if target.contains(char::is_whitespace) || target.is_empty() {
+        bridge.notify(chat, "Usage: !bridge <hub invite>").await;
+        return;
+    }
+    let Some((hub_name, hub, invite)) = find_hub(&bridge.db, target).await else {
+        bridge
+            .notify(chat, &format!("No hub invite found matching \"{target}\""))
+            .await;
+        return;
+    };
+    let name = matrix
+        .room_name(room)
+        .await
+        .unwrap_or_else(|| room.to_string());
+    let user = UserRef::Matrix(sender.to_string());
+    let request = HubBridgeRequest {
+        chat: chat.clone(),
+        chat_name: &name,
+        user: &user,
+        requester: sender,
+        members: matrix.member_count(room).await.map(|n| n as u32),
+        direction: db::Direction::Both,
+        invite,
+    };
+    match request_hub_bridge(bridge, &hub_name, hub, request).await {
+        Ok(false) => {
+            bridge
+                .notify(chat, "Successfully created and linked channel.")
+                .await
+        }
+        Ok(true) => {
+            bridge
+                .notify(
+                    chat,
+                    "The hub's moderators have been asked to approve the bridge. \
+                    The channel will be created once they do.",
+                )
+                .await
+        }
+        Err(e) => {
+            if let Some(invite) = invite {
+                if let Err(e) = db::unuse_hub_invite(&bridge.db, invite).await {
+                    log::error!("Failed to give back hub invite use: {e}");
                 }
             }
+            bridge.notify(chat, e).await
         }
-        t::UpdateKind::EditedMessage(msg) => {
-            match db::get_discord_message_id(&db, msg.id, telegram_chat.id)
-                .await
-                .as_deref()
-            {
-                Ok(&[mirror_id, ..]) => {
-                    let text = msg
-                        .text()
-                        .zip(msg.parse_entities())
-                        .or_else(|| msg.caption().zip(msg.parse_caption_entities()))
-                        .map(|(t, e)| format::telegram_to_discord_format(t, e));
-                    if let Some(".") = text.as_deref() {
-                        telegram_request!(bot.delete_message(msg.chat.id, msg.id)).await;
-                        let discord_result = discord_request!(webhook.delete_message(
-                            discord_http.clone(),
-                            None,
-                            mirror_id
-                        ))
-                        .await;
-                        if let Some(()) = discord_result {
-                            if let Err(e) =
-                                db::delete_by_telegram(&db, msg.id, telegram_chat.id).await
-                            {
-                                log::error!("Failed to delete message mapping: {}", e);
-                            }
-                        }
-                        return Ok(());
-                    }
-                    let author = format::telegram_author_name(&msg);
-                    let mut content = text.unwrap_or_default();
-                    let mut mentions = d::CreateAllowedMentions::new();
-
-                    if let Some(ReplyInfo {
-                        content_suffix,
-                        mentions: new_mentions,
-                        ..
-                    }) = reply_info(
-                        &bot,
-                        &me,
-                        &msg,
-                        &avatar_cache,
-                        &discord_http,
-                        &discord_cache,
-                        discord_chat,
-                        &telegram_chat,
-                        &db,
-                        false,
-                    )
-                    .await
-                    {
-                        mentions = new_mentions;
-                        content = format!("{content}\n{content_suffix}");
-                    }
+    }
+}
 
-                    let message = d::EditWebhookMessage::new()
-                        .content(&content)
-                        .allowed_mentions(mentions);
+async fn handle_matrix_unbridge_command(
+    bridge: &Bridge,
+    matrix: &MatrixPlatform,
+    chat: &ChatRef,
+    sender: &str,
+) {
+    let ChatRef::Matrix(room) = chat else { return };
+    if !matrix.can_manage(room, sender).await {
+        bridge
+            .notify(
+                chat,
+                "Only users who can change the room's settings can remove bridges.",
+            )
+            .await;
+        return;
+    }
+    let remaining = match db::leave_bridge(&bridge.db, chat).await {
+        Ok(Some((_, remaining))) => remaining,
+        Ok(None) => {
+            bridge
+                .notify(chat, "This room is not bridged to any chats.")
+                .await;
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to leave bridge: {e}");
+            bridge
+                .notify(chat, "An internal error occurred trying to remove bridge.")
+                .await;
+            return;
+        }
+    };
+    bridge.notify(chat, "Successfully removed bridge!").await;
+    for peer in remaining {
+        bridge
+            .notify(&peer, "A Matrix room has left this bridge.")
+            .await;
+    }
+}
 
-                    discord_request!(
-                        webhook.edit_message(cache_http, mirror_id, message.clone()),
-                        edbg!(author, msg.text().unwrap_or(""), content)
-                    )
-                    .await;
-                }
-                // the edited message had no known counterpart
-                // if the edit is a ., delete it for consistency
-                Ok([]) => {
-                    if let (Some("."), _) | (_, Some(".")) =
-                        (msg.text().as_deref(), msg.caption().as_deref())
-                    {
-                        telegram_request!(bot.delete_message(msg.chat.id, msg.id)).await;
-                        return Ok(());
-                    }
+async fn run_matrix(bridge: Arc<Bridge>) {
+    let Some(matrix) = &bridge.matrix else {
+        return;
+    };
+    let mut since: Option<String> = None;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let sync = match matrix.sync(since.as_deref()).await {
+            Ok(sync) => sync,
+            Err(matrix::Error::RateLimited(d)) => {
+                tokio::time::sleep(d).await;
+                continue;
+            }
+            Err(e) => {
+                log::error!("Failed matrix sync: {e:?}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+                continue;
+            }
+        };
+        backoff = INITIAL_BACKOFF;
+        for room in sync.rooms.invite.keys() {
+            matrix.join(room).await;
+        }
+        // the first sync only catches up, so that old messages aren't bridged again
+        if since.is_some() {
+            for (room, joined) in sync.rooms.join {
+                for event in joined.timeline.events {
+                    handle_matrix_event(&bridge, matrix, &room, event).await;
                 }
-                Err(e) => log::error!("Failed to get message mapping: {}", e),
             }
         }
-        t::UpdateKind::MessageReaction(reaction) => {
-            if !db::should_forward_reactions(db::EitherChatId::Telegram(telegram_chat.id)) {
-                return Ok(());
+        since = Some(sync.next_batch);
+    }
+}
+
+async fn handle_irc_message(bridge: &Bridge, irc: &IrcPlatform, message: irc::IrcMessage) {
+    let chat = ChatRef::Irc(message.channel.clone());
+    if !bridge.is_bridged(&chat) {
+        return;
+    }
+    bridge.relay_message(irc.bridge_message(&message)).await;
+}
+
+async fn run_irc(bridge: Arc<Bridge>) {
+    let Some(irc) = &bridge.irc else {
+        return;
+    };
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let mut conn = match irc.connect().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::error!("Failed to connect to IRC: {e}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+                continue;
             }
-            let telegram_id = reaction.message_id;
-            let discord_id = match db::get_discord_message_id(&db, telegram_id, telegram_chat.id)
-                .await
-                .as_deref()
-            {
-                Ok(&[discord_id, ..]) => discord_id,
-                Ok([]) => {
-                    log::info!("Got reaction for unknown message, {reaction:?}");
-                    return Ok(());
+        };
+        loop {
+            match irc.next_message(&mut conn).await {
+                Ok(message) => {
+                    backoff = INITIAL_BACKOFF;
+                    handle_irc_message(&bridge, irc, message).await;
                 }
                 Err(e) => {
-                    log::error!("Failed to get reaction message mapping: {}", e);
-                    return Ok(());
-                }
-            };
-            match db::get_discord_reaction_message_id(&db, telegram_id, telegram_chat.id).await {
-                Ok(Some((reaction_message_id, reactions))) => {
-                    let mut reactions = format::parse_discord_reaction_message(&reactions);
-                    let author = format::telegram_reactor_name(&reaction);
-                    let _ = reactions.insert(
-                        author,
-                        format::filter_telegram_reactions(&reaction.new_reaction),
-                    );
-
-                    let new_text = format::format_discord_reaction_message(&reactions);
-                    if new_text == "**Reactions**" {
-                        if let Some(_) = discord_request!(
-                            discord_chat.delete_message(&*discord_http, reaction_message_id)
-                        )
-                        .await
-                        {
-                            if let Err(e) = db::remove_reaction_mapping_by_telegram(
-                                &db,
-                                telegram_id,
-                                telegram_chat.id,
-                            )
-                            .await
-                            {
-                                log::error!("Failed to remove reaction message mapping: {}", e);
-                            }
-                        }
-                    } else {
-                        if let Some(_) = discord_request!(discord_chat.edit_message(
-                            &*discord_http,
-                            reaction_message_id,
-                            d::EditMessage::new().content(&new_text)
-                        ))
-                        .await
-                        {
-                            if let Err(e) = db::update_discord_reaction_mapping(
-                                &db,
-                                telegram_id,
-                                telegram_chat.id,
-                                &new_text,
-                            )
-                            .await
-                            {
-                                log::error!("Failed to update reaction message mapping: {}", e);
-                            }
-                        }
-                    }
-                }
-                Ok(None)
-                    if reaction
-                        .new_reaction
-                        .iter()
-                        .any(|r| matches!(r, t::ReactionType::Emoji { .. })) =>
-                {
-                    let author = format::telegram_reactor_name(&reaction);
-                    let emojis =
-                        format::filter_telegram_reactions(&reaction.new_reaction).join(", ");
-                    let content = format!("**Reactions**\n**{author}**: {emojis}");
-                    let reaction_msg = d::CreateMessage::new()
-                        .content(&content)
-                        .reference_message((discord_chat, discord_id))
-                        .allowed_mentions(d::CreateAllowedMentions::new().replied_user(false));
-
-                    if let Some(reaction_msg) = discord_request!(
-                        discord_chat.send_message(&*discord_http, reaction_msg.clone()),
-                        edbg!(author, emojis, content)
-                    )
-                    .await
-                    {
-                        if let Err(e) = db::insert_reaction_mapping(
-                            &db,
-                            reaction_msg.id,
-                            telegram_id,
-                            telegram_chat.id,
-                            &content,
-                        )
-                        .await
-                        {
-                            log::error!("Failed to insert reaction mapping: {}", e);
-                        }
-                    }
+                    log::error!("Lost IRC connection: {e}");
+                    break;
                 }
-                Ok(None) => log::info!("Got reaction removal for unknown message, {reaction:?}"),
-                Err(e) => log::error!("Failed to get reaction message mapping: {}", e),
             }
         }
-        _ => {}
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(60));
     }
-    Ok(())
 }
 
 const RETRIES: usize = 5;
 const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
 
+/// Reloads the options in the config file when it changes or on SIGHUP.
+async fn watch_config() {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to listen for SIGHUP");
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    let mut modified = db::config_modified();
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => log::info!("Got SIGHUP, reloading config"),
+            _ = interval.tick() => {
+                if db::config_modified() == modified {
+                    continue;
+                }
+            }
+        }
+        modified = db::config_modified();
+        db::reload_options().await;
+    }
+}
+
+/// Deletes old message mappings once a day, as set by the retention options.
+async fn prune_mappings(db: sql::Pool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+    loop {
+        interval.tick().await;
+        match db::prune_mappings(&db).await {
+            Ok((0, 0)) => {}
+            Ok((messages, reactions)) => {
+                log::info!("Pruned {messages} message mappings and {reactions} reaction mappings")
+            }
+            Err(e) => log::error!("Failed to prune mappings: {e}"),
+        }
+    }
+}
+
 async fn telegram_request<F, Log, Fut, T: Send + Sync>(mut f: F, log: Log) -> Option<T>
 where
     Fut: IntoFuture<Output = Result<T, t::RequestError>>,
@@ -2325,12 +3416,160 @@ where
     }
 }
 
+async fn matrix_request<F, Log, Fut, T: Send + Sync>(mut f: F, log: Log) -> Option<T>
+where
+    Fut: IntoFuture<Output = Result<T, matrix::Error>>,
+    Log: Fn(),
+    F: FnMut() -> Fut,
+{
+    let mut retries: usize = RETRIES;
+    let mut backoff: Duration = INITIAL_BACKOFF;
+    loop {
+        match f().await {
+            Ok(x) => break Some(x),
+            Err(matrix::Error::RateLimited(d)) => tokio::time::sleep(d).await,
+            Err(why @ matrix::Error::Network(_)) => {
+                if retries == 0 {
+                    log::error!("Failed matrix request: {why:?}");
+                    log();
+                    break None;
+                } else {
+                    log::warn!("Failed matrix request: {why:?}. Retrying {retries} more times...");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    retries -= 1;
+                }
+            }
+            Err(why) => {
+                log::error!("Failed matrix request: {why:?}");
+                log();
+                break None;
+            }
+        }
+    }
+}
+
+/// Mirrors messages between Discord, Telegram, Matrix and IRC.
+#[derive(clap::Parser)]
+#[command(version)]
+struct Args {
+    /// Directory with the `.env` file, and by default the config file and
+    /// database [env: TELEDISCORD_DATA_DIR] [default: the working directory if
+    /// it has a messages.db or config.toml, otherwise $XDG_DATA_HOME/telediscord]
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+    /// Config file [env: TELEDISCORD_CONFIG] [default: <data dir>/config.toml]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Database URL, sqlite:<file> or postgres://<user>:<password>@<host>/<database>
+    /// [env: TELEDISCORD_DATABASE_URL] [default: sqlite:<data dir>/messages.db]
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Validate the config file and print its bridges and hubs, without
+    /// connecting to anything
+    CheckConfig {
+        /// Config file to check instead of the one the bot would use
+        path: Option<PathBuf>,
+    },
+    /// Write a copy of the database to a new file, which works while the bot is
+    /// running
+    Backup { path: PathBuf },
+    /// Replace the database with a backup, keeping the current one as
+    /// <database>.before-restore. Stop the bot first
+    Restore { path: PathBuf },
+    /// Write the bridges, hubs and message mappings to a JSON file
+    Export { path: PathBuf },
+    /// Merge bridges, hubs and message mappings from a JSON export into the
+    /// database. Stop the bot first
+    Import { path: PathBuf },
+}
+
+fn default_data_dir() -> PathBuf {
+    // where everything was kept before the data directory was configurable
+    if Path::new("messages.db").exists() || Path::new("config.toml").exists() {
+        return PathBuf::from(".");
+    }
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map_or_else(|| PathBuf::from("."), |dir| dir.join("telediscord"))
+}
+
 #[tokio::main]
 async fn main() {
-    dotenv::dotenv().ok();
+    let args = <Args as clap::Parser>::parse();
+
+    let data_dir = args
+        .data_dir
+        .or_else(|| env::var_os(DATA_DIR_ENV).map(PathBuf::from))
+        .unwrap_or_else(default_data_dir);
+    dotenv::from_path(data_dir.join(".env")).ok();
     pretty_env_logger::init();
 
-    let db_pool = db::init_db().await.expect("Failed to initialize database");
+    let config_file = args
+        .config
+        .or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from))
+        .unwrap_or_else(|| data_dir.join("config.toml"));
+    let database_url = args
+        .database_url
+        .or_else(|| env::var(DATABASE_URL_ENV).ok())
+        .unwrap_or_else(|| format!("sqlite:{}", data_dir.join("messages.db").display()));
+
+    if let Some(command) = args.command {
+        // these create the database, which on a new host is the first use of the directory
+        if matches!(
+            command,
+            Command::Restore { .. } | Command::Export { .. } | Command::Import { .. }
+        ) {
+            if let Err(e) = std::fs::create_dir_all(&data_dir) {
+                eprintln!(
+                    "Failed to create the data directory {}: {e}",
+                    data_dir.display()
+                );
+                std::process::exit(1);
+            }
+        }
+        let result = match command {
+            Command::CheckConfig { path } => config::check(path.as_deref().unwrap_or(&config_file)),
+            Command::Backup { path } => backup::backup(&database_url, &path).await,
+            Command::Restore { path } => backup::restore(&database_url, &path).await,
+            Command::Export { path } => match db::init_db(config_file, &database_url).await {
+                Ok(pool) => backup::export(&pool, &path).await,
+                Err(e) => Err(e),
+            },
+            Command::Import { path } => match db::init_db(config_file, &database_url).await {
+                Ok(pool) => backup::import(&pool, &path).await,
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    std::fs::create_dir_all(&data_dir).expect("Failed to create the data directory");
+    let mut shown_url = database_url.clone();
+    if let Ok(mut url) = url::Url::parse(&database_url) {
+        if url.password().is_some() && url.set_password(Some("***")).is_ok() {
+            shown_url = url.to_string();
+        }
+    }
+    log::info!(
+        "Using config file {} and database {shown_url}",
+        config_file.display()
+    );
+    let db_pool = db::init_db(config_file, &database_url)
+        .await
+        .expect("Failed to initialize database");
 
     let telegram_bot = t::Bot::from_env();
 
@@ -2342,32 +3581,72 @@ async fn main() {
     // Create a new instance of the Client, logging in as a bot. This will
     // automatically prepend your bot token with "Bot ", which is a requirement
     // by Discord for bot users.
+    let bridge_cell = Arc::new(OnceLock::new());
     let mut discord_client = d::Client::builder(&discord_token, intents)
         .event_handler(DiscordState {
             telegram_bot: telegram_bot.clone(),
             db: db_pool.clone(),
+            bridge: bridge_cell.clone(),
         })
         .await
         .expect("Err creating client");
 
-    let discord_cache = discord_client.cache.clone();
-    let discord_http = discord_client.http.clone();
+    let matrix = match (env::var(MATRIX_HOMESERVER_ENV), env::var(MATRIX_TOKEN_ENV)) {
+        (Ok(homeserver), Ok(token)) => {
+            let homeserver = url::Url::parse(&homeserver).expect("Invalid Matrix homeserver URL");
+            let matrix = MatrixPlatform::new(homeserver, token)
+                .await
+                .expect("Failed to log in to Matrix");
+            Some(matrix)
+        }
+        _ => None,
+    };
+
+    let irc = env::var(IRC_SERVER_ENV).ok().map(|server| {
+        let tls = env::var(IRC_TLS_ENV).map_or(true, |v| v != "false" && v != "0");
+        let port = match env::var(IRC_PORT_ENV) {
+            Ok(port) => port.parse().expect("Invalid IRC port"),
+            Err(_) if tls => 6697,
+            Err(_) => 6667,
+        };
+        IrcPlatform::new(IrcConfig {
+            server,
+            port,
+            tls,
+            nick: env::var(IRC_NICK_ENV).unwrap_or_else(|_| "telediscord".to_string()),
+            password: env::var(IRC_PASSWORD_ENV).ok(),
+        })
+    });
+
+    let bridge = Arc::new(Bridge {
+        discord: DiscordPlatform::new(
+            discord_client.http.clone(),
+            discord_client.cache.clone(),
+            db_pool.clone(),
+        ),
+        telegram: TelegramPlatform {
+            bot: telegram_bot.clone(),
+        },
+        matrix,
+        irc,
+        db: db_pool,
+        deliveries: Default::default(),
+        hub_locks: Default::default(),
+    });
+    let _ = bridge_cell.set(bridge.clone());
 
     log::info!("Starting telegram...");
+    register_telegram_commands(&telegram_bot).await;
 
-    let webhook_cache = Arc::new(DashMap::<d::ChannelId, d::Webhook>::new());
-    let avatar_cache = Arc::new(DashMap::<t::UserId, AvatarCacheRecord>::new());
+    let _matrix_handle = tokio::spawn(run_matrix(bridge.clone()));
+    let _irc_handle = tokio::spawn(run_irc(bridge.clone()));
+    let _config_handle = tokio::spawn(watch_config());
+    let _prune_handle = tokio::spawn(prune_mappings(bridge.db.clone()));
 
     let telegram_handler = t::dptree::endpoint(handle_update);
 
     let mut telegram_dispatch = t::Dispatcher::builder(telegram_bot, telegram_handler)
-        .dependencies(t::dptree::deps![
-            webhook_cache,
-            avatar_cache,
-            discord_cache,
-            discord_http,
-            db_pool
-        ])
+        .dependencies(t::dptree::deps![bridge])
         .enable_ctrlc_handler()
         .build();
 
@@ -0,0 +1,53 @@
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+use crate::platform::ChatRef;
+
+/// How long a pairing code can be used after it's made.
+pub const CODE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+// without letters and digits that are easily mixed up, like O and 0
+const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+lazy_static! {
+    static ref CODES: DashMap<String, (ChatRef, Instant)> = DashMap::new();
+}
+
+/// Makes a one-time code that bridges the chat to wherever it's used, replacing
+/// any earlier code for the chat.
+pub fn issue(chat: ChatRef) -> String {
+    CODES.retain(|_, (c, expires)| *c != chat && *expires > Instant::now());
+    let mut rng = rand::thread_rng();
+    let code = (0..8)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+        .collect::<String>();
+    CODES.insert(code.clone(), (chat, Instant::now() + CODE_LIFETIME));
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// The chat the code was made for, without using it up.
+pub fn peek(code: &str) -> Option<ChatRef> {
+    let entry = CODES.get(&normalize(code))?;
+    let (chat, expires) = entry.value();
+    (*expires > Instant::now()).then(|| chat.clone())
+}
+
+/// Uses up the code, returning the chat it was made for unless it has expired.
+pub fn redeem(code: &str) -> Option<ChatRef> {
+    let (_, (chat, expires)) = CODES.remove(&normalize(code))?;
+    (expires > Instant::now()).then_some(chat)
+}
+
+/// Makes a redeemed code usable again, for when bridging with it failed.
+pub fn restore(code: &str, chat: ChatRef) {
+    CODES.insert(normalize(code), (chat, Instant::now() + CODE_LIFETIME));
+}
@@ -0,0 +1,877 @@
+use dashmap::DashMap;
+use serenity::prelude::Mentionable as _;
+use tokio::time::Instant;
+use url::Url;
+
+use std::{sync::Arc, time::Duration};
+
+use super::{
+    Attachment, AttachmentData, AttachmentKind as AK, Author, Avatar, BridgeMessage, ChatRef,
+    ForwardOrigin, MessageRef, Mirror, Platform, Poll, PollKind, ReactionChange, ReactionUpdate,
+    Reactions, ReplyTarget, UserRef,
+};
+use crate::bridge::Bridge;
+use crate::db;
+use crate::discord as d;
+use crate::format;
+use crate::sql::Pool;
+
+#[derive(Clone, Debug)]
+struct AvatarCacheRecord {
+    url: Arc<str>, // usually a base64 encoded image
+    last_updated: Instant,
+}
+
+pub struct DiscordPlatform {
+    pub http: Arc<d::Http>,
+    pub cache: Arc<d::Cache>,
+    db: Pool,
+    webhooks: DashMap<d::ChannelId, d::Webhook>,
+    avatars: DashMap<UserRef, AvatarCacheRecord>,
+}
+
+struct ReplyInfo {
+    content_suffix: String,
+    embed: d::CreateEmbed,
+    mentions: d::CreateAllowedMentions,
+}
+
+const KEYCAPS: [&str; 12] = [
+    "\u{0030}\u{fe0f}\u{20e3}",
+    "\u{0031}\u{fe0f}\u{20e3}",
+    "\u{0032}\u{fe0f}\u{20e3}",
+    "\u{0033}\u{fe0f}\u{20e3}",
+    "\u{0034}\u{fe0f}\u{20e3}",
+    "\u{0035}\u{fe0f}\u{20e3}",
+    "\u{0036}\u{fe0f}\u{20e3}",
+    "\u{0037}\u{fe0f}\u{20e3}",
+    "\u{0038}\u{fe0f}\u{20e3}",
+    "\u{0039}\u{fe0f}\u{20e3}",
+    "\u{1f51f}",
+    "\u{1f520}",
+];
+
+fn preview(s: &str) -> String {
+    const MAX_LENGTH: usize = 200;
+    const MAX_LINES: usize = 5;
+    let mut changed = false;
+    let s = if s.chars().count() > MAX_LENGTH {
+        changed = true;
+        &s[..s
+            .char_indices()
+            .take(MAX_LENGTH)
+            .filter(|&(_, c)| c.is_whitespace())
+            .last()
+            .map_or(0, |(i, _)| i)]
+    } else {
+        s
+    };
+    let s = if let Some(last_linebreak) = s
+        .char_indices()
+        .filter(|&(_, c)| c == '\n')
+        .nth(MAX_LINES - 1)
+    {
+        changed = true;
+        &s[..last_linebreak.0 + 1]
+    } else {
+        s
+    };
+    let mut s = s.to_string();
+    if changed {
+        s.push_str("...");
+    }
+    s
+}
+
+async fn discord_avatar_url_by_display_name(
+    cache_http: &impl d::CacheHttp,
+    channel: d::ChannelId,
+    display_name: &str,
+) -> Option<String> {
+    let channel = discord_request!(channel.to_channel(cache_http)).await?;
+    let channel = channel.guild()?;
+    let members = channel.members(cache_http.cache()?).ok()?;
+    let member = members
+        .iter()
+        .find(|m| {
+            m.nick
+                .as_deref()
+                .or_else(|| m.user.global_name.as_deref())
+                .unwrap_or(&m.user.name)
+                == display_name
+        })
+        .or_else(|| {
+            members
+                .iter()
+                .find(|m| m.user.global_name.as_deref().unwrap_or(&m.user.name) == display_name)
+        })?;
+    member.user.avatar_url()
+}
+
+fn attachment(a: &d::Attachment) -> Option<Attachment> {
+    let kind = match a.content_type.as_deref() {
+        Some(ct) if ct.starts_with("video/") || ct == "image/gif" => AK::Video,
+        Some(ct) if ct.starts_with("image/") => AK::Image,
+        Some(ct) if ct.starts_with("audio/") => AK::Audio,
+        _ => AK::Other,
+    };
+    let url = match Url::parse(a.url.as_str()) {
+        Ok(url) => url,
+        Err(e) => {
+            log::error!("Failed to parse attachment url: {e}");
+            return None;
+        }
+    };
+    Some(Attachment {
+        filename: a.filename.trim_start_matches("SPOILER_").to_string(),
+        kind,
+        data: AttachmentData::Url(url),
+        spoiler: a.filename.starts_with("SPOILER_"),
+    })
+}
+
+fn sticker(s: &d::StickerItem) -> Option<Attachment> {
+    let kind = match s.format_type {
+        d::StickerFormatType::Gif | d::StickerFormatType::Apng => AK::Video,
+        d::StickerFormatType::Png => AK::Image,
+        _ => return None,
+    };
+    let Some(url) = s.image_url() else {
+        log::error!("Failed to get sticker url: {s:?}");
+        return None;
+    };
+    let url = match Url::parse(url.as_str()) {
+        Ok(url) => url,
+        Err(e) => {
+            log::error!("Failed to parse attachment url: {e}");
+            return None;
+        }
+    };
+    let filename = url
+        .path_segments()
+        .and_then(Iterator::last)
+        .unwrap_or("sticker")
+        .to_string();
+    Some(Attachment {
+        filename,
+        kind,
+        data: AttachmentData::Url(url),
+        spoiler: false,
+    })
+}
+
+async fn reply_target(ctx: &d::Context, ref_msg: &d::Message) -> ReplyTarget {
+    ReplyTarget {
+        message: MessageRef::Discord(ref_msg.channel_id, ref_msg.id),
+        author: Author {
+            name: format::discord_author_name(ctx, ref_msg).await,
+            user: Some(UserRef::Discord(ref_msg.author.id)),
+        },
+        text: ref_msg.content.clone(),
+        is_quote: false,
+    }
+}
+
+/// Converts a Discord message into the messages to relay: the message itself
+/// and, if it forwards another message, the forwarded snapshot.
+pub async fn bridge_messages(ctx: &d::Context, msg: &d::Message) -> Vec<BridgeMessage> {
+    let source = MessageRef::Discord(msg.channel_id, msg.id);
+    let author = Author {
+        name: format::discord_author_name(ctx, msg).await,
+        user: Some(UserRef::Discord(msg.author.id)),
+    };
+    let content = msg.content_safe(ctx);
+    let mut reply_to = match &msg.referenced_message {
+        Some(ref_msg) => Some(reply_target(ctx, ref_msg).await),
+        None => None,
+    };
+
+    let mut messages = vec![];
+    if msg.message_snapshots.is_empty()
+        || !content.is_empty()
+        || !msg.attachments.is_empty()
+        || !msg.sticker_items.is_empty()
+    {
+        messages.push(BridgeMessage {
+            source: source.clone(),
+            author: author.clone(),
+            text: content,
+            attachments: msg
+                .attachments
+                .iter()
+                .filter_map(attachment)
+                .chain(msg.sticker_items.iter().filter_map(sticker))
+                .collect(),
+            reply_to: reply_to.take(),
+            forwarded_from: None,
+            poll: None,
+            topic: None,
+        });
+    }
+    if let Some(snapshot) = msg.message_snapshots.first() {
+        if msg.message_snapshots.len() > 1 {
+            log::error!(
+                "More than 1 forwarded message is unsupported, {:?}",
+                &msg.message_snapshots
+            )
+        }
+        let content = d::content_safe(
+            ctx,
+            &snapshot.content,
+            &d::ContentSafeOptions::default(),
+            &[],
+        );
+        messages.push(BridgeMessage {
+            source,
+            author,
+            text: content,
+            attachments: snapshot
+                .attachments
+                .iter()
+                .filter_map(attachment)
+                .chain(snapshot.sticker_items.iter().filter_map(sticker))
+                .collect(),
+            reply_to,
+            forwarded_from: Some(ForwardOrigin {
+                name: None,
+                user: None,
+                mirrored: false,
+            }),
+            poll: None,
+            topic: None,
+        });
+    }
+    messages
+}
+
+pub async fn bridge_edit(ctx: &d::Context, upd: &d::MessageUpdateEvent) -> Option<BridgeMessage> {
+    let content = d::content_safe(
+        ctx,
+        upd.content.as_deref()?,
+        &d::ContentSafeOptions::default(),
+        &[],
+    );
+
+    let mut msg_with_author = ctx.cache.message(upd.channel_id, upd.id).map(|m| m.clone());
+    if msg_with_author.is_none() {
+        msg_with_author =
+            crate::discord_request(|| ctx.http.get_message(upd.channel_id, upd.id), || ()).await;
+    }
+    let author = match &msg_with_author {
+        Some(msg) => Author {
+            name: format::discord_author_name(ctx, msg).await,
+            user: Some(UserRef::Discord(msg.author.id)),
+        },
+        None => Author {
+            name: "Unknown".into(),
+            user: None,
+        },
+    };
+    let reply_to = match upd.referenced_message.as_ref().and_then(|m| m.as_ref()) {
+        Some(ref_msg) => Some(reply_target(ctx, ref_msg).await),
+        None => None,
+    };
+    Some(BridgeMessage {
+        source: MessageRef::Discord(upd.channel_id, upd.id),
+        author,
+        text: content,
+        attachments: vec![],
+        reply_to,
+        forwarded_from: None,
+        poll: None,
+        topic: None,
+    })
+}
+
+pub async fn reaction_update(
+    ctx: &d::Context,
+    reaction: &d::Reaction,
+    added: bool,
+) -> Option<ReactionUpdate> {
+    let Some(emoji) = format::discord_reaction_string(&reaction.emoji) else {
+        log::info!("Got reaction {reaction:?} with nameless emoji");
+        return None;
+    };
+    Some(ReactionUpdate {
+        message: MessageRef::Discord(reaction.channel_id, reaction.message_id),
+        reactor: format::discord_reactor_name(ctx, reaction).await,
+        change: if added {
+            ReactionChange::Add(emoji.to_string())
+        } else {
+            ReactionChange::Remove(emoji.to_string())
+        },
+    })
+}
+
+impl DiscordPlatform {
+    pub fn new(http: Arc<d::Http>, cache: Arc<d::Cache>, db: Pool) -> Self {
+        Self {
+            http,
+            cache,
+            db,
+            webhooks: DashMap::new(),
+            avatars: DashMap::new(),
+        }
+    }
+
+    fn cache_http(&self) -> (&Arc<d::Cache>, &d::Http) {
+        (&self.cache, &self.http)
+    }
+
+    /// Threads are posted to through their parent channel's webhook.
+    async fn webhook_in(
+        &self,
+        channel: d::ChannelId,
+    ) -> Option<(d::Webhook, Option<d::ChannelId>)> {
+        let parent = match db::thread_parent(channel) {
+            Some(parent) => Some(parent),
+            // other threads, like the comments of a channel post
+            None => discord_request!(channel.to_channel(self.cache_http()))
+                .await?
+                .guild()
+                .filter(|c| c.thread_metadata.is_some())
+                .and_then(|c| c.parent_id),
+        };
+        match parent {
+            Some(parent) => Some((self.webhook(parent).await?, Some(channel))),
+            None => Some((self.webhook(channel).await?, None)),
+        }
+    }
+
+    /// Creates a thread in a text channel, or a post in a forum channel.
+    pub async fn create_thread(&self, channel: d::ChannelId, name: &str) -> Option<d::ChannelId> {
+        let name: String = name.chars().take(100).collect();
+        let kind = discord_request!(channel.to_channel(self.cache_http()))
+            .await?
+            .guild()?
+            .kind;
+        let thread = if kind == d::ChannelType::Forum {
+            let starter =
+                d::CreateMessage::new().content(format!("Topic \"{name}\" from Telegram"));
+            discord_request!(channel.create_forum_post(
+                self.cache_http(),
+                d::CreateForumPost::new(name.clone(), starter.clone())
+            ))
+            .await?
+        } else {
+            discord_request!(channel.create_thread(
+                self.cache_http(),
+                d::CreateThread::new(name.clone()).kind(d::ChannelType::PublicThread)
+            ))
+            .await?
+        };
+        Some(thread.id)
+    }
+
+    /// Opens a thread on a message, which shares the message's id.
+    pub async fn create_message_thread(
+        &self,
+        channel: d::ChannelId,
+        message: d::MessageId,
+        name: &str,
+    ) -> Option<d::ChannelId> {
+        let thread = discord_request!(channel.create_thread_from_message(
+            self.cache_http(),
+            message,
+            d::CreateThread::new(name)
+        ))
+        .await?;
+        Some(thread.id)
+    }
+
+    pub async fn edit_thread(&self, thread: d::ChannelId, name: &str, closed: bool) {
+        let mut edit = d::EditThread::new().name(name).locked(closed);
+        if !closed {
+            edit = edit.archived(false);
+        }
+        discord_request!(thread.edit_thread(&*self.http, edit.clone())).await;
+    }
+
+    async fn webhook(&self, channel: d::ChannelId) -> Option<d::Webhook> {
+        if let Some(webhook) = self.webhooks.get(&channel) {
+            return Some(webhook.clone());
+        }
+        let webhook = match db::get_webhook_url(channel) {
+            Some(url) => {
+                discord_request!(d::Webhook::from_url(self.http.clone(), url.as_str())).await
+            }
+            None => {
+                let make_webhook = d::CreateWebhook::new("Discogram");
+                let webhook = discord_request!(
+                    channel.create_webhook(self.http.clone(), make_webhook.clone())
+                )
+                .await;
+                if let Some(webhook) = &webhook {
+                    let url = webhook.url().expect("Bot running without token");
+                    if let Err(e) = db::set_webhook_url(&self.db, channel, url).await {
+                        log::error!("Failed to insert chat mapping: {}", e);
+                    }
+                }
+                webhook
+            }
+        };
+        match webhook {
+            Some(webhook) => {
+                self.webhooks.insert(channel, webhook.clone());
+                Some(webhook)
+            }
+            None => {
+                log::error!("Failed to get webhook");
+                None
+            }
+        }
+    }
+
+    /// Gets a URL for the user's avatar which Discord can display, uploading it
+    /// to the image channel if the avatar isn't already hosted somewhere.
+    async fn avatar_url(&self, bridge: &Bridge, user: &UserRef) -> Option<Arc<str>> {
+        const CACHE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+        if let Some(record) = self.avatars.get(user) {
+            if record.last_updated + CACHE_LIFETIME > Instant::now() {
+                return Some(record.url.clone());
+            }
+        }
+        // There was no record, or the record was invalid, we need to fetch the image
+        let url: Arc<str> = match bridge.avatar(user).await? {
+            Avatar::Url(url) => url.into(),
+            Avatar::Bytes(buf) => {
+                let files = vec![d::CreateAttachment::bytes(buf, "avatar.jpg")];
+                let builder = d::CreateMessage::new().add_files(files.clone());
+                let discord_image_channel = db::discord_image_channel().await?;
+                let message = discord_request!(
+                    discord_image_channel.send_message(self.http.as_ref(), builder.clone())
+                )
+                .await?;
+                message
+                    .attachments
+                    .first()
+                    .map(|a| Arc::from(a.url.as_str()))
+                    .or_else(|| {
+                        log::error!("Failed to get avatar URL");
+                        None
+                    })?
+            }
+        };
+        let record = AvatarCacheRecord {
+            url: url.clone(),
+            last_updated: Instant::now(),
+        };
+        self.avatars.insert(user.clone(), record);
+        Some(url)
+    }
+
+    async fn reply_info(
+        &self,
+        bridge: &Bridge,
+        discord_chat: d::ChannelId,
+        reply: &ReplyTarget,
+        include_author_icon: bool,
+    ) -> ReplyInfo {
+        let cache_http = self.cache_http();
+        let mut mentions = d::CreateAllowedMentions::new();
+        let mut ref_user = None;
+        let mut ref_nick = None;
+        let mut ref_link = None;
+        let mut ref_image = None;
+
+        match bridge
+            .counterpart(&reply.message, &ChatRef::Discord(discord_chat))
+            .await
+        {
+            Some(Mirror {
+                message: MessageRef::Discord(_, mirror_id),
+                ..
+            }) => {
+                let mut ref_disc_message = self
+                    .cache
+                    .message(discord_chat, mirror_id)
+                    .map(|msg| msg.clone());
+                if ref_disc_message.is_none() {
+                    ref_disc_message =
+                        discord_request!(self.http.get_message(discord_chat, mirror_id)).await;
+                }
+                let mut guild_id = None;
+                if let Ok(channel) = discord_chat.to_channel(cache_http).await {
+                    if let Some(c) = channel.guild() {
+                        guild_id = Some(c.guild_id);
+                    }
+                }
+                ref_link = Some(mirror_id.link(discord_chat, guild_id));
+                if let Some(msg) = ref_disc_message {
+                    ref_nick = Some(format::discord_author_name(&cache_http, &msg).await);
+                    ref_image = msg.attachments.first().map(|a| a.url.clone()).or_else(|| {
+                        msg.embeds
+                            .first()
+                            .and_then(|e| e.image.as_ref().map(|t| t.url.clone()))
+                    });
+                    // messages sent through the webhook have no real user to mention
+                    if msg.webhook_id.is_none() {
+                        ref_user = Some(msg.author);
+                    }
+                }
+            }
+            _ => {
+                log::info!(
+                    "Replying to message {:?} with no known counterpart",
+                    reply.message
+                );
+            }
+        }
+        let ref_author = if let Some(author) = &ref_user {
+            mentions = mentions.users(Some(author.id));
+            author.id.mention().to_string()
+        } else {
+            reply.author.name.clone()
+        };
+        let reply_str = if reply.is_quote {
+            "quoting"
+        } else {
+            "replying to"
+        };
+        let reply_str = if let Some(link) = ref_link {
+            format!("[{reply_str}]({link})")
+        } else {
+            reply_str.to_string()
+        };
+        let mut embed_author = d::CreateEmbedAuthor::new(ref_nick.as_ref().unwrap_or(&ref_author));
+        if include_author_icon {
+            let url = match &ref_user {
+                Some(user) => user.avatar_url().map(Arc::from),
+                None => match &reply.author.user {
+                    Some(user) => self.avatar_url(bridge, user).await,
+                    None => None,
+                },
+            };
+            if let Some(url) = url {
+                embed_author = embed_author.icon_url(&*url);
+            }
+        }
+        let mut embed = d::CreateEmbed::new()
+            .description(preview(&reply.text))
+            .author(embed_author);
+        if let Some(image) = ref_image {
+            embed = embed.thumbnail(image);
+        }
+        ReplyInfo {
+            content_suffix: format!("-# **{reply_str} {ref_author}**"),
+            embed,
+            mentions,
+        }
+    }
+
+    async fn send_poll(
+        &self,
+        webhook: &d::Webhook,
+        thread: Option<d::ChannelId>,
+        avatar_url: Option<Arc<str>>,
+        poll: &Poll,
+        author: &str,
+    ) -> Option<d::Message> {
+        let embed = d::CreateEmbed::new()
+            .title(match poll.kind {
+                PollKind::Quiz => "Quiz",
+                PollKind::Single => "Poll (pick one)",
+                PollKind::Multiple => "Poll (multiple)",
+            })
+            .description(&poll.question)
+            .fields(poll.options.iter().enumerate().map(|(i, opt)| {
+                (
+                    if i < KEYCAPS.len() {
+                        format!("Option {}", KEYCAPS[i])
+                    } else {
+                        format!("Option {}", i)
+                    },
+                    opt,
+                    false,
+                )
+            }));
+        let mut builder = d::ExecuteWebhook::new().username(author).embed(embed);
+        if let Some(avatar_url) = avatar_url {
+            builder = builder.avatar_url(&*avatar_url);
+        }
+        if let Some(thread) = thread {
+            builder = builder.in_thread(thread);
+        }
+        let discord_result = discord_request!(
+            webhook.execute(self.http.clone(), true, builder.clone()),
+            edbg!("Poll")
+        )
+        .await
+        .flatten();
+        if let Some(msg) = &discord_result {
+            for keycap in KEYCAPS.iter().take(poll.options.len()) {
+                _ = msg
+                    .react(&self.http, d::ReactionType::Unicode(keycap.to_string()))
+                    .await;
+            }
+        }
+        discord_result
+    }
+
+    async fn create_attachment(&self, a: &Attachment) -> Option<d::CreateAttachment> {
+        let filename = if a.spoiler {
+            format!("SPOILER_{}", a.filename)
+        } else {
+            a.filename.clone()
+        };
+        match &a.data {
+            AttachmentData::Bytes(bytes) => {
+                Some(d::CreateAttachment::bytes(bytes.clone(), filename))
+            }
+            AttachmentData::Url(url) => {
+                let mut attachment =
+                    discord_request!(d::CreateAttachment::url(&self.http, url.as_str())).await?;
+                attachment.filename = filename;
+                Some(attachment)
+            }
+        }
+    }
+}
+
+#[d::async_trait]
+impl Platform for DiscordPlatform {
+    async fn send(&self, bridge: &Bridge, chat: &ChatRef, msg: &BridgeMessage) -> Vec<Mirror> {
+        let &ChatRef::Discord(discord_chat) = chat else {
+            log::error!("Discord platform asked to send to {chat:?}");
+            return vec![];
+        };
+        let Some(webhook) = self.webhook(discord_chat).await else {
+            return vec![];
+        };
+        // messages from a Telegram topic go to the thread mirroring it, and
+        // comments on a channel post to the thread on the post's mirror
+        let thread = match (&msg.source, msg.topic) {
+            (&MessageRef::Telegram(telegram_chat, _), Some(topic)) => {
+                match db::topic_threads(telegram_chat, topic)
+                    .into_iter()
+                    .find(|t| t.discord_channel == discord_chat)
+                {
+                    Some(t) => Some(t.discord_thread),
+                    None => db::comment_thread(&bridge.db, telegram_chat, topic.0, discord_chat)
+                        .await
+                        .unwrap_or_else(|e| {
+                            log::error!("Failed to get comment thread: {e}");
+                            None
+                        }),
+                }
+            }
+            _ => None,
+        };
+        let target = thread.unwrap_or(discord_chat);
+        let author = &msg.author.name;
+        let avatar_url = match &msg.author.user {
+            Some(user) => self.avatar_url(bridge, user).await,
+            None => None,
+        };
+
+        let discord_result = if let Some(poll) = &msg.poll {
+            self.send_poll(&webhook, thread, avatar_url, poll, author)
+                .await
+        } else {
+            let mut content = msg.text.clone();
+            let mut message = d::ExecuteWebhook::new().username(author);
+            let mut embed = None;
+
+            if let Some(origin) = &msg.forwarded_from {
+                let name = origin.name.as_deref().unwrap_or("Unknown");
+                let mut original_author = d::CreateEmbedAuthor::new(name);
+                let mut icon_url = None;
+                if origin.mirrored {
+                    icon_url =
+                        discord_avatar_url_by_display_name(&self.cache_http(), discord_chat, name)
+                            .await
+                            .map(Arc::from);
+                }
+                if icon_url.is_none() {
+                    if let Some(user) = &origin.user {
+                        icon_url = self.avatar_url(bridge, user).await;
+                    }
+                }
+                if let Some(url) = icon_url {
+                    original_author = original_author.icon_url(&*url);
+                }
+                embed = Some(
+                    d::CreateEmbed::new()
+                        .author(original_author)
+                        .description(&content),
+                );
+                content = "-# Forwarded message".to_string();
+            }
+
+            if let Some(reply) = &msg.reply_to {
+                let ReplyInfo {
+                    content_suffix,
+                    embed,
+                    mentions,
+                } = self.reply_info(bridge, target, reply, true).await;
+                message = message.embed(embed).allowed_mentions(mentions);
+                content = format!("{content}\n{content_suffix}");
+            }
+
+            for a in &msg.attachments {
+                if let Some(attachment) = self.create_attachment(a).await {
+                    if let Some(e) = embed {
+                        embed = Some(e.attachment(&attachment.filename));
+                    }
+                    message = message.add_file(attachment);
+                }
+            }
+
+            if let Some(e) = embed {
+                message = message.embed(e);
+            }
+
+            if let Some(avatar_url) = avatar_url {
+                message = message.avatar_url(&*avatar_url);
+            }
+
+            if let Some(thread) = thread {
+                message = message.in_thread(thread);
+            }
+
+            message = message.content(&content);
+
+            discord_request!(
+                webhook.execute(self.http.clone(), true, message.clone()),
+                edbg!(author, msg.text, content)
+            )
+            .await
+            .flatten()
+        };
+
+        if let Some(discord_msg) = &discord_result {
+            if thread.is_none() && db::is_crosspost(chat) {
+                discord_request!(discord_chat.crosspost(&*self.http, discord_msg.id)).await;
+            }
+        }
+
+        discord_result
+            .map(|discord_msg| Mirror {
+                message: MessageRef::Discord(target, discord_msg.id),
+                has_caption: false,
+            })
+            .into_iter()
+            .collect()
+    }
+
+    async fn edit(&self, bridge: &Bridge, mirror: &Mirror, msg: &BridgeMessage) {
+        let MessageRef::Discord(discord_chat, mirror_id) = mirror.message else {
+            return;
+        };
+        let Some((webhook, thread)) = self.webhook_in(discord_chat).await else {
+            return;
+        };
+        let author = &msg.author.name;
+        let mut content = msg.text.clone();
+        let mut mentions = d::CreateAllowedMentions::new();
+
+        if let Some(reply) = &msg.reply_to {
+            let ReplyInfo {
+                content_suffix,
+                mentions: new_mentions,
+                ..
+            } = self.reply_info(bridge, discord_chat, reply, false).await;
+            mentions = new_mentions;
+            content = format!("{content}\n{content_suffix}");
+        }
+
+        let mut message = d::EditWebhookMessage::new()
+            .content(&content)
+            .allowed_mentions(mentions);
+        if let Some(thread) = thread {
+            message = message.in_thread(thread);
+        }
+
+        discord_request!(
+            webhook.edit_message(self.cache_http(), mirror_id, message.clone()),
+            edbg!(author, msg.text, content)
+        )
+        .await;
+    }
+
+    async fn delete(&self, message: &MessageRef) -> bool {
+        let &MessageRef::Discord(discord_chat, discord_id) = message else {
+            return false;
+        };
+        let Some((webhook, thread)) = self.webhook_in(discord_chat).await else {
+            return false;
+        };
+        discord_request!(webhook.delete_message(self.http.clone(), thread, discord_id))
+            .await
+            .is_some()
+    }
+
+    async fn pin(&self, message: &MessageRef) {
+        let &MessageRef::Discord(discord_chat, discord_id) = message else {
+            return;
+        };
+        let _ =
+            discord_request!(self
+                .http
+                .pin_message(discord_chat, discord_id, Some("Bridged pin")))
+            .await;
+    }
+
+    async fn avatar(&self, user: &UserRef) -> Option<Avatar> {
+        let &UserRef::Discord(user_id) = user else {
+            return None;
+        };
+        let user = discord_request!(user_id.to_user(self.cache_http())).await?;
+        user.avatar_url().map(Avatar::Url)
+    }
+
+    async fn notify(&self, chat: &ChatRef, text: &str) {
+        let &ChatRef::Discord(discord_chat) = chat else {
+            return;
+        };
+        let notification = d::CreateMessage::new().content(text);
+        let _ =
+            discord_request!(discord_chat.send_message(&*self.http, notification.clone())).await;
+    }
+
+    fn format_reactions(&self, reactions: &Reactions) -> String {
+        format::format_discord_reaction_message(reactions)
+    }
+
+    fn parse_reactions(&self, text: &str) -> Reactions {
+        format::parse_discord_reaction_message(text)
+    }
+
+    async fn send_reactions(&self, reacted: &MessageRef, text: &str) -> Option<MessageRef> {
+        let &MessageRef::Discord(discord_chat, discord_id) = reacted else {
+            return None;
+        };
+        let reaction_msg = d::CreateMessage::new()
+            .content(text)
+            .reference_message((discord_chat, discord_id))
+            .allowed_mentions(d::CreateAllowedMentions::new().replied_user(false));
+        discord_request!(
+            discord_chat.send_message(&*self.http, reaction_msg.clone()),
+            edbg!(text)
+        )
+        .await
+        .map(|reaction_msg| MessageRef::Discord(discord_chat, reaction_msg.id))
+    }
+
+    async fn edit_reactions(&self, summary: &MessageRef, text: &str) -> bool {
+        let &MessageRef::Discord(discord_chat, discord_id) = summary else {
+            return false;
+        };
+        discord_request!(discord_chat.edit_message(
+            &*self.http,
+            discord_id,
+            d::EditMessage::new().content(text)
+        ))
+        .await
+        .is_some()
+    }
+
+    async fn delete_reactions(&self, summary: &MessageRef) -> bool {
+        let &MessageRef::Discord(discord_chat, discord_id) = summary else {
+            return false;
+        };
+        discord_request!(discord_chat.delete_message(&*self.http, discord_id))
+            .await
+            .is_some()
+    }
+}
@@ -0,0 +1,502 @@
+use base64::Engine;
+use dashmap::DashSet;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    net::TcpStream,
+    sync::{mpsc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::{
+    AttachmentData, Author, Avatar, BridgeMessage, ChatRef, ForwardOrigin, MessageRef, Mirror,
+    Platform, Poll, Reactions, UserRef,
+};
+use crate::bridge::Bridge;
+use crate::db;
+use crate::discord as d;
+use crate::format;
+
+// servers cut lines at 512 bytes including the prefix they add, so leave some room
+const LINE_LIMIT: usize = 400;
+const FLOOD_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug)]
+pub struct IrcConfig {
+    pub server: String,
+    pub port: u16,
+    pub tls: bool,
+    pub nick: String,
+    /// Used for SASL PLAIN with the nick as the account name.
+    pub password: Option<String>,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub struct Connection {
+    lines: tokio::io::Split<BufReader<ReadHalf<Box<dyn Stream>>>>,
+    writer: WriteHalf<Box<dyn Stream>>,
+    nick: String,
+    registered: bool,
+    last_sent: Instant,
+}
+
+/// A message said in a channel.
+#[derive(Clone, Debug)]
+pub struct IrcMessage {
+    pub channel: String,
+    pub nick: String,
+    pub text: String,
+    pub action: bool,
+}
+
+pub struct IrcPlatform {
+    config: IrcConfig,
+    // lines are queued so that a reconnect doesn't lose them and sending respects the flood delay
+    outgoing: mpsc::UnboundedSender<String>,
+    queue: Mutex<mpsc::UnboundedReceiver<String>>,
+    joined: DashSet<String>,
+    next_id: AtomicU64,
+}
+
+struct Line<'a> {
+    source: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+fn parse_line(line: &str) -> Option<Line<'_>> {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    if rest.starts_with('@') {
+        rest = rest.split_once(' ')?.1;
+    }
+    let source = match rest.strip_prefix(':') {
+        Some(s) => {
+            let (source, r) = s.split_once(' ')?;
+            rest = r;
+            Some(source)
+        }
+        None => None,
+    };
+    let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+    let mut params = vec![];
+    while !rest.is_empty() {
+        if let Some(trailing) = rest.strip_prefix(':') {
+            params.push(trailing);
+            break;
+        }
+        let (param, r) = rest.split_once(' ').unwrap_or((rest, ""));
+        if !param.is_empty() {
+            params.push(param);
+        }
+        rest = r;
+    }
+    Some(Line {
+        source,
+        command,
+        params,
+    })
+}
+
+/// Splits each line of `text` into pieces of at most `max` bytes, preferring to break at spaces.
+fn split_message(text: &str, max: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    for mut line in text.lines() {
+        while line.len() > max {
+            let mut end = max;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            let end = match line[..end].rfind(' ') {
+                Some(space) if space > 0 => space,
+                _ => end,
+            };
+            pieces.push(line[..end].to_string());
+            line = line[end..].trim_start();
+        }
+        if !line.trim().is_empty() {
+            pieces.push(line.to_string());
+        }
+    }
+    pieces
+}
+
+fn poll_text(poll: &Poll) -> String {
+    let options = poll
+        .options
+        .iter()
+        .enumerate()
+        .map(|(i, opt)| format!("{}. {opt}", i + 1))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("Poll: {}\n{options}", poll.question)
+}
+
+pub fn is_channel_name(s: &str) -> bool {
+    (s.starts_with('#') || s.starts_with('&'))
+        && s.len() > 1
+        && !s.contains([' ', ',', '\x07', '\r', '\n'])
+}
+
+impl IrcPlatform {
+    pub fn new(config: IrcConfig) -> Self {
+        let (outgoing, queue) = mpsc::unbounded_channel();
+        Self {
+            config,
+            outgoing,
+            queue: Mutex::new(queue),
+            joined: DashSet::new(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn connect(&self) -> io::Result<Connection> {
+        let tcp = TcpStream::connect((self.config.server.as_str(), self.config.port)).await?;
+        let stream: Box<dyn Stream> = if self.config.tls {
+            let connector = native_tls::TlsConnector::new().map_err(io::Error::other)?;
+            let tls = TlsConnector::from(connector)
+                .connect(&self.config.server, tcp)
+                .await
+                .map_err(io::Error::other)?;
+            Box::new(tls)
+        } else {
+            Box::new(tcp)
+        };
+        let (reader, writer) = tokio::io::split(stream);
+        let mut conn = Connection {
+            lines: BufReader::new(reader).split(b'\n'),
+            writer,
+            nick: self.config.nick.clone(),
+            registered: false,
+            last_sent: Instant::now(),
+        };
+        self.joined.clear();
+        if self.config.password.is_some() {
+            conn.write("CAP REQ :sasl").await?;
+        }
+        conn.write(&format!("NICK {}", conn.nick)).await?;
+        conn.write(&format!("USER {} 0 * :{}", conn.nick, conn.nick))
+            .await?;
+        Ok(conn)
+    }
+
+    /// Handles the connection until someone says something in a channel.
+    pub async fn next_message(&self, conn: &mut Connection) -> io::Result<IrcMessage> {
+        let mut queue = self.queue.lock().await;
+        loop {
+            let segment = tokio::select! {
+                segment = conn.lines.next_segment() => segment?,
+                Some(line) = queue.recv(), if conn.registered => {
+                    tokio::time::sleep_until(conn.last_sent + FLOOD_DELAY).await;
+                    conn.write(&line).await?;
+                    continue;
+                }
+            };
+            let Some(segment) = segment else {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            };
+            let raw = String::from_utf8_lossy(&segment);
+            let Some(line) = parse_line(&raw) else {
+                continue;
+            };
+            if let Some(message) = self.handle_line(conn, line).await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    async fn handle_line(
+        &self,
+        conn: &mut Connection,
+        line: Line<'_>,
+    ) -> io::Result<Option<IrcMessage>> {
+        let param = |i: usize| line.params.get(i).copied().unwrap_or_default();
+        match line.command {
+            "PING" => conn.write(&format!("PONG :{}", param(0))).await?,
+            "ERROR" => return Err(io::Error::other(param(0).to_string())),
+            "CAP" if param(1) == "ACK" && param(2).split(' ').any(|c| c == "sasl") => {
+                conn.write("AUTHENTICATE PLAIN").await?
+            }
+            "CAP" if param(1) == "NAK" => {
+                log::error!("IRC server doesn't support SASL");
+                conn.write("CAP END").await?
+            }
+            "AUTHENTICATE" if param(0) == "+" => {
+                let password = self.config.password.as_deref().unwrap_or_default();
+                let credentials = format!("{0}\0{0}\0{password}", self.config.nick);
+                let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+                conn.write(&format!("AUTHENTICATE {encoded}")).await?
+            }
+            "903" => conn.write("CAP END").await?,
+            "902" | "904" | "905" | "906" | "908" => {
+                log::error!("IRC SASL authentication failed: {}", line.params.join(" "));
+                conn.write("CAP END").await?
+            }
+            // nick in use
+            "433" if !conn.registered => {
+                conn.nick.push('_');
+                conn.write(&format!("NICK {}", conn.nick)).await?
+            }
+            "001" => {
+                conn.registered = true;
+                log::info!("Connected to IRC as {}", conn.nick);
+                for chat in db::all_chats() {
+                    if let ChatRef::Irc(channel) = chat {
+                        conn.write(&format!("JOIN {channel}")).await?;
+                        self.joined.insert(channel);
+                    }
+                }
+            }
+            "PRIVMSG" => {
+                let nick = line
+                    .source
+                    .and_then(|s| s.split('!').next())
+                    .unwrap_or_default();
+                let channel = param(0);
+                if !is_channel_name(channel) || nick == conn.nick {
+                    return Ok(None);
+                }
+                let mut text = param(1);
+                let mut action = false;
+                if let Some(ctcp) = text.strip_prefix('\x01') {
+                    let ctcp = ctcp.trim_end_matches('\x01');
+                    match ctcp.strip_prefix("ACTION ") {
+                        Some(rest) => {
+                            text = rest;
+                            action = true;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                return Ok(Some(IrcMessage {
+                    channel: channel.to_lowercase(),
+                    nick: nick.to_string(),
+                    text: text.to_string(),
+                    action,
+                }));
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn queue(&self, line: String) {
+        // the receiver lives as long as the platform
+        let _ = self.outgoing.send(line);
+    }
+
+    pub fn join(&self, channel: &str) {
+        if self.joined.insert(channel.to_string()) {
+            self.queue(format!("JOIN {channel}"));
+        }
+    }
+
+    fn say(&self, channel: &str, prefix: &str, text: &str) -> usize {
+        let max = LINE_LIMIT
+            .saturating_sub(channel.len() + prefix.len())
+            .max(1);
+        let pieces = split_message(text, max);
+        for piece in &pieces {
+            self.queue(format!("PRIVMSG {channel} :{prefix}{piece}"));
+        }
+        pieces.len()
+    }
+
+    fn message_id(&self) -> String {
+        self.next_id.fetch_add(1, Ordering::Relaxed).to_string()
+    }
+
+    pub fn bridge_message(&self, message: &IrcMessage) -> BridgeMessage {
+        let text = format::irc_to_discord_format(&message.text);
+        let text = if message.action {
+            format!("_{} {text}_", message.nick)
+        } else {
+            text
+        };
+        BridgeMessage {
+            source: MessageRef::Irc(message.channel.clone(), self.message_id()),
+            author: Author {
+                name: message.nick.clone(),
+                user: Some(UserRef::Irc(message.nick.clone())),
+            },
+            text,
+            attachments: vec![],
+            reply_to: None,
+            forwarded_from: None,
+            poll: None,
+            topic: None,
+        }
+    }
+}
+
+impl Connection {
+    async fn write(&mut self, line: &str) -> io::Result<()> {
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.write_all(b"\r\n").await?;
+        self.writer.flush().await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+#[d::async_trait]
+impl Platform for IrcPlatform {
+    async fn send(&self, _bridge: &Bridge, chat: &ChatRef, msg: &BridgeMessage) -> Vec<Mirror> {
+        let ChatRef::Irc(channel) = chat else {
+            log::error!("IRC platform asked to send to {chat:?}");
+            return vec![];
+        };
+        self.join(channel);
+        let author = &msg.author.name;
+        let mut prefix = match &msg.forwarded_from {
+            Some(ForwardOrigin {
+                name: Some(name), ..
+            }) => format!("<{author}> (forwarded from {name}) "),
+            Some(_) => format!("<{author}> (forwarded) "),
+            None => format!("<{author}> "),
+        };
+        if let Some(reply) = &msg.reply_to {
+            prefix.push_str(&format!("{}: ", reply.author.name));
+        }
+        let mut text = match &msg.poll {
+            Some(poll) => poll_text(poll),
+            None => format::discord_to_irc_format(&msg.text),
+        };
+        for a in &msg.attachments {
+            let line = match &a.data {
+                AttachmentData::Url(url) => url.to_string(),
+                AttachmentData::Bytes(_) => format!("[{}]", a.filename),
+            };
+            text.push('\n');
+            text.push_str(&line);
+        }
+        if self.say(channel, &prefix, &text) == 0 {
+            return vec![];
+        }
+        vec![Mirror {
+            message: MessageRef::Irc(channel.clone(), self.message_id()),
+            has_caption: false,
+        }]
+    }
+
+    // IRC messages can't be edited, deleted or pinned
+    async fn edit(&self, _bridge: &Bridge, _mirror: &Mirror, _msg: &BridgeMessage) {}
+
+    async fn delete(&self, _message: &MessageRef) -> bool {
+        false
+    }
+
+    async fn pin(&self, _message: &MessageRef) {}
+
+    async fn avatar(&self, _user: &UserRef) -> Option<Avatar> {
+        None
+    }
+
+    async fn notify(&self, chat: &ChatRef, text: &str) {
+        let ChatRef::Irc(channel) = chat else {
+            return;
+        };
+        self.join(channel);
+        self.say(channel, "", &format::discord_to_irc_format(text));
+    }
+
+    fn format_reactions(&self, reactions: &Reactions) -> String {
+        format::format_discord_reaction_message(reactions)
+    }
+
+    fn parse_reactions(&self, text: &str) -> Reactions {
+        format::parse_discord_reaction_message(text)
+    }
+
+    // reaction summaries would have to be resent on every change, which is just noise
+    async fn send_reactions(&self, _reacted: &MessageRef, _text: &str) -> Option<MessageRef> {
+        None
+    }
+
+    async fn edit_reactions(&self, _summary: &MessageRef, _text: &str) -> bool {
+        false
+    }
+
+    async fn delete_reactions(&self, _summary: &MessageRef) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let line = parse_line(":nick!user@host PRIVMSG #chan :hello there\r\n").unwrap();
+        assert_eq!(line.source, Some("nick!user@host"));
+        assert_eq!(line.command, "PRIVMSG");
+        assert_eq!(line.params, vec!["#chan", "hello there"]);
+
+        let line = parse_line("PING :server").unwrap();
+        assert_eq!(line.source, None);
+        assert_eq!(line.command, "PING");
+        assert_eq!(line.params, vec!["server"]);
+    }
+
+    #[test]
+    fn test_parse_line_tags_and_spaces() {
+        let line = parse_line("@time=2020-01-01T00:00:00Z :nick JOIN  #chan").unwrap();
+        assert_eq!(line.source, Some("nick"));
+        assert_eq!(line.command, "JOIN");
+        assert_eq!(line.params, vec!["#chan"]);
+
+        let line = parse_line(":server 001 bot :").unwrap();
+        assert_eq!(line.params, vec!["bot", ""]);
+
+        // a source with nothing after it isn't a line
+        assert!(parse_line(":server").is_none());
+    }
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(split_message("short", 10), vec!["short"]);
+        assert_eq!(
+            split_message("one two three four", 9),
+            vec!["one two", "three", "four"]
+        );
+        assert_eq!(
+            split_message("first\n\nsecond\n", 10),
+            vec!["first", "second"]
+        );
+        assert!(split_message("", 10).is_empty());
+    }
+
+    #[test]
+    fn test_split_message_without_spaces() {
+        assert_eq!(split_message("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn test_split_message_multibyte() {
+        // pieces are cut at character boundaries, never inside a character
+        let pieces = split_message("ééééé", 3);
+        assert_eq!(pieces, vec!["é", "é", "é", "é", "é"]);
+        let pieces = split_message("🎉🎉 🎉", 9);
+        assert_eq!(pieces, vec!["🎉🎉", "🎉"]);
+        for piece in split_message(&"日本語のテキスト ".repeat(40), 50) {
+            assert!(piece.len() <= 50);
+        }
+    }
+
+    #[test]
+    fn test_is_channel_name() {
+        assert!(is_channel_name("#rust"));
+        assert!(is_channel_name("&local"));
+        assert!(!is_channel_name("#"));
+        assert!(!is_channel_name("rust"));
+        assert!(!is_channel_name("#two words"));
+        assert!(!is_channel_name("#a,#b"));
+    }
+}
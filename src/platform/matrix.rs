@@ -0,0 +1,721 @@
+use dashmap::DashMap;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use url::Url;
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    Attachment, AttachmentData, AttachmentKind as AK, Author, Avatar, BridgeMessage, ChatRef,
+    ForwardOrigin, MessageRef, Mirror, Platform, Poll, ReactionChange, ReactionUpdate, Reactions,
+    ReplyTarget, UserRef,
+};
+use crate::bridge::Bridge;
+use crate::discord as d;
+use crate::format;
+
+#[derive(Debug)]
+pub enum Error {
+    Network(reqwest::Error),
+    RateLimited(Duration),
+    Api(StatusCode, String),
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Network(e)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Event {
+    pub event_id: String,
+    pub sender: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub content: Value,
+    pub redacts: Option<String>,
+}
+
+impl Event {
+    pub fn body(&self) -> Option<&str> {
+        self.content["body"].as_str()
+    }
+
+    pub fn replaced_event(&self) -> Option<&str> {
+        let relation = &self.content["m.relates_to"];
+        if relation["rel_type"] != "m.replace" {
+            return None;
+        }
+        relation["event_id"].as_str()
+    }
+
+    pub fn redacted_event(&self) -> Option<&str> {
+        self.redacts
+            .as_deref()
+            .or_else(|| self.content["redacts"].as_str())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SyncResponse {
+    pub next_batch: String,
+    #[serde(default)]
+    pub rooms: SyncRooms,
+}
+
+#[derive(Default, Deserialize)]
+pub struct SyncRooms {
+    #[serde(default)]
+    pub join: HashMap<String, JoinedRoom>,
+    #[serde(default)]
+    pub invite: HashMap<String, Value>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct JoinedRoom {
+    #[serde(default)]
+    pub timeline: Timeline,
+}
+
+#[derive(Default, Deserialize)]
+pub struct Timeline {
+    #[serde(default)]
+    pub events: Vec<Event>,
+}
+
+pub struct MatrixPlatform {
+    http: reqwest::Client,
+    homeserver: Url,
+    token: String,
+    pub user_id: String,
+    transaction_prefix: u128,
+    transaction: AtomicU64,
+    // reactions are removed by redacting them, which doesn't say what the reaction was
+    reactions: DashMap<String, ReactionUpdate>,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn markdown(content: &Value) -> String {
+    let html = content["formatted_body"]
+        .as_str()
+        .filter(|_| content["format"] == "org.matrix.custom.html");
+    match html {
+        Some(html) => format::matrix_to_discord_format(html),
+        None => {
+            let body = content["body"].as_str().unwrap_or_default();
+            // plain text replies start with a quote of the replied message
+            if body.starts_with("> <") {
+                body.split_once("\n\n")
+                    .map_or(body, |(_, rest)| rest)
+                    .to_string()
+            } else {
+                body.to_string()
+            }
+        }
+    }
+}
+
+fn poll_text(poll: &Poll) -> String {
+    let options = poll
+        .options
+        .iter()
+        .enumerate()
+        .map(|(i, opt)| format!("{}. {opt}", i + 1))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("**Poll**: {}\n{options}", poll.question)
+}
+
+fn mime_type(a: &Attachment) -> &'static str {
+    let extension = a
+        .filename
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg" | "oga") => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+impl MatrixPlatform {
+    pub async fn new(homeserver: Url, token: String) -> Result<Self, Error> {
+        let mut platform = Self {
+            http: reqwest::Client::new(),
+            homeserver,
+            token,
+            user_id: String::new(),
+            // transaction ids have to stay unique across restarts
+            transaction_prefix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            transaction: AtomicU64::new(0),
+            reactions: DashMap::new(),
+        };
+        let whoami: Value = platform
+            .request(
+                Method::GET,
+                &["_matrix", "client", "v3", "account", "whoami"],
+                None,
+            )
+            .await?;
+        platform.user_id = whoami["user_id"].as_str().unwrap_or_default().to_string();
+        Ok(platform)
+    }
+
+    fn url(&self, path: &[&str]) -> Url {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .expect("homeserver URL is a base URL")
+            .pop_if_empty()
+            .extend(path);
+        url
+    }
+
+    async fn execute_raw(&self, request: RequestBuilder) -> Result<Response, Error> {
+        let response = request.bearer_auth(&self.token).send().await?;
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let body: Value = response.json().await.unwrap_or_default();
+            let retry_after = body["retry_after_ms"].as_u64().unwrap_or(1000);
+            return Err(Error::RateLimited(Duration::from_millis(retry_after)));
+        }
+        if !status.is_success() {
+            return Err(Error::Api(
+                status,
+                response.text().await.unwrap_or_default(),
+            ));
+        }
+        Ok(response)
+    }
+
+    async fn execute<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, Error> {
+        Ok(self.execute_raw(request).await?.json().await?)
+    }
+
+    fn request<T: DeserializeOwned + 'static>(
+        &self,
+        method: Method,
+        path: &[&str],
+        body: Option<&Value>,
+    ) -> impl Future<Output = Result<T, Error>> + Send + '_ {
+        let mut request = self.http.request(method, self.url(path));
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        self.execute(request)
+    }
+
+    fn transaction_id(&self) -> String {
+        let n = self.transaction.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{n}", self.transaction_prefix)
+    }
+
+    async fn send_event(&self, room: &str, kind: &str, content: &Value) -> Option<String> {
+        let txn = self.transaction_id();
+        let path = ["_matrix", "client", "v3", "rooms", room, "send", kind, &txn];
+        let response: Value = matrix_request!(
+            self.request(Method::PUT, &path, Some(content)),
+            edbg!(room, content)
+        )
+        .await?;
+        response["event_id"].as_str().map(String::from)
+    }
+
+    async fn redact(&self, room: &str, event: &str) -> bool {
+        let txn = self.transaction_id();
+        let path = [
+            "_matrix", "client", "v3", "rooms", room, "redact", event, &txn,
+        ];
+        let body = json!({});
+        matrix_request!(self.request::<Value>(Method::PUT, &path, Some(&body)))
+            .await
+            .is_some()
+    }
+
+    async fn state(&self, room: &str, kind: &str, key: &str) -> Option<Value> {
+        let path = ["_matrix", "client", "v3", "rooms", room, "state", kind, key];
+        // missing state is an error response, so failures aren't logged here
+        self.request(Method::GET, &path, None).await.ok()
+    }
+
+    pub async fn get_event(&self, room: &str, event: &str) -> Option<Event> {
+        let path = ["_matrix", "client", "v3", "rooms", room, "event", event];
+        matrix_request!(self.request(Method::GET, &path, None)).await
+    }
+
+    pub async fn display_name(&self, room: &str, user: &str) -> String {
+        self.state(room, "m.room.member", user)
+            .await
+            .and_then(|member| member["displayname"].as_str().map(String::from))
+            .unwrap_or_else(|| user.to_string())
+    }
+
+    /// Gets the name of the room, or `None` if the bot isn't in it.
+    pub async fn room_name(&self, room: &str) -> Option<String> {
+        let path = ["_matrix", "client", "v3", "joined_rooms"];
+        let joined: Value = matrix_request!(self.request(Method::GET, &path, None)).await?;
+        if !joined["joined_rooms"]
+            .as_array()
+            .is_some_and(|rooms| rooms.iter().any(|r| r == room))
+        {
+            return None;
+        }
+        let name = self.state(room, "m.room.name", "").await;
+        Some(
+            name.and_then(|name| name["name"].as_str().map(String::from))
+                .unwrap_or_else(|| room.to_string()),
+        )
+    }
+
+    pub async fn member_count(&self, room: &str) -> Option<usize> {
+        let path = ["_matrix", "client", "v3", "rooms", room, "joined_members"];
+        let members: Value = matrix_request!(self.request(Method::GET, &path, None)).await?;
+        members["joined"].as_object().map(|joined| joined.len())
+    }
+
+    /// Whether the user is allowed to change the room's settings.
+    pub async fn can_manage(&self, room: &str, user: &str) -> bool {
+        let Some(levels) = self.state(room, "m.room.power_levels", "").await else {
+            return false;
+        };
+        let level = levels["users"][user]
+            .as_i64()
+            .or_else(|| levels["users_default"].as_i64())
+            .unwrap_or(0);
+        level >= levels["state_default"].as_i64().unwrap_or(50)
+    }
+
+    pub async fn join(&self, room: &str) {
+        let path = ["_matrix", "client", "v3", "join", room];
+        let body = json!({});
+        matrix_request!(self.request::<Value>(Method::POST, &path, Some(&body))).await;
+    }
+
+    pub async fn sync(&self, since: Option<&str>) -> Result<SyncResponse, Error> {
+        let mut request = self
+            .http
+            .get(self.url(&["_matrix", "client", "v3", "sync"]))
+            .query(&[("timeout", "30000")]);
+        if let Some(since) = since {
+            request = request.query(&[("since", since)]);
+        }
+        self.execute(request).await
+    }
+
+    async fn upload(&self, a: &Attachment) -> Option<String> {
+        let bytes = match &a.data {
+            AttachmentData::Bytes(bytes) => bytes.clone(),
+            AttachmentData::Url(url) => {
+                matrix_request!(async {
+                    let response = self.http.get(url.clone()).send().await?;
+                    Ok::<_, Error>(response.error_for_status()?.bytes().await?.to_vec())
+                })
+                .await?
+            }
+        };
+        let url = self.url(&["_matrix", "media", "v3", "upload"]);
+        let response: Value = matrix_request!(self.execute(
+            self.http
+                .post(url.clone())
+                .query(&[("filename", &a.filename)])
+                .header("Content-Type", mime_type(a))
+                .body(bytes.clone())
+        ))
+        .await?;
+        response["content_uri"].as_str().map(String::from)
+    }
+
+    async fn download(&self, mxc: &str) -> Option<Vec<u8>> {
+        let (server, media) = mxc.strip_prefix("mxc://")?.split_once('/')?;
+        let url = self.url(&[
+            "_matrix", "client", "v1", "media", "download", server, media,
+        ]);
+        matrix_request!(async {
+            let response = self.execute_raw(self.http.get(url.clone())).await?;
+            Ok::<_, Error>(response.bytes().await?.to_vec())
+        })
+        .await
+    }
+
+    /// Builds the content of a mirrored message, including its reply if the
+    /// replied message has a counterpart in the room.
+    async fn message_content(&self, bridge: &Bridge, room: &str, msg: &BridgeMessage) -> Value {
+        let author = &msg.author.name;
+        let text = match &msg.poll {
+            Some(poll) => poll_text(poll),
+            None => msg.text.clone(),
+        };
+        let header = match &msg.forwarded_from {
+            Some(ForwardOrigin {
+                name: Some(name), ..
+            }) => format!("{author} (forwarded from {name})"),
+            Some(_) => format!("{author} (forwarded)"),
+            None => author.clone(),
+        };
+        let mut html = format!(
+            "<b>{}</b><br>{}",
+            escape(&header),
+            format::discord_to_matrix_format(&text)
+        );
+        let mut body = format!("{header}\n{text}");
+        let mut content = json!({ "msgtype": "m.text" });
+
+        if let Some(reply) = &msg.reply_to {
+            match bridge
+                .counterpart(&reply.message, &ChatRef::Matrix(room.to_string()))
+                .await
+            {
+                Some(Mirror {
+                    message: MessageRef::Matrix(_, event),
+                    ..
+                }) => {
+                    content["m.relates_to"] = json!({ "m.in_reply_to": { "event_id": event } });
+                }
+                // if we couldn't find the message in the database, copy the message
+                // as a block quote
+                _ => {
+                    html = format!(
+                        "<blockquote><b>{}</b><br>{}</blockquote>{html}",
+                        escape(&reply.author.name),
+                        format::discord_to_matrix_format(&reply.text)
+                    );
+                    body = format!("> {}: {}\n{body}", reply.author.name, reply.text);
+                }
+            }
+        }
+        content["body"] = body.into();
+        content["format"] = "org.matrix.custom.html".into();
+        content["formatted_body"] = html.into();
+        content
+    }
+
+    async fn reply_target(&self, room: &str, content: &Value) -> Option<ReplyTarget> {
+        let event_id = content["m.relates_to"]["m.in_reply_to"]["event_id"].as_str()?;
+        let event = self.get_event(room, event_id).await?;
+        let text = markdown(&event.content);
+        // messages sent by the bot start with a bolded author line
+        let (author, text) = if event.sender == self.user_id {
+            let (first_line, rest) = text.split_once('\n').unwrap_or((&text, ""));
+            let name = first_line
+                .split("**")
+                .nth(1)
+                .unwrap_or("Unknown")
+                .to_string();
+            (Author { name, user: None }, rest.to_string())
+        } else {
+            let author = Author {
+                name: self.display_name(room, &event.sender).await,
+                user: Some(UserRef::Matrix(event.sender.clone())),
+            };
+            (author, text)
+        };
+        Some(ReplyTarget {
+            message: MessageRef::Matrix(room.to_string(), event.event_id),
+            author,
+            text,
+            is_quote: false,
+        })
+    }
+
+    async fn convert(
+        &self,
+        room: &str,
+        event_id: &str,
+        sender: &str,
+        content: &Value,
+        reply_to: Option<ReplyTarget>,
+    ) -> BridgeMessage {
+        let name = self.display_name(room, sender).await;
+        let mut text = String::new();
+        let mut attachments = vec![];
+        match content["msgtype"].as_str() {
+            Some(msgtype @ ("m.image" | "m.video" | "m.audio" | "m.file")) => {
+                let filename = content["filename"]
+                    .as_str()
+                    .or_else(|| content["body"].as_str())
+                    .unwrap_or("file")
+                    .to_string();
+                // the body is a caption when the file name is given separately
+                if content["filename"].is_string() && content["body"] != content["filename"] {
+                    text = markdown(content);
+                }
+                // encrypted media has no plain url and isn't supported
+                let bytes = match content["url"].as_str() {
+                    Some(mxc) => self.download(mxc).await,
+                    None => None,
+                };
+                if let Some(bytes) = bytes {
+                    attachments.push(Attachment {
+                        filename,
+                        kind: match msgtype {
+                            "m.image" => AK::Image,
+                            "m.video" => AK::Video,
+                            "m.audio" => AK::Audio,
+                            _ => AK::Other,
+                        },
+                        data: AttachmentData::Bytes(bytes),
+                        spoiler: false,
+                    });
+                }
+            }
+            Some("m.emote") => text = format!("_{name}_ {}", markdown(content)),
+            _ => text = markdown(content),
+        }
+        BridgeMessage {
+            source: MessageRef::Matrix(room.to_string(), event_id.to_string()),
+            author: Author {
+                name,
+                user: Some(UserRef::Matrix(sender.to_string())),
+            },
+            text,
+            attachments,
+            reply_to,
+            forwarded_from: None,
+            poll: None,
+            topic: None,
+        }
+    }
+
+    pub async fn bridge_message(&self, room: &str, event: &Event) -> BridgeMessage {
+        let reply_to = self.reply_target(room, &event.content).await;
+        self.convert(
+            room,
+            &event.event_id,
+            &event.sender,
+            &event.content,
+            reply_to,
+        )
+        .await
+    }
+
+    pub async fn bridge_edit(&self, room: &str, event: &Event) -> Option<BridgeMessage> {
+        let original = event.replaced_event()?;
+        // the edit itself doesn't say what the message replies to
+        let reply_to = match self.get_event(room, original).await {
+            Some(original) => self.reply_target(room, &original.content).await,
+            None => None,
+        };
+        let content = &event.content["m.new_content"];
+        Some(
+            self.convert(room, original, &event.sender, content, reply_to)
+                .await,
+        )
+    }
+
+    pub async fn reaction_update(&self, room: &str, event: &Event) -> Option<ReactionUpdate> {
+        let relation = &event.content["m.relates_to"];
+        if relation["rel_type"] != "m.annotation" {
+            return None;
+        }
+        let update = ReactionUpdate {
+            message: MessageRef::Matrix(room.to_string(), relation["event_id"].as_str()?.into()),
+            reactor: self.display_name(room, &event.sender).await,
+            change: ReactionChange::Add(relation["key"].as_str()?.into()),
+        };
+        self.reactions
+            .insert(event.event_id.clone(), update.clone());
+        Some(update)
+    }
+
+    /// Undoes a reaction if the redacted event was one.
+    pub fn redacted_reaction(&self, event_id: &str) -> Option<ReactionUpdate> {
+        let (_, mut update) = self.reactions.remove(event_id)?;
+        if let ReactionChange::Add(key) = update.change {
+            update.change = ReactionChange::Remove(key);
+        }
+        Some(update)
+    }
+}
+
+#[d::async_trait]
+impl Platform for MatrixPlatform {
+    async fn send(&self, bridge: &Bridge, chat: &ChatRef, msg: &BridgeMessage) -> Vec<Mirror> {
+        let ChatRef::Matrix(room) = chat else {
+            log::error!("Matrix platform asked to send to {chat:?}");
+            return vec![];
+        };
+        let mut mirrors = vec![];
+        if !msg.text.is_empty() || msg.poll.is_some() || msg.attachments.is_empty() {
+            let content = self.message_content(bridge, room, msg).await;
+            if let Some(event) = self.send_event(room, "m.room.message", &content).await {
+                mirrors.push(Mirror {
+                    message: MessageRef::Matrix(room.clone(), event),
+                    has_caption: false,
+                });
+            }
+        }
+        for a in &msg.attachments {
+            let Some(uri) = self.upload(a).await else {
+                continue;
+            };
+            let msgtype = match a.kind {
+                AK::Image => "m.image",
+                AK::Video => "m.video",
+                AK::Audio => "m.audio",
+                AK::Other => "m.file",
+            };
+            let mut content = json!({
+                "msgtype": msgtype,
+                "body": a.filename,
+                "filename": a.filename,
+                "url": uri,
+                "info": { "mimetype": mime_type(a) },
+            });
+            // the author header goes on the file itself if there was no text to carry it
+            if mirrors.is_empty() {
+                content["body"] = format!("{}: {}", msg.author.name, a.filename).into();
+            }
+            if let Some(event) = self.send_event(room, "m.room.message", &content).await {
+                mirrors.push(Mirror {
+                    message: MessageRef::Matrix(room.clone(), event),
+                    has_caption: true,
+                });
+            }
+        }
+        mirrors
+    }
+
+    async fn edit(&self, bridge: &Bridge, mirror: &Mirror, msg: &BridgeMessage) {
+        let MessageRef::Matrix(room, event) = &mirror.message else {
+            return;
+        };
+        // files can't be edited into text
+        if mirror.has_caption {
+            return;
+        }
+        let mut new_content = self.message_content(bridge, room, msg).await;
+        if let Some(content) = new_content.as_object_mut() {
+            content.remove("m.relates_to");
+        }
+        let content = json!({
+            "msgtype": "m.text",
+            "body": format!("* {}", new_content["body"].as_str().unwrap_or_default()),
+            "m.new_content": new_content,
+            "m.relates_to": { "rel_type": "m.replace", "event_id": event },
+        });
+        self.send_event(room, "m.room.message", &content).await;
+    }
+
+    async fn delete(&self, message: &MessageRef) -> bool {
+        let MessageRef::Matrix(room, event) = message else {
+            return false;
+        };
+        self.redact(room, event).await
+    }
+
+    async fn pin(&self, message: &MessageRef) {
+        let MessageRef::Matrix(room, event) = message else {
+            return;
+        };
+        let mut pinned: Vec<String> = self
+            .state(room, "m.room.pinned_events", "")
+            .await
+            .and_then(|state| serde_json::from_value(state["pinned"].clone()).ok())
+            .unwrap_or_default();
+        if pinned.contains(event) {
+            return;
+        }
+        pinned.push(event.clone());
+        let path = [
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            room,
+            "state",
+            "m.room.pinned_events",
+            "",
+        ];
+        let body = json!({ "pinned": pinned });
+        matrix_request!(self.request::<Value>(Method::PUT, &path, Some(&body))).await;
+    }
+
+    async fn avatar(&self, user: &UserRef) -> Option<Avatar> {
+        let UserRef::Matrix(user) = user else {
+            return None;
+        };
+        let path = ["_matrix", "client", "v3", "profile", user, "avatar_url"];
+        // users without an avatar get an error response
+        let profile: Value = self.request(Method::GET, &path, None).await.ok()?;
+        let mxc = profile["avatar_url"].as_str()?;
+        self.download(mxc).await.map(Avatar::Bytes)
+    }
+
+    async fn notify(&self, chat: &ChatRef, text: &str) {
+        let ChatRef::Matrix(room) = chat else {
+            return;
+        };
+        let content = json!({ "msgtype": "m.notice", "body": text });
+        self.send_event(room, "m.room.message", &content).await;
+    }
+
+    fn format_reactions(&self, reactions: &Reactions) -> String {
+        format::format_telegram_reaction_message(reactions)
+    }
+
+    fn parse_reactions(&self, text: &str) -> Reactions {
+        format::parse_telegram_reaction_message(text)
+    }
+
+    async fn send_reactions(&self, reacted: &MessageRef, text: &str) -> Option<MessageRef> {
+        let MessageRef::Matrix(room, event) = reacted else {
+            return None;
+        };
+        let content = json!({
+            "msgtype": "m.notice",
+            "body": text.replace("<b>", "").replace("</b>", ""),
+            "format": "org.matrix.custom.html",
+            "formatted_body": text.replace('\n', "<br>"),
+            "m.relates_to": { "m.in_reply_to": { "event_id": event } },
+        });
+        self.send_event(room, "m.room.message", &content)
+            .await
+            .map(|summary| MessageRef::Matrix(room.clone(), summary))
+    }
+
+    async fn edit_reactions(&self, summary: &MessageRef, text: &str) -> bool {
+        let MessageRef::Matrix(room, event) = summary else {
+            return false;
+        };
+        let body = text.replace("<b>", "").replace("</b>", "");
+        let content = json!({
+            "msgtype": "m.notice",
+            "body": format!("* {body}"),
+            "m.new_content": {
+                "msgtype": "m.notice",
+                "body": body,
+                "format": "org.matrix.custom.html",
+                "formatted_body": text.replace('\n', "<br>"),
+            },
+            "m.relates_to": { "rel_type": "m.replace", "event_id": event },
+        });
+        self.send_event(room, "m.room.message", &content)
+            .await
+            .is_some()
+    }
+
+    async fn delete_reactions(&self, summary: &MessageRef) -> bool {
+        self.delete(summary).await
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use crate::bridge::Bridge;
+use crate::discord as d;
+use crate::telegram as t;
+
+pub mod discord;
+pub mod irc;
+pub mod matrix;
+pub mod telegram;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChatRef {
+    Discord(d::ChannelId),
+    Telegram(t::ChatId),
+    Matrix(String),
+    Irc(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MessageRef {
+    Discord(d::ChannelId, d::MessageId),
+    Telegram(t::ChatId, t::MessageId),
+    Matrix(String, String),
+    /// IRC has no message ids, so the bridge makes up its own.
+    Irc(String, String),
+}
+
+// chats are stored in the database and config as "<platform>:<id>"
+impl std::fmt::Display for ChatRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Discord(c) => write!(f, "discord:{c}"),
+            Self::Telegram(c) => write!(f, "telegram:{c}"),
+            Self::Matrix(room) => write!(f, "matrix:{room}"),
+            Self::Irc(channel) => write!(f, "irc:{channel}"),
+        }
+    }
+}
+
+impl std::str::FromStr for ChatRef {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let Some((platform, id)) = s.split_once(':') else {
+            eyre::bail!("Invalid chat \"{s}\"");
+        };
+        Ok(match platform {
+            "discord" => Self::Discord(d::ChannelId::from(id.parse::<u64>()?)),
+            "telegram" => Self::Telegram(t::ChatId(id.parse()?)),
+            "matrix" => Self::Matrix(id.to_string()),
+            "irc" => Self::Irc(id.to_string()),
+            _ => eyre::bail!("Unknown platform in chat \"{s}\""),
+        })
+    }
+}
+
+impl ChatRef {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Discord(_) => "Discord channel",
+            Self::Telegram(_) => "Telegram chat",
+            Self::Matrix(_) => "Matrix room",
+            Self::Irc(_) => "IRC channel",
+        }
+    }
+
+    pub fn platform_name(&self) -> &'static str {
+        match self {
+            Self::Discord(_) => "Discord",
+            Self::Telegram(_) => "Telegram",
+            Self::Matrix(_) => "Matrix",
+            Self::Irc(_) => "IRC",
+        }
+    }
+}
+
+impl MessageRef {
+    pub fn chat(&self) -> ChatRef {
+        match self {
+            &Self::Discord(c, _) => ChatRef::Discord(c),
+            &Self::Telegram(c, _) => ChatRef::Telegram(c),
+            Self::Matrix(room, _) => ChatRef::Matrix(room.clone()),
+            Self::Irc(channel, _) => ChatRef::Irc(channel.clone()),
+        }
+    }
+
+    pub fn id(&self) -> String {
+        match self {
+            Self::Discord(_, m) => m.to_string(),
+            Self::Telegram(_, m) => m.0.to_string(),
+            Self::Matrix(_, event) => event.clone(),
+            Self::Irc(_, id) => id.clone(),
+        }
+    }
+
+    pub fn from_parts(chat: ChatRef, id: &str) -> eyre::Result<Self> {
+        Ok(match chat {
+            ChatRef::Discord(c) => Self::Discord(c, d::MessageId::from(id.parse::<u64>()?)),
+            ChatRef::Telegram(c) => Self::Telegram(c, t::MessageId(id.parse()?)),
+            ChatRef::Matrix(room) => Self::Matrix(room, id.to_string()),
+            ChatRef::Irc(channel) => Self::Irc(channel, id.to_string()),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum UserRef {
+    Discord(d::UserId),
+    Telegram(t::UserId),
+    /// A Telegram channel, or an anonymous group admin, posting as the chat.
+    TelegramChat(t::ChatId),
+    Matrix(String),
+    Irc(String),
+}
+
+// users are stored in the database the same way as chats
+impl std::fmt::Display for UserRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Discord(u) => write!(f, "discord:{u}"),
+            Self::Telegram(u) => write!(f, "telegram:{u}"),
+            Self::TelegramChat(c) => write!(f, "telegram-chat:{c}"),
+            Self::Matrix(user) => write!(f, "matrix:{user}"),
+            Self::Irc(nick) => write!(f, "irc:{nick}"),
+        }
+    }
+}
+
+impl std::str::FromStr for UserRef {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let Some((platform, id)) = s.split_once(':') else {
+            eyre::bail!("Invalid user \"{s}\"");
+        };
+        Ok(match platform {
+            "discord" => Self::Discord(d::UserId::from(id.parse::<u64>()?)),
+            "telegram" => Self::Telegram(t::UserId(id.parse()?)),
+            "telegram-chat" => Self::TelegramChat(t::ChatId(id.parse()?)),
+            "matrix" => Self::Matrix(id.to_string()),
+            "irc" => Self::Irc(id.to_string()),
+            _ => eyre::bail!("Unknown platform in user \"{s}\""),
+        })
+    }
+}
+
+/// A message sent by the bridge on behalf of a message from another chat.
+#[derive(Clone, Debug)]
+pub struct Mirror {
+    pub message: MessageRef,
+    pub has_caption: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Author {
+    pub name: String,
+    pub user: Option<UserRef>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AttachmentKind {
+    Image,
+    Video,
+    Audio,
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub enum AttachmentData {
+    Url(url::Url),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub filename: String,
+    pub kind: AttachmentKind,
+    pub data: AttachmentData,
+    pub spoiler: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplyTarget {
+    pub message: MessageRef,
+    pub author: Author,
+    pub text: String,
+    pub is_quote: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ForwardOrigin {
+    pub name: Option<String>,
+    pub user: Option<UserRef>,
+    /// The name was recovered from the header of a message the bridge itself sent.
+    pub mirrored: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PollKind {
+    Single,
+    Multiple,
+    Quiz,
+}
+
+#[derive(Clone, Debug)]
+pub struct Poll {
+    pub kind: PollKind,
+    pub question: String,
+    pub options: Vec<String>,
+}
+
+/// A message in a platform independent form. `text` is always Discord-flavoured
+/// markdown; platforms convert it to and from their own formatting.
+#[derive(Clone, Debug)]
+pub struct BridgeMessage {
+    pub source: MessageRef,
+    pub author: Author,
+    pub text: String,
+    pub attachments: Vec<Attachment>,
+    pub reply_to: Option<ReplyTarget>,
+    pub forwarded_from: Option<ForwardOrigin>,
+    pub poll: Option<Poll>,
+    /// The forum topic, or comments of a channel post, a Telegram message was
+    /// sent in.
+    pub topic: Option<t::ThreadId>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ReactionChange {
+    Add(String),
+    Remove(String),
+    Set(Vec<String>),
+}
+
+#[derive(Clone, Debug)]
+pub struct ReactionUpdate {
+    pub message: MessageRef,
+    pub reactor: String,
+    pub change: ReactionChange,
+}
+
+pub type Reactions = HashMap<String, Vec<String>>;
+
+impl ReactionChange {
+    pub fn apply(&self, reactions: &mut Reactions, reactor: &str) {
+        let entry = reactions.entry(reactor.to_string()).or_default();
+        match self {
+            Self::Add(emoji) => entry.push(emoji.clone()),
+            Self::Remove(emoji) => entry.retain(|e| e != emoji),
+            Self::Set(emojis) => *entry = emojis.clone(),
+        }
+    }
+}
+
+pub enum Avatar {
+    Url(String),
+    Bytes(Vec<u8>),
+}
+
+#[d::async_trait]
+pub trait Platform: Send + Sync {
+    async fn send(&self, bridge: &Bridge, chat: &ChatRef, msg: &BridgeMessage) -> Vec<Mirror>;
+    async fn edit(&self, bridge: &Bridge, mirror: &Mirror, msg: &BridgeMessage);
+    async fn delete(&self, message: &MessageRef) -> bool;
+    async fn pin(&self, message: &MessageRef);
+    async fn avatar(&self, user: &UserRef) -> Option<Avatar>;
+    /// Sends a plain message from the bot itself, e.g. about bridge changes.
+    async fn notify(&self, chat: &ChatRef, text: &str);
+
+    fn format_reactions(&self, reactions: &Reactions) -> String;
+    fn parse_reactions(&self, text: &str) -> Reactions;
+    async fn send_reactions(&self, reacted: &MessageRef, text: &str) -> Option<MessageRef>;
+    async fn edit_reactions(&self, summary: &MessageRef, text: &str) -> bool;
+    async fn delete_reactions(&self, summary: &MessageRef) -> bool;
+}
@@ -0,0 +1,705 @@
+use teloxide::{net::Download as _, prelude::*};
+
+use super::{
+    Attachment, AttachmentData, AttachmentKind as AK, Author, Avatar, BridgeMessage, ChatRef,
+    ForwardOrigin, MessageRef, Mirror, Platform, Poll, PollKind, ReactionChange, ReactionUpdate,
+    Reactions, ReplyTarget, UserRef,
+};
+use crate::bridge::Bridge;
+use crate::db;
+use crate::discord as d;
+use crate::format;
+use crate::telegram as t;
+use crate::{INITIAL_BACKOFF, RETRIES};
+
+pub struct TelegramPlatform {
+    pub bot: t::Bot,
+}
+
+/// Messages outside of any topic in a forum are in the "General" topic, which
+/// can't be passed as a thread when sending.
+pub const GENERAL_TOPIC: t::ThreadId = t::ThreadId(t::MessageId(1));
+
+pub fn is_forum(chat: &t::Chat) -> bool {
+    matches!(
+        &chat.kind,
+        t::ChatKind::Public(t::ChatPublic {
+            kind: t::PublicChatKind::Supergroup(t::PublicChatSupergroup { is_forum: true, .. }),
+            ..
+        })
+    )
+}
+
+pub fn topic(msg: &t::Message) -> Option<t::ThreadId> {
+    if msg.is_topic_message {
+        msg.thread_id
+    } else if is_forum(&msg.chat) {
+        Some(GENERAL_TOPIC)
+    } else {
+        // comments on a channel post, in its discussion group
+        msg.thread_id
+    }
+}
+
+/// Gets the name of the message's topic, which is only known from the message
+/// that created the topic.
+pub fn topic_name(msg: &t::Message) -> Option<String> {
+    if topic(msg) == Some(GENERAL_TOPIC) {
+        return Some("General".to_string());
+    }
+    msg.forum_topic_created()
+        .or_else(|| msg.reply_to_message()?.forum_topic_created())
+        .map(|created| created.name.clone())
+}
+
+fn markdown(msg: &t::Message) -> Option<String> {
+    msg.text()
+        .zip(msg.parse_entities())
+        .or_else(|| msg.caption().zip(msg.parse_caption_entities()))
+        .map(|(t, e)| format::telegram_to_discord_format(t, e))
+}
+
+fn reply_target(me: &t::Me, msg: &t::Message) -> Option<ReplyTarget> {
+    let ref_msg = msg.reply_to_message()?;
+    // messages in a topic that aren't replies "reply" to the start of the topic
+    if ref_msg.forum_topic_created().is_some() {
+        return None;
+    }
+    let quote = msg.quote().map(|q| {
+        format::telegram_to_discord_format(
+            &q.text,
+            t::MessageEntityRef::parse(&q.text, &q.entities),
+        )
+    });
+    let ref_text = markdown(ref_msg).unwrap_or_default();
+    // messages sent by the bot start with a bolded author line
+    let (author, text) = if ref_msg.from.as_ref().map(|f| f.id) == Some(me.id) {
+        let (first_line, rest) = ref_text.split_once('\n').unwrap_or((&ref_text, ""));
+        let name = first_line
+            .split("**")
+            .nth(1)
+            .unwrap_or("Unknown")
+            .to_string();
+        let text = quote.clone().unwrap_or_else(|| rest.to_string());
+        (Author { name, user: None }, text)
+    } else {
+        let author = Author {
+            name: format::telegram_author_name(ref_msg),
+            user: ref_msg.from.as_ref().map(|u| UserRef::Telegram(u.id)),
+        };
+        (author, quote.clone().unwrap_or(ref_text))
+    };
+    Some(ReplyTarget {
+        message: MessageRef::Telegram(ref_msg.chat.id, ref_msg.id),
+        author,
+        text,
+        is_quote: quote.is_some(),
+    })
+}
+
+pub fn bridge_message(me: &t::Me, msg: &t::Message) -> BridgeMessage {
+    let mut text = markdown(msg).unwrap_or_default();
+    let forwarded_from = msg.forward_origin().map(|origin| {
+        let mut forwarded = ForwardOrigin {
+            name: Some(match origin {
+                t::MessageOrigin::User { sender_user, .. } => sender_user.full_name(),
+                t::MessageOrigin::Chat {
+                    sender_chat: chat, ..
+                }
+                | t::MessageOrigin::Channel { chat, .. } => chat
+                    .title()
+                    .or_else(|| chat.username())
+                    .unwrap_or("Unknown")
+                    .to_string(),
+                t::MessageOrigin::HiddenUser {
+                    sender_user_name, ..
+                } => sender_user_name.clone(),
+            }),
+            user: None,
+            mirrored: false,
+        };
+        if let t::MessageOrigin::User { sender_user, .. } = origin {
+            forwarded.user = Some(UserRef::Telegram(sender_user.id));
+            if sender_user.id == me.id && !text.starts_with("**Reactions**\n") {
+                let name = text
+                    .lines()
+                    .next()
+                    .and_then(|s| s.strip_prefix("**"))
+                    .and_then(|s| s.strip_suffix("**"))
+                    .unwrap_or("Unknown [this shouldn't be possible]")
+                    .to_string();
+                text = text
+                    .split_once('\n')
+                    .map_or(text.as_str(), |(_, rest)| rest)
+                    .to_string();
+                forwarded.name = Some(name);
+                forwarded.mirrored = true;
+            }
+        }
+        forwarded
+    });
+    let poll = msg.poll().map(|poll| Poll {
+        kind: match (&poll.poll_type, poll.allows_multiple_answers) {
+            (t::PollType::Quiz, _) => PollKind::Quiz,
+            (_, false) => PollKind::Single,
+            (_, true) => PollKind::Multiple,
+        },
+        question: poll.question.clone(),
+        options: poll.options.iter().map(|o| o.text.clone()).collect(),
+    });
+    BridgeMessage {
+        source: MessageRef::Telegram(msg.chat.id, msg.id),
+        author: Author {
+            name: format::telegram_author_name(msg),
+            user: msg
+                .from
+                .as_ref()
+                .map(|u| UserRef::Telegram(u.id))
+                .or_else(|| {
+                    msg.sender_chat
+                        .as_ref()
+                        .map(|c| UserRef::TelegramChat(c.id))
+                }),
+        },
+        text,
+        attachments: vec![],
+        reply_to: reply_target(me, msg),
+        forwarded_from,
+        poll,
+        topic: topic(msg),
+    }
+}
+
+pub fn reaction_update(reaction: &t::MessageReactionUpdated) -> ReactionUpdate {
+    ReactionUpdate {
+        message: MessageRef::Telegram(reaction.chat.id, reaction.message_id),
+        reactor: format::telegram_reactor_name(reaction),
+        change: ReactionChange::Set(format::filter_telegram_reactions(&reaction.new_reaction)),
+    }
+}
+
+/// Reactions in channels are anonymous, so only their counts are known.
+pub fn reaction_count_update(reaction: &t::MessageReactionCountUpdated) -> ReactionUpdate {
+    ReactionUpdate {
+        message: MessageRef::Telegram(reaction.chat.id, reaction.message_id),
+        reactor: reaction.chat.title().unwrap_or("Telegram").to_string(),
+        change: ReactionChange::Set(format::telegram_reaction_counts(&reaction.reactions)),
+    }
+}
+
+pub async fn download_attachment(bot: &t::Bot, msg: &t::Message) -> Option<Attachment> {
+    let common = match &msg.kind {
+        t::MessageKind::Common(common) => common,
+        _ => return None,
+    };
+    let (file, file_name, kind) = match common.media_kind.clone() {
+        t::MediaKind::Document(t::MediaDocument { document, .. }) => {
+            (document.file, document.file_name, AK::Other)
+        }
+        t::MediaKind::Photo(t::MediaPhoto { mut photo, .. }) => {
+            (photo.pop()?.file, None, AK::Image)
+        }
+        t::MediaKind::Video(t::MediaVideo { video, .. }) => {
+            (video.file, video.file_name, AK::Video)
+        }
+        t::MediaKind::Audio(t::MediaAudio { audio, .. }) => {
+            (audio.file, audio.file_name, AK::Audio)
+        }
+        t::MediaKind::Animation(t::MediaAnimation { animation, .. }) => {
+            (animation.file, animation.file_name, AK::Video)
+        }
+        t::MediaKind::Sticker(t::MediaSticker { sticker, .. }) => {
+            let kind = if sticker.is_video() {
+                AK::Video
+            } else if sticker.is_static() {
+                AK::Image
+            } else {
+                AK::Other
+            };
+            (sticker.file, None, kind)
+        }
+        _ => return None,
+    };
+
+    if file.size > 50 * 1024 * 1024 {
+        telegram_request!(bot
+            .send_message(
+                msg.chat.id,
+                "File too large. Files over 50 MB won't be forwarded"
+            )
+            .reply_parameters(t::ReplyParameters::new(msg.id))
+            .clone())
+        .await;
+        return None;
+    }
+    let file = match bot.get_file(file.id).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Failed to get file: {e:?}");
+            return None;
+        }
+    };
+    let path = file.path;
+    let Some(name) = file_name
+        .clone()
+        .or_else(|| path.split('/').last().map(String::from))
+    else {
+        log::error!("Failed to get file name");
+        return None;
+    };
+    let mut bytes = Vec::new();
+    let mut retries = RETRIES;
+    let mut backoff = INITIAL_BACKOFF;
+    while let Err(e) = bot.download_file(&path, &mut bytes).await {
+        match e {
+            t::DownloadError::Network(_) if retries > 0 => {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries -= 1;
+            }
+            _ => {
+                log::error!("Failed to download file: {e:?}");
+                break;
+            }
+        }
+    }
+    Some(Attachment {
+        filename: name,
+        kind,
+        data: AttachmentData::Bytes(bytes),
+        spoiler: msg.has_media_spoiler(),
+    })
+}
+
+fn input_file(a: &Attachment) -> t::InputFile {
+    match &a.data {
+        AttachmentData::Url(url) => t::InputFile::url(url.clone()),
+        AttachmentData::Bytes(bytes) => t::InputFile::memory(bytes.clone()),
+    }
+    .file_name(a.filename.clone())
+}
+
+impl TelegramPlatform {
+    async fn reply_parameters(
+        &self,
+        bridge: &Bridge,
+        chat: &ChatRef,
+        reply: Option<&ReplyTarget>,
+        text: &mut String,
+    ) -> Option<t::MessageId> {
+        let reply = reply?;
+        match bridge.counterpart(&reply.message, chat).await {
+            Some(Mirror {
+                message: MessageRef::Telegram(_, id),
+                ..
+            }) => Some(id),
+            // if we couldn't find the message in the database, copy the message
+            // as a block quote
+            _ => {
+                let ref_content = format::discord_to_telegram_format(&reply.text);
+                *text = format!(
+                    "<blockquote expandable><b>{}</b>\n{ref_content}</blockquote>\n{text}",
+                    reply.author.name
+                );
+                None
+            }
+        }
+    }
+
+    async fn send_poll(
+        &self,
+        telegram_chat: t::ChatId,
+        thread: Option<t::ThreadId>,
+        author: &str,
+        poll: &Poll,
+        reply_to_message_id: Option<t::MessageId>,
+    ) -> Option<t::Message> {
+        let mut builder = self
+            .bot
+            .send_poll(
+                telegram_chat,
+                format!("{author}: {}", poll.question),
+                poll.options.iter().map(t::InputPollOption::new),
+            )
+            .allows_multiple_answers(poll.kind == PollKind::Multiple);
+        if let Some(id) = reply_to_message_id {
+            builder = builder.reply_parameters(t::ReplyParameters::new(id));
+        }
+        if let Some(thread) = thread {
+            builder = builder.message_thread_id(thread);
+        }
+        telegram_request!(builder.send_ref()).await
+    }
+
+    async fn send_message(
+        &self,
+        telegram_chat: t::ChatId,
+        thread: Option<t::ThreadId>,
+        text: &str,
+        attachments: &[Attachment],
+        reply_to_message_id: Option<t::MessageId>,
+    ) -> Vec<Mirror> {
+        macro_rules! _if_method_may_spoiler {
+            (send_photo, $code:expr) => {
+                $code
+            };
+            (send_video, $code:expr) => {
+                $code
+            };
+            ($method:ident, $code:expr) => {};
+        }
+
+        macro_rules! _send_with_method {
+            ($method:ident, $a:expr, $caption:expr, $replyto:expr $(,)?) => {
+                async {
+                    let mut s = self.bot.$method(telegram_chat, input_file($a));
+                    _if_method_may_spoiler!($method, {
+                        s = s.has_spoiler($a.spoiler);
+                    });
+                    if let Some(caption) = $caption {
+                        s = s.caption(caption).parse_mode(t::ParseMode::Html);
+                    }
+                    if let Some(id) = $replyto {
+                        s = s.reply_parameters(t::ReplyParameters::new(id));
+                    }
+                    if let Some(thread) = thread {
+                        s = s.message_thread_id(thread);
+                    }
+                    telegram_request!(s.send_ref()).await
+                }
+            };
+        }
+
+        macro_rules! send_with_attachment {
+            ($a:expr, $caption:expr, $replyto:expr $(,)?) => {
+                async {
+                    let a: &Attachment = $a;
+                    let caption: Option<&str> = $caption;
+                    let replyto: Option<t::MessageId> = $replyto;
+                    match a.kind {
+                        AK::Image => _send_with_method!(send_photo, a, caption, replyto).await,
+                        AK::Video => _send_with_method!(send_video, a, caption, replyto).await,
+                        AK::Audio => _send_with_method!(send_audio, a, caption, replyto).await,
+                        AK::Other => _send_with_method!(send_document, a, caption, replyto).await,
+                    }
+                }
+            };
+        }
+
+        let mut attachments_processed = false;
+        let media_count = attachments
+            .iter()
+            .filter(|a| matches!(a.kind, AK::Image | AK::Video))
+            .count();
+
+        let telegram_result: Vec<_> = if attachments.len() == 1 {
+            attachments_processed = true;
+            send_with_attachment!(&attachments[0], Some(text), reply_to_message_id)
+                .await
+                .into_iter()
+                .collect()
+        } else if media_count >= 1 {
+            attachments_processed = attachments.len() == media_count;
+            let mut builder = self.bot.send_media_group(
+                telegram_chat,
+                attachments
+                    .iter()
+                    .filter(|a| matches!(a.kind, AK::Image | AK::Video))
+                    .enumerate()
+                    .map(|(i, a)| match a.kind {
+                        AK::Image => t::InputMedia::Photo({
+                            let mut m = t::InputMediaPhoto::new(input_file(a));
+                            if i == 0 {
+                                m = m.caption(text).parse_mode(t::ParseMode::Html);
+                            }
+                            if a.spoiler {
+                                m = m.spoiler();
+                            }
+                            m
+                        }),
+                        AK::Video => t::InputMedia::Video({
+                            let mut m = t::InputMediaVideo::new(input_file(a));
+                            if i == 0 {
+                                m = m.caption(text).parse_mode(t::ParseMode::Html);
+                            }
+                            if a.spoiler {
+                                m = m.spoiler();
+                            }
+                            m
+                        }),
+                        _ => unreachable!(),
+                    })
+                    .collect::<Vec<_>>(),
+            );
+            if let Some(id) = reply_to_message_id {
+                builder = builder.reply_parameters(t::ReplyParameters::new(id));
+            }
+            if let Some(thread) = thread {
+                builder = builder.message_thread_id(thread);
+            }
+            telegram_request!(builder.send_ref())
+                .await
+                .into_iter()
+                .flatten()
+                .collect()
+        } else {
+            vec![]
+        };
+        let telegram_result = if telegram_result.is_empty() {
+            let mut s = self
+                .bot
+                .send_message(telegram_chat, text)
+                .parse_mode(t::ParseMode::Html);
+            if let Some(id) = reply_to_message_id {
+                s = s.reply_parameters(t::ReplyParameters::new(id));
+            }
+            if let Some(thread) = thread {
+                s = s.message_thread_id(thread);
+            }
+
+            telegram_request!(s.send_ref(), edbg!(text),)
+                .await
+                .into_iter()
+                .collect()
+        } else {
+            telegram_result
+        };
+
+        let mut mirrors = telegram_result
+            .into_iter()
+            .map(|telegram_msg| Mirror {
+                message: MessageRef::Telegram(telegram_chat, telegram_msg.id),
+                has_caption: telegram_msg.caption().is_some(),
+            })
+            .collect::<Vec<_>>();
+
+        if attachments_processed {
+            return mirrors;
+        }
+
+        let att_futs = attachments
+            .iter()
+            .filter(|a| media_count == 0 || !matches!(a.kind, AK::Image | AK::Video))
+            .map(|a| async move { send_with_attachment!(a, None, None).await })
+            .collect::<Vec<_>>();
+        mirrors.extend(
+            futures::future::join_all(att_futs)
+                .await
+                .into_iter()
+                .flatten()
+                .map(|telegram_msg| Mirror {
+                    message: MessageRef::Telegram(telegram_chat, telegram_msg.id),
+                    has_caption: true,
+                }),
+        );
+        mirrors
+    }
+
+    pub async fn is_forum(&self, telegram_chat: t::ChatId) -> bool {
+        let Some(chat) = telegram_request!(self.bot.get_chat(telegram_chat)).await else {
+            return false;
+        };
+        matches!(
+            chat.kind,
+            t::ChatFullInfoKind::Public(public)
+                if matches!(&public.kind, t::ChatFullInfoPublicKind::Supergroup(s) if s.is_forum)
+        )
+    }
+
+    pub async fn create_topic(&self, telegram_chat: t::ChatId, name: &str) -> Option<t::ThreadId> {
+        telegram_request!(self.bot.create_forum_topic(telegram_chat, name))
+            .await
+            .map(|topic| topic.thread_id)
+    }
+
+    pub async fn rename_topic(&self, telegram_chat: t::ChatId, topic: t::ThreadId, name: &str) {
+        telegram_request!(self.bot.edit_forum_topic(telegram_chat, topic).name(name)).await;
+    }
+
+    pub async fn close_topic(&self, telegram_chat: t::ChatId, topic: t::ThreadId, closed: bool) {
+        if closed {
+            telegram_request!(self.bot.close_forum_topic(telegram_chat, topic)).await;
+        } else {
+            telegram_request!(self.bot.reopen_forum_topic(telegram_chat, topic)).await;
+        }
+    }
+}
+
+#[d::async_trait]
+impl Platform for TelegramPlatform {
+    async fn send(&self, bridge: &Bridge, chat: &ChatRef, msg: &BridgeMessage) -> Vec<Mirror> {
+        let &ChatRef::Telegram(telegram_chat) = chat else {
+            log::error!("Telegram platform asked to send to {chat:?}");
+            return vec![];
+        };
+        let content = format::discord_to_telegram_format(&msg.text);
+        let author = &msg.author.name;
+        let mut text = match &msg.forwarded_from {
+            Some(ForwardOrigin {
+                name: Some(name), ..
+            }) => format!("<b>{author}</b> (forwarded from {name})\n{content}"),
+            Some(_) => format!("<b>{author}</b> (forwarded)\n{content}"),
+            None => format!("<b>{author}</b>\n{content}"),
+        };
+        let reply_to_message_id = self
+            .reply_parameters(bridge, chat, msg.reply_to.as_ref(), &mut text)
+            .await;
+        let thread = match msg.source {
+            MessageRef::Discord(channel, _) => db::thread_topic(channel, telegram_chat)
+                .map(|topic| topic.telegram_topic)
+                .filter(|&topic| topic != GENERAL_TOPIC),
+            _ => None,
+        };
+
+        if let Some(poll) = &msg.poll {
+            return self
+                .send_poll(telegram_chat, thread, author, poll, reply_to_message_id)
+                .await
+                .map(|telegram_msg| Mirror {
+                    message: MessageRef::Telegram(telegram_chat, telegram_msg.id),
+                    has_caption: false,
+                })
+                .into_iter()
+                .collect();
+        }
+
+        self.send_message(
+            telegram_chat,
+            thread,
+            &text,
+            &msg.attachments,
+            reply_to_message_id,
+        )
+        .await
+    }
+
+    async fn edit(&self, bridge: &Bridge, mirror: &Mirror, msg: &BridgeMessage) {
+        let MessageRef::Telegram(telegram_chat, mirror_id) = mirror.message else {
+            return;
+        };
+        let content = format::discord_to_telegram_format(&msg.text);
+        let author = &msg.author.name;
+        let mut message_text = format!("<b>{author}</b>\n{content}");
+        if let Some(reply) = &msg.reply_to {
+            if bridge
+                .counterpart(&reply.message, &mirror.message.chat())
+                .await
+                .is_none()
+            {
+                let ref_content = format::discord_to_telegram_format(&reply.text);
+                message_text = format!(
+                    "<blockquote expandable><b>{}</b>\n{ref_content}</blockquote>\n{message_text}",
+                    reply.author.name
+                );
+            }
+        }
+        if !mirror.has_caption {
+            let builder = self
+                .bot
+                .edit_message_text(telegram_chat, mirror_id, message_text)
+                .parse_mode(t::ParseMode::Html);
+
+            telegram_request!(builder.send_ref(), edbg!(author, content)).await;
+        } else {
+            let builder = self
+                .bot
+                .edit_message_caption(telegram_chat, mirror_id)
+                .caption(message_text)
+                .parse_mode(t::ParseMode::Html);
+            telegram_request!(builder.send_ref(), edbg!(author, content)).await;
+        }
+    }
+
+    async fn delete(&self, message: &MessageRef) -> bool {
+        let &MessageRef::Telegram(telegram_chat, telegram_id) = message else {
+            return false;
+        };
+        telegram_request!(self.bot.delete_message(telegram_chat, telegram_id))
+            .await
+            .is_some()
+    }
+
+    async fn pin(&self, message: &MessageRef) {
+        let &MessageRef::Telegram(telegram_chat, telegram_id) = message else {
+            return;
+        };
+        telegram_request!(self.bot.pin_chat_message(telegram_chat, telegram_id)).await;
+    }
+
+    async fn avatar(&self, user: &UserRef) -> Option<Avatar> {
+        let file_id = match *user {
+            UserRef::Telegram(user_id) => {
+                let photos =
+                    telegram_request!(self.bot.get_user_profile_photos(user_id).limit(1)).await;
+                let photo = photos.and_then(|photos| {
+                    photos
+                        .photos
+                        .first()
+                        .and_then(|sizes| sizes.iter().last().cloned())
+                })?;
+                photo.file.id
+            }
+            UserRef::TelegramChat(chat_id) => {
+                let chat = telegram_request!(self.bot.get_chat(chat_id)).await?;
+                chat.photo?.big_file_id
+            }
+            _ => return None,
+        };
+        let file = telegram_request!(self.bot.get_file(file_id.clone())).await?;
+        let t::File {
+            path,
+            meta: t::FileMeta { size, .. },
+            ..
+        } = file;
+        let mut buf = Vec::with_capacity(size as usize);
+        if let Err(e) = self.bot.download_file(&path, &mut buf).await {
+            log::error!("Failed to download file: {e:?}");
+            return None;
+        }
+        Some(Avatar::Bytes(buf))
+    }
+
+    async fn notify(&self, chat: &ChatRef, text: &str) {
+        let &ChatRef::Telegram(telegram_chat) = chat else {
+            return;
+        };
+        telegram_request!(self.bot.send_message(telegram_chat, text)).await;
+    }
+
+    fn format_reactions(&self, reactions: &Reactions) -> String {
+        format::format_telegram_reaction_message(reactions)
+    }
+
+    fn parse_reactions(&self, text: &str) -> Reactions {
+        format::parse_telegram_reaction_message(text)
+    }
+
+    async fn send_reactions(&self, reacted: &MessageRef, text: &str) -> Option<MessageRef> {
+        let &MessageRef::Telegram(telegram_chat, telegram_id) = reacted else {
+            return None;
+        };
+        telegram_request!(self
+            .bot
+            .send_message(telegram_chat, text)
+            .parse_mode(t::ParseMode::Html)
+            .reply_parameters(t::ReplyParameters::new(telegram_id)))
+        .await
+        .map(|telegram_msg| MessageRef::Telegram(telegram_chat, telegram_msg.id))
+    }
+
+    async fn edit_reactions(&self, summary: &MessageRef, text: &str) -> bool {
+        let &MessageRef::Telegram(telegram_chat, telegram_id) = summary else {
+            return false;
+        };
+        telegram_request!(self
+            .bot
+            .edit_message_text(telegram_chat, telegram_id, text)
+            .parse_mode(t::ParseMode::Html))
+        .await
+        .is_some()
+    }
+
+    async fn delete_reactions(&self, summary: &MessageRef) -> bool {
+        self.delete(summary).await
+    }
+}
@@ -0,0 +1,411 @@
+use std::str::FromStr;
+
+use sqlx::postgres::{PgConnectOptions, PgPool, PgRow};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqliteRow};
+
+/// The database engines the bot can store its data in, picked by the scheme of
+/// the database URL.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Engine {
+    Sqlite,
+    Postgres,
+}
+
+impl Engine {
+    pub fn of_url(url: &str) -> Self {
+        if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+            Self::Postgres
+        } else {
+            Self::Sqlite
+        }
+    }
+}
+
+// Queries are written once, with `$1`-style parameters, which both engines
+// understand, and run against whichever pool is in use.
+#[derive(Clone, Debug)]
+pub enum Pool {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
+}
+
+pub enum Transaction {
+    Sqlite(sqlx::Transaction<'static, sqlx::Sqlite>),
+    Postgres(sqlx::Transaction<'static, sqlx::Postgres>),
+}
+
+pub enum Executor<'a> {
+    Pool(&'a Pool),
+    Transaction(&'a mut Transaction),
+}
+
+impl<'a> From<&'a Pool> for Executor<'a> {
+    fn from(pool: &'a Pool) -> Self {
+        Self::Pool(pool)
+    }
+}
+
+impl<'a> From<&'a mut Transaction> for Executor<'a> {
+    fn from(tx: &'a mut Transaction) -> Self {
+        Self::Transaction(tx)
+    }
+}
+
+macro_rules! on_executor {
+    ($executor:expr, $ex:ident => $body:expr) => {
+        match $executor {
+            Executor::Pool(Pool::Sqlite($ex)) => $body,
+            Executor::Pool(Pool::Postgres($ex)) => $body,
+            Executor::Transaction(Transaction::Sqlite(tx)) => {
+                let $ex = &mut **tx;
+                $body
+            }
+            Executor::Transaction(Transaction::Postgres(tx)) => {
+                let $ex = &mut **tx;
+                $body
+            }
+        }
+    };
+}
+
+macro_rules! bind_all {
+    ($query:expr, $args:expr) => {{
+        let mut query = $query;
+        for arg in $args {
+            query = match arg {
+                Value::Bool(v) => query.bind(v),
+                Value::Int(v) => query.bind(v),
+                Value::Text(v) => query.bind(v),
+            };
+        }
+        query
+    }};
+}
+
+impl Pool {
+    pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+        Ok(match Engine::of_url(url) {
+            Engine::Sqlite => {
+                let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+                Self::Sqlite(SqlitePool::connect_with(options).await?)
+            }
+            Engine::Postgres => {
+                // timestamps are stored without a time zone, as UTC like SQLite's
+                let options = PgConnectOptions::from_str(url)?.options([("TimeZone", "UTC")]);
+                Self::Postgres(PgPool::connect_with(options).await?)
+            }
+        })
+    }
+
+    pub fn engine(&self) -> Engine {
+        match self {
+            Self::Sqlite(_) => Engine::Sqlite,
+            Self::Postgres(_) => Engine::Postgres,
+        }
+    }
+
+    pub async fn begin(&self) -> Result<Transaction, sqlx::Error> {
+        Ok(match self {
+            Self::Sqlite(pool) => Transaction::Sqlite(pool.begin().await?),
+            Self::Postgres(pool) => Transaction::Postgres(pool.begin().await?),
+        })
+    }
+
+    pub async fn close(&self) {
+        match self {
+            Self::Sqlite(pool) => pool.close().await,
+            Self::Postgres(pool) => pool.close().await,
+        }
+    }
+}
+
+impl Transaction {
+    /// Runs several statements at once, without parameters.
+    pub async fn execute_script(&mut self, sql: &str) -> Result<(), sqlx::Error> {
+        on_executor!(Executor::Transaction(self), ex => {
+            sqlx::raw_sql(sql).execute(ex).await?;
+        });
+        Ok(())
+    }
+
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        match self {
+            Self::Sqlite(tx) => tx.commit().await,
+            Self::Postgres(tx) => tx.commit().await,
+        }
+    }
+}
+
+/// A query parameter, typed so that Postgres knows the type of a `NULL`.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Bool(Option<bool>),
+    Int(Option<i64>),
+    Text(Option<String>),
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Self::Bool(Some(v))
+    }
+}
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Self {
+        Self::Int(Some(v.into()))
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Self::Int(Some(v))
+    }
+}
+
+impl From<Option<i64>> for Value {
+    fn from(v: Option<i64>) -> Self {
+        Self::Int(v)
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Self::Text(Some(v))
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Self::Text(Some(v.to_string()))
+    }
+}
+
+impl From<&String> for Value {
+    fn from(v: &String) -> Self {
+        Self::Text(Some(v.clone()))
+    }
+}
+
+impl From<Option<String>> for Value {
+    fn from(v: Option<String>) -> Self {
+        Self::Text(v)
+    }
+}
+
+impl From<&Option<String>> for Value {
+    fn from(v: &Option<String>) -> Self {
+        Self::Text(v.clone())
+    }
+}
+
+/// A row type that both engines can decode.
+pub trait FromRow:
+    for<'r> sqlx::FromRow<'r, SqliteRow> + for<'r> sqlx::FromRow<'r, PgRow> + Send + Unpin
+{
+}
+
+impl<T> FromRow for T where
+    T: for<'r> sqlx::FromRow<'r, SqliteRow> + for<'r> sqlx::FromRow<'r, PgRow> + Send + Unpin
+{
+}
+
+pub struct QueryResult {
+    rows_affected: u64,
+}
+
+impl QueryResult {
+    pub fn rows_affected(&self) -> u64 {
+        self.rows_affected
+    }
+}
+
+pub struct Query<'q> {
+    sql: &'q str,
+    args: Vec<Value>,
+}
+
+pub fn query(sql: &str) -> Query<'_> {
+    Query { sql, args: vec![] }
+}
+
+impl Query<'_> {
+    pub fn bind(mut self, value: impl Into<Value>) -> Self {
+        self.args.push(value.into());
+        self
+    }
+
+    pub async fn execute<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<QueryResult, sqlx::Error> {
+        let rows_affected = on_executor!(executor.into(), ex => {
+            bind_all!(sqlx::query(self.sql), self.args)
+                .execute(ex)
+                .await?
+                .rows_affected()
+        });
+        Ok(QueryResult { rows_affected })
+    }
+
+    pub async fn fetch_optional<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<Option<()>, sqlx::Error> {
+        on_executor!(executor.into(), ex => {
+            Ok(bind_all!(sqlx::query(self.sql), self.args)
+                .fetch_optional(ex)
+                .await?
+                .map(|_| ()))
+        })
+    }
+}
+
+pub struct QueryAs<'q, T> {
+    sql: &'q str,
+    args: Vec<Value>,
+    row: std::marker::PhantomData<T>,
+}
+
+pub fn query_as<T: FromRow>(sql: &str) -> QueryAs<'_, T> {
+    QueryAs {
+        sql,
+        args: vec![],
+        row: std::marker::PhantomData,
+    }
+}
+
+impl<T: FromRow> QueryAs<'_, T> {
+    pub fn bind(mut self, value: impl Into<Value>) -> Self {
+        self.args.push(value.into());
+        self
+    }
+
+    pub async fn fetch_all<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<Vec<T>, sqlx::Error> {
+        on_executor!(executor.into(), ex => {
+            bind_all!(sqlx::query_as::<_, T>(self.sql), self.args)
+                .fetch_all(ex)
+                .await
+        })
+    }
+
+    pub async fn fetch_one<'e>(self, executor: impl Into<Executor<'e>>) -> Result<T, sqlx::Error> {
+        on_executor!(executor.into(), ex => {
+            bind_all!(sqlx::query_as::<_, T>(self.sql), self.args)
+                .fetch_one(ex)
+                .await
+        })
+    }
+
+    pub async fn fetch_optional<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<Option<T>, sqlx::Error> {
+        on_executor!(executor.into(), ex => {
+            bind_all!(sqlx::query_as::<_, T>(self.sql), self.args)
+                .fetch_optional(ex)
+                .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn memory_pool() -> Pool {
+        Pool::connect("sqlite::memory:").await.unwrap()
+    }
+
+    #[test]
+    fn test_engine_of_url() {
+        assert_eq!(Engine::of_url("postgres://user@host/db"), Engine::Postgres);
+        assert_eq!(Engine::of_url("postgresql://host/db"), Engine::Postgres);
+        assert_eq!(Engine::of_url("sqlite:messages.db"), Engine::Sqlite);
+        assert_eq!(Engine::of_url("messages.db"), Engine::Sqlite);
+    }
+
+    #[tokio::test]
+    async fn test_numbered_parameters() {
+        let pool = memory_pool().await;
+        // parameters are bound by number, so they can be reused and out of order
+        let row = query_as::<(String, i64, String)>("SELECT $2, $1 + $1, $2")
+            .bind(21)
+            .bind("text")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(row, ("text".to_string(), 42, "text".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_null_parameters() {
+        let pool = memory_pool().await;
+        let row = query_as::<(Option<i64>, Option<String>)>("SELECT $1, $2")
+            .bind(None::<i64>)
+            .bind(None::<String>)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(row, (None, None));
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let pool = memory_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        tx.execute_script(
+            "CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT NOT NULL, done BOOLEAN NOT NULL);
+            INSERT INTO items (name, done) VALUES ('first', 0);",
+        )
+        .await
+        .unwrap();
+        let inserted = query("INSERT INTO items (name, done) VALUES ($1, $2)")
+            .bind("second")
+            .bind(true)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(inserted.rows_affected(), 1);
+        tx.commit().await.unwrap();
+
+        let items = query_as::<(String, bool)>("SELECT name, done FROM items ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            items,
+            vec![("first".to_string(), false), ("second".to_string(), true)]
+        );
+        let missing = query("SELECT 1 FROM items WHERE name = $1")
+            .bind("third")
+            .fetch_optional(&pool)
+            .await
+            .unwrap();
+        assert!(missing.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rolled_back_transaction() {
+        let pool = memory_pool().await;
+        query("CREATE TABLE items (name TEXT NOT NULL)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        query("INSERT INTO items (name) VALUES ($1)")
+            .bind("dropped")
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        drop(tx);
+
+        let (count,) = query_as::<(i64,)>("SELECT COUNT(*) FROM items")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}